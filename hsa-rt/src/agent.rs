@@ -76,6 +76,9 @@ pub enum DeviceType {
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct MachineModels(bool, bool);
 impl MachineModels {
+  pub fn new(small: bool, large: bool) -> Self {
+    MachineModels(small, large)
+  }
   pub fn supports_small(&self) -> bool {
     self.0
   }
@@ -113,6 +116,10 @@ pub struct DefaultFloatRoundingModes(pub(crate) bool,
                                      pub(crate) bool,
                                      pub(crate) bool);
 impl DefaultFloatRoundingModes {
+  pub fn new(default: bool, zero: bool, near: bool) -> Self {
+    DefaultFloatRoundingModes(default, zero, near)
+  }
+
   /// As (poorly) named by the spec.
   pub fn supports_default(&self) -> bool { self.0 }
   pub fn supports_zero(&self) -> bool { self.1 }
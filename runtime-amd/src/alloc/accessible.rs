@@ -4,17 +4,29 @@ use std::sync::atomic::{AtomicU64, Ordering};
 
 use indexvec::Idx;
 
+use crate::grt_core::context::WeakContext;
+
 /// As of time of writ, the largest count of GPUs per server I could find for any price was 32.
 /// A more common max was 20 GPUs per server.
 pub struct Accessible {
   bits: AtomicU64,
   pub(crate) lock: Mutex<()>,
+  /// Accelerator ids are only unique within a context.
+  ctx: WeakContext,
 }
 
 impl Accessible {
   #[inline(always)]
-  pub(crate) fn new_local(id: AcceleratorId) -> Self {
-    let mut this = Accessible::default();
+  pub(crate) fn new(ctx: &Context) -> Self {
+    Accessible {
+      bits: AtomicU64::new(0),
+      lock: Mutex::new(()),
+      ctx: ctx.downgrade_ref(),
+    }
+  }
+  #[inline(always)]
+  pub(crate) fn new_local(ctx: &Context, id: AcceleratorId) -> Self {
+    let mut this = Accessible::new(ctx);
     this.set_mut(id);
     this
   }
@@ -55,7 +67,11 @@ impl Accessible {
 
     if bitvec == 0 { return; }
 
-    let ctx = Context::global().expect("no Geobacter context?");
+    let ctx = match self.ctx.upgrade() {
+      Some(ctx) => ctx,
+      // no context, no devices.
+      None => { return; },
+    };
     for i in 0..64 {
       if bitvec & (1 << i) != 0 {
         let id = AcceleratorId::new(i as _);
@@ -72,21 +88,13 @@ impl Accessible {
     bits.count_ones() as _
   }
 }
-impl Default for Accessible {
-  #[inline(always)]
-  fn default() -> Self {
-    Accessible {
-      bits: AtomicU64::new(0),
-      lock: Mutex::new(()),
-    }
-  }
-}
 impl Clone for Accessible {
   #[inline(always)]
   fn clone(&self) -> Self {
     Accessible {
       bits: AtomicU64::new(self.bits.load(Ordering::Acquire)),
       lock: Mutex::new(()),
+      ctx: self.ctx.clone(),
     }
   }
 }
@@ -1,26 +1,216 @@
 
+use grt_core::AcceleratorTargetDesc;
 use grt_core::codegen::attrs::*;
 
-use rustc_ast::ast::MetaItem;
+use rustc_ast::ast::{MetaItem, LitKind, };
 use rustc_middle::ty::TyCtxt;
 use rustc_span::symbol::Symbol;
 
+use crate::HsaAmdTargetDescHelper;
+
 #[derive(Clone, Debug, Hash, Eq, PartialEq)]
 pub enum Condition {
-  /// XXX make more specific
+  /// `platform = "amdgpu"`
   Platform,
+  /// `arch = "gfx906"`: the GPU's processor name.
+  Arch(String),
+  /// `feature = "xnack"`: an LLVM AMDGPU target feature, eg `xnack`,
+  /// `sramecc`, or `dot2-insts`.
+  Feature(String),
+  /// `wavefront_size = 64`
+  WavefrontSize(u32),
 }
 impl ConditionItem for Condition {
   fn parse_name_value(tcx: TyCtxt, item: &MetaItem) -> Option<Self> {
-    if item.has_name(Symbol::intern("platform")) &&
-      item.value_str().unwrap().as_str() == "amdgpu" {
-      return Some(Condition::Platform);
+    let value_str = || {
+      let v = item.value_str().map(|v| v.as_str().to_string() );
+      if v.is_none() {
+        tcx.sess.span_err(item.span, "expected a string literal");
+      }
+      v
+    };
+
+    if item.has_name(Symbol::intern("platform")) {
+      return match value_str() {
+        Some(ref platform) if platform == "amdgpu" => Some(Condition::Platform),
+        // some other platform.
+        _ => None,
+      };
+    } else if item.has_name(Symbol::intern("arch")) {
+      return value_str().map(Condition::Arch);
+    } else if item.has_name(Symbol::intern("feature")) {
+      return value_str().map(Condition::Feature);
+    } else if item.has_name(Symbol::intern("wavefront_size")) {
+      return match item.name_value_literal().map(|lit| &lit.kind ) {
+        Some(&LitKind::Int(v, _)) => {
+          u32_from(tcx, item.span, v).map(Condition::WavefrontSize)
+        },
+        _ => {
+          tcx.sess.span_err(item.span, "expected an integer literal");
+          None
+        },
+      };
     }
-    let msg = format!("unknown attr key `{}`; (no keys currently)",
+    let msg = format!("unknown attr key `{}`; expected one of `platform`, \
+                       `arch`, `feature`, or `wavefront_size`",
                       item.name_or_empty());
     tcx.sess.span_err(item.span, &msg);
 
-
     None
   }
+
+  fn eval(&self, target_desc: &AcceleratorTargetDesc, root_conditions: &[Self]) -> bool {
+    match self {
+      Condition::Platform => root_conditions.contains(self),
+      Condition::Arch(arch) => target_desc.target.options.cpu == *arch,
+      Condition::Feature(feature) => {
+        has_feature(&target_desc.target.options.cpu,
+                    &target_desc.target.options.features,
+                    feature)
+      },
+      Condition::WavefrontSize(size) => {
+        target_desc.isa_info().wavefronts.iter()
+          .any(|wavefront| wavefront.size == *size )
+      },
+    }
+  }
+}
+
+/// Features are enabled explicitly in `features` (which includes those in the
+/// HSA ISA name, eg `xnack`), or implied by the processor.
+fn has_feature(arch: &str, features: &str, feature: &str) -> bool {
+  let explicit = features.split(',')
+    .filter(|f| f.len() > 1 )
+    .find(|f| &f[1..] == feature );
+  if let Some(f) = explicit {
+    return f.starts_with('+');
+  }
+
+  implied_features(arch).contains(&feature)
+}
+/// Instruction set features LLVM enables by default for `arch`, but which
+/// don't appear in the ISA name. This mirrors LLVM's processor definitions;
+/// the tests check it against `llc`.
+fn implied_features(arch: &str) -> &'static [&'static str] {
+  match arch {
+    "gfx906" => &["dot1-insts", "dot2-insts", ],
+    "gfx908" => &["dot1-insts", "dot2-insts", "dot3-insts", "dot4-insts",
+                  "dot5-insts", "dot6-insts", "mai-insts", ],
+    "gfx1011" | "gfx1012" | "gfx1030" => {
+      &["dot1-insts", "dot2-insts", "dot5-insts", "dot6-insts", ]
+    },
+    _ => &[],
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use grt_core::codegen::help::LlvmBuildRoot;
+
+  #[test]
+  fn features() {
+    let features = "+code-object-v3,-xnack,+sramecc,+dpp";
+    assert!(has_feature("gfx906", features, "sramecc"));
+    assert!(!has_feature("gfx906", features, "xnack"));
+    assert!(has_feature("gfx906", features, "dot2-insts"));
+    assert!(!has_feature("gfx900", features, "dot2-insts"));
+    assert!(!has_feature("gfx906", features, "mai-insts"));
+    // explicit features override the processor's defaults:
+    assert!(!has_feature("gfx906", "-dot1-insts", "dot1-insts"));
+  }
+
+  const ARCHES: &[&str] = &["gfx900", "gfx906", "gfx908", "gfx1011", "gfx1012", "gfx1030", ];
+
+  /// Kernels which only compile if the processor has the feature.
+  const PROBES: &[(&str, &str)] = &[
+    ("dot2-insts", r#"
+      define amdgpu_kernel void @probe(float addrspace(1)* %out, <2 x half> %a, <2 x half> %b) {
+        %r = call float @llvm.amdgcn.fdot2(<2 x half> %a, <2 x half> %b, float 0.0, i1 false)
+        store float %r, float addrspace(1)* %out
+        ret void
+      }
+      declare float @llvm.amdgcn.fdot2(<2 x half>, <2 x half>, float, i1)
+    "#),
+    ("mai-insts", r#"
+      define amdgpu_kernel void @probe(<4 x float> addrspace(1)* %out, float %a, float %b) {
+        %r = call <4 x float> @llvm.amdgcn.mfma.f32.4x4x1f32(float %a, float %b, <4 x float> zeroinitializer, i32 0, i32 0, i32 0)
+        store <4 x float> %r, <4 x float> addrspace(1)* %out
+        ret void
+      }
+      declare <4 x float> @llvm.amdgcn.mfma.f32.4x4x1f32(float, float, <4 x float>, i32, i32, i32)
+    "#),
+  ];
+
+  /// `None`, so the test is skipped, if there's no LLVM build to check
+  /// against.
+  fn llvm() -> Option<LlvmBuildRoot> {
+    match LlvmBuildRoot::find() {
+      Ok(root) => Some(root),
+      Err(err) => {
+        eprintln!("skipping: {}", err);
+        None
+      },
+    }
+  }
+  fn llc(llvm: &LlvmBuildRoot, arch: &str) -> std::process::Command {
+    let mut cmd = std::process::Command::new(llvm.llc());
+    cmd.arg("-mtriple=amdgcn-amd-amdhsa")
+      .arg(format!("-mcpu={}", arch));
+    cmd
+  }
+
+  #[test]
+  fn implied_features_match_llvm() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let llvm = match llvm() {
+      Some(llvm) => llvm,
+      None => { return; },
+    };
+    for &arch in ARCHES.iter() {
+      for &(feature, ir) in PROBES.iter() {
+        let mut llc = llc(&llvm, arch)
+          .arg("-filetype=null")
+          .stdin(Stdio::piped())
+          .stdout(Stdio::null())
+          .stderr(Stdio::null())
+          .spawn()
+          .expect("failed to run llc");
+        llc.stdin.take().unwrap()
+          .write_all(ir.as_bytes())
+          .unwrap();
+        let llvm_has = llc.wait().unwrap().success();
+        assert_eq!(llvm_has, has_feature(arch, "", feature),
+                   "`{}` on {}", feature, arch);
+      }
+    }
+  }
+
+  #[test]
+  fn implied_features_are_known_to_llvm() {
+    use std::process::Stdio;
+
+    let llvm = match llvm() {
+      Some(llvm) => llvm,
+      None => { return; },
+    };
+    for &arch in ARCHES.iter() {
+      // lists every feature of the target:
+      let out = llc(&llvm, arch)
+        .arg("-mattr=help")
+        .arg("-filetype=null")
+        .stdin(Stdio::null())
+        .output()
+        .expect("failed to run llc");
+      let help = format!("{}{}", String::from_utf8_lossy(&out.stdout),
+                         String::from_utf8_lossy(&out.stderr));
+      for &feature in implied_features(arch).iter() {
+        let known = help.lines()
+          .any(|line| line.split_whitespace().next() == Some(feature) );
+        assert!(known, "LLVM doesn't know `{}` (implied for {})", feature, arch);
+      }
+    }
+  }
 }
@@ -1,6 +1,5 @@
 
-use std::collections::HashMap;
-use std::env::var_os;
+use std::collections::{BTreeMap, HashMap, };
 use std::fs::{File, };
 use std::geobacter::platform::{*, hsa::AmdGcn, };
 use std::io::{Write, Read, stderr, };
@@ -39,18 +38,51 @@ pub mod attrs;
 #[derive(Clone, Copy, Debug, Default)]
 pub struct Codegenner;
 
+/// Call at the start of `main` in programs which enable subprocess codegen;
+/// see `grt_core::codegen::run_codegen_helper`.
+pub fn run_codegen_helper() {
+  core_codegen::run_codegen_helper(Codegenner::default());
+}
+
 impl PlatformCodegen for Codegenner {
   type Device = HsaAmdGpuAccel;
   type KernelDesc = KernelDesc;
   type CodegenDesc = CodegenDesc;
   type Condition = self::attrs::Condition;
 
+  /// Report panics to the host instead of just aborting, and allocate
+  /// from the dispatch's device heap.
+  fn stubs(&self) -> BTreeMap<StubTarget, Stub> {
+    let mut stubs = crate::module::panic::stubs();
+    stubs.extend(crate::module::heap::stubs());
+    stubs
+  }
+
   fn modify_rustc_session_options(&self, _target_desc: &Arc<AcceleratorTargetDesc>,
                                   opts: &mut rustc_session::config::Options)
   {
     // we sometimes get a SCEV assertion in the SLP vectorizer; since it's not needed here,
     // just disable.
     opts.cg.no_vectorize_slp = true;
+
+    opts.cg.llvm_args.push("-amdgpu-early-inline-all".into());
+    opts.cg.llvm_args.push("-amdgpu-prelink".into());
+  }
+
+  fn encode_codegen_desc(&self, desc: &CodegenDesc) -> Option<Vec<u8>> {
+    rmps::encode::to_vec(desc).ok()
+  }
+  fn decode_codegen_desc(&self, bytes: &[u8]) -> Option<CodegenDesc> {
+    rmps_from_slice(bytes).ok()
+  }
+  fn encode_kernel_desc(&self, desc: &KernelDesc) -> Option<Vec<u8>> {
+    rmps::encode::to_vec(desc).ok()
+  }
+  fn decode_kernel_desc(&self, bytes: &[u8]) -> Option<KernelDesc> {
+    rmps_from_slice(bytes).ok()
+  }
+  fn decode_target_desc(&self, bytes: &[u8]) -> Option<AcceleratorTargetDesc> {
+    core_codegen::decode_target_desc::<crate::TargetDesc>(bytes)
   }
 
   fn insert_intrinsics<F>(&self,
@@ -105,6 +137,7 @@ impl PlatformCodegen for Codegenner {
 
   fn post_codegen(&self,
                   target_desc: &Arc<AcceleratorTargetDesc>,
+                  options: &CodegenOptions,
                   tdir: &Path,
                   codegen: &mut PCodegenResults<Self>)
     -> Result<(), Error>
@@ -131,19 +164,7 @@ impl PlatformCodegen for Codegenner {
         out.write_all(&bc)?;
       }
 
-      // be helpful if this var isn't set:
-      if var_os("RUST_BUILD_ROOT").is_none() &&
-        var_os("LLVM_BUILD").is_none() {
-        println!("Due to some required LLVM patches, I need a build of \
-                 Geobacter's LLVM");
-        println!("You shouldn't need to build this separately; it should \
-                 have been built with Rust");
-        println!("Set either LLVM_BUILD or RUST_BUILD_ROOT \
-                 (RUST_BUILD_ROOT takes priority)");
-        println!("XXX Temporary");
-      }
-
-      let llvm = LlvmBuildRoot::default();
+      let llvm = LlvmBuildRoot::find()?;
       let llc = llvm.llc();
       let llc_cmd = || {
         let mut cmd = Command::new(&llc);
@@ -152,7 +173,7 @@ impl PlatformCodegen for Codegenner {
           .arg(format!("-mcpu={}", target_desc.target.options.cpu))
           .arg(format!("-mattr={}", target_desc.target.options.features))
           .arg("-relocation-model=pic")
-          .arg("-O3");
+          .arg(options.opt_level.llc_arg());
         cmd
       };
 
@@ -6,6 +6,8 @@ use std::io::Error as IoError;
 
 use alloc_wg::alloc::Layout;
 
+use grt_core::codegen::diagnostics::Diagnostics;
+
 use hsa_rt::queue::QueueError;
 
 use crate::HsaError;
@@ -23,12 +25,17 @@ pub enum Error {
   KernelInfoMessagePack(rmps::decode::Error),
   ConvertKernelInstance(KernelInstanceRef<'static>),
   ContextDead,
-  Codegen,
-  Linking,
+  /// The accelerator was retired from its context.
+  AcceleratorRetired(grt_core::AcceleratorId),
+  Codegen(Diagnostics),
+  Linking(Diagnostics),
   NoCpuAgent,
   NoGpuAgent,
   NoGpuAgentIsa,
   UnknownAmdGpuArch(String),
+  /// The accelerator target desc given to a virtual accelerator doesn't
+  /// describe an AMDGPU.
+  NotAmdGpuTargetDesc,
   UnsupportedBigEndianHost,
   MissingKernelArgumentsRegion,
   MissingHostLocalFineGrainedPool,
@@ -40,10 +47,23 @@ pub enum Error {
   CodegenInitConditions(Box<Error>),
   CodegenPreCodegen(Box<Error>),
   CodegenPostCodegen(Box<Error>),
+  KernelArgLayoutMismatch(Box<grt_core::codegen::host::LayoutMismatch>),
+  /// The layout of a kernel argument type couldn't be computed.
+  KernelArgLayout(String),
+  UnknownSpecParam(String),
+  SpecParam(grt_core::codegen::SpecParamError),
+  /// The codegen helper process failed; see
+  /// `CodegenDriver::set_subprocess_codegen`.
+  CodegenSubprocess(String),
+  /// The codegen server couldn't be reached, or failed; see
+  /// `CodegenDriver::set_codegen_server`.
+  CodegenServer(String),
   Underflow,
   Overflow,
   /// The dispatch grid has a zero length along one or more of it's axes.
   ZeroGridLaunchAxis,
+  /// A `LaunchGrid` isn't one along an axis the kernel's grid doesn't have.
+  UnusedLaunchGridAxis(grt_core::launch::LaunchGrid),
   KernelArgsPoolOom,
   HsaQueue(QueueError),
   Alloc(Layout),
@@ -51,6 +71,33 @@ pub enum Error {
   KernelWorkgroupLenTooLargeForDevice,
   LaunchGridDimTooLargeForDevice,
   LaunchGridLenTooLargeForDevice,
+  /// A workitem of the dispatch panicked, aborting the dispatch.
+  KernelPanicked(Box<crate::module::panic::DevicePanic>),
+  /// The dispatch's completion signal was set negative, but no panic was
+  /// recorded.
+  NegativeCompletionSignal(crate::signal::Value),
+  /// The kernel's `DeviceHeap` is still in use by another dispatch.
+  DeviceHeapInUse,
+  /// A device allocation failed, aborting the dispatch.
+  DeviceHeapExhausted {
+    size: usize,
+    align: usize,
+  },
+}
+impl Error {
+  /// The rustc/LLVM diagnostics emitted by a failed codegen, if this is a
+  /// codegen error.
+  pub fn diagnostics(&self) -> Option<&Diagnostics> {
+    match self {
+      Error::Codegen(diagnostics) |
+      Error::Linking(diagnostics) => Some(diagnostics),
+      Error::CodegenInitConditions(inner) |
+      Error::CodegenInitRoot(inner) |
+      Error::CodegenPostCodegen(inner) |
+      Error::CodegenPreCodegen(inner) => inner.diagnostics(),
+      _ => None,
+    }
+  }
 }
 impl StdError for Error {
   fn source(&self) -> Option<&(dyn StdError + 'static)> {
@@ -64,6 +111,8 @@ impl StdError for Error {
       Error::CodegenInitRoot(inner) |
       Error::CodegenPostCodegen(inner) |
       Error::CodegenPreCodegen(inner) => Some(inner),
+      Error::KernelArgLayoutMismatch(inner) => Some(&**inner),
+      Error::SpecParam(inner) => Some(inner),
       _ => None,
     }
   }
@@ -127,13 +176,20 @@ impl From<grt_core::codegen::error::Error<Error>> for Error {
       Io(_, err) => Error::Io(err),
       LoadMetadata(err) => Error::LoadRustcMetadata(err),
       ConvertKernelInstance(ki) => Error::ConvertKernelInstance(ki),
-      Codegen => Error::Codegen,
-      Linking => Error::Linking,
+      Codegen(diagnostics) => Error::Codegen(diagnostics),
+      Linking(diagnostics) => Error::Linking(diagnostics),
       InitRoot(inner) => Error::CodegenInitRoot(Box::new(inner)),
       InitConditions(inner) => Error::CodegenInitConditions(Box::new(inner)),
       PreCodegen(inner) => Error::CodegenPreCodegen(Box::new(inner)),
       PostCodegen(inner) => Error::CodegenPostCodegen(Box::new(inner)),
+      LayoutMismatch(inner) => Error::KernelArgLayoutMismatch(inner),
+      Layout(msg) => Error::KernelArgLayout(msg),
+      UnknownSpecParam(param) => Error::UnknownSpecParam(param),
+      SpecParam(inner) => Error::SpecParam(inner),
+      Subprocess(msg) => Error::CodegenSubprocess(msg),
+      Server(msg) => Error::CodegenServer(msg),
       ContextDead => Error::ContextDead,
+      AcceleratorRetired(id) => Error::AcceleratorRetired(id),
     }
   }
 }
@@ -150,6 +206,38 @@ impl From<Box<dyn StdError + Send + Sync + 'static>> for Error {
 }
 impl fmt::Display for Error {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    write!(f, "{:?}", self)
+    match self {
+      Error::Codegen(diagnostics) => {
+        write!(f, "codegen failed:\n{}", diagnostics)
+      },
+      Error::Linking(diagnostics) => {
+        write!(f, "linking failed:\n{}", diagnostics)
+      },
+      Error::UnknownSpecParam(param) => {
+        write!(f, "spec param `{}` doesn't match any function in this program", param)
+      },
+      Error::SpecParam(inner) => fmt::Display::fmt(inner, f),
+      Error::CodegenSubprocess(msg) => write!(f, "subprocess codegen failed: {}", msg),
+      Error::CodegenServer(msg) => write!(f, "codegen server failed: {}", msg),
+      Error::UnusedLaunchGridAxis(grid) => {
+        write!(f, "launch grid {:?} isn't one along an axis the kernel doesn't use",
+               grid)
+      },
+      Error::KernelArgLayoutMismatch(inner) => fmt::Display::fmt(inner, f),
+      Error::KernelArgLayout(msg) => {
+        write!(f, "kernel argument layout check failed: {}", msg)
+      },
+      Error::KernelPanicked(panic) => {
+        write!(f, "kernel panicked: {}", panic)
+      },
+      Error::DeviceHeapExhausted { size, align, } => {
+        write!(f, "device heap exhausted: failed to allocate {} bytes (align {})",
+               size, align)
+      },
+      Error::NegativeCompletionSignal(code) => {
+        write!(f, "got negative signal from dispatch: {}", code)
+      },
+      _ => write!(f, "{:?}", self),
+    }
   }
 }
@@ -38,6 +38,7 @@
 // #![warn(incomplete_features)] XXX can't just allow ^
 
 extern crate any_key;
+extern crate erased_serde;
 extern crate goblin;
 extern crate tracing as log;
 extern crate serde;
@@ -118,6 +119,7 @@ pub mod error;
 pub mod lds;
 pub mod mem;
 pub mod module;
+pub mod offline;
 pub mod signal;
 pub mod texture;
 
@@ -266,6 +268,7 @@ impl HsaAmdGpuAccel {
       .info()?;
     let target_desc = TargetDesc {
       isa,
+      // set by `init_target_desc`, once it's known:
       has_pcie_large_bar: false,
     };
 
@@ -289,6 +292,7 @@ impl HsaAmdGpuAccel {
       kernarg_region,
 
       self_codegen: None,
+      // determined below:
       has_pcie_large_bar: false,
     };
     out.determine_large_bar_support();
@@ -380,7 +384,7 @@ impl HsaAmdGpuAccel {
   pub unsafe fn coarse_lap_node_alloc(&self, node: u32) -> alloc::LapAlloc {
     alloc::LapAlloc {
       pool: self.host_nodes()[node as usize].coarse.pool(),
-      accessible: Default::default(),
+      accessible: alloc::accessible::Accessible::new(&self.ctx),
     }
   }
   /// Returns an allocator interface for allocating in the provided NUMA node
@@ -393,7 +397,7 @@ impl HsaAmdGpuAccel {
         .as_ref()
         .unwrap()
         .pool(),
-      accessible: Default::default(),
+      accessible: alloc::accessible::Accessible::new(&self.ctx),
     }
   }
 
@@ -434,7 +438,7 @@ impl HsaAmdGpuAccel {
   pub unsafe fn unchecked_device_lap_alloc(&self) -> alloc::LapAlloc {
     alloc::LapAlloc {
       pool: self.device.coarse.pool(),
-      accessible: alloc::accessible::Accessible::new_local(self.id),
+      accessible: alloc::accessible::Accessible::new_local(&self.ctx, self.id),
     }
   }
 
@@ -867,186 +871,195 @@ impl HsaAmdGpuAccel {
     self.has_pcie_large_bar = accessible;
   }
   fn init_target_desc(&mut self) -> Result<(), Error> {
-    use rustc_target::spec::{PanicStrategy, abi::Abi, AddrSpaceKind,
-                             AddrSpaceIdx, AddrSpaceProps, CodeModel};
-
     let desc = Arc::get_mut(&mut self.target_desc).unwrap();
 
-    desc.has_pcie_large_bar = self.has_pcie_large_bar;
-    desc.allow_indirect_function_calls = true;
-    desc.kernel_abi = Abi::AmdGpuKernel;
+    let mut platform = desc.platform_data().clone();
+    platform.has_pcie_large_bar = self.has_pcie_large_bar;
+    desc.platform = Box::new(platform);
 
-    // we get the triple and gpu "cpu" from the name of the isa:
-    let full = {
-      &TargetDesc::downcast_ref(&*desc.platform)
-        .expect("accelerator target isn't HSA")
-        .isa
-        .name
-    };
-    let (triple, isa_target_features) = full.find(':')
-      .map(|idx| {
-        (&full[..idx], if idx < full.len() {
-          &full[idx + 1..]
-        } else {
-          Default::default()
-        })
-      })
-      .unwrap_or((&full[..], ""));
+    init_target_desc(desc)
+  }
+}
 
-    let mut isa_target_features = isa_target_features
-      .split(|c| c == ':' )
-      .filter_map(|feature| {
-        if feature.len() < 1 {
+/// Fill in the Rustc target from the ISA in `desc`'s platform desc. Used
+/// for both real devices and virtual accelerators.
+fn init_target_desc(desc: &mut AcceleratorTargetDesc) -> Result<(), Error> {
+  use rustc_target::spec::{PanicStrategy, abi::Abi, AddrSpaceKind,
+                           AddrSpaceIdx, AddrSpaceProps, CodeModel};
+
+  desc.allow_indirect_function_calls = true;
+  desc.kernel_abi = Abi::AmdGpuKernel;
+
+  // we get the triple and gpu "cpu" from the name of the isa:
+  let full = {
+    &TargetDesc::downcast_ref(&*desc.platform)
+      .ok_or(Error::NotAmdGpuTargetDesc)?
+      .isa
+      .name
+  };
+  let (triple, isa_target_features) = full.find(':')
+    .map(|idx| {
+      (&full[..idx], if idx < full.len() {
+        &full[idx + 1..]
+      } else {
+        Default::default()
+      })
+    })
+    .unwrap_or((&full[..], ""));
+
+  let mut isa_target_features = isa_target_features
+    .split(|c| c == ':' )
+    .filter_map(|feature| {
+      if feature.len() < 1 {
+        warn!("got weird GPU target triple: `{}`", full);
+        return None;
+      }
+      let (feature, pm) = feature.split_at(feature.len() - 1);
+      let pm = match pm {
+        "-" => false,
+        "+" => true,
+        _ => {
           warn!("got weird GPU target triple: `{}`", full);
           return None;
-        }
-        let (feature, pm) = feature.split_at(feature.len() - 1);
-        let pm = match pm {
-          "-" => false,
-          "+" => true,
-          _ => {
-            warn!("got weird GPU target triple: `{}`", full);
-            return None;
-          },
-        };
-
-        Some((feature, pm))
-      })
-      .collect::<HashMap<_, _>>();
-    isa_target_features.insert("code-object-v3", true); // Must be enabled
-
-    let add_target_feature = |target_features: &mut HashMap<_, _>, feature| {
-      match target_features.entry(feature) {
-        HashMapEntry::Occupied(_) => {
-          // Don't override if present in the HSA ISA name
-        },
-        HashMapEntry::Vacant(v) => {
-          v.insert(true);
         },
-      }
-    };
-
-    let cpu = {
-      let idx = triple.rfind('-')
-        .expect("expected at least one hyphen in the AMDGPU ISA name");
-      assert_ne!(idx, triple.len(),
-                 "AMDGPU ISA target triple has no cpu model, or something else weird");
-      triple[idx + 1..].into()
-    };
+      };
 
-    desc.target.llvm_target = "amdgcn-amd-amdhsa-amdgiz".into();
-    desc.target.options.cpu = cpu;
+      Some((feature, pm))
+    })
+    .collect::<HashMap<_, _>>();
+  isa_target_features.insert("code-object-v3", true); // Must be enabled
 
-    add_target_feature(&mut isa_target_features, "dpp");
-    add_target_feature(&mut isa_target_features, "s-memrealtime");
-    if desc.isa_info().fast_f16 {
-      add_target_feature(&mut isa_target_features, "16-bit-insts");
+  let add_target_feature = |target_features: &mut HashMap<_, _>, feature| {
+    match target_features.entry(feature) {
+      HashMapEntry::Occupied(_) => {
+        // Don't override if present in the HSA ISA name
+      },
+      HashMapEntry::Vacant(v) => {
+        v.insert(true);
+      },
     }
+  };
 
+  // the cpu model is after the last hyphen:
+  let cpu = match triple.rfind('-') {
+    Some(idx) if idx + 1 < triple.len() => triple[idx + 1..].to_string(),
+    _ => { return Err(Error::UnknownAmdGpuArch(full.clone())); },
+  };
+  AmdGcn::from_str(&cpu)
+    .map_err(|()| Error::UnknownAmdGpuArch(cpu.clone()) )?;
 
-    {
-      let len = isa_target_features.keys()
-        .fold(0, |acc, feature| {
-          acc + feature.len()
-        }) + 2 * isa_target_features.len() - 1;
-
-      desc.target.options.features = isa_target_features.into_iter()
-        .fold(String::with_capacity(len), |mut acc, (feature, enabled)| {
-          if !acc.is_empty() {
-            acc.push(',');
-          }
-          acc.push(if enabled { '+' } else { '-' });
-          acc.push_str(feature);
-          acc
-        });
-    }
+  desc.target.llvm_target = "amdgcn-amd-amdhsa-amdgiz".into();
+  desc.target.options.cpu = cpu;
 
-    desc.target.target_endian = desc.host_target
-      .target_endian
-      .clone();
-    desc.target.target_pointer_width = desc.host_target
-      .target_pointer_width
-      .clone();
-
-    let target = &mut desc.target;
-
-    target.arch = "amdgpu".into();
-    target.data_layout = "e-p:64:64-p1:64:64-p2:32:32-p3:32:32-\
-                          p4:64:64-p5:32:32-p6:32:32-i64:64-v16:16-\
-                          v24:32-v32:32-v48:64-v96:128-v192:256-\
-                          v256:256-v512:512-v1024:1024-v2048:2048-\
-                          n32:64-S32-A5-ni:7".into();
-    target.options.panic_strategy = PanicStrategy::Abort;
-    target.options.trap_unreachable = true;
-    target.options.position_independent_executables = true;
-    target.options.dynamic_linking = true;
-    target.options.executables = true;
-    target.options.requires_lto = false;
-    target.options.atomic_cas = true;
-    target.options.default_codegen_units = Some(1);
-    target.options.obj_is_bitcode = false;
-    target.options.is_builtin = false;
-    target.options.simd_types_indirect = false;
-    target.options.stack_probes = false;
-    target.options.code_model = Some(CodeModel::Small);
-    {
-      let addr_spaces = &mut target.options.addr_spaces;
-      addr_spaces.clear();
+  add_target_feature(&mut isa_target_features, "dpp");
+  add_target_feature(&mut isa_target_features, "s-memrealtime");
+  if desc.isa_info().fast_f16 {
+    add_target_feature(&mut isa_target_features, "16-bit-insts");
+  }
+
+
+  {
+    let len = isa_target_features.keys()
+      .fold(0, |acc, feature| {
+        acc + feature.len()
+      }) + 2 * isa_target_features.len() - 1;
+
+    desc.target.options.features = isa_target_features.into_iter()
+      .fold(String::with_capacity(len), |mut acc, (feature, enabled)| {
+        if !acc.is_empty() {
+          acc.push(',');
+        }
+        acc.push(if enabled { '+' } else { '-' });
+        acc.push_str(feature);
+        acc
+      });
+  }
+
+  desc.target.target_endian = desc.host_target
+    .target_endian
+    .clone();
+  desc.target.target_pointer_width = desc.host_target
+    .target_pointer_width
+    .clone();
+
+  let target = &mut desc.target;
+
+  target.arch = "amdgpu".into();
+  target.data_layout = "e-p:64:64-p1:64:64-p2:32:32-p3:32:32-\
+                        p4:64:64-p5:32:32-p6:32:32-i64:64-v16:16-\
+                        v24:32-v32:32-v48:64-v96:128-v192:256-\
+                        v256:256-v512:512-v1024:1024-v2048:2048-\
+                        n32:64-S32-A5-ni:7".into();
+  target.options.panic_strategy = PanicStrategy::Abort;
+  target.options.trap_unreachable = true;
+  target.options.position_independent_executables = true;
+  target.options.dynamic_linking = true;
+  target.options.executables = true;
+  target.options.requires_lto = false;
+  target.options.atomic_cas = true;
+  target.options.default_codegen_units = Some(1);
+  target.options.obj_is_bitcode = false;
+  target.options.is_builtin = false;
+  target.options.simd_types_indirect = false;
+  target.options.stack_probes = false;
+  target.options.code_model = Some(CodeModel::Small);
+  {
+    let addr_spaces = &mut target.options.addr_spaces;
+    addr_spaces.clear();
+
+    let flat = AddrSpaceKind::Flat;
+    let flat_idx = AddrSpaceIdx(0);
 
-      let flat = AddrSpaceKind::Flat;
-      let flat_idx = AddrSpaceIdx(0);
+    let global = AddrSpaceKind::ReadWrite;
+    let global_idx = AddrSpaceIdx(1);
 
-      let global = AddrSpaceKind::ReadWrite;
-      let global_idx = AddrSpaceIdx(1);
+    let region = AddrSpaceKind::from_str("region").unwrap();
+    let region_idx = AddrSpaceIdx(2);
 
-      let region = AddrSpaceKind::from_str("region").unwrap();
-      let region_idx = AddrSpaceIdx(2);
+    let local = AddrSpaceKind::from_str("local").unwrap();
+    let local_idx = AddrSpaceIdx(3);
 
-      let local = AddrSpaceKind::from_str("local").unwrap();
-      let local_idx = AddrSpaceIdx(3);
+    let constant = AddrSpaceKind::ReadOnly;
+    let constant_idx = AddrSpaceIdx(4);
 
-      let constant = AddrSpaceKind::ReadOnly;
-      let constant_idx = AddrSpaceIdx(4);
+    let private = AddrSpaceKind::Alloca;
+    let private_idx = AddrSpaceIdx(5);
 
-      let private = AddrSpaceKind::Alloca;
-      let private_idx = AddrSpaceIdx(5);
+    let constant_32b = AddrSpaceKind::from_str("32bit constant").unwrap();
+    let constant_32b_idx = AddrSpaceIdx(6);
 
-      let constant_32b = AddrSpaceKind::from_str("32bit constant").unwrap();
-      let constant_32b_idx = AddrSpaceIdx(6);
+    let props = AddrSpaceProps {
+      index: flat_idx,
+      shared_with: vec![private.clone(),
+                        region.clone(),
+                        local.clone(),
+                        constant.clone(),
+                        global.clone(),
+                        constant_32b.clone(), ]
+        .into_iter()
+        .collect(),
+    };
+    addr_spaces.insert(flat.clone(), props);
 
+    let insert_as = |addr_spaces: &mut BTreeMap<_, _>, kind,
+                     idx| {
       let props = AddrSpaceProps {
-        index: flat_idx,
-        shared_with: vec![private.clone(),
-                          region.clone(),
-                          local.clone(),
-                          constant.clone(),
-                          global.clone(),
-                          constant_32b.clone(), ]
+        index: idx,
+        shared_with: vec![flat.clone()]
           .into_iter()
           .collect(),
       };
-      addr_spaces.insert(flat.clone(), props);
-
-      let insert_as = |addr_spaces: &mut BTreeMap<_, _>, kind,
-                       idx| {
-        let props = AddrSpaceProps {
-          index: idx,
-          shared_with: vec![flat.clone()]
-            .into_iter()
-            .collect(),
-        };
-        addr_spaces.insert(kind, props);
-      };
-      insert_as(addr_spaces, global.clone(), global_idx);
-      insert_as(addr_spaces, region.clone(), region_idx);
-      insert_as(addr_spaces, local.clone(), local_idx);
-      insert_as(addr_spaces, constant.clone(), constant_idx);
-      insert_as(addr_spaces, private.clone(), private_idx);
-      insert_as(addr_spaces, constant_32b.clone(), constant_32b_idx);
-    }
-
-    Ok(())
+      addr_spaces.insert(kind, props);
+    };
+    insert_as(addr_spaces, global.clone(), global_idx);
+    insert_as(addr_spaces, region.clone(), region_idx);
+    insert_as(addr_spaces, local.clone(), local_idx);
+    insert_as(addr_spaces, constant.clone(), constant_idx);
+    insert_as(addr_spaces, private.clone(), private_idx);
+    insert_as(addr_spaces, constant_32b.clone(), constant_32b_idx);
   }
+
+  Ok(())
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Hash, Eq, PartialEq)]
@@ -1054,10 +1067,25 @@ pub struct TargetDesc {
   isa: IsaInfo,
   has_pcie_large_bar: bool,
 }
+impl TargetDesc {
+  /// Describe a device with the provided ISA. Use with
+  /// `offline::VirtualAccel::from_target_desc` to codegen for devices which
+  /// aren't present.
+  pub fn new(isa: IsaInfo, has_pcie_large_bar: bool) -> Self {
+    TargetDesc {
+      isa,
+      has_pcie_large_bar,
+    }
+  }
+}
 impl PlatformTargetDesc for TargetDesc {
   fn as_any_hash(&self) -> &dyn any_key::AnyHash {
     self
   }
+  /// `has_pcie_large_bar` only affects how memory is allocated.
+  fn codegen_key(&self) -> Option<&dyn erased_serde::Serialize> {
+    Some(&self.isa)
+  }
 }
 
 pub trait HsaAmdTargetDescHelper {
@@ -6,13 +6,21 @@ use num_traits::Zero;
 
 use crate::{Error, HsaAmdGpuAccel};
 use crate::module::*;
+use crate::module::heap::HeapSlot;
+use crate::module::panic::{DevicePanic, PanicSlot, };
 use crate::signal::{DeviceConsumable, SignalHandle};
 
 pub use crate::signal::completion::Completion;
 
+/// `repr(C)` so that the device panic and allocation stubs can find
+/// `panic` and `heap` from the kernarg pointer, as `LaunchArgs<(), ()>`.
+/// They must stay the first fields.
+#[repr(C)]
 pub struct LaunchArgs<A, G>
   where A: ?Sized,
 {
+  pub(super) panic: PanicSlot,
+  pub(super) heap: HeapSlot,
   /// The real grid size. The grid size as given to HSA will be rounded up to align with the
   /// workgroup size. This field records the user grid size as originally given.
   pub(super) grid: G,
@@ -24,6 +32,14 @@ impl<A, G> LaunchArgs<A, G>
 {
   #[inline(always)]
   pub fn original_grid(&self) -> &G { &self.grid }
+  /// The first panic of the dispatch, if any workitem panicked. Only
+  /// meaningful once the dispatch has finished.
+  pub fn device_panic(&self) -> Option<DevicePanic> { self.panic.read() }
+  /// The size and alignment of the first failed device heap allocation,
+  /// if any. Only meaningful once the dispatch has finished.
+  pub fn failed_device_alloc(&self) -> Option<(usize, usize)> {
+    self.heap.failed_alloc()
+  }
 }
 impl<A, G> Deref for LaunchArgs<A, G>
   where A: ?Sized,
@@ -1,3 +1,4 @@
+use std::convert::TryFrom;
 use std::fmt;
 use std::ops::*;
 
@@ -7,6 +8,8 @@ use num_traits::{AsPrimitive, ToPrimitive};
 use num_traits::identities::{One, Zero, };
 use num_traits::ops::{checked::*, saturating::*, };
 
+use grt_core::launch::LaunchGrid;
+
 use crate::Error;
 use crate::texture::geometry::*;
 
@@ -252,6 +255,34 @@ impl<T> From<T> for Dim3D<T>
     }
   }
 }
+/// Fails if the y or z axes aren't one.
+impl TryFrom<LaunchGrid> for Dim1D<RangeTo<u32>> {
+  type Error = Error;
+  #[inline(always)]
+  fn try_from(v: LaunchGrid) -> Result<Self, Error> {
+    if v.y != 1 || v.z != 1 {
+      return Err(Error::UnusedLaunchGridAxis(v));
+    }
+    Ok(Dim1D { x: ..v.x, })
+  }
+}
+/// Fails if the z axis isn't one.
+impl TryFrom<LaunchGrid> for Dim2D<RangeTo<u32>> {
+  type Error = Error;
+  #[inline(always)]
+  fn try_from(v: LaunchGrid) -> Result<Self, Error> {
+    if v.z != 1 {
+      return Err(Error::UnusedLaunchGridAxis(v));
+    }
+    Ok(Dim2D { x: ..v.x, y: ..v.y, })
+  }
+}
+impl From<LaunchGrid> for Dim3D<RangeTo<u32>> {
+  #[inline(always)]
+  fn from(v: LaunchGrid) -> Self {
+    Dim3D { x: ..v.x, y: ..v.y, z: ..v.z, }
+  }
+}
 impl<T> DimTranspose for Dim1D<T> {
   #[inline(always)]
   fn transpose(self) -> Self {
@@ -955,6 +986,22 @@ mod test {
     }.is_zero());
   }
 
+  #[test]
+  fn try_from_launch_grid() {
+    let g = Dim1D::<RangeTo<u32>>::try_from(LaunchGrid::new(7, 1, 1)).unwrap();
+    assert_eq!(g.x, ..7);
+    match Dim1D::<RangeTo<u32>>::try_from(LaunchGrid::new(7, 2, 1)) {
+      Err(Error::UnusedLaunchGridAxis(grid)) => assert_eq!(grid, LaunchGrid::new(7, 2, 1)),
+      r => panic!("unexpected result: {:?}", r),
+    }
+    let g = Dim2D::<RangeTo<u32>>::try_from(LaunchGrid::new(7, 2, 1)).unwrap();
+    assert_eq!((g.x, g.y), (..7, ..2));
+    match Dim2D::<RangeTo<u32>>::try_from(LaunchGrid::new(7, 2, 3)) {
+      Err(Error::UnusedLaunchGridAxis(_)) => { },
+      r => panic!("unexpected result: {:?}", r),
+    }
+  }
+
   #[test]
   fn glid_2d_a() {
     let grid = Dim2D {
@@ -0,0 +1,286 @@
+//! A device side heap, so kernels can use `Box`, `Vec`, etc. This platform
+//! replaces the allocation functions (see `stubs`) with a bump allocator
+//! over the `DeviceHeap` attached to the dispatch's `FuncModule`. Each
+//! dispatch starts with an empty heap, and everything it allocates is freed
+//! when it finishes. Freeing or growing the most recent allocation is done in
+//! place; other frees are leaked until the dispatch finishes.
+//!
+//! When an allocation fails (or the dispatch has no heap), the failed layout
+//! is recorded and the dispatch aborts, like a panic (see `panic`).
+//! `InvocCompletion::wait_for_completion` then returns
+//! `Error::DeviceHeapExhausted`.
+
+use std::cell::UnsafeCell;
+use std::collections::BTreeMap;
+use std::geobacter::platform::platform;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering, };
+
+use crate::{HsaAmdGpuAccel, Error, };
+use crate::boxed::RawPoolBox;
+use crate::grt_core::codegen::{Stub, StubTarget, };
+
+/// Device local memory for kernel allocations. Only one dispatch can use a
+/// heap at a time; launching with a heap which is still in use by an
+/// unfinished dispatch fails with `Error::DeviceHeapInUse`.
+#[derive(Clone, Debug)]
+pub struct DeviceHeap(Arc<HeapInner>);
+#[derive(Debug)]
+struct HeapInner {
+  mem: RawPoolBox<[u8]>,
+  in_use: AtomicBool,
+}
+impl DeviceHeap {
+  pub fn new(accel: &Arc<HsaAmdGpuAccel>, len: usize) -> Result<Self, Error> {
+    let mem = unsafe { accel.alloc_device_local_slice(len)? };
+    Ok(DeviceHeap(Arc::new(HeapInner {
+      mem,
+      in_use: AtomicBool::new(false),
+    })))
+  }
+
+  pub fn len(&self) -> usize { self.0.mem.len() }
+
+  pub(crate) fn lease(&self) -> Result<HeapLease, Error> {
+    self.0.in_use.compare_exchange(false, true, Ordering::Acquire,
+                                   Ordering::Relaxed)
+      .map_err(|_| Error::DeviceHeapInUse )?;
+    Ok(HeapLease(self.0.clone()))
+  }
+}
+
+/// Marks the heap as in use until the dispatch's args are dropped.
+#[derive(Debug)]
+pub(crate) struct HeapLease(Arc<HeapInner>);
+impl Drop for HeapLease {
+  fn drop(&mut self) {
+    self.0.in_use.store(false, Ordering::Release);
+  }
+}
+
+/// The heap state of a single dispatch. Lives in the launch args, after
+/// the `PanicSlot`. Shared by every workitem of the dispatch, so it's only
+/// accessed through `&self`.
+#[repr(C)]
+pub(crate) struct HeapSlot {
+  /// Null if the dispatch doesn't have a heap.
+  base: *mut u8,
+  len: usize,
+  /// Offset of the first free byte.
+  next: AtomicUsize,
+  /// Set once the first failed allocation has been recorded.
+  oom: AtomicU32,
+  /// The size and alignment of the first failed allocation. Only written by
+  /// the workitem which moved `oom` to 1, and only read once `oom` is 2.
+  oom_layout: UnsafeCell<(usize, usize)>,
+  /// Host only.
+  lease: Option<HeapLease>,
+}
+impl HeapSlot {
+  pub(crate) fn new(lease: Option<HeapLease>) -> Self {
+    let (base, len) = match lease {
+      Some(ref lease) => (lease.0.mem.as_ptr() as *mut u8, lease.0.mem.len()),
+      None => (ptr::null_mut(), 0),
+    };
+    HeapSlot {
+      base,
+      len,
+      next: AtomicUsize::new(0),
+      oom: AtomicU32::new(0),
+      oom_layout: UnsafeCell::new((0, 0)),
+      lease,
+    }
+  }
+
+  /// The size and alignment of the first allocation which failed, if any.
+  /// Only meaningful after the dispatch has finished or aborted.
+  pub(crate) fn failed_alloc(&self) -> Option<(usize, usize)> {
+    if self.oom.load(Ordering::Acquire) == 2 {
+      // the writer is done with it:
+      Some(unsafe { *self.oom_layout.get() })
+    } else {
+      None
+    }
+  }
+
+  fn alloc(&self, size: usize, align: usize) -> *mut u8 {
+    let base = self.base as usize;
+    let mut next = self.next.load(Ordering::Relaxed);
+    loop {
+      let start = match (base + next).checked_add(align - 1) {
+        Some(v) => (v & !(align - 1)) - base,
+        None => { return ptr::null_mut(); },
+      };
+      let end = match start.checked_add(size) {
+        Some(end) if end <= self.len => end,
+        _ => { return ptr::null_mut(); },
+      };
+      match self.next.compare_exchange_weak(next, end, Ordering::Relaxed,
+                                            Ordering::Relaxed) {
+        Ok(_) => { return (base + start) as *mut u8; },
+        Err(actual) => { next = actual; },
+      }
+    }
+  }
+  /// Resize the allocation at `ptr` in place. Only possible for the most
+  /// recent allocation.
+  fn resize_in_place(&self, ptr: *mut u8, old_size: usize, new_size: usize) -> bool {
+    let start = ptr as usize - self.base as usize;
+    let end = start + new_size;
+    if end > self.len { return false; }
+    self.next.compare_exchange(start + old_size, end, Ordering::Relaxed,
+                               Ordering::Relaxed)
+      .is_ok()
+  }
+
+  /// Device only.
+  unsafe fn record_oom(&self, size: usize, align: usize) {
+    if self.oom.compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed).is_err() {
+      return;
+    }
+    ptr::write(self.oom_layout.get(), (size, align));
+    self.oom.store(2, Ordering::Release);
+  }
+}
+unsafe impl Send for HeapSlot { }
+unsafe impl Sync for HeapSlot { }
+
+/// This platform's allocation stubs; these replace the aborting builtin
+/// allocation stubs.
+pub(crate) fn stubs() -> BTreeMap<StubTarget, Stub> {
+  use self::stubs::*;
+
+  let mut out = BTreeMap::new();
+  let mut add = |path: &str, stub| {
+    out.insert(StubTarget::Path(path.into()), stub);
+  };
+
+  add("alloc::alloc::__rust_alloc", Stub::replace(__rust_alloc));
+  add("alloc::alloc::__rust_alloc_zeroed", Stub::replace(__rust_alloc_zeroed));
+  add("alloc::alloc::__rust_dealloc", Stub::replace(__rust_dealloc));
+  add("alloc::alloc::__rust_realloc", Stub::replace(__rust_realloc));
+  add("alloc::alloc::handle_alloc_error", Stub::replace(handle_alloc_error));
+
+  out
+}
+
+/// The signatures must match the stubbed functions; see
+/// `grt_core::codegen::stubbing`.
+mod stubs {
+  use std::alloc::Layout;
+
+  use super::*;
+  use crate::module::args::LaunchArgs;
+
+  /// The launch args of the current dispatch. The slots come first in
+  /// `LaunchArgs` (which is `repr(C)`), so their offsets don't depend on the
+  /// kernel's grid or argument types.
+  #[inline(always)]
+  unsafe fn slots() -> Option<&'static LaunchArgs<(), ()>> {
+    if !platform().is_amdgcn() { return None; }
+
+    use std::geobacter::amdgpu::dispatch_packet;
+    let kernargs = dispatch_packet().kernarg_address as *const *const LaunchArgs<(), ()>;
+    Some(&**kernargs)
+  }
+
+  pub fn __rust_alloc(size: usize, align: usize) -> *mut u8 {
+    unsafe {
+      match slots() {
+        Some(slots) => slots.heap.alloc(size, align),
+        None => ptr::null_mut(),
+      }
+    }
+  }
+  pub fn __rust_alloc_zeroed(size: usize, align: usize) -> *mut u8 {
+    let ptr = __rust_alloc(size, align);
+    if !ptr.is_null() {
+      unsafe { ptr::write_bytes(ptr, 0, size); }
+    }
+    ptr
+  }
+  pub fn __rust_dealloc(ptr: *mut u8, size: usize, _align: usize) {
+    unsafe {
+      if let Some(slots) = slots() {
+        slots.heap.resize_in_place(ptr, size, 0);
+      }
+    }
+  }
+  pub fn __rust_realloc(ptr: *mut u8,
+                        old_size: usize,
+                        align: usize,
+                        new_size: usize) -> *mut u8 {
+    unsafe {
+      let slots = match slots() {
+        Some(slots) => slots,
+        None => { return ptr::null_mut(); },
+      };
+      if slots.heap.resize_in_place(ptr, old_size, new_size) {
+        return ptr;
+      }
+      let new_ptr = slots.heap.alloc(new_size, align);
+      if !new_ptr.is_null() {
+        ptr::copy_nonoverlapping(ptr, new_ptr, old_size.min(new_size));
+      }
+      new_ptr
+    }
+  }
+  pub fn handle_alloc_error(layout: Layout) -> ! {
+    unsafe {
+      if let Some(slots) = slots() {
+        slots.heap.record_oom(layout.size(), layout.align());
+        slots.panic.record("", 0, 0, Some("device heap exhausted"));
+      }
+
+      use std::geobacter::intrinsics::geobacter_suicide;
+      geobacter_suicide("device heap exhausted")
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn slot(mem: &mut [u64]) -> HeapSlot {
+    let mut slot = HeapSlot::new(None);
+    slot.base = mem.as_mut_ptr() as *mut u8;
+    slot.len = mem.len() * 8;
+    slot
+  }
+
+  #[test]
+  fn bump_alloc() {
+    let mut mem = [0u64; 8];
+    let slot = slot(&mut mem);
+
+    let a = slot.alloc(3, 1);
+    let b = slot.alloc(8, 8);
+    assert_eq!(b as usize - a as usize, 8);
+    // the last allocation can grow in place:
+    assert!(slot.resize_in_place(b, 8, 16));
+    assert!(!slot.resize_in_place(a, 3, 4));
+    assert!(slot.alloc(64, 8).is_null());
+    // and be freed:
+    assert!(slot.resize_in_place(b, 16, 0));
+    assert_eq!(slot.alloc(8, 8), b);
+  }
+
+  #[test]
+  fn no_heap() {
+    let slot = HeapSlot::new(None);
+    assert!(slot.alloc(1, 1).is_null());
+    assert_eq!(slot.failed_alloc(), None);
+  }
+
+  #[test]
+  fn first_oom_is_recorded() {
+    let slot = HeapSlot::new(None);
+    unsafe {
+      slot.record_oom(16, 8);
+      slot.record_oom(32, 4);
+    }
+    assert_eq!(slot.failed_alloc(), Some((16, 8)));
+  }
+}
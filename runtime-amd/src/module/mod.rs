@@ -1,3 +1,4 @@
+use std::convert::TryFrom;
 use std::geobacter::kernel::{KernelInstanceRef, OptionalKernelFn, };
 use std::marker::{PhantomData, Unsize, };
 use std::mem::{transmute, size_of, };
@@ -23,10 +24,11 @@ pub use hsa_rt::queue::{FenceScope, QueueError, };
 pub use hsa_rt::queue::KernelMultiQueue as DeviceMultiQueue;
 pub use hsa_rt::queue::KernelSingleQueue as DeviceSingleQueue;
 
-use crate::grt_core::{Device, AcceleratorId, };
+use crate::grt_core::{Accelerator, Device, AcceleratorId, };
 use crate::grt_core::codegen as core_codegen;
-use crate::grt_core::codegen::PKernelDesc;
+use crate::grt_core::codegen::{CodegenOptions, PKernelDesc, };
 use crate::grt_core::context::{ModuleContextData, PlatformModuleData, ModuleData, };
+use crate::grt_core::launch as core_launch;
 
 use crate::{HsaAmdGpuAccel, Error};
 use crate::codegen::{Codegenner, KernelDesc, CodegenDesc};
@@ -38,12 +40,16 @@ use self::args_pool::ArgsPoolAlloc;
 pub use self::args::*;
 pub use self::args_pool::ArgsPool;
 pub use self::grid::*;
+pub use self::heap::DeviceHeap;
+pub use self::panic::DevicePanic;
 pub use crate::signal::deps;
 pub use crate::signal::deps::Deps;
 
 pub mod args;
 pub mod args_pool;
 pub mod grid;
+pub mod heap;
+pub mod panic;
 
 #[cfg(test)]
 mod test;
@@ -63,6 +69,21 @@ fn launch_kernel<A>(this: &KLaunchArgs<A>)
 
 type InvocArgs<'a, A> = (KLaunchArgs<A>, &'a KLaunchArgs<A>, );
 
+/// The desc of `A`'s kernel, with no spec params defined.
+pub(crate) fn kernel_desc<A>() -> PKernelDesc<Codegenner>
+  where A: Kernel,
+{
+  let f = launch_kernel::<A>;
+  core_codegen::KernelDesc {
+    instance: f.kernel_instance(),
+    spec_params: Default::default(),
+    options: None,
+    platform_desc: KernelDesc {
+      max_vgpr_count: A::MAX_VGPR_USAGE,
+    },
+  }
+}
+
 pub struct FuncModule<A>
   where A: Kernel,
 {
@@ -81,6 +102,8 @@ pub struct FuncModule<A>
   instance: KernelInstanceRef<'static>,
   desc: KernelDesc,
   spec_params: core_codegen::SpecParamsDesc,
+  options: Option<Arc<CodegenOptions>>,
+  heap: Option<DeviceHeap>,
 
   /// To ensure we are only called with this argument type.
   _arg: PhantomData<*const A>,
@@ -106,6 +129,8 @@ impl<A> FuncModule<A>
         max_vgpr_count: A::MAX_VGPR_USAGE,
       },
       spec_params: Default::default(),
+      options: None,
+      heap: None,
 
       _arg: PhantomData,
     }
@@ -114,6 +139,7 @@ impl<A> FuncModule<A>
     core_codegen::KernelDesc {
       instance: self.instance.clone(),
       spec_params: self.spec_params.clone(),
+      options: self.options.clone(),
       platform_desc: self.desc.clone(),
     }
   }
@@ -182,6 +208,11 @@ impl<A> FuncModule<A>
   fn compile_internal(&mut self)
     -> Result<&HsaModuleData, Error>
   {
+    if self.device.ctx().is_accel_retired(self.device.id()) {
+      // release the executable, if we still have it.
+      self.module_data.take();
+      return Err(Error::AcceleratorRetired(self.device.id()));
+    }
     if self.module_data.is_none() {
       let module_data = self.context_data
         .compile(&self.device, self.desc(),
@@ -195,20 +226,22 @@ impl<A> FuncModule<A>
     self.compile_internal()?;
     Ok(())
   }
-  pub fn compile_async(&self) {
-    use rustc_data_structures::rayon::*;
-
-    if self.module_data.is_none() {
-      let context_data = self.context_data.clone();
-      let device = self.device.clone();
-      let desc = self.desc();
-      spawn(move || {
-        // ignore errors here; if an error does happen,
-        // we'll compile again to get the actual error from codegen.
-        let _ = context_data.compile(&device, desc, device.codegen(),
-                                     cfg!(test));
-      });
+  /// Start compiling on the context's thread pool. The request can be
+  /// dropped if the results aren't needed; `compile` and launches will use
+  /// the compiled kernel once it's done. Cancelling the request doesn't stop
+  /// a codegen which has already started.
+  pub fn compile_async(&self) -> CompileRequest {
+    if let Some(ref module_data) = self.module_data {
+      return core_codegen::Request::ready(Ok(module_data.clone()));
     }
+
+    let context_data = self.context_data.clone();
+    let device = self.device.clone();
+    let desc = self.desc();
+    // queued by the codegen limiter, so we don't block a pool thread:
+    self.device.codegen().spawn(move || {
+      context_data.compile(&device, desc, device.codegen(), cfg!(test))
+    })
   }
 
   /// Undefine all params. If this function was already compiled, it will be compiled
@@ -244,6 +277,35 @@ impl<A> FuncModule<A>
     self.module_data.take();
     self.spec_params.define(f, value)
   }
+  /// The currently defined specialization params, eg to serialize them.
+  pub fn spec_params(&self) -> &core_codegen::SpecParamsDesc { &self.spec_params }
+  /// Replace every specialization param, eg with params which were
+  /// deserialized. Params from a different build of this program must first
+  /// be re-keyed with `SpecParamsDesc::bind`.
+  ///
+  /// If this function was already compiled, it will be compiled again.
+  pub fn set_spec_params(&mut self, params: core_codegen::SpecParamsDesc) {
+    self.module_data.take();
+    self.spec_params = params;
+  }
+
+  /// Override the codegen options of the accelerator for this kernel. `None`
+  /// reverts to the accelerator's options. If this function was already
+  /// compiled, it will be compiled again.
+  pub fn set_codegen_options(&mut self, options: Option<CodegenOptions>) {
+    self.module_data.take();
+    self.options = options.map(Arc::new);
+  }
+  pub fn codegen_options(&self) -> Option<&CodegenOptions> {
+    self.options.as_deref()
+  }
+
+  /// Give dispatches of this kernel a heap to allocate from. Without one,
+  /// every device allocation fails. Doesn't require recompiling.
+  pub fn set_device_heap(&mut self, heap: Option<DeviceHeap>) {
+    self.heap = heap;
+  }
+  pub fn device_heap(&self) -> Option<&DeviceHeap> { self.heap.as_ref() }
 
   /// Attach a kernel args pool, in preparation for dispatching.
   pub fn invoc<P>(&self, pool: P) -> Invoc<A, P, Self>
@@ -291,6 +353,8 @@ impl<A> Clone for FuncModule<A>
       context_data: self.context_data.clone(),
       desc: self.desc.clone(),
       spec_params: self.spec_params.clone(),
+      options: self.options.clone(),
+      heap: self.heap.clone(),
 
       _arg: PhantomData,
     }
@@ -302,16 +366,19 @@ impl<A> Clone for FuncModule<A>
 pub trait FuncModuleMut<A>
   where A: Kernel,
 {
+  fn fm(&self) -> &FuncModule<A>;
   fn fm_mut(&mut self) -> &mut FuncModule<A>;
 }
 impl<A> FuncModuleMut<A> for FuncModule<A>
   where A: Kernel,
 {
+  fn fm(&self) -> &FuncModule<A> { self }
   fn fm_mut(&mut self) -> &mut FuncModule<A> { self }
 }
 impl<'a, A> FuncModuleMut<A> for &'a mut FuncModule<A>
   where A: Kernel,
 {
+  fn fm(&self) -> &FuncModule<A> { self }
   fn fm_mut(&mut self) -> &mut FuncModule<A> { self }
 }
 
@@ -423,6 +490,11 @@ impl<A, P, FM> Invoc<A, P, FM>
 
     args.as_ref().expect("provide args");
 
+    let heap_lease = match self.f.fm().heap {
+      Some(ref heap) => Some(heap.lease()?),
+      None => None,
+    };
+
     let mut kernargs = self.pool.alloc::<InvocArgs<A>>()
       .ok_or(Error::KernelArgsPoolOom)?;
     let launch_args = (&mut kernargs.as_mut().0) as *mut _;
@@ -458,7 +530,12 @@ impl<A, P, FM> Invoc<A, P, FM>
       }
     }
 
+    let panic = panic::PanicSlot::new(Some(args.as_ref().unwrap()
+      .completion()
+      .signal_ref()));
     ptr::write(launch_args, KLaunchArgs {
+      panic,
+      heap: heap::HeapSlot::new(heap_lease),
       args: args.take().unwrap(),
       grid: grid.clone(),
     });
@@ -504,7 +581,52 @@ impl<A, P, FM> Invoc<A, P, FM>
   }
 }
 
+/// Launches through the platform neutral interface wait on the completion
+/// signal from the host, so the signal must be host consumable. Deps are
+/// still taken from the arguments.
+impl<A, P, FM> core_launch::KernelLauncher<A> for Invoc<A, P, FM>
+  where A: Kernel + Send + 'static,
+        A::Grid: TryFrom<core_launch::LaunchGrid> + Send + Sync + 'static,
+        <A::Grid as TryFrom<core_launch::LaunchGrid>>::Error: Into<core_launch::LaunchError>,
+        A::Queue: RingQueue,
+        A::CompletionSignal: HostConsumable,
+        P: Deref<Target = ArgsPool> + Clone + Send + Sync + 'static,
+        FM: FuncModuleMut<A>,
+{
+  fn accel_id(&self) -> AcceleratorId {
+    self.f.fm().device.id()
+  }
+  unsafe fn launch(&mut self, grid: core_launch::LaunchGrid, args: A)
+    -> Result<core_launch::Completion, core_launch::LaunchError>
+  {
+    let grid = A::Grid::try_from(grid)
+      .map_err(Into::<core_launch::LaunchError>::into)?;
+    let completion = self.unchecked_call_async(&grid, args)?;
+    Ok(core_launch::Completion::new(HostLaunchCompletion(completion)))
+  }
+}
+
+struct HostLaunchCompletion<P, A>(LaunchCompletion<P, A, A::CompletionSignal, A::Grid>)
+  where P: Deref<Target = ArgsPool> + Clone,
+        A: Kernel;
+impl<P, A> core_launch::LaunchCompletion for HostLaunchCompletion<P, A>
+  where A: Kernel + Send + 'static,
+        A::Grid: Send + Sync + 'static,
+        A::CompletionSignal: HostConsumable,
+        P: Deref<Target = ArgsPool> + Clone + Send + Sync + 'static,
+{
+  fn is_complete(&self) -> bool {
+    // negative values are errors, but still mean the dispatch is done.
+    self.0.signal_ref().load_scacquire() <= 0
+  }
+  fn wait(&self) -> Result<(), core_launch::LaunchError> {
+    Ok(self.0.wait_for_completion(false)?)
+  }
+}
+
 pub type CallError = crate::error::Error;
+/// A compile running in the background; see `FuncModule::compile_async`.
+pub type CompileRequest = core_codegen::Request<Arc<HsaModuleData>, Error>;
 
 #[must_use]
 pub struct InvocCompletion<P, A, S>
@@ -532,6 +654,35 @@ impl<P, A, S> InvocCompletion<P, KLaunchArgs<A>, S>
     SignaledDeref::new(r, signal)
   }
 }
+impl<P, A, S, G> InvocCompletion<P, LaunchArgs<A, G>, S>
+  where P: Deref<Target = ArgsPool> + Clone,
+        S: SignalHandle + ?Sized,
+        A: Completion<CompletionSignal = S> + ?Sized,
+{
+  /// The first panic of the dispatch, if any workitem panicked. Only
+  /// meaningful once the dispatch has finished.
+  pub fn device_panic(&self) -> Option<panic::DevicePanic> {
+    self.args.device_panic()
+  }
+  /// Wait for the dispatch to finish. If a workitem panicked, returns
+  /// `Error::KernelPanicked` with where and why, or, if it was because of
+  /// a failed allocation, `Error::DeviceHeapExhausted`.
+  pub fn wait_for_completion(&self, spin: bool) -> Result<(), Error>
+    where S: HostConsumable,
+  {
+    let code = match self.wait_for_zero(spin) {
+      Ok(()) => { return Ok(()); },
+      Err(code) => code,
+    };
+    if let Some((size, align)) = self.args.failed_device_alloc() {
+      return Err(Error::DeviceHeapExhausted { size, align, });
+    }
+    Err(match self.device_panic() {
+      Some(panic) => Error::KernelPanicked(Box::new(panic)),
+      None => Error::NegativeCompletionSignal(code),
+    })
+  }
+}
 impl<P, A, S> InvocCompletion<P, A, S>
   where P: Deref<Target = ArgsPool> + Clone,
         S: SignalHandle + ?Sized,
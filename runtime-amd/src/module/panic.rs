@@ -0,0 +1,325 @@
+//! Device panic reporting. Every dispatch has a `PanicSlot` at the start of
+//! its launch args. This platform's panic stubs (see `stubs`) record the
+//! location and message of the first panic in a dispatch, and which workitem
+//! panicked, into the slot. They then set the completion signal negative, to
+//! wake the host, and abort. `InvocCompletion::wait_for_completion` turns
+//! this into `Error::KernelPanicked`.
+
+use std::any::TypeId;
+use std::cell::UnsafeCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::geobacter::platform::platform;
+use std::mem::transmute_copy;
+use std::sync::atomic::{AtomicU32, Ordering, fence, };
+
+use std::ptr;
+
+use hsa_rt::ext::signal::{AsAmdSignal, AmdSignal, };
+use hsa_rt::signal::SignalRef;
+
+use crate::grt_core::codegen::{Stub, StubTarget, };
+
+const EMPTY: u32 = 0;
+const WRITING: u32 = 1;
+const WRITTEN: u32 = 2;
+
+/// Longer file names keep their end.
+const FILE_LEN: usize = 96;
+/// Longer messages are truncated.
+const MSG_LEN: usize = 160;
+
+/// Written by the device, read by the host once the dispatch has finished.
+/// Every workitem of the dispatch shares the slot, so it's only ever
+/// accessed through `&self`; `state` decides who may write `data`.
+#[repr(C)]
+pub(crate) struct PanicSlot {
+  state: AtomicU32,
+  /// Only written by the workitem which moved `state` to `WRITING`, and
+  /// only read once `state` is `WRITTEN`.
+  data: UnsafeCell<PanicData>,
+  /// Null if the dispatch doesn't have a completion signal.
+  completion: *const AmdSignal,
+}
+#[repr(C)]
+struct PanicData {
+  line: u32,
+  col: u32,
+  file_len: u32,
+  /// `u32::MAX` if there isn't a message.
+  msg_len: u32,
+  workgroup_id: [u32; 3],
+  workitem_id: [u32; 3],
+  file: [u8; FILE_LEN],
+  msg: [u8; MSG_LEN],
+}
+impl PanicSlot {
+  pub(crate) fn new(completion: Option<SignalRef>) -> Self {
+    let completion = completion
+      .map(|s| unsafe { s.as_amd_signal() as *const AmdSignal } )
+      .unwrap_or(ptr::null());
+    PanicSlot {
+      state: AtomicU32::new(EMPTY),
+      data: UnsafeCell::new(PanicData {
+        line: 0,
+        col: 0,
+        file_len: 0,
+        msg_len: u32::MAX,
+        workgroup_id: [0; 3],
+        workitem_id: [0; 3],
+        file: [0; FILE_LEN],
+        msg: [0; MSG_LEN],
+      }),
+      completion,
+    }
+  }
+
+  /// Only meaningful after the dispatch has finished or aborted.
+  pub(crate) fn read(&self) -> Option<DevicePanic> {
+    if self.state.load(Ordering::Acquire) != WRITTEN { return None; }
+    // the writer is done with it:
+    let data = unsafe { &*self.data.get() };
+
+    let file = &data.file[..(data.file_len as usize).min(FILE_LEN)];
+    let message = if data.msg_len == u32::MAX {
+      None
+    } else {
+      let msg = &data.msg[..(data.msg_len as usize).min(MSG_LEN)];
+      Some(String::from_utf8_lossy(msg).into_owned())
+    };
+    Some(DevicePanic {
+      file: String::from_utf8_lossy(file).into_owned(),
+      line: data.line,
+      col: data.col,
+      message,
+      workgroup_id: data.workgroup_id,
+      workitem_id: data.workitem_id,
+    })
+  }
+
+  /// Device only.
+  #[inline(always)]
+  pub(super) unsafe fn record(&self, file: &str, line: u32, col: u32, msg: Option<&str>) {
+    use std::geobacter::amdgpu::workitem::*;
+
+    if self.state.compare_exchange(EMPTY, WRITING, Ordering::Acquire, Ordering::Relaxed).is_err() {
+      // another workitem got here first.
+      return;
+    }
+    // nobody else touches `data` until we store `WRITTEN`:
+    let data = &mut *self.data.get();
+
+    let file = file.as_bytes();
+    let file = &file[file.len().saturating_sub(FILE_LEN)..];
+    data.file[..file.len()].copy_from_slice(file);
+    data.file_len = file.len() as _;
+    if let Some(msg) = msg {
+      let msg = &msg.as_bytes()[..msg.len().min(MSG_LEN)];
+      data.msg[..msg.len()].copy_from_slice(msg);
+      data.msg_len = msg.len() as _;
+    }
+    data.line = line;
+    data.col = col;
+    data.workgroup_id = [
+      XAxis.workgroup_id() as _,
+      YAxis.workgroup_id() as _,
+      ZAxis.workgroup_id() as _,
+    ];
+    data.workitem_id = [
+      XAxis.workitem_id() as _,
+      YAxis.workitem_id() as _,
+      ZAxis.workitem_id() as _,
+    ];
+
+    self.state.store(WRITTEN, Ordering::Release);
+    fence(Ordering::SeqCst);
+
+    // The dispatch won't complete, so wake the host ourselves:
+    if let Some(s) = self.completion.as_ref() {
+      s.value.value.store(-1, Ordering::Release);
+      crate::signal::gpu::update_mbox(s);
+    }
+  }
+}
+
+unsafe impl Send for PanicSlot { }
+unsafe impl Sync for PanicSlot { }
+
+/// Where and why a kernel panicked.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct DevicePanic {
+  /// Possibly truncated.
+  pub file: String,
+  pub line: u32,
+  pub col: u32,
+  /// Only available for static string messages, and possibly truncated.
+  pub message: Option<String>,
+  pub workgroup_id: [u32; 3],
+  pub workitem_id: [u32; 3],
+}
+impl fmt::Display for DevicePanic {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "workitem {:?} of workgroup {:?} panicked at ",
+           self.workitem_id, self.workgroup_id)?;
+    if let Some(ref msg) = self.message {
+      write!(f, "'{}', ", msg)?;
+    }
+    write!(f, "{}:{}:{}", self.file, self.line, self.col)
+  }
+}
+
+/// The panic stubs of this platform; these replace the aborting builtin
+/// panic stubs.
+pub(crate) fn stubs() -> BTreeMap<StubTarget, Stub> {
+  use self::stubs::*;
+
+  let mut out = BTreeMap::new();
+  let mut add = |path: &str, stub| {
+    out.insert(StubTarget::Path(path.into()), stub);
+  };
+
+  add("std::rt::begin_panic", Stub::replace(begin_panic::<()>));
+  add("std::rt::begin_panic_fmt", Stub::replace(begin_panic_fmt));
+  add("std::panicking::rust_panic_with_hook", Stub::replace(rust_panic_with_hook));
+  add("core::panicking::panic", Stub::replace(panic));
+  add("core::panicking::panic_fmt", Stub::replace(panic_fmt));
+  add("core::panicking::panic_fmt::panic_impl", Stub::replace(panic_impl));
+  add("core::panicking::panic_bounds_check", Stub::replace(panic_bounds_check));
+  add("core::slice::slice_index_order_fail", Stub::replace(slice_index_order_fail));
+  add("core::slice::slice_index_len_fail", Stub::replace(slice_index_len_fail));
+  add("core::slice::slice_index_overflow_fail", Stub::replace(slice_index_overflow_fail));
+  add("core::str::slice_error_fail", Stub::replace(slice_error_fail));
+
+  out
+}
+
+/// The signatures must match the stubbed functions; see
+/// `grt_core::codegen::stubbing`.
+mod stubs {
+  #![allow(unused_variables)]
+
+  use std::any::Any;
+  use core::panic::{Location, PanicInfo, BoxMeUp, };
+
+  use super::*;
+
+  #[inline(always)]
+  fn record(file: &str, line: u32, col: u32, msg: Option<&str>) {
+    if !platform().is_amdgcn() { return; }
+
+    unsafe {
+      use std::geobacter::amdgpu::dispatch_packet;
+
+      // the first (and only) kernel argument is a pointer to the launch
+      // args, which start with the panic slot.
+      let kernargs = dispatch_packet().kernarg_address as *const *const PanicSlot;
+      (**kernargs).record(file, line, col, msg);
+    }
+  }
+  #[inline(always)]
+  fn record_location(location: &Location, msg: Option<&str>) {
+    record(location.file(), location.line(), location.column(), msg);
+  }
+  #[inline(always)]
+  fn abort() -> ! {
+    use std::geobacter::intrinsics::geobacter_suicide;
+    unsafe { geobacter_suicide("kernel panicked") }
+  }
+
+  pub fn begin_panic<M>(msg: M, file_line_col: &(&'static str, u32, u32)) -> !
+    where M: Any + Send,
+  {
+    let msg = if TypeId::of::<M>() == TypeId::of::<&'static str>() {
+      Some(unsafe { transmute_copy::<M, &'static str>(&msg) })
+    } else {
+      None
+    };
+    let &(file, line, col) = file_line_col;
+    record(file, line, col, msg);
+    abort()
+  }
+  pub fn begin_panic_fmt(_fmt: &fmt::Arguments,
+                         file_line_col: &(&'static str, u32, u32))
+    -> !
+  {
+    let &(file, line, col) = file_line_col;
+    record(file, line, col, None);
+    abort()
+  }
+  pub fn rust_panic_with_hook(_payload: &mut dyn BoxMeUp,
+                              _message: Option<&fmt::Arguments>,
+                              file_line_col: &(&str, u32, u32))
+    -> !
+  {
+    let &(file, line, col) = file_line_col;
+    record(file, line, col, None);
+    abort()
+  }
+  #[track_caller]
+  pub fn panic(expr: &'static str) -> ! {
+    record_location(Location::caller(), Some(expr));
+    abort()
+  }
+  pub fn panic_fmt(_fmt: fmt::Arguments, location: &Location<'_>) -> ! {
+    record_location(location, None);
+    abort()
+  }
+  pub fn panic_impl(info: &PanicInfo) -> ! {
+    match info.location() {
+      Some(location) => record_location(location, None),
+      None => record("", 0, 0, None),
+    }
+    abort()
+  }
+  pub fn panic_bounds_check(index: usize, len: usize, location: &Location<'_>) -> ! {
+    record_location(location, Some("index out of bounds"));
+    abort()
+  }
+
+  pub fn slice_index_len_fail(_index: usize, _len: usize) -> ! {
+    record("", 0, 0, Some("slice index out of range"));
+    abort()
+  }
+  pub fn slice_index_order_fail(_index: usize, _end: usize) -> ! {
+    record("", 0, 0, Some("slice index starts after it ends"));
+    abort()
+  }
+  pub fn slice_index_overflow_fail() -> ! {
+    record("", 0, 0, Some("attempted to index slice up to maximum usize"));
+    abort()
+  }
+  pub fn slice_error_fail(_s: &str, _begin: usize, _end: usize) -> ! {
+    record("", 0, 0, Some("byte index is out of bounds or not a char boundary"));
+    abort()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn empty_slot_has_no_panic() {
+    let slot = PanicSlot::new(None);
+    assert_eq!(slot.read(), None);
+  }
+
+  #[test]
+  fn written_slot() {
+    let mut slot = PanicSlot::new(None);
+    let data = slot.data.get_mut();
+    let file = b"src/lib.rs";
+    data.file[..file.len()].copy_from_slice(file);
+    data.file_len = file.len() as _;
+    data.line = 4;
+    data.col = 2;
+    data.workitem_id = [1, 0, 0];
+    slot.state.store(WRITTEN, Ordering::Release);
+
+    let p = slot.read().unwrap();
+    assert_eq!(p.file, "src/lib.rs");
+    assert_eq!(p.message, None);
+    assert_eq!(p.to_string(),
+               "workitem [1, 0, 0] of workgroup [0, 0, 0] panicked at src/lib.rs:4:2");
+  }
+}
@@ -318,6 +318,145 @@ fn device_wg_limit_err() {
   }
 }
 
+#[test]
+fn device_panic_reported() {
+  let dev = device();
+
+  let mut m = LapVec::new_in(dev.fine_lap_node_alloc(0));
+
+  const GRID: Dim1D<Range<u32>> = Dim1D { x: 0..16, };
+
+  fn panicking_f(_: *mut [u32], vp: VectorParams<Dim1D<Range<u32>>>) {
+    if vp.gl_id() == 9 {
+      panic!("workitem nine");
+    }
+  }
+
+  unsafe {
+    let (mut invoc, k) = TestKernel::new_global(&dev, &mut m,
+                                                &GRID, 0u32,
+                                                panicking_f);
+    let wait = invoc
+      .unchecked_call_async(&GRID, k)
+      .unwrap();
+    match wait.wait_for_completion(false) {
+      Err(Error::KernelPanicked(panic)) => {
+        assert_eq!(panic.message.as_deref(), Some("workitem nine"));
+        assert_eq!(panic.workgroup_id, [1, 0, 0]);
+        assert_eq!(panic.workitem_id, [1, 0, 0]);
+        assert!(panic.file.ends_with("test.rs"));
+      },
+      r => panic!("unexpected dispatch result: {:?}", r),
+    }
+  }
+}
+
+#[test]
+fn device_heap() {
+  let dev = device();
+
+  let mut m = LapVec::new_in(dev.fine_lap_node_alloc(0));
+
+  const GRID: Dim1D<Range<u32>> = Dim1D { x: 0..16, };
+
+  fn vec_f(dst: *mut [u32], vp: VectorParams<Dim1D<Range<u32>>>) {
+    let dst = unsafe { &mut *dst };
+    let v: Vec<u32> = (0..vp.gl_id()).collect();
+    dst[vp.gl_id() as usize] = v.iter().sum();
+  }
+
+  unsafe {
+    let (mut invoc, k) = TestKernel::new_global(&dev, &mut m,
+                                                &GRID, 0u32,
+                                                vec_f);
+    invoc.f.fm_mut().set_device_heap(Some(DeviceHeap::new(&dev, 1 << 16).unwrap()));
+    invoc.unchecked_call_async(&GRID, k)
+      .unwrap()
+      .wait_for_completion(false)
+      .unwrap();
+  }
+
+  for (i, &v) in m.iter().enumerate() {
+    assert_eq!(v, (0..i as u32).sum::<u32>());
+  }
+}
+#[test]
+fn device_heap_exhausted() {
+  let dev = device();
+
+  let mut m = LapVec::new_in(dev.fine_lap_node_alloc(0));
+
+  const GRID: Dim1D<Range<u32>> = Dim1D { x: 0..1, };
+
+  fn large_f(_: *mut [u32], _: VectorParams<Dim1D<Range<u32>>>) {
+    let v = vec![0u32; 1 << 20];
+    unsafe { ptr::read_volatile(&v[0]); }
+  }
+
+  unsafe {
+    let (mut invoc, k) = TestKernel::new_global(&dev, &mut m,
+                                                &GRID, 0u32,
+                                                large_f);
+    invoc.f.fm_mut().set_device_heap(Some(DeviceHeap::new(&dev, 4096).unwrap()));
+    match invoc.unchecked_call_async(&GRID, k).unwrap().wait_for_completion(false) {
+      Err(Error::DeviceHeapExhausted { size, .. }) => {
+        assert_eq!(size, 4 << 20);
+      },
+      r => panic!("unexpected dispatch result: {:?}", r),
+    }
+  }
+}
+
+#[test]
+fn retire_drops_codegen_results() {
+  // a context of our own, so the shared test device isn't retired.
+  let ctx = grt_core::context::Context::new().unwrap();
+  let dev = HsaAmdGpuAccel::first_device(&ctx).unwrap();
+
+  macro_rules! noop {
+    ($name:ident) => {
+      #[derive(GeobacterDeps)]
+      struct $name;
+      impl Completion for $name {
+        type CompletionSignal = GlobalSignal;
+        fn completion(&self) -> &GlobalSignal { unreachable!(); }
+      }
+      impl Kernel for $name {
+        type Grid = Dim1D<Range<u32>>;
+        const WORKGROUP: Dim1D<RangeTo<u16>> = Dim1D { x: ..64, };
+
+        type Queue = DeviceMultiQueue;
+        fn queue(&self) -> &Self::Queue { unreachable!(); }
+
+        fn kernel(&self, _: KVectorParams<Self>)
+          where Self: Sized,
+        { }
+      }
+    };
+  }
+  noop!(Noop);
+  noop!(PinnedNoop);
+
+  let codegen = dev.codegen().clone();
+  codegen.pin(&kernel_desc::<PinnedNoop>());
+  let mut module: FuncModule<PinnedNoop> = FuncModule::new(&dev);
+  module.compile().unwrap();
+  let pinned_size = codegen.cache_size();
+  assert!(pinned_size > 0);
+  let mut module: FuncModule<Noop> = FuncModule::new(&dev);
+  module.compile().unwrap();
+  assert!(codegen.cache_size() > pinned_size);
+
+  assert!(ctx.retire_accel(dev.id()).is_some());
+  // only the pinned results are kept:
+  assert_eq!(codegen.cache_size(), pinned_size);
+  // the loaded kernel is gone too:
+  let mut module: FuncModule<Noop> = FuncModule::new(&dev);
+  match module.compile() {
+    Err(Error::AcceleratorRetired(id)) => assert_eq!(id, dev.id()),
+    r => panic!("unexpected compile result: {:?}", r),
+  }
+}
 
 mod one_d {
   use super::*;
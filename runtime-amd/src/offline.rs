@@ -0,0 +1,195 @@
+//! Offline kernel compilation. A `VirtualAccel` runs codegen for a device
+//! described only by its target desc, which can be loaded from a desc
+//! serialized from a real device or built by hand from an `IsaInfo`. No
+//! GPU (or even the HSA runtime) is required.
+//!
+//! Combined with `KernelBundle`, this can be used to precompile kernels at
+//! build time for the GPUs a program will be deployed to.
+
+use std::geobacter::platform::hsa::AmdGcn;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use hsa_rt::agent::IsaInfo;
+
+use crate::grt_core::{AcceleratorTargetDesc, PlatformTargetDesc, };
+use crate::grt_core::codegen::{CodegenDriver, KernelBundle, PKernelDesc, };
+use crate::grt_core::codegen::products::PCodegenResults;
+use crate::grt_core::context::Context;
+use crate::serde::Deserializer;
+
+use crate::{Error, TargetDesc, init_target_desc, };
+use crate::codegen::Codegenner;
+use crate::module::{Kernel, kernel_desc, };
+
+pub struct VirtualAccel {
+  target_desc: Arc<AcceleratorTargetDesc>,
+  codegen: Arc<CodegenDriver<Codegenner>>,
+}
+impl VirtualAccel {
+  /// Use a complete target desc, eg one serialized from
+  /// `HsaAmdGpuAccel::accel_target_desc`.
+  pub fn new(ctx: &Context, target_desc: Arc<AcceleratorTargetDesc>)
+    -> Result<Self, Error>
+  {
+    if !target_desc.is_amdgpu() {
+      return Err(Error::NotAmdGpuTargetDesc);
+    }
+    TargetDesc::downcast_ref(&*target_desc.platform)
+      .ok_or(Error::NotAmdGpuTargetDesc)?;
+    // `Codegenner` assumes this was checked already.
+    let gpu = &target_desc.target.options.cpu;
+    AmdGcn::from_str(gpu)
+      .map_err(|()| Error::UnknownAmdGpuArch(gpu.to_string()) )?;
+
+    let codegen = CodegenDriver::new(ctx, target_desc.clone(),
+                                     Default::default())?;
+    Ok(VirtualAccel {
+      target_desc,
+      codegen: Arc::new(codegen),
+    })
+  }
+  /// Derive the rest of the accelerator target desc from `desc`, in the same
+  /// way as is done for real devices.
+  pub fn from_target_desc(ctx: &Context, desc: TargetDesc)
+    -> Result<Self, Error>
+  {
+    let mut target_desc = AcceleratorTargetDesc::new(desc);
+    init_target_desc(&mut target_desc)?;
+    Self::new(ctx, Arc::new(target_desc))
+  }
+  /// `has_pcie_large_bar` should match the devices the kernels will run
+  /// on, though it doesn't affect the generated code.
+  pub fn from_isa(ctx: &Context, isa: IsaInfo, has_pcie_large_bar: bool)
+    -> Result<Self, Error>
+  {
+    Self::from_target_desc(ctx, TargetDesc::new(isa, has_pcie_large_bar))
+  }
+  /// Load a serialized accelerator target desc.
+  pub fn deserialize<'de, D>(ctx: &Context, deserializer: D) -> Result<Self, Error>
+    where D: Deserializer<'de>,
+          D::Error: Send + Sync + 'static,
+  {
+    let desc = AcceleratorTargetDesc::deserialize_platform::<TargetDesc, _>(deserializer)
+      .map_err(|err| Error::Generic(Box::new(err)) )?;
+    Self::new(ctx, Arc::new(desc))
+  }
+
+  pub fn target_desc(&self) -> &Arc<AcceleratorTargetDesc> { &self.target_desc }
+  pub fn codegen(&self) -> &Arc<CodegenDriver<Codegenner>> { &self.codegen }
+
+  /// Codegen `A`'s kernel, with no spec params defined.
+  pub fn compile<A>(&self) -> Result<Arc<PCodegenResults<Codegenner>>, Error>
+    where A: Kernel,
+  {
+    self.compile_desc(kernel_desc::<A>())
+  }
+  pub fn compile_desc(&self, desc: PKernelDesc<Codegenner>)
+    -> Result<Arc<PCodegenResults<Codegenner>>, Error>
+  {
+    Ok(self.codegen.codegen(desc)?)
+  }
+
+  /// Codegen `A`'s kernel and add it to `bundle`.
+  pub fn add_to_bundle<A>(&self, bundle: &mut KernelBundle) -> Result<(), Error>
+    where A: Kernel,
+  {
+    let mut desc = kernel_desc::<A>();
+    // the bundle is keyed on the options actually used.
+    desc.options = Some(self.codegen.codegen_options());
+    let results = self.compile_desc(desc.clone())?;
+    bundle.insert(self.codegen.platform(), &desc, &self.target_desc, &results)
+      .map_err(|err| Error::Generic(Box::new(err)) )
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::prelude::*;
+  use crate::HsaAmdTargetDescHelper;
+  use hsa_rt::agent::{DefaultFloatRoundingModes, MachineModels, Profiles,
+                      WavefrontInfo, };
+
+  fn isa(name: &str) -> IsaInfo {
+    IsaInfo {
+      name: name.into(),
+      machine_model: MachineModels::new(false, true),
+      profiles: Profiles::base(),
+      default_float_rounding_modes: DefaultFloatRoundingModes::new(true, false, true),
+      base_profile_default_float_rounding_modes: DefaultFloatRoundingModes::near(),
+      fast_f16: true,
+      workgroup_max_dim: [1024; 3],
+      workgroup_max_size: 1024,
+      grid_max_dim: [u32::max_value(); 3],
+      grid_max_size: u64::max_value(),
+      fbarrier_max_size: 32,
+      wavefronts: vec![WavefrontInfo { size: 64, }],
+    }
+  }
+
+  #[derive(GeobacterDeps)]
+  struct Noop;
+  impl Completion for Noop {
+    type CompletionSignal = GlobalSignal;
+    fn completion(&self) -> &GlobalSignal { unreachable!(); }
+  }
+  impl Kernel for Noop {
+    type Grid = Dim1D<Range<u32>>;
+    const WORKGROUP: Dim1D<RangeTo<u16>> = Dim1D { x: ..64, };
+
+    type Queue = DeviceMultiQueue;
+    fn queue(&self) -> &Self::Queue { unreachable!(); }
+
+    fn kernel(&self, _: KVectorParams<Self>)
+      where Self: Sized,
+    { }
+  }
+
+  #[test]
+  fn codegen_without_a_device() {
+    let ctx = Context::new().unwrap();
+    let accel = VirtualAccel::from_isa(&ctx, isa("amdgcn-amd-amdhsa--gfx906"), true)
+      .unwrap();
+    assert_eq!(accel.target_desc().target.options.cpu, "gfx906");
+    assert!(accel.target_desc().has_pcie_large_bar());
+
+    let results = accel.compile::<Noop>().unwrap();
+    assert!(!results.outputs.is_empty());
+  }
+
+  #[test]
+  fn bundle_key_ignores_large_bar() {
+    use crate::grt_core::codegen::CodegenOptions;
+
+    let ctx = Context::new().unwrap();
+    let isa_name = "amdgcn-amd-amdhsa--gfx906";
+    let builder = VirtualAccel::from_isa(&ctx, isa(isa_name), true).unwrap();
+    let mut bundle = KernelBundle::new();
+    builder.add_to_bundle::<Noop>(&mut bundle).unwrap();
+
+    let accel = VirtualAccel::from_isa(&ctx, isa(isa_name), false).unwrap();
+    let platform = accel.codegen().platform();
+    let mut desc = kernel_desc::<Noop>();
+    desc.options = Some(accel.codegen().codegen_options());
+    let first = bundle.get(platform, &desc, accel.target_desc())
+      .expect("bundled kernel not found");
+    // decoded once:
+    let second = bundle.get(platform, &desc, accel.target_desc()).unwrap();
+    assert!(Arc::ptr_eq(&first, &second));
+
+    desc.options = Some(Arc::new(CodegenOptions::debug()));
+    assert!(bundle.get(platform, &desc, accel.target_desc()).is_none());
+  }
+
+  #[test]
+  fn bad_isa_name_is_an_error() {
+    let ctx = Context::new().unwrap();
+    for &name in ["amdgcn-amd-amdhsa--gfx9999", "amdgcn-amd-amdhsa-", "gfx906", ].iter() {
+      match VirtualAccel::from_isa(&ctx, isa(name), false) {
+        Err(Error::UnknownAmdGpuArch(_)) => { },
+        r => panic!("{}: unexpected {:?}", name, r.map(|_| () )),
+      }
+    }
+  }
+}
@@ -10,6 +10,8 @@ use rustc_span::{Span, sym, Symbol};
 use rustc_middle::ty::TyCtxt;
 use rustc_hir::def_id::DefId;
 
+use crate::AcceleratorTargetDesc;
+
 /// Implementers need to implement one of `parse_name_value` or `parse_word`.
 pub trait ConditionItem: Debug + PartialEq<Self> + Sized {
   /// If this returns `None`, it is assumed that an error is also emitted.
@@ -29,6 +31,14 @@ pub trait ConditionItem: Debug + PartialEq<Self> + Sized {
 
     None
   }
+
+  /// Does this condition hold when compiling for `target_desc`? The default
+  /// looks for an equal condition in `root_conditions` (see
+  /// `PlatformCodegen::root_conditions`); override this for conditions which
+  /// depend on the accelerator, eg its ISA.
+  fn eval(&self, _target_desc: &AcceleratorTargetDesc, root_conditions: &[Self]) -> bool {
+    root_conditions.iter().any(|root_cond| root_cond == self )
+  }
 }
 
 #[derive(Clone, Debug, Hash, Eq, PartialEq)]
@@ -198,9 +208,10 @@ pub fn geobacter_attrs<F>(tcx: TyCtxt<'_>, did: DefId,
 /// have at least one condition which passes.
 /// This function accepts a list of attributes instead of a DefId because
 /// the list of attributes will probably need to originate from the unmodified
-/// providers.
+/// providers. Conditions are evaluated with `ConditionItem::eval`.
 pub fn geobacter_cfg_attrs<'tcx, T>(tcx: TyCtxt<'tcx>,
                                     previous: &'tcx [ast::Attribute],
+                                    target_desc: &AcceleratorTargetDesc,
                                     root_conditions: &[T])
   -> &'tcx [ast::Attribute]
   where T: ConditionItem,
@@ -232,7 +243,7 @@ pub fn geobacter_cfg_attrs<'tcx, T>(tcx: TyCtxt<'tcx>,
         // TODO eval these conditions in place instead of building this tree.
         let expr = ConditionalExpr::parse_from_attrs(tcx, cond);
         if let Some(expr) = expr {
-          if expr.eval(&|cond| root_conditions.iter().any(|root_cond| root_cond == cond )) {
+          if expr.eval(&|cond| cond.eval(target_desc, root_conditions) ) {
             let sp = list[1].span();
 
             let attr = match list[1] {
@@ -0,0 +1,475 @@
+//! Ahead-of-time kernel bundles. A bundle holds the codegen results of a set
+//! of kernels, for a set of accelerator target descs, produced by a build
+//! step (eg with a codegen driver for a virtual accelerator). At runtime,
+//! bundles are consulted before running codegen, so programs which only use
+//! bundled kernels don't need their crate metadata at all; ie they can be
+//! stripped.
+//!
+//! Bundles are found in two places: embedded into the program via
+//! `embed_kernel_bundle!`, and next to the program, in a file named after
+//! the executable with the extension `grtkb` appended (eg
+//! `my-app.grtkb`). Others can be added with `Context::add_kernel_bundle`.
+//!
+//! Bundles are produced by the program itself, eg behind a command line
+//! flag: create codegen drivers for the target descs to be supported (eg
+//! with a platform's virtual accelerator), add the kernels with
+//! `KernelBundle::codegen_all`, then `write` the bundle to `sidecar_path`.
+//!
+//! Kernels are identified by their kernel instance, spec params, codegen
+//! options and the parts of the target desc which affect codegen, and a
+//! bundle records the build of the program it was produced by (its GNU
+//! build ID, or a hash of the executable). Sidecar bundles, and those passed to
+//! `Context::add_kernel_bundle`, from any other build are rejected. That
+//! can't be checked for embedded bundles, as embedding a bundle changes the
+//! program; they *must* be rebuilt, from the program without the bundle,
+//! whenever the program is rebuilt.
+
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, };
+use std::io::{self, ErrorKind, };
+use std::path::{Path, PathBuf, };
+use std::sync::Arc;
+
+use any_key::AnyHash;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize, };
+
+use crate::AcceleratorTargetDesc;
+use crate::codegen::{CodegenDriver, CodegenOptions, PlatformCodegen, PKernelDesc, };
+use crate::codegen::error::PError;
+use crate::codegen::products::{PCodegenResults, SerializedCodegenResults, };
+use crate::platform::os::binary_id;
+use crate::utils::{HashMap, StableHash, };
+
+/// Bump this whenever the format changes.
+const BUNDLE_VERSION: u32 = 2;
+/// The name of the object file section embedded bundles are placed in. Kept
+/// to eight bytes so it is also a valid PE section name.
+pub const BUNDLE_SECTION_NAME: &'static str = ".grtkb";
+pub const BUNDLE_EXTENSION: &'static str = "grtkb";
+
+/// Embed the kernel bundle at `$path` into this program. The bundle is
+/// loaded by the context automatically. Only ELF and PE executables are
+/// supported.
+#[macro_export]
+macro_rules! embed_kernel_bundle {
+  ($path:expr) => {
+    const _: () = {
+      #[used]
+      #[link_section = ".grtkb"]
+      static GEOBACTER_KERNEL_BUNDLE: [u8; include_bytes!($path).len()] =
+        *include_bytes!($path);
+    };
+  };
+}
+
+#[derive(Debug)]
+pub enum BundleError {
+  Io(io::Error),
+  Encode(rmps::encode::Error),
+  Decode(rmps::decode::Error),
+  ObjectFormat(goblin::error::Error),
+  /// The bundle was written by an incompatible version of Geobacter.
+  Version(u32),
+  /// The platform doesn't implement `PlatformCodegen::encode_codegen_desc`.
+  UnsupportedPlatform,
+  /// The bundle was produced by a different build of this program, so its
+  /// kernels may be stale.
+  WrongBuild,
+  /// A kernel couldn't be codegen-ed for the bundle.
+  Codegen(Box<dyn Error + Send + Sync + 'static>),
+}
+impl Error for BundleError { }
+impl fmt::Display for BundleError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      &BundleError::Io(ref e) => fmt::Display::fmt(e, f),
+      &BundleError::Encode(ref e) => fmt::Display::fmt(e, f),
+      &BundleError::Decode(ref e) => fmt::Display::fmt(e, f),
+      &BundleError::ObjectFormat(ref e) => fmt::Display::fmt(e, f),
+      &BundleError::Version(v) => {
+        write!(f, "unsupported kernel bundle version {}, expected {}",
+               v, BUNDLE_VERSION)
+      },
+      &BundleError::UnsupportedPlatform => {
+        f.pad("platform doesn't support serializing codegen results")
+      },
+      &BundleError::WrongBuild => {
+        f.pad("kernel bundle was produced by a different build of this program")
+      },
+      &BundleError::Codegen(ref e) => {
+        write!(f, "failed to codegen bundled kernel: {}", e)
+      },
+    }
+  }
+}
+impl From<io::Error> for BundleError {
+  fn from(v: io::Error) -> Self { BundleError::Io(v) }
+}
+impl From<rmps::encode::Error> for BundleError {
+  fn from(v: rmps::encode::Error) -> Self { BundleError::Encode(v) }
+}
+impl From<rmps::decode::Error> for BundleError {
+  fn from(v: rmps::decode::Error) -> Self { BundleError::Decode(v) }
+}
+impl From<goblin::error::Error> for BundleError {
+  fn from(v: goblin::error::Error) -> Self { BundleError::ObjectFormat(v) }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+struct BundleKey {
+  kernel: u64,
+  spec_params: u64,
+  options: u64,
+  target_desc: u64,
+}
+impl BundleKey {
+  fn new<P>(desc: &PKernelDesc<P>, target_desc: &AcceleratorTargetDesc) -> Self
+    where P: PlatformCodegen,
+  {
+    let kernel = {
+      let mut hasher = seahash::SeaHasher::new();
+      ::std::hash::Hash::hash(&desc.instance, &mut hasher);
+      AnyHash::hash(&desc.platform_desc as &dyn AnyHash, &mut hasher);
+      ::std::hash::Hasher::finish(&hasher)
+    };
+    let options = match desc.options {
+      Some(ref options) => options.stable_hash(),
+      None => CodegenOptions::default().stable_hash(),
+    };
+    BundleKey {
+      kernel,
+      spec_params: desc.spec_params.stable_hash(),
+      options,
+      target_desc: target_desc.codegen_hash(),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize)]
+struct BundleData {
+  version: u32,
+  binary: Option<u64>,
+  entries: Vec<(BundleKey, SerializedCodegenResults)>,
+}
+
+type DecodedResults = Option<Arc<dyn Any + Send + Sync>>;
+
+#[derive(Debug, Default)]
+pub struct KernelBundle {
+  /// The `binary_id` of the program which produced the entries. `None` if
+  /// there aren't any.
+  binary: Option<u64>,
+  entries: HashMap<BundleKey, SerializedCodegenResults>,
+  /// Entries are decoded on first use. Failures are cached too, so they're
+  /// only reported once.
+  decoded: RwLock<HashMap<BundleKey, DecodedResults>>,
+}
+impl Clone for KernelBundle {
+  fn clone(&self) -> Self {
+    KernelBundle {
+      binary: self.binary,
+      entries: self.entries.clone(),
+      decoded: RwLock::new(self.decoded.read().clone()),
+    }
+  }
+}
+impl KernelBundle {
+  pub fn new() -> Self { Default::default() }
+
+  pub fn len(&self) -> usize { self.entries.len() }
+  pub fn is_empty(&self) -> bool { self.entries.is_empty() }
+
+  /// Add the results of codegen-ing `desc` for `target_desc`. Replaces any
+  /// previous results for the same kernel.
+  ///
+  /// `desc.options` should be the options the kernel was actually codegen-ed
+  /// with (see `CodegenDriver::codegen_options`); `None` means the default
+  /// options.
+  ///
+  /// The bundle is tied to the current build of the program; adding to a
+  /// bundle produced by another build is an error.
+  pub fn insert<P>(&mut self, platform: &P, desc: &PKernelDesc<P>,
+                   target_desc: &AcceleratorTargetDesc,
+                   results: &PCodegenResults<P>)
+    -> Result<(), BundleError>
+    where P: PlatformCodegen,
+  {
+    let binary = binary_id()?;
+    if self.binary.map_or(false, |b| b != binary ) {
+      return Err(BundleError::WrongBuild);
+    }
+    let results = SerializedCodegenResults::encode(platform, results)
+      .ok_or(BundleError::UnsupportedPlatform)?;
+    self.binary = Some(binary);
+    let key = BundleKey::new(desc, target_desc);
+    self.entries.insert(key, results);
+    self.decoded.get_mut().remove(&key);
+    Ok(())
+  }
+  /// Codegen every kernel in `descs` with `driver`, concurrently (see
+  /// `CodegenDriver::codegen_all`), and add them. Descs without options
+  /// are codegen-ed with, and keyed on, the driver's options.
+  pub fn codegen_all<P, I>(&mut self, driver: &Arc<CodegenDriver<P>>, descs: I)
+    -> Result<(), BundleError>
+    where P: PlatformCodegen,
+          I: IntoIterator<Item = PKernelDesc<P>>,
+          PError<P>: Error + Send + Sync + 'static,
+  {
+    let descs: Vec<_> = descs.into_iter()
+      .map(|mut desc| {
+        desc.options.get_or_insert_with(|| driver.codegen_options() );
+        desc
+      })
+      .collect();
+    let results = driver.codegen_all(descs.iter().cloned());
+    for (desc, results) in descs.iter().zip(results.into_iter()) {
+      let results = results
+        .map_err(|err| BundleError::Codegen(Box::new(err)) )?;
+      self.insert(driver.platform(), desc, driver.target_desc(), &results)?;
+    }
+    Ok(())
+  }
+  /// Find the results for `desc`, with its options resolved as in `insert`.
+  pub fn get<P>(&self, platform: &P, desc: &PKernelDesc<P>,
+                target_desc: &AcceleratorTargetDesc)
+    -> Option<Arc<PCodegenResults<P>>>
+    where P: PlatformCodegen,
+  {
+    let key = BundleKey::new(desc, target_desc);
+    let entry = self.entries.get(&key)?;
+
+    let cached = self.decoded.read().get(&key).cloned();
+    let decoded = match cached {
+      Some(decoded) => decoded,
+      None => {
+        let decoded = entry.decode(platform)
+          .map(|results| Arc::new(results) as Arc<dyn Any + Send + Sync> );
+        if decoded.is_none() {
+          warn!("failed to decode bundled kernel {:?}", desc.instance);
+        }
+        self.decoded.write()
+          .entry(key)
+          .or_insert(decoded)
+          .clone()
+      },
+    };
+    decoded?.downcast().ok()
+  }
+
+  /// Merge all of `other`'s kernels into this bundle. Both must have been
+  /// produced by the same build of the program.
+  pub fn extend(&mut self, other: KernelBundle) -> Result<(), BundleError> {
+    match (self.binary, other.binary) {
+      (Some(a), Some(b)) if a != b => { return Err(BundleError::WrongBuild); },
+      (None, binary) => { self.binary = binary; },
+      _ => { },
+    }
+    let decoded = self.decoded.get_mut();
+    for (key, entry) in other.entries.into_iter() {
+      decoded.remove(&key);
+      self.entries.insert(key, entry);
+    }
+    Ok(())
+  }
+
+  /// Check that this bundle was produced by the running build of the
+  /// program.
+  pub fn check_build(&self) -> Result<(), BundleError> {
+    match self.binary {
+      Some(binary) if binary != binary_id()? => Err(BundleError::WrongBuild),
+      _ => Ok(()),
+    }
+  }
+
+  pub fn to_bytes(&self) -> Result<Vec<u8>, BundleError> {
+    let data = BundleData {
+      version: BUNDLE_VERSION,
+      binary: self.binary,
+      entries: self.entries.iter()
+        .map(|(&k, v)| (k, v.clone()) )
+        .collect(),
+    };
+    Ok(rmps::encode::to_vec(&data)?)
+  }
+  pub fn from_bytes(bytes: &[u8]) -> Result<Self, BundleError> {
+    let mut bytes = bytes;
+    Self::read_one(&mut bytes)
+  }
+  /// Decode one bundle from the start of `bytes`, advancing it past the
+  /// bundle.
+  fn read_one(bytes: &mut &[u8]) -> Result<Self, BundleError> {
+    let data: BundleData = rmps::decode::from_read(&mut *bytes)?;
+    if data.version != BUNDLE_VERSION {
+      return Err(BundleError::Version(data.version));
+    }
+    Ok(KernelBundle {
+      binary: data.binary,
+      entries: data.entries.into_iter().collect(),
+      decoded: Default::default(),
+    })
+  }
+
+  pub fn write<T>(&self, path: T) -> Result<(), BundleError>
+    where T: AsRef<Path>,
+  {
+    fs::write(path, self.to_bytes()?)?;
+    Ok(())
+  }
+  pub fn read<T>(path: T) -> Result<Self, BundleError>
+    where T: AsRef<Path>,
+  {
+    Self::from_bytes(&fs::read(path)?)
+  }
+
+  /// The path the sidecar bundle for the current executable is expected at.
+  pub fn sidecar_path() -> io::Result<PathBuf> {
+    use crate::platform::os::self_exe_path;
+
+    let mut path = self_exe_path()?.into_os_string();
+    path.push(".");
+    path.push(BUNDLE_EXTENSION);
+    Ok(path.into())
+  }
+
+  /// Load every bundle embedded into, or placed next to, the current
+  /// executable.
+  pub(crate) fn load_program_bundles() -> Vec<Arc<KernelBundle>> {
+    use crate::platform::os::self_exe_path;
+
+    let mut out = Vec::new();
+
+    match self_exe_path().map_err(BundleError::from)
+      .and_then(|exe| Self::read_embedded(&exe) )
+    {
+      Ok(embedded) => {
+        out.extend(embedded.into_iter().map(Arc::new));
+      },
+      Err(err) => {
+        warn!("failed to load embedded kernel bundles: {}", err);
+      },
+    }
+
+    let sidecar = Self::sidecar_path().map_err(BundleError::from)
+      .and_then(Self::read)
+      .and_then(|bundle| {
+        bundle.check_build()?;
+        Ok(bundle)
+      });
+    match sidecar {
+      Ok(bundle) => {
+        out.push(Arc::new(bundle));
+      },
+      Err(BundleError::Io(ref err)) if err.kind() == ErrorKind::NotFound => { },
+      Err(err) => {
+        warn!("failed to load sidecar kernel bundle: {}", err);
+      },
+    }
+
+    out
+  }
+  /// The linker concatenates every embedded bundle into one section. These
+  /// aren't checked with `check_build`; see the module docs.
+  fn read_embedded(exe: &Path) -> Result<Vec<KernelBundle>, BundleError> {
+    use goblin::Object;
+    use memmap::*;
+
+    let file = File::open(exe)?;
+    let buffer = unsafe {
+      MmapOptions::new().map(&file)?
+    };
+
+    let section = match Object::parse(&buffer)? {
+      Object::Elf(elf) => {
+        elf.section_headers.iter()
+          .find(|header| {
+            if header.sh_type == 0 { return false; }
+            match elf.shdr_strtab.get(header.sh_name) {
+              Some(Ok(name)) => name == BUNDLE_SECTION_NAME,
+              _ => false,
+            }
+          })
+          .map(|header| header.file_range() )
+      },
+      Object::PE(pe) => {
+        pe.sections.iter()
+          .find(|section| section.name().ok() == Some(BUNDLE_SECTION_NAME) )
+          .map(|section| {
+            let start = section.pointer_to_raw_data as usize;
+            start..start + section.size_of_raw_data as usize
+          })
+      },
+      _ => None,
+    };
+    let mut section = match section {
+      Some(range) => {
+        buffer.get(range).ok_or_else(|| {
+          let msg = "kernel bundle section is out of bounds";
+          BundleError::Io(io::Error::new(ErrorKind::InvalidData, msg))
+        })?
+      },
+      None => { return Ok(vec![]); },
+    };
+
+    let mut out = Vec::new();
+    // PE pads sections with zeros.
+    while section.iter().any(|&b| b != 0) {
+      out.push(Self::read_one(&mut section)?);
+    }
+    Ok(out)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn bundle_with(n: u64) -> KernelBundle {
+    let mut bundle = KernelBundle::new();
+    bundle.binary = Some(binary_id().unwrap());
+    let key = BundleKey {
+      kernel: n,
+      spec_params: 0,
+      options: 0,
+      target_desc: 0,
+    };
+    bundle.entries.insert(key, Default::default());
+    bundle
+  }
+
+  #[test]
+  fn bytes_roundtrip() {
+    let bundle = bundle_with(1);
+    let bytes = bundle.to_bytes().unwrap();
+    let bundle2 = KernelBundle::from_bytes(&bytes).unwrap();
+    assert_eq!(bundle2.len(), 1);
+    bundle2.check_build().unwrap();
+  }
+  #[test]
+  fn other_builds_are_rejected() {
+    let mut bundle = bundle_with(1);
+    bundle.binary = Some(!binary_id().unwrap());
+    let bundle = KernelBundle::from_bytes(&bundle.to_bytes().unwrap()).unwrap();
+    match bundle.check_build() {
+      Err(BundleError::WrongBuild) => { },
+      r => panic!("unexpected {:?}", r),
+    }
+    match bundle_with(2).extend(bundle) {
+      Err(BundleError::WrongBuild) => { },
+      r => panic!("unexpected {:?}", r),
+    }
+  }
+  #[test]
+  fn read_concatenated() {
+    let mut bytes = bundle_with(1).to_bytes().unwrap();
+    bytes.extend(bundle_with(2).to_bytes().unwrap());
+
+    let mut section = &bytes[..];
+    let a = KernelBundle::read_one(&mut section).unwrap();
+    let b = KernelBundle::read_one(&mut section).unwrap();
+    assert!(section.is_empty());
+    assert_eq!(a.len() + b.len(), 2);
+  }
+}
@@ -1,6 +1,7 @@
 //! Helpers for platform specific codegen steps.
 
 use std::env::{var_os, };
+use std::io;
 use std::path::{Path, PathBuf, };
 
 use rustc_session::config::host_triple;
@@ -12,6 +13,21 @@ const MESSAGE: &'static str =
 /// Helper for finding LLVM tools.
 pub struct LlvmBuildRoot(PathBuf);
 impl LlvmBuildRoot {
+  /// Like `default`, but returns an error if neither variable is set.
+  pub fn find() -> io::Result<Self> {
+    if let Some(root) = var_os("RUST_BUILD_ROOT") {
+      let llvm = PathBuf::from(root)
+        .join(host_triple())
+        .join("llvm");
+
+      return Ok(LlvmBuildRoot(llvm));
+    }
+
+    var_os("LLVM_BUILD")
+      .map(|root| LlvmBuildRoot(root.into()) )
+      .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, MESSAGE) )
+  }
+
   pub fn llvm_root(&self) -> &PathBuf { &self.0 }
   pub fn llvm_tool<T>(&self, tool: T) -> PathBuf
     where T: AsRef<Path>,
@@ -41,16 +57,6 @@ impl LlvmBuildRoot {
 }
 impl Default for LlvmBuildRoot {
   fn default() -> Self {
-    if let Some(root) = var_os("RUST_BUILD_ROOT") {
-      let llvm = PathBuf::from(root)
-        .join(host_triple())
-        .join("llvm");
-
-      return LlvmBuildRoot(llvm);
-    }
-
-    let root = var_os("LLVM_BUILD")
-      .expect(MESSAGE);
-    LlvmBuildRoot(root.into())
+    Self::find().expect(MESSAGE)
   }
 }
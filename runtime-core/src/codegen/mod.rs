@@ -3,12 +3,10 @@ use std::any::Any;
 use std::cmp::{Eq, PartialEq, };
 use std::collections::BTreeMap;
 use std::fmt::{self, Debug, };
-use std::geobacter::kernel::{KernelInstanceRef, OptionalKernelFn};
+use std::geobacter::kernel::KernelInstanceRef;
 use std::hash;
-use std::mem::size_of;
 use std::ops::Deref;
 use std::path::{Path, };
-use std::slice::from_raw_parts;
 use std::sync::Arc;
 
 use rustc_ast::ast;
@@ -22,16 +20,33 @@ use crate::{Accelerator, AcceleratorTargetDesc, };
 use self::products::{PlatformCodegenDesc, };
 use crate::codegen::products::PCodegenResults;
 
+pub use self::worker::artifacts::{ArtifactSink, KernelArtifacts, Manifest, };
+pub use self::worker::diagnostics;
 pub use self::worker::error;
+pub use self::worker::host;
 pub use self::worker::DriverData;
-pub use self::worker::{CodegenDriver, };
+pub use self::worker::{CodegenDriver, CodegenRequest, };
+pub use self::worker::disk_cache::DiskCacheConfig;
+pub use self::worker::server::CodegenServer;
+pub use self::worker::subprocess::{decode_target_desc, run_codegen_helper, };
+pub use self::options::{CodegenOptions, ForbiddenCalls, };
+pub use self::request::{CancelHandle, Request, RequestError, };
+pub use self::stubbing::{Stub, StubTarget, };
+pub use self::spec_params::{SpecParam, SpecParamChange, SpecParamError, SpecParamsDesc, };
+pub use self::bundle::{KernelBundle, BundleError, };
 
 use crate::any_key::AnyHash;
 
+use serde::{Deserialize, Serialize, };
+
 pub mod attrs;
+pub mod bundle;
 pub mod help;
+pub mod options;
 pub mod worker;
 pub mod products;
+pub mod request;
+pub mod spec_params;
 pub mod stubbing;
 
 use crate::codegen::{attrs::ConditionItem, attrs::geobacter_cfg_attrs, };
@@ -42,6 +57,9 @@ pub struct KernelDesc<P>
 {
   pub instance: KernelInstanceRef<'static>,
   pub spec_params: SpecParamsDesc,
+  /// Overrides the driver's (and context's) codegen options for this
+  /// kernel.
+  pub options: Option<Arc<CodegenOptions>>,
   pub platform_desc: P,
 }
 
@@ -52,6 +70,7 @@ impl<P> KernelDesc<P>
     KernelDesc {
       instance,
       spec_params: Default::default(),
+      options: None,
       platform_desc: platform,
     }
   }
@@ -66,6 +85,8 @@ impl<LP, RP> PartialEq<KernelDesc<RP>> for KernelDesc<LP>
 {
   fn eq(&self, rhs: &KernelDesc<RP>) -> bool {
     if self.instance != rhs.instance { return false; }
+    if self.spec_params != rhs.spec_params { return false; }
+    if self.options != rhs.options { return false; }
 
     self.platform_desc == rhs.platform_desc
   }
@@ -77,76 +98,26 @@ impl<P> hash::Hash for KernelDesc<P>
     where H: hash::Hasher,
   {
     ::std::hash::Hash::hash(&self.instance, hasher);
+    ::std::hash::Hash::hash(&self.spec_params, hasher);
+    ::std::hash::Hash::hash(&self.options, hasher);
     let platform = &self.platform_desc as &dyn AnyHash;
     AnyHash::hash(platform, hasher);
   }
 }
 
-#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
-pub struct SpecParamsDesc(Option<Arc<BTreeMap<KernelInstanceRef<'static>, Vec<u8>>>>);
-impl SpecParamsDesc {
-  fn iter(&self) -> impl Iterator<Item = (&KernelInstanceRef<'static>, &Vec<u8>)> {
-    self.0
-      .iter()
-      .flat_map(|inner| {
-        inner.iter()
-      })
-  }
-  fn get_mut(&mut self) -> &mut BTreeMap<KernelInstanceRef<'static>, Vec<u8>> {
-    let m = self.0.get_or_insert_with(Default::default);
-    Arc::make_mut(m)
-  }
-  pub fn empty(&self) -> bool {
-    self.0.is_none() || self.0.as_ref().unwrap().len() == 0
-  }
-  pub fn clear(&mut self) {
-    if let Some(arc) = self.0.as_mut() {
-      if let Some(map) = Arc::get_mut(arc) {
-        // if we are the only owner, just clear in place.
-        map.clear();
-        return;
-      }
-    } else {
-      return;
-    }
-
-    // else just set to None
-    self.0 = None;
-  }
-  pub fn undefine<F, R>(&mut self, f: F)
-    where F: Fn() -> R,
-  {
-    if self.0.is_none() { return; }
-    let key = f.kernel_instance();
-    self.get_mut().remove(&key);
-  }
-  pub fn define<F, R>(&mut self, f: F, value: &R)
-    where F: Fn() -> R,
-          R: Copy + Unpin + 'static,
-  {
-    unsafe { self.define_raw(f, value) }
-  }
-  pub unsafe fn define_raw<F, R>(&mut self, f: F, value: &R)
-    where F: Fn() -> R,
-  {
-    let key = f.kernel_instance();
-
-    let mut bytes = Vec::with_capacity(size_of::<R>());
-    bytes.set_len(size_of::<R>());
-    let value_bytes =
-      from_raw_parts(value as *const R as *const u8,
-                     size_of::<R>());
-    bytes.copy_from_slice(value_bytes);
-
-    self.get_mut().insert(key, bytes);
-  }
-}
-
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct CodegenKernelInstance {
   pub name: String,
   pub instance: Vec<u8>,
 }
+impl CodegenKernelInstance {
+  pub fn as_ref(&self) -> KernelInstanceRef {
+    KernelInstanceRef {
+      name: &self.name,
+      instance: &self.instance,
+    }
+  }
+}
 impl<'a> From<KernelInstanceRef<'a>> for CodegenKernelInstance {
   fn from(v: KernelInstanceRef<'a>) -> Self {
     CodegenKernelInstance {
@@ -243,12 +214,18 @@ pub trait PlatformCodegen: Sized + Clone + Debug + Send + Sync + 'static {
     <Self::Device as Accelerator>::downcast_ref(accel).is_some()
   }
 
+  /// Stubs this platform always uses, in addition to the builtin stubs,
+  /// eg to report panics. Stubs in the kernel's `CodegenOptions` take
+  /// precedence over these.
+  fn stubs(&self) -> BTreeMap<StubTarget, Stub> { BTreeMap::new() }
+
   /// Change Rust `Session` options to suit.
   fn modify_rustc_session_options(&self, _target_desc: &Arc<AcceleratorTargetDesc>,
                                   _opts: &mut rustc_session::config::Options)
   { }
 
-  /// Add intrinsics which don't depend on the kernel.
+  /// Add intrinsics which don't depend on the kernel. These replace any
+  /// generic intrinsic of the same name.
   fn insert_intrinsics<F>(&self,
                           target_desc: &Arc<AcceleratorTargetDesc>,
                           into: &mut F)
@@ -299,12 +276,43 @@ pub trait PlatformCodegen: Sized + Clone + Debug + Send + Sync + 'static {
   /// `codegen.outputs` with the results.
   /// Currently run inside the codegen worker, however in the future this will
   /// likely be sent to a thread pool.
+  /// `options` are those the kernel was compiled with. There's only an
+  /// object output if `GEOBACTER_USE_LLC` isn't set; platforms which run
+  /// llc themselves otherwise should pass `options.opt_level.llc_arg()`.
   fn post_codegen(&self,
                   target_desc: &Arc<AcceleratorTargetDesc>,
+                  options: &CodegenOptions,
                   tdir: &Path,
                   codegen: &mut PCodegenResults<Self>)
     -> Result<(), <Self::Device as crate::Device>::Error>;
 
+  /// Encode a `CodegenDesc` for storage in the on-disk codegen cache.
+  /// Platforms which return `None` (the default) never have their results
+  /// persisted.
+  fn encode_codegen_desc(&self, _desc: &Self::CodegenDesc) -> Option<Vec<u8>> {
+    None
+  }
+  /// The inverse of `encode_codegen_desc`. Returning `None` causes the
+  /// cache entry to be discarded.
+  fn decode_codegen_desc(&self, _bytes: &[u8]) -> Option<Self::CodegenDesc> {
+    None
+  }
+  /// Encode a `KernelDesc` for a codegen helper process; see
+  /// `CodegenDriver::set_subprocess_codegen`. Platforms which return `None`
+  /// (the default) always codegen in process.
+  fn encode_kernel_desc(&self, _desc: &Self::KernelDesc) -> Option<Vec<u8>> {
+    None
+  }
+  /// The inverse of `encode_kernel_desc`.
+  fn decode_kernel_desc(&self, _bytes: &[u8]) -> Option<Self::KernelDesc> {
+    None
+  }
+  /// Decode the accelerator target desc in a codegen helper process. Usually
+  /// just `decode_target_desc::<MyTargetDesc>(bytes)`.
+  fn decode_target_desc(&self, _bytes: &[u8]) -> Option<AcceleratorTargetDesc> {
+    None
+  }
+
   // The following are all overrides for queries.
 
   /// Modify the provided `attrs` to suit platforms requirement, including
@@ -318,7 +326,7 @@ pub trait PlatformCodegen: Sized + Clone + Debug + Send + Sync + 'static {
     -> &'tcx [ast::Attribute]
   {
     let conditions = dd.root_conditions();
-    geobacter_cfg_attrs(tcx, attrs, &*conditions)
+    geobacter_cfg_attrs(tcx, attrs, &**dd.target_desc, &*conditions)
   }
 
   /// Modify the provided `attrs` to suit the needs of the platform.
@@ -330,3 +338,35 @@ pub trait PlatformCodegen: Sized + Clone + Debug + Send + Sync + 'static {
                             _attrs: &mut CodegenFnAttrs)
   { }
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::utils::StableHash;
+
+  #[derive(Clone, Debug, Eq, PartialEq, Hash)]
+  struct TestDesc;
+  impl PlatformKernelDesc for TestDesc { }
+
+  fn dim() -> u32 { 0 }
+
+  /// Kernels differing only in their spec params used to share a cache
+  /// entry.
+  #[test]
+  fn kernel_desc_includes_spec_params() {
+    let instance = KernelInstanceRef {
+      name: "kernel",
+      instance: &[],
+    };
+    let mut l = KernelDesc::new(instance, TestDesc);
+    let mut r = l.clone();
+    l.spec_params.define(dim, &16u32);
+    r.spec_params.define(dim, &32u32);
+    assert_ne!(l, r);
+    assert_ne!(l.stable_hash(), r.stable_hash());
+
+    r.spec_params.define(dim, &16u32);
+    assert_eq!(l, r);
+    assert_eq!(l.stable_hash(), r.stable_hash());
+  }
+}
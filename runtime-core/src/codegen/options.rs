@@ -0,0 +1,172 @@
+//! Options controlling how kernels are optimized and compiled. These can be
+//! set for a whole `Context`, for a single `CodegenDriver` (ie accelerator),
+//! or for a single kernel, in increasing order of precedence. The options a
+//! kernel was compiled with are part of its cache keys.
+
+use std::collections::BTreeMap;
+
+use rustc_session::config;
+
+use serde::{Deserialize, Serialize, };
+
+use crate::codegen::stubbing::{Stub, StubTarget, };
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum OptLevel {
+  /// `-O0`
+  No,
+  /// `-O1`
+  Less,
+  /// `-O2`
+  Default,
+  /// `-O3`
+  Aggressive,
+  /// `-Os`
+  Size,
+  /// `-Oz`
+  SizeMin,
+}
+impl OptLevel {
+  /// The `llc` flag for this level. `llc` has no size levels; rustc uses
+  /// `-O2` for codegen at those.
+  pub fn llc_arg(&self) -> &'static str {
+    match self {
+      OptLevel::No => "-O0",
+      OptLevel::Less => "-O1",
+      OptLevel::Default | OptLevel::Size | OptLevel::SizeMin => "-O2",
+      OptLevel::Aggressive => "-O3",
+    }
+  }
+}
+impl From<OptLevel> for config::OptLevel {
+  fn from(v: OptLevel) -> config::OptLevel {
+    match v {
+      OptLevel::No => config::OptLevel::No,
+      OptLevel::Less => config::OptLevel::Less,
+      OptLevel::Default => config::OptLevel::Default,
+      OptLevel::Aggressive => config::OptLevel::Aggressive,
+      OptLevel::Size => config::OptLevel::Size,
+      OptLevel::SizeMin => config::OptLevel::SizeMin,
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum DebugInfo {
+  None,
+  Limited,
+  Full,
+}
+impl From<DebugInfo> for config::DebugInfo {
+  fn from(v: DebugInfo) -> config::DebugInfo {
+    match v {
+      DebugInfo::None => config::DebugInfo::None,
+      DebugInfo::Limited => config::DebugInfo::Limited,
+      DebugInfo::Full => config::DebugInfo::Full,
+    }
+  }
+}
+
+/// What to do about calls reachable from the kernel which won't work as
+/// written on the device: calls of functions stubbed to abort, of `extern`
+/// functions without a device definition, and indirect calls, if the device
+/// doesn't support them. Each is reported with its call path from the kernel.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum ForbiddenCalls {
+  /// Don't check.
+  Allow,
+  /// Emit a warning for each call. Warnings from successful codegens are
+  /// logged.
+  Warn,
+  /// Emit an error for each call, failing the codegen.
+  Deny,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct CodegenOptions {
+  pub opt_level: OptLevel,
+  /// Run Polly. Ignored unless `opt_level` is `Aggressive`.
+  pub polly: bool,
+  pub debuginfo: DebugInfo,
+  /// LLVM passes to run in addition to the usual pipeline.
+  pub passes: Vec<String>,
+  /// Extra arguments passed to LLVM, eg `-unroll-threshold=100`.
+  pub llvm_args: Vec<String>,
+  /// Functions to replace on the device, in addition to the builtin
+  /// stubs. See `stubbing`.
+  pub stubs: BTreeMap<StubTarget, Stub>,
+  pub forbidden_calls: ForbiddenCalls,
+}
+impl CodegenOptions {
+  /// Quick to compile. No optimizations, full debuginfo. Forbidden calls
+  /// are warned about.
+  pub fn debug() -> Self {
+    CodegenOptions {
+      opt_level: OptLevel::No,
+      polly: false,
+      debuginfo: DebugInfo::Full,
+      passes: vec![],
+      llvm_args: vec![],
+      stubs: Default::default(),
+      forbidden_calls: ForbiddenCalls::Warn,
+    }
+  }
+
+  pub fn with_opt_level(mut self, opt_level: OptLevel) -> Self {
+    self.opt_level = opt_level;
+    self
+  }
+  pub fn with_polly(mut self, polly: bool) -> Self {
+    self.polly = polly;
+    self
+  }
+  pub fn with_debuginfo(mut self, debuginfo: DebugInfo) -> Self {
+    self.debuginfo = debuginfo;
+    self
+  }
+  pub fn with_pass<T>(mut self, pass: T) -> Self
+    where T: Into<String>,
+  {
+    self.passes.push(pass.into());
+    self
+  }
+  pub fn with_llvm_arg<T>(mut self, arg: T) -> Self
+    where T: Into<String>,
+  {
+    self.llvm_args.push(arg.into());
+    self
+  }
+  /// Replaces an earlier stub of `target`.
+  pub fn with_stub(mut self, target: StubTarget, stub: Stub) -> Self {
+    self.stubs.insert(target, stub);
+    self
+  }
+  /// Shorthand for stubbing the function at `path`.
+  pub fn with_path_stub<T>(self, path: T, stub: Stub) -> Self
+    where T: Into<String>,
+  {
+    self.with_stub(StubTarget::Path(path.into()), stub)
+  }
+  pub fn with_forbidden_calls(mut self, forbidden_calls: ForbiddenCalls) -> Self {
+    self.forbidden_calls = forbidden_calls;
+    self
+  }
+
+  pub(crate) fn polly_enabled(&self) -> bool {
+    self.polly && self.opt_level == OptLevel::Aggressive
+  }
+}
+/// What was hardcoded before these options existed.
+impl Default for CodegenOptions {
+  fn default() -> Self {
+    CodegenOptions {
+      opt_level: OptLevel::Aggressive,
+      polly: true,
+      debuginfo: DebugInfo::None,
+      passes: vec![],
+      llvm_args: vec![],
+      stubs: Default::default(),
+      forbidden_calls: ForbiddenCalls::Allow,
+    }
+  }
+}
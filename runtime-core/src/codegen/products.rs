@@ -10,6 +10,8 @@ use any_key::AnyHash;
 
 use rustc_session::config::{OutputType, };
 
+use serde::{Deserialize, Serialize, };
+
 use super::{PlatformCodegen, CodegenKernelInstance, };
 
 #[derive(Debug)]
@@ -107,9 +109,119 @@ impl<CD> hash::Hash for EntryDesc<CD>
   }
 }
 
+/// A platform independent form of `CodegenResults`, for writing to disk.
+/// Only available for platforms which implement
+/// `PlatformCodegen::encode_codegen_desc`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct SerializedCodegenResults {
+  outputs: Vec<(String, Vec<u8>)>,
+  entries: Vec<SerializedEntryDesc>,
+}
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct SerializedEntryDesc {
+  name: String,
+  instance: Vec<u8>,
+  symbol: String,
+  platform: Vec<u8>,
+}
+impl SerializedCodegenResults {
+  /// Returns `None` if the platform can't serialize its codegen descs.
+  pub(crate) fn encode<P>(platform: &P, results: &PCodegenResults<P>) -> Option<Self>
+    where P: PlatformCodegen,
+  {
+    let mut entries = Vec::with_capacity(results.entries.len());
+    for entry in results.entries.iter() {
+      entries.push(SerializedEntryDesc {
+        name: entry.kernel_instance.name.clone(),
+        instance: entry.kernel_instance.instance.clone(),
+        symbol: entry.symbol.clone(),
+        platform: platform.encode_codegen_desc(&entry.platform)?,
+      });
+    }
+
+    Some(SerializedCodegenResults {
+      outputs: results.outputs.iter()
+        .map(|(&ty, data)| (output_type_name(ty).to_string(), data.clone()) )
+        .collect(),
+      entries,
+    })
+  }
+  #[cfg(test)]
+  pub(crate) fn from_outputs(outputs: Vec<(String, Vec<u8>)>) -> Self {
+    SerializedCodegenResults {
+      outputs,
+      entries: vec![],
+    }
+  }
+  /// Returns `None` if any part couldn't be decoded.
+  pub(crate) fn decode<P>(&self, platform: &P) -> Option<PCodegenResults<P>>
+    where P: PlatformCodegen,
+  {
+    let mut results: PCodegenResults<P> = CodegenResults::new();
+    for (name, data) in self.outputs.iter() {
+      results.outputs.insert(output_type_from_name(name)?, data.clone());
+    }
+    for desc in self.entries.iter() {
+      results.entries.push(EntryDesc {
+        kernel_instance: CodegenKernelInstance {
+          name: desc.name.clone(),
+          instance: desc.instance.clone(),
+        },
+        symbol: desc.symbol.clone(),
+        platform: platform.decode_codegen_desc(&desc.platform)?,
+      });
+    }
+
+    Some(results)
+  }
+}
+
+pub(crate) fn output_type_name(ty: OutputType) -> &'static str {
+  match ty {
+    OutputType::Bitcode => "llvm-bc",
+    OutputType::Assembly => "asm",
+    OutputType::LlvmAssembly => "llvm-ir",
+    OutputType::Mir => "mir",
+    OutputType::Metadata => "metadata",
+    OutputType::Object => "obj",
+    OutputType::Exe => "link",
+    OutputType::DepInfo => "dep-info",
+  }
+}
+fn output_type_from_name(name: &str) -> Option<OutputType> {
+  Some(match name {
+    "llvm-bc" => OutputType::Bitcode,
+    "asm" => OutputType::Assembly,
+    "llvm-ir" => OutputType::LlvmAssembly,
+    "mir" => OutputType::Mir,
+    "metadata" => OutputType::Metadata,
+    "obj" => OutputType::Object,
+    "link" => OutputType::Exe,
+    "dep-info" => OutputType::DepInfo,
+    _ => { return None; },
+  })
+}
+
 /// Codegen specific data. This is generated in the codegen worker context,
 /// with access to the `TyCtxt`.
 pub trait PlatformCodegenDesc
   where Self: Debug + Any + Send + Sync,
         Self: AnyHash + 'static,
 { }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn output_type_names_roundtrip() {
+    let all = [
+      OutputType::Bitcode, OutputType::Assembly, OutputType::LlvmAssembly,
+      OutputType::Mir, OutputType::Metadata, OutputType::Object,
+      OutputType::Exe, OutputType::DepInfo,
+    ];
+    for &ty in all.iter() {
+      assert_eq!(output_type_from_name(output_type_name(ty)), Some(ty));
+    }
+  }
+}
@@ -0,0 +1,345 @@
+//! Handles to work, like codegen, running on a `Context`'s thread pool. A
+//! `Request` can be polled, waited on (optionally with a deadline), awaited
+//! as a `Future`, or cancelled.
+//!
+//! Cancelling a request only detaches it: waiters return
+//! `RequestError::Cancelled` immediately, and work which hasn't started yet
+//! is skipped. Work which has already started (eg LLVM) still runs to
+//! completion, so shared state like the codegen caches is still filled in
+//! for other requesters; its result is just dropped. Dropping a request
+//! without cancelling it lets the work finish in the background.
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Weak, };
+use std::sync::atomic::{AtomicBool, Ordering, };
+use std::task::{Context as TaskContext, Poll, Waker, };
+use std::thread;
+use std::time::{Duration, Instant, };
+
+use parking_lot::{Condvar, Mutex, };
+
+use crate::context::Context;
+
+#[derive(Debug)]
+pub enum RequestError<E> {
+  /// The work itself failed.
+  Failed(E),
+  /// The request was cancelled, or the work was dropped before it finished.
+  Cancelled,
+  /// The request's deadline passed before the work finished. The work
+  /// continues in the background.
+  TimedOut,
+}
+impl<E> RequestError<E> {
+  /// Get the error of the work, if it failed.
+  pub fn failed(self) -> Option<E> {
+    match self {
+      RequestError::Failed(err) => Some(err),
+      _ => None,
+    }
+  }
+}
+impl<E> From<E> for RequestError<E> {
+  fn from(v: E) -> Self { RequestError::Failed(v) }
+}
+impl<E> fmt::Display for RequestError<E>
+  where E: fmt::Display,
+{
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      RequestError::Failed(err) => fmt::Display::fmt(err, f),
+      RequestError::Cancelled => write!(f, "request cancelled"),
+      RequestError::TimedOut => write!(f, "request deadline exceeded"),
+    }
+  }
+}
+impl<E> StdError for RequestError<E>
+  where E: StdError + 'static,
+{
+  fn source(&self) -> Option<&(dyn StdError + 'static)> {
+    match self {
+      RequestError::Failed(err) => Some(err),
+      _ => None,
+    }
+  }
+}
+
+enum State<T, E> {
+  Pending {
+    waker: Option<Waker>,
+  },
+  Done(Result<T, E>),
+  Cancelled,
+  /// The result was already returned.
+  Taken,
+}
+struct Shared<T, E> {
+  state: Mutex<State<T, E>>,
+  cv: Condvar,
+  cancelled: AtomicBool,
+}
+impl<T, E> Shared<T, E> {
+  /// Finish the request, unless it was cancelled.
+  fn finish(&self, state: State<T, E>) {
+    let mut guard = self.state.lock();
+    if let State::Pending { ref mut waker, } = *guard {
+      if let Some(waker) = waker.take() {
+        waker.wake();
+      }
+      *guard = state;
+      self.cv.notify_all();
+    }
+  }
+  fn wake(&self) {
+    if let State::Pending { ref mut waker, } = *self.state.lock() {
+      if let Some(waker) = waker.take() {
+        waker.wake();
+      }
+    }
+  }
+  fn cancel(&self) {
+    self.cancelled.store(true, Ordering::Release);
+    self.finish(State::Cancelled);
+  }
+}
+
+/// Completes the request with `Cancelled` if the work panics or is
+/// otherwise dropped without finishing.
+struct Completer<T, E>(Arc<Shared<T, E>>);
+impl<T, E> Drop for Completer<T, E> {
+  fn drop(&mut self) {
+    self.0.finish(State::Cancelled);
+  }
+}
+
+pub struct Request<T, E> {
+  shared: Arc<Shared<T, E>>,
+  deadline: Option<Instant>,
+  timer_started: bool,
+}
+impl<T, E> Request<T, E>
+  where T: Send + 'static,
+        E: Send + 'static,
+{
+  /// Run `f` on `context`'s thread pool.
+  pub fn spawn<F>(context: &Context, f: F) -> Self
+    where F: FnOnce() -> Result<T, E> + Send + 'static,
+  {
+    Self::spawn_with(|job| context.spawn(job), f)
+  }
+  /// Run `f` with `spawn`, eg to queue it somewhere before it's run on a
+  /// thread pool. If `spawn` drops the job, the request is cancelled.
+  pub(crate) fn spawn_with<S, F>(spawn: S, f: F) -> Self
+    where S: FnOnce(Box<dyn FnOnce() + Send>),
+          F: FnOnce() -> Result<T, E> + Send + 'static,
+  {
+    let shared = Arc::new(Shared {
+      state: Mutex::new(State::Pending { waker: None, }),
+      cv: Condvar::new(),
+      cancelled: AtomicBool::new(false),
+    });
+    let completer = Completer(shared.clone());
+    spawn(Box::new(move || {
+      if completer.0.cancelled.load(Ordering::Acquire) { return; }
+      let result = f();
+      completer.0.finish(State::Done(result));
+    }));
+
+    Request {
+      shared,
+      deadline: None,
+      timer_started: false,
+    }
+  }
+}
+impl<T, E> Request<T, E> {
+  /// A request which has already finished.
+  pub fn ready(result: Result<T, E>) -> Self {
+    Request {
+      shared: Arc::new(Shared {
+        state: Mutex::new(State::Done(result)),
+        cv: Condvar::new(),
+        cancelled: AtomicBool::new(false),
+      }),
+      deadline: None,
+      timer_started: false,
+    }
+  }
+
+  /// Waiting past `deadline` returns `RequestError::TimedOut`.
+  pub fn with_deadline(mut self, deadline: Instant) -> Self {
+    self.deadline = Some(deadline);
+    self
+  }
+  pub fn with_timeout(self, timeout: Duration) -> Self {
+    self.with_deadline(Instant::now() + timeout)
+  }
+  pub fn deadline(&self) -> Option<Instant> { self.deadline }
+
+  /// Stop waiting for the work. See the module docs.
+  pub fn cancel(&self) { self.shared.cancel(); }
+  /// Get a handle which can cancel this request from another thread.
+  pub fn cancel_handle(&self) -> CancelHandle<T, E> {
+    CancelHandle(Arc::downgrade(&self.shared))
+  }
+
+  /// Has the work finished, or been cancelled?
+  pub fn is_complete(&self) -> bool {
+    match *self.shared.state.lock() {
+      State::Pending { .. } => false,
+      _ => true,
+    }
+  }
+
+  /// Returns `None` if the work hasn't finished yet and the deadline (if
+  /// any) hasn't passed.
+  /// Panics if the result was already returned.
+  pub fn try_wait(&mut self) -> Option<Result<T, RequestError<E>>> {
+    let mut state = self.shared.state.lock();
+    self.take(&mut *state)
+  }
+  /// Block until the work finishes, the request is cancelled, or the
+  /// deadline passes.
+  pub fn wait(self) -> Result<T, RequestError<E>> {
+    let mut state = self.shared.state.lock();
+    loop {
+      if let Some(result) = self.take(&mut *state) {
+        return result;
+      }
+      match self.deadline {
+        Some(deadline) => {
+          self.shared.cv.wait_until(&mut state, deadline);
+        },
+        None => {
+          self.shared.cv.wait(&mut state);
+        },
+      }
+    }
+  }
+  /// Like `wait`, but with a deadline `timeout` from now, if that's earlier
+  /// than the current deadline.
+  pub fn wait_timeout(self, timeout: Duration) -> Result<T, RequestError<E>> {
+    let deadline = Instant::now() + timeout;
+    let deadline = self.deadline
+      .map(|d| d.min(deadline) )
+      .unwrap_or(deadline);
+    self.with_deadline(deadline).wait()
+  }
+
+  fn take(&self, state: &mut State<T, E>) -> Option<Result<T, RequestError<E>>> {
+    match state {
+      State::Pending { .. } => {
+        match self.deadline {
+          Some(deadline) if Instant::now() >= deadline => {
+            Some(Err(RequestError::TimedOut))
+          },
+          _ => None,
+        }
+      },
+      State::Taken => panic!("request result was already taken"),
+      _ => {
+        match std::mem::replace(state, State::Taken) {
+          State::Done(result) => Some(result.map_err(RequestError::Failed)),
+          State::Cancelled => Some(Err(RequestError::Cancelled)),
+          _ => unreachable!(),
+        }
+      },
+    }
+  }
+}
+impl<T, E> Future for Request<T, E> {
+  type Output = Result<T, RequestError<E>>;
+  fn poll(mut self: Pin<&mut Self>, cx: &mut TaskContext) -> Poll<Self::Output> {
+    let this = &mut *self;
+    let mut state = this.shared.state.lock();
+    if let Some(result) = this.take(&mut *state) {
+      return Poll::Ready(result);
+    }
+    if let State::Pending { ref mut waker, } = *state {
+      *waker = Some(cx.waker().clone());
+    }
+    drop(state);
+
+    // nothing else will wake us at the deadline:
+    if let (Some(deadline), false) = (this.deadline, this.timer_started) {
+      this.timer_started = true;
+      let shared = Arc::downgrade(&this.shared);
+      thread::spawn(move || {
+        let now = Instant::now();
+        if deadline > now {
+          thread::sleep(deadline - now);
+        }
+        if let Some(shared) = shared.upgrade() {
+          shared.wake();
+        }
+      });
+    }
+
+    Poll::Pending
+  }
+}
+impl<T, E> fmt::Debug for Request<T, E> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("Request")
+      .field("complete", &self.is_complete())
+      .field("deadline", &self.deadline)
+      .finish()
+  }
+}
+
+/// Cancels a `Request`, possibly from another thread. Does nothing once the
+/// request is dropped.
+pub struct CancelHandle<T, E>(Weak<Shared<T, E>>);
+impl<T, E> CancelHandle<T, E> {
+  pub fn cancel(&self) {
+    if let Some(shared) = self.0.upgrade() {
+      shared.cancel();
+    }
+  }
+}
+impl<T, E> Clone for CancelHandle<T, E> {
+  fn clone(&self) -> Self { CancelHandle(self.0.clone()) }
+}
+
+#[cfg(test)]
+mod test {
+  use std::sync::mpsc::channel;
+
+  use super::*;
+
+  #[test]
+  fn wait_cancel_and_timeout() {
+    let context = Context::new().unwrap();
+
+    let r: Request<u32, ()> = Request::spawn(&context, || Ok(1) );
+    assert_eq!(r.wait().unwrap(), 1);
+
+    let (tx, rx) = channel::<()>();
+    let blocked: Request<u32, ()> = Request::spawn(&context, move || {
+      let _ = rx.recv();
+      Ok(2)
+    });
+    let timed_out = blocked.with_timeout(Duration::from_millis(10));
+    match timed_out.wait() {
+      Err(RequestError::TimedOut) => { },
+      r => panic!("unexpected {:?}", r),
+    }
+    drop(tx);
+
+    // blocked, so it can't finish before it's cancelled:
+    let (tx, rx) = channel::<()>();
+    let mut r: Request<u32, ()> = Request::spawn(&context, move || {
+      let _ = rx.recv();
+      Ok(3)
+    });
+    r.cancel();
+    match r.try_wait() {
+      Some(Err(RequestError::Cancelled)) => { },
+      r => panic!("unexpected {:?}", r),
+    }
+    drop(tx);
+  }
+}
@@ -0,0 +1,373 @@
+//! Specialization params: values, keyed by a function, which a kernel reads
+//! with `std::geobacter::spec_param::get` as if they were constants.
+//!
+//! Each param is named by the path of its key function and records the type
+//! of its value, so a set of params can be serialized (eg to persist tuning
+//! results) and checked against the kernel when it's loaded again. Params
+//! loaded from a different build of the program need to be re-keyed with
+//! `SpecParamsDesc::bind` before use.
+
+use std::any::type_name;
+use std::collections::BTreeMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::geobacter::kernel::OptionalKernelFn;
+use std::intrinsics::type_id;
+use std::mem::{align_of, size_of, };
+use std::ptr;
+use std::slice::from_raw_parts;
+use std::sync::Arc;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer, };
+
+use crate::codegen::CodegenKernelInstance;
+
+/// The value of a single param.
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub struct SpecParam {
+  /// `std::any::type_name` of the value's type.
+  pub ty: String,
+  /// `std::intrinsics::type_id` of the value's type. Unlike `ty`, this is
+  /// only meaningful to the build of the program which defined the param;
+  /// `SpecParamsDesc::bind` updates it.
+  pub type_id: u64,
+  pub size: usize,
+  pub align: usize,
+  /// The bytes of the value.
+  pub value: Vec<u8>,
+}
+impl SpecParam {
+  pub fn new<R>(value: &R) -> Self
+    where R: Copy + Unpin + 'static,
+  {
+    unsafe { Self::new_raw(value) }
+  }
+  /// Unsafe because the value is copied bytewise, and will be read as a `R`
+  /// in the kernel.
+  pub unsafe fn new_raw<R>(value: &R) -> Self
+    where R: 'static,
+  {
+    let bytes = from_raw_parts(value as *const R as *const u8, size_of::<R>());
+    SpecParam {
+      ty: type_name::<R>().into(),
+      type_id: type_id::<R>(),
+      size: size_of::<R>(),
+      align: align_of::<R>(),
+      value: bytes.to_owned(),
+    }
+  }
+
+  /// Check this param has type `R`. `name` is only used for the error.
+  pub fn check<R>(&self, name: &str) -> Result<(), SpecParamError>
+    where R: 'static,
+  {
+    if self.type_id == type_id::<R>() {
+      self.check_name::<R>(name)
+    } else {
+      Err(self.mismatch::<R>(name))
+    }
+  }
+  /// Like `check`, but doesn't compare the type id, which differs between
+  /// builds.
+  fn check_name<R>(&self, name: &str) -> Result<(), SpecParamError> {
+    if self.ty == type_name::<R>() && self.size == size_of::<R>() &&
+      self.align == align_of::<R>() && self.value.len() == self.size
+    {
+      Ok(())
+    } else {
+      Err(self.mismatch::<R>(name))
+    }
+  }
+  fn mismatch<R>(&self, name: &str) -> SpecParamError {
+    SpecParamError::TypeMismatch {
+      param: name.into(),
+      expected: type_name::<R>().into(),
+      expected_size: size_of::<R>(),
+      found: self.ty.clone(),
+      found_size: self.value.len(),
+    }
+  }
+  pub fn get<R>(&self, name: &str) -> Result<R, SpecParamError>
+    where R: Copy + Unpin + 'static,
+  {
+    self.check::<R>(name)?;
+    Ok(unsafe { ptr::read_unaligned(self.value.as_ptr() as *const R) })
+  }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SpecParamError {
+  /// The param's value isn't of the expected type.
+  TypeMismatch {
+    param: String,
+    expected: String,
+    expected_size: usize,
+    found: String,
+    found_size: usize,
+  },
+  /// No param is named `0`.
+  Undefined(String),
+}
+impl fmt::Display for SpecParamError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      SpecParamError::TypeMismatch {
+        param, expected, expected_size, found, found_size,
+      } => {
+        write!(f, "spec param `{}` has type `{}` ({} bytes), expected `{}` ({} bytes)",
+               param, found, found_size, expected, expected_size)
+      },
+      SpecParamError::Undefined(param) => {
+        write!(f, "spec param `{}` isn't defined", param)
+      },
+    }
+  }
+}
+impl StdError for SpecParamError { }
+
+/// How a param differs between two `SpecParamsDesc`s. See
+/// `SpecParamsDesc::diff`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SpecParamChange<'a> {
+  Added(&'a SpecParam),
+  Removed(&'a SpecParam),
+  Changed {
+    old: &'a SpecParam,
+    new: &'a SpecParam,
+  },
+}
+
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct SpecParamsDesc(Option<Arc<BTreeMap<CodegenKernelInstance, SpecParam>>>);
+impl SpecParamsDesc {
+  /// Every defined param, with its key function.
+  pub fn iter(&self) -> impl Iterator<Item = (&CodegenKernelInstance, &SpecParam)> {
+    self.0
+      .iter()
+      .flat_map(|inner| {
+        inner.iter()
+      })
+  }
+  /// The names of the defined params, ie the paths of their key functions.
+  pub fn names(&self) -> impl Iterator<Item = &str> {
+    self.iter().map(|(key, _)| &key.name[..] )
+  }
+  fn get_mut(&mut self) -> &mut BTreeMap<CodegenKernelInstance, SpecParam> {
+    let m = self.0.get_or_insert_with(Default::default);
+    Arc::make_mut(m)
+  }
+  pub fn empty(&self) -> bool {
+    self.0.is_none() || self.0.as_ref().unwrap().len() == 0
+  }
+  pub fn len(&self) -> usize {
+    self.0.as_ref().map(|m| m.len() ).unwrap_or_default()
+  }
+  pub fn clear(&mut self) {
+    if let Some(arc) = self.0.as_mut() {
+      if let Some(map) = Arc::get_mut(arc) {
+        // if we are the only owner, just clear in place.
+        map.clear();
+        return;
+      }
+    } else {
+      return;
+    }
+
+    // else just set to None
+    self.0 = None;
+  }
+  pub fn undefine<F, R>(&mut self, f: F)
+    where F: Fn() -> R,
+  {
+    if self.0.is_none() { return; }
+    let key = f.kernel_instance().into();
+    self.get_mut().remove(&key);
+  }
+  pub fn define<F, R>(&mut self, f: F, value: &R)
+    where F: Fn() -> R,
+          R: Copy + Unpin + 'static,
+  {
+    unsafe { self.define_raw(f, value) }
+  }
+  pub unsafe fn define_raw<F, R>(&mut self, f: F, value: &R)
+    where F: Fn() -> R,
+          R: 'static,
+  {
+    let key = f.kernel_instance().into();
+    self.get_mut().insert(key, SpecParam::new_raw(value));
+  }
+
+  pub fn get_param<F, R>(&self, f: F) -> Option<&SpecParam>
+    where F: Fn() -> R,
+  {
+    let key: CodegenKernelInstance = f.kernel_instance().into();
+    self.0.as_ref()?.get(&key)
+  }
+  /// Get the value of the param keyed by `f`, checking that it was defined
+  /// with the type `f` returns.
+  pub fn get<F, R>(&self, f: F) -> Result<Option<R>, SpecParamError>
+    where F: Fn() -> R,
+          R: Copy + Unpin + 'static,
+  {
+    let key: CodegenKernelInstance = f.kernel_instance().into();
+    match self.0.as_ref().and_then(|m| m.get(&key) ) {
+      Some(param) => param.get::<R>(&key.name).map(Some),
+      None => Ok(None),
+    }
+  }
+
+  /// Re-key the param with the same name as `f` to `f`, after checking its
+  /// type by name. Needed for params deserialized from a different build of
+  /// the program, whose function keys and type ids are stale. Returns an
+  /// error if there is no such param.
+  pub fn bind<F, R>(&mut self, f: F) -> Result<(), SpecParamError>
+    where F: Fn() -> R,
+          R: 'static,
+  {
+    let key: CodegenKernelInstance = f.kernel_instance().into();
+    let old_key = self.iter()
+      .find(|(k, _)| k.name == key.name )
+      .map(|(k, param)| {
+        param.check_name::<R>(&k.name)
+          .map(|()| k.clone() )
+      })
+      .ok_or_else(|| SpecParamError::Undefined(key.name.clone()) )??;
+
+    let map = self.get_mut();
+    let mut param = map.remove(&old_key).unwrap();
+    param.type_id = type_id::<R>();
+    map.insert(key, param);
+    Ok(())
+  }
+
+  /// The params which were added, removed, or changed in `newer`, in key
+  /// order.
+  pub fn diff<'a>(&'a self, newer: &'a Self)
+    -> Vec<(&'a CodegenKernelInstance, SpecParamChange<'a>)>
+  {
+    let mut out = vec![];
+    for (key, old) in self.iter() {
+      match newer.0.as_ref().and_then(|m| m.get(key) ) {
+        None => out.push((key, SpecParamChange::Removed(old))),
+        Some(new) if new != old => {
+          out.push((key, SpecParamChange::Changed { old, new, }));
+        },
+        Some(_) => { },
+      }
+    }
+    for (key, new) in newer.iter() {
+      if self.0.as_ref().map(|m| !m.contains_key(key) ).unwrap_or(true) {
+        out.push((key, SpecParamChange::Added(new)));
+      }
+    }
+    out.sort_by(|(l, _), (r, _)| l.cmp(r) );
+    out
+  }
+}
+
+/// The serialized form of a single param.
+#[derive(Serialize, Deserialize)]
+struct SerdeSpecParam {
+  name: String,
+  instance: Vec<u8>,
+  ty: String,
+  #[serde(default)]
+  type_id: u64,
+  size: usize,
+  align: usize,
+  value: Vec<u8>,
+}
+impl Serialize for SpecParamsDesc {
+  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where S: Serializer,
+  {
+    serializer.collect_seq(self.iter().map(|(key, param)| {
+      SerdeSpecParam {
+        name: key.name.clone(),
+        instance: key.instance.clone(),
+        ty: param.ty.clone(),
+        type_id: param.type_id,
+        size: param.size,
+        align: param.align,
+        value: param.value.clone(),
+      }
+    }))
+  }
+}
+impl<'de> Deserialize<'de> for SpecParamsDesc {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where D: Deserializer<'de>,
+  {
+    let params = Vec::<SerdeSpecParam>::deserialize(deserializer)?;
+    if params.is_empty() { return Ok(Default::default()); }
+
+    let map = params.into_iter()
+      .map(|p| {
+        let key = CodegenKernelInstance {
+          name: p.name,
+          instance: p.instance,
+        };
+        (key, SpecParam {
+          ty: p.ty,
+          type_id: p.type_id,
+          size: p.size,
+          align: p.align,
+          value: p.value,
+        })
+      })
+      .collect();
+    Ok(SpecParamsDesc(Some(Arc::new(map))))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn key(name: &str, instance: u8) -> CodegenKernelInstance {
+    CodegenKernelInstance {
+      name: name.into(),
+      instance: vec![instance],
+    }
+  }
+
+  #[test]
+  fn typed_param() {
+    let p = SpecParam::new(&16u32);
+    assert_eq!(p.get::<u32>("dim"), Ok(16));
+    assert!(p.get::<i32>("dim").is_err());
+    assert!(p.get::<u64>("dim").is_err());
+
+    // Same name and layout, but not the same type:
+    let mut p = p;
+    p.type_id = type_id::<i32>();
+    assert!(p.get::<u32>("dim").is_err());
+  }
+
+  #[test]
+  fn serde_roundtrip_and_diff() {
+    let mut old = SpecParamsDesc::default();
+    old.get_mut().insert(key("dim", 0), SpecParam::new(&16u32));
+    old.get_mut().insert(key("tile", 1), SpecParam::new(&8u16));
+
+    let json = serde_json::to_string(&old).unwrap();
+    let mut new: SpecParamsDesc = serde_json::from_str(&json).unwrap();
+    assert_eq!(new, old);
+    assert!(old.diff(&new).is_empty());
+
+    new.get_mut().insert(key("dim", 0), SpecParam::new(&32u32));
+    new.get_mut().remove(&key("tile", 1));
+    new.get_mut().insert(key("unroll", 2), SpecParam::new(&true));
+    let names = old.diff(&new).into_iter()
+      .map(|(key, change)| {
+        let change = match change {
+          SpecParamChange::Added(_) => "added",
+          SpecParamChange::Removed(_) => "removed",
+          SpecParamChange::Changed { .. } => "changed",
+        };
+        (&key.name[..], change)
+      })
+      .collect::<Vec<_>>();
+    assert_eq!(names, vec![("dim", "changed"), ("tile", "removed"), ("unroll", "added"), ]);
+  }
+}
@@ -3,25 +3,142 @@
 /// we need to override with an aborting stub. `panic!`
 /// will likely never be supported in shaders, and probably won't
 /// receive support in kernels.
-/// TODO info about which stub caused an abort.
+/// More stubs can be added with `CodegenOptions::with_stub`, eg to remove
+/// logging calls in third-party crates. These take precedence over the
+/// builtin stubs.
+/// Platforms can replace the panic stubs to report where a panic happened;
+/// see `PlatformCodegen::stubs`.
 /// TODO it would be nice to be able to map DefId -> KernelId instead of
 /// String -> KernelId. Need a way to translate the absolute path into a
 /// DefId.
 
+use std::collections::BTreeMap;
 use std::geobacter::kernel::*;
 
 use rustc_data_structures::fx::{FxHashMap};
 use rustc_geobacter::TyCtxtKernelInstance;
 use rustc_hir::def_id::DefId;
+use rustc_index::vec::IndexVec;
+use rustc_middle::mir::*;
 use rustc_middle::ty::TyCtxt;
 
+use serde::{Deserialize, Serialize, };
+
+use crate::codegen::CodegenKernelInstance;
+
+/// The function(s) a user stub replaces.
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+pub enum StubTarget {
+  /// An absolute path, as printed by rustc, eg
+  /// `log::__private_api_log`.
+  Path(String),
+  /// The name of a lang item, ie `#[lang = "name"]`.
+  LangItem(String),
+}
+/// What a stubbed function is replaced with.
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum Stub {
+  /// Abort the dispatch.
+  Abort,
+  /// Return immediately. Only functions returning `()` can be no-ops;
+  /// stubbing any other function this way fails the codegen.
+  Noop,
+  /// Call this function instead. Its signature must match the stubbed
+  /// function's signature, including generic parameters. Stubbed foreign
+  /// functions must use this.
+  Replace(CodegenKernelInstance),
+}
+impl Stub {
+  pub fn replace<F, Args, Ret>(f: F) -> Self
+    where F: Fn<Args, Output = Ret>,
+  {
+    Stub::Replace(f.kernel_instance().into())
+  }
+}
+
+/// A stub which doesn't have an implementation; its MIR is generated.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum GeneratedStub {
+  Abort,
+  Noop,
+}
+
+/// The path of `self::stubs`.
+const BUILTIN_STUBS: &'static str = "geobacter_runtime_core::codegen::stubbing::stubs::";
+
 pub struct Stubber {
   /// Are we stubbing the "builtin" stubs? See `self::stubs`.
   builtins: bool,
-  stubs: FxHashMap<String, KernelInstanceRef<'static>>,
+  paths: FxHashMap<String, Stub>,
+  lang_items: FxHashMap<String, Stub>,
 }
 
 impl Stubber {
+  /// `user` stubs take precedence over `platform` stubs.
+  pub fn new(platform: &BTreeMap<StubTarget, Stub>,
+             user: &BTreeMap<StubTarget, Stub>)
+    -> Self
+  {
+    let mut out = Stubber::default();
+    for (target, stub) in platform.iter().chain(user.iter()) {
+      match target {
+        StubTarget::Path(path) => {
+          out.paths.insert(path.clone(), stub.clone());
+        },
+        StubTarget::LangItem(name) => {
+          out.lang_items.insert(name.clone(), stub.clone());
+        },
+      }
+    }
+    out
+  }
+
+  fn user_stub<'tcx>(&self, tcx: TyCtxt<'tcx>, did: DefId, path: &str) -> Option<&Stub> {
+    if let Some(stub) = self.paths.get(path) {
+      return Some(stub);
+    }
+    // `core` is sometimes reached through std's workspace shim:
+    const SHIM: &'static str = "rustc_std_workspace_core::";
+    if path.starts_with(SHIM) {
+      let path = format!("core::{}", &path[SHIM.len()..]);
+      if let Some(stub) = self.paths.get(&path) {
+        return Some(stub);
+      }
+    }
+    if self.lang_items.len() != 0 {
+      let attrs = tcx.get_attrs(did);
+      if let Some((name, _)) = rustc_hir::lang_items::extract(&attrs) {
+        return self.lang_items.get(&*name.as_str());
+      }
+    }
+    None
+  }
+
+  /// If `did` is replaced with a generated stub, its kind. See
+  /// `generated_stub_body`.
+  pub fn generated_stub<'tcx>(&self, tcx: TyCtxt<'tcx>, did: DefId) -> Option<GeneratedStub> {
+    if self.paths.len() == 0 && self.lang_items.len() == 0 { return None; }
+
+    let path = tcx.def_path_str(did);
+    match self.user_stub(tcx, did, &path)? {
+      Stub::Abort => Some(GeneratedStub::Abort),
+      Stub::Noop => Some(GeneratedStub::Noop),
+      Stub::Replace(_) => None,
+    }
+  }
+
+  /// Does calling `original`, which `stub_def_id` resolved to `stubbed`,
+  /// just abort? True for `Stub::Abort` and the builtin stubs (see
+  /// `self::stubs`); replacements, eg a platform's heap or panic stubs, are
+  /// real implementations.
+  pub fn aborts<'tcx>(&self, tcx: TyCtxt<'tcx>, original: DefId, stubbed: DefId) -> bool {
+    if original != stubbed {
+      tcx.def_path_str(stubbed).starts_with(BUILTIN_STUBS)
+    } else {
+      self.generated_stub(tcx, original) == Some(GeneratedStub::Abort)
+    }
+  }
+
   pub fn stub_def_id<'tcx>(&self, tcx: TyCtxt<'tcx>, did: DefId) -> DefId {
     use self::stubs::*;
 
@@ -38,11 +155,12 @@ impl Stubber {
       stub_instance.def_id()
     };
 
-    // let an entry in stubs override our builtin stubs (see `self::stubs`):
-    if self.stubs.len() != 0 {
-      if let Some(&ki) = self.stubs.get(&path) {
-        return convert_ki(ki);
-      }
+    // let user stubs override our builtin stubs (see `self::stubs`):
+    match self.user_stub(tcx, did, &path) {
+      Some(Stub::Replace(ki)) => { return convert_ki(ki.as_ref()); },
+      // the MIR of `did` is generated instead; see `generated_stub_body`.
+      Some(Stub::Abort) | Some(Stub::Noop) => { return did; },
+      None => { },
     }
 
     if !self.builtins { return did; }
@@ -144,11 +262,58 @@ impl Default for Stubber {
   fn default() -> Self {
     Stubber {
       builtins: true,
-      stubs: Default::default(),
+      paths: Default::default(),
+      lang_items: Default::default(),
     }
   }
 }
 
+/// Create the MIR of a generated stub of `did`: a single block which
+/// either aborts or returns.
+pub fn generated_stub_body<'tcx>(tcx: TyCtxt<'tcx>, did: DefId, stub: GeneratedStub)
+  -> Body<'tcx>
+{
+  let span = tcx.def_span(did);
+  let sig = tcx.fn_sig(did);
+  let sig = tcx.erase_late_bound_regions(&sig);
+
+  let kind = match stub {
+    GeneratedStub::Noop if sig.output().is_unit() => TerminatorKind::Return,
+    GeneratedStub::Noop => {
+      let msg = format!("`{}` can't be a no-op stub: it doesn't return `()`",
+                        tcx.def_path_str(did));
+      tcx.sess.span_err(span, &msg);
+      TerminatorKind::Abort
+    },
+    GeneratedStub::Abort => TerminatorKind::Abort,
+  };
+
+  let source_info = SourceInfo::outermost(span);
+  let mut local_decls = IndexVec::new();
+  local_decls.push(LocalDecl::new(sig.output(), span));
+  for &input in sig.inputs().iter() {
+    local_decls.push(LocalDecl::new(input, span));
+  }
+
+  let mut blocks = IndexVec::new();
+  blocks.push(BasicBlockData::new(Some(Terminator {
+    source_info,
+    kind,
+  })));
+
+  let mut scopes = IndexVec::new();
+  scopes.push(SourceScopeData {
+    span,
+    parent_scope: None,
+    local_data: ClearCrossCrate::Clear,
+  });
+
+  let mut body = Body::new(blocks, scopes, local_decls, IndexVec::new(),
+                           sig.inputs().len(), vec![], span, None);
+  body.phase = MirPhase::Optimization;
+  body
+}
+
 mod stubs {
   #![allow(unused_variables)]
 
@@ -288,3 +453,21 @@ mod stubs {
   // Never called.
   pub fn rust_eh_personality() {}
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::codegen::CodegenOptions;
+
+  #[test]
+  fn user_stubs_by_target() {
+    let options = CodegenOptions::default()
+      .with_path_stub("log::__private_api_log", Stub::Abort)
+      .with_path_stub("log::__private_api_log", Stub::Noop)
+      .with_stub(StubTarget::LangItem("panic_impl".into()), Stub::Abort);
+    let stubber = Stubber::new(&Default::default(), &options.stubs);
+    assert_eq!(stubber.paths.get("log::__private_api_log"), Some(&Stub::Noop));
+    assert_eq!(stubber.lang_items.get("panic_impl"), Some(&Stub::Abort));
+    assert_eq!(stubber.paths.len(), 1);
+  }
+}
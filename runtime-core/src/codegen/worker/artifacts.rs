@@ -0,0 +1,349 @@
+//! What happens to codegen intermediates (the LLVM IR, assembly, bitcode,
+//! objects, etc) once a kernel is compiled. By default they are deleted.
+//! Otherwise they can be kept, in a per-kernel directory, or handed to a
+//! callback.
+//!
+//! Kept kernels are indexed in `manifest.json`, in the root artifact
+//! directory, so tooling can find the IR of a kernel without having to know
+//! how the directories are named. The directory can be shared by several
+//! processes; updates to the manifest are serialized with a lock file, and
+//! the manifest is replaced atomically, so readers never see a partial one.
+
+use std::fmt;
+use std::fs::{self, OpenOptions, };
+use std::geobacter::kernel::KernelInstanceRef;
+use std::io::{self, ErrorKind, Write, };
+use std::path::{Path, PathBuf, };
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize, };
+
+use tempfile::{NamedTempFile, TempDir, };
+
+use crate::utils::CreateIfNotExists;
+use crate::utils::env::codegen_artifacts_dir;
+
+pub const MANIFEST_NAME: &'static str = "manifest.json";
+const MANIFEST_LOCK_NAME: &'static str = "manifest.json.lock";
+
+pub type ArtifactCallback = dyn Fn(&KernelArtifacts) -> io::Result<()> + Send + Sync;
+
+#[derive(Clone)]
+pub enum ArtifactSink {
+  /// Delete everything once codegen is finished.
+  Delete,
+  /// Keep the intermediates of every kernel in a subdirectory of this
+  /// directory, named after the kernel. The subdirectory is replaced if the
+  /// kernel is compiled again.
+  Keep(PathBuf),
+  /// Call this with the intermediates. They are deleted once it returns.
+  Callback(Arc<ArtifactCallback>),
+}
+impl ArtifactSink {
+  /// The sink used when a driver is created: `Keep` if
+  /// `GEOBACTER_CODEGEN_ARTIFACTS_DIR` is set, otherwise `Delete`.
+  pub fn from_env() -> Self {
+    match codegen_artifacts_dir() {
+      Some(dir) => ArtifactSink::Keep(dir),
+      None => ArtifactSink::Delete,
+    }
+  }
+
+  /// Called after codegen has finished, successfully or not.
+  pub(crate) fn retire(&self, artifacts: TempDir, kernel: KernelArtifactsDesc)
+    -> io::Result<()>
+  {
+    match self {
+      ArtifactSink::Delete => artifacts.close(),
+      ArtifactSink::Keep(root) => {
+        let files = list_files(artifacts.path())?;
+        let dir = kernel.dir_name();
+        let dest = root.join(&dir);
+
+        root.create_if_not_exists()?;
+        // Held until the manifest is updated, so concurrent compiles of the
+        // same kernel don't race to replace its directory.
+        let lock = Manifest::lock(root)?;
+        match fs::remove_dir_all(&dest) {
+          Ok(()) => { },
+          Err(err) if err.kind() == ErrorKind::NotFound => { },
+          Err(err) => { return Err(err); },
+        }
+        let src = artifacts.into_path();
+        if fs::rename(&src, &dest).is_err() {
+          // probably on different filesystems.
+          let copied = copy_files(&src, &dest, &files);
+          // `src` isn't deleted automatically anymore:
+          let removed = fs::remove_dir_all(&src);
+          if let Err(err) = copied {
+            let _ = fs::remove_dir_all(&dest);
+            return Err(err);
+          }
+          removed?;
+        }
+
+        info!("codegen intermediates of {:?} kept in {}",
+              kernel.instance, dest.display());
+
+        Manifest::add(root, &lock, ManifestEntry {
+          kernel: kernel.instance.name.to_owned(),
+          hash: format!("{:016x}", kernel.hash),
+          target: kernel.target,
+          dir: dir.into(),
+          files,
+        })
+      },
+      ArtifactSink::Callback(cb) => {
+        let files = list_files(artifacts.path())?;
+        cb(&KernelArtifacts {
+          instance: kernel.instance,
+          hash: kernel.hash,
+          target: &kernel.target,
+          dir: artifacts.path(),
+          files,
+        })?;
+        artifacts.close()
+      },
+    }
+  }
+}
+impl Default for ArtifactSink {
+  fn default() -> Self { ArtifactSink::Delete }
+}
+impl fmt::Debug for ArtifactSink {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ArtifactSink::Delete => f.pad("Delete"),
+      ArtifactSink::Keep(dir) => f.debug_tuple("Keep").field(dir).finish(),
+      ArtifactSink::Callback(_) => f.pad("Callback(..)"),
+    }
+  }
+}
+
+/// Identifies the kernel the artifacts belong to.
+pub(crate) struct KernelArtifactsDesc {
+  pub instance: KernelInstanceRef<'static>,
+  pub hash: u64,
+  pub target: String,
+}
+impl KernelArtifactsDesc {
+  /// The kernel name, made path friendly, and the hash.
+  fn dir_name(&self) -> String {
+    const MAX_NAME_LEN: usize = 96;
+
+    let name: String = self.instance.name.chars()
+      .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' } )
+      .take(MAX_NAME_LEN)
+      .collect();
+    format!("{}-{:016x}", name, self.hash)
+  }
+}
+
+/// Passed to `ArtifactSink::Callback`.
+pub struct KernelArtifacts<'a> {
+  pub instance: KernelInstanceRef<'static>,
+  /// Unique to the kernel desc (including spec params and options) and the
+  /// accelerator target desc. Stable across runs of the same program.
+  pub hash: u64,
+  /// The LLVM target triple and CPU.
+  pub target: &'a str,
+  pub dir: &'a Path,
+  /// Relative to `dir`.
+  pub files: Vec<PathBuf>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+  /// The kernel instance name.
+  pub kernel: String,
+  pub hash: String,
+  /// The LLVM target triple and CPU.
+  pub target: String,
+  /// Relative to the manifest.
+  pub dir: PathBuf,
+  /// Relative to `dir`.
+  pub files: Vec<PathBuf>,
+}
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+  pub kernels: Vec<ManifestEntry>,
+}
+impl Manifest {
+  /// Read the manifest in the artifact directory `root`. A missing manifest
+  /// is treated as empty.
+  pub fn read(root: &Path) -> io::Result<Self> {
+    let bytes = match fs::read(root.join(MANIFEST_NAME)) {
+      Ok(bytes) => bytes,
+      Err(err) if err.kind() == ErrorKind::NotFound => {
+        return Ok(Default::default());
+      },
+      Err(err) => { return Err(err); },
+    };
+    serde_json::from_slice(&bytes)
+      .map_err(|err| io::Error::new(ErrorKind::InvalidData, err) )
+  }
+
+  /// Every entry for kernels named `kernel`; there is one per target,
+  /// spec params, and options combination.
+  pub fn find<'a>(&'a self, kernel: &'a str) -> impl Iterator<Item = &'a ManifestEntry> + 'a {
+    self.kernels.iter()
+      .filter(move |entry| entry.kernel == kernel )
+  }
+
+  /// Serializes updates of the artifact directory `root`: replacing a
+  /// kernel's directory, and then the manifest. This also serializes
+  /// threads in this process, as each opens the lock file separately.
+  fn lock(root: &Path) -> io::Result<ManifestLock> {
+    use crate::platform::os::lock_exclusive;
+
+    let lock = OpenOptions::new()
+      .write(true)
+      .create(true)
+      .open(root.join(MANIFEST_LOCK_NAME))?;
+    lock_exclusive(&lock)?;
+    Ok(ManifestLock { _file: lock, })
+  }
+  fn add(root: &Path, _lock: &ManifestLock, entry: ManifestEntry) -> io::Result<()> {
+    let mut manifest = Self::read(root)?;
+    manifest.kernels.retain(|prev| prev.dir != entry.dir );
+    manifest.kernels.push(entry);
+
+    let bytes = serde_json::to_vec_pretty(&manifest)
+      .map_err(|err| io::Error::new(ErrorKind::InvalidData, err) )?;
+    let mut tmp = NamedTempFile::new_in(root)?;
+    tmp.write_all(&bytes)?;
+    // atomically replaces the old manifest:
+    tmp.persist(root.join(MANIFEST_NAME))
+      .map_err(|err| err.error )?;
+    Ok(())
+  }
+}
+/// Released when dropped. See `Manifest::lock`.
+struct ManifestLock {
+  _file: fs::File,
+}
+
+/// Every file in `dir`, recursively, relative to `dir`.
+fn list_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+  fn visit(root: &Path, dir: &Path, out: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+      let path = entry?.path();
+      if path.is_dir() {
+        visit(root, &path, out)?;
+      } else {
+        out.push(path.strip_prefix(root).unwrap().into());
+      }
+    }
+    Ok(())
+  }
+
+  let mut out = Vec::new();
+  visit(dir, dir, &mut out)?;
+  out.sort();
+  Ok(out)
+}
+fn copy_files(src: &Path, dest: &Path, files: &[PathBuf]) -> io::Result<()> {
+  for file in files.iter() {
+    let to = dest.join(file);
+    if let Some(parent) = to.parent() {
+      fs::create_dir_all(parent)?;
+    }
+    fs::copy(src.join(file), to)?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::geobacter::kernel::OptionalKernelFn;
+
+  fn kernel() { }
+
+  #[test]
+  fn keep_writes_manifest() {
+    let root = tempfile::tempdir().unwrap();
+    let sink = ArtifactSink::Keep(root.path().into());
+
+    let instance = kernel.kernel_instance();
+    for _ in 0..2 {
+      let artifacts = tempfile::tempdir().unwrap();
+      fs::write(artifacts.path().join("codegen.ll"), b"; ir").unwrap();
+      let desc = KernelArtifactsDesc {
+        instance,
+        hash: 1,
+        target: "amdgcn-amd-amdhsa gfx900".into(),
+      };
+      sink.retire(artifacts, desc).unwrap();
+    }
+
+    let manifest = Manifest::read(root.path()).unwrap();
+    // compiled twice; the second replaces the first:
+    assert_eq!(manifest.kernels.len(), 1);
+    let entry = manifest.find(instance.name).next().unwrap();
+    let dir = entry.dir.to_str().unwrap();
+    assert!(dir.ends_with("-0000000000000001"), "{}", dir);
+    assert!(dir.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' ));
+    assert_eq!(entry.files, vec![PathBuf::from("codegen.ll")]);
+    assert!(root.path().join(&entry.dir).join("codegen.ll").exists());
+  }
+  #[test]
+  fn concurrent_manifest_updates() {
+    let root = tempfile::tempdir().unwrap();
+    let sink = ArtifactSink::Keep(root.path().into());
+    let instance = kernel.kernel_instance();
+
+    let threads: Vec<_> = (0..8u64)
+      .map(|hash| {
+        let sink = sink.clone();
+        std::thread::spawn(move || {
+          let artifacts = tempfile::tempdir().unwrap();
+          fs::write(artifacts.path().join("codegen.ll"), b"; ir").unwrap();
+          let desc = KernelArtifactsDesc {
+            instance,
+            hash,
+            target: "amdgcn-amd-amdhsa gfx900".into(),
+          };
+          sink.retire(artifacts, desc).unwrap();
+        })
+      })
+      .collect();
+    for t in threads.into_iter() {
+      t.join().unwrap();
+    }
+
+    // no update was lost:
+    let manifest = Manifest::read(root.path()).unwrap();
+    assert_eq!(manifest.find(instance.name).count(), 8);
+  }
+  #[test]
+  fn concurrent_replacements() {
+    let root = tempfile::tempdir().unwrap();
+    let sink = ArtifactSink::Keep(root.path().into());
+    let instance = kernel.kernel_instance();
+
+    let threads: Vec<_> = (0..8)
+      .map(|_| {
+        let sink = sink.clone();
+        std::thread::spawn(move || {
+          let artifacts = tempfile::tempdir().unwrap();
+          fs::write(artifacts.path().join("codegen.ll"), b"; ir").unwrap();
+          let desc = KernelArtifactsDesc {
+            instance,
+            hash: 1,
+            target: "amdgcn-amd-amdhsa gfx900".into(),
+          };
+          sink.retire(artifacts, desc).unwrap();
+        })
+      })
+      .collect();
+    for t in threads.into_iter() {
+      t.join().unwrap();
+    }
+
+    let manifest = Manifest::read(root.path()).unwrap();
+    assert_eq!(manifest.kernels.len(), 1);
+    let entry = &manifest.kernels[0];
+    assert_eq!(list_files(&root.path().join(&entry.dir)).unwrap(), entry.files);
+  }
+}
@@ -18,8 +18,12 @@ use rustc_middle::ty::{self, TyCtxt, Instance, ParamEnv, TypeFoldable, InstanceD
 use rustc_middle::ty::adjustment::{CustomCoerceUnsized, PointerCast};
 use rustc_mir::monomorphize::{self, collector::InliningMap,
                               partitioning::partition};
+use rustc_span::Span;
 
-use crate::codegen::PlatformCodegen;
+use std::hash::Hash;
+
+use crate::codegen::{ForbiddenCalls, PlatformCodegen, };
+use crate::codegen::stubbing::Stubber;
 use super::driver_data::DriverData;
 
 pub fn collect_and_partition_mono_items<'tcx, P>(tcx: TyCtxt<'tcx>,
@@ -41,12 +45,21 @@ pub fn collect_and_partition_mono_items<'tcx, P>(tcx: TyCtxt<'tcx>,
 
   let mut visited: FxHashSet<_> = Default::default();
   let mut inlining_map = Some(InliningMap::new());
+  let mut checker = match dd.forbidden_calls {
+    ForbiddenCalls::Allow => None,
+    _ => Some(CallChecker::new(dd)),
+  };
 
   collect_items_rec(tcx, mono_root,
                     &mut visited,
-                    &mut inlining_map);
+                    &mut inlining_map,
+                    &mut checker);
   let inlining_map = inlining_map.unwrap();
 
+  if let Some(checker) = checker {
+    checker.emit(tcx, dd.forbidden_calls);
+  }
+
   let items = visited;
   let mut units = partition(tcx, &mut items.iter().cloned(),
                             tcx.sess.codegen_units(),
@@ -135,20 +148,24 @@ pub fn collect_and_partition_mono_items<'tcx, P>(tcx: TyCtxt<'tcx>,
 pub fn collect_items_rec<'tcx>(tcx: TyCtxt<'tcx>,
                                start: MonoItem<'tcx>,
                                visited: &mut FxHashSet<MonoItem<'tcx>>,
-                               inlining_map: &mut Option<InliningMap<'tcx>>)
+                               inlining_map: &mut Option<InliningMap<'tcx>>,
+                               checker: &mut Option<CallChecker<'tcx>>)
 {
   if !visited.insert(start.clone()) {
     return;
   }
   //info!("BEGIN collect_items_rec({})", start.to_string(tcx));
 
-  let mut indirect_neighbors = Vec::new();
   let mut neighbors = Vec::new();
 
   {
-    let mut output_indirect = |item: MonoItem<'tcx>| {
-      indirect_neighbors.push(item);
+    let checker = &mut *checker;
+    let mut output_indirect = |callee: ty::Ty<'tcx>, span: Span| {
+      if let Some(checker) = checker.as_mut() {
+        checker.indirect_call(start, callee, span);
+      }
     };
+    let mut direct = Vec::new();
     let mut output = |item: MonoItem<'tcx>| {
 
       let item = match item {
@@ -157,7 +174,9 @@ pub fn collect_items_rec<'tcx>(tcx: TyCtxt<'tcx>,
             // We still want our intrinsics to be defined
             inst
           } else {
+            let original = inst;
             let inst = tcx.stubbed_instance(inst);
+            direct.push((original, inst));
 
             if tcx.is_foreign_item(inst.def_id()) {
               // Exclude extern "X" { } stuff, but only if the
@@ -199,6 +218,12 @@ pub fn collect_items_rec<'tcx>(tcx: TyCtxt<'tcx>,
         unimplemented!();
       },
     }
+
+    if let Some(checker) = checker.as_mut() {
+      for (original, inst) in direct {
+        checker.direct_use(tcx, start, original, inst);
+      }
+    }
   }
 
   if let Some(inlining_map) = inlining_map.as_mut() {
@@ -209,13 +234,143 @@ pub fn collect_items_rec<'tcx>(tcx: TyCtxt<'tcx>,
   }
 
   for neighbour in neighbors {
+    if let Some(checker) = checker.as_mut() {
+      checker.reached(start, neighbour, visited);
+    }
     collect_items_rec(tcx, neighbour, visited,
-                      inlining_map);
+                      inlining_map, checker);
   }
 
   //info!("END collect_items_rec({})", start.to_string(tcx));
 }
 
+/// A call reachable from the kernel which won't work as written on the
+/// device.
+enum ForbiddenCall<'tcx> {
+  /// Replaced by a stub which aborts.
+  Stubbed(Instance<'tcx>),
+  /// An `extern` function without a definition on the device.
+  Foreign(Instance<'tcx>),
+  /// A call through a function pointer or trait object.
+  Indirect(ty::Ty<'tcx>, Span),
+}
+
+/// Finds forbidden calls during collection (see
+/// `CodegenOptions::forbidden_calls`), and keeps track of which item first
+/// reached each item, so that each forbidden call can be reported with a
+/// call path from the kernel root.
+pub struct CallChecker<'tcx> {
+  allow_indirect: bool,
+  stubber: &'tcx Stubber,
+  parents: FxHashMap<MonoItem<'tcx>, MonoItem<'tcx>>,
+  /// Each stubbed or foreign function is only reported once.
+  seen: FxHashSet<Instance<'tcx>>,
+  /// Indirect calls are reported once per caller.
+  seen_indirect: FxHashSet<MonoItem<'tcx>>,
+  found: Vec<(MonoItem<'tcx>, ForbiddenCall<'tcx>)>,
+}
+impl<'tcx> CallChecker<'tcx> {
+  fn new<P>(dd: &'tcx DriverData<'tcx, P>) -> Self
+    where P: PlatformCodegen,
+  {
+    CallChecker {
+      allow_indirect: dd.target_desc.allow_indirect_function_calls(),
+      stubber: &dd.stubber,
+      parents: Default::default(),
+      seen: Default::default(),
+      seen_indirect: Default::default(),
+      found: vec![],
+    }
+  }
+
+  fn reached(&mut self, caller: MonoItem<'tcx>, item: MonoItem<'tcx>,
+             visited: &FxHashSet<MonoItem<'tcx>>)
+  {
+    // the first caller wins; this keeps `parents` a tree.
+    if !visited.contains(&item) {
+      self.parents.entry(item).or_insert(caller);
+    }
+  }
+
+  /// `inst` is `original` after stubbing.
+  fn direct_use(&mut self, tcx: TyCtxt<'tcx>, caller: MonoItem<'tcx>,
+                original: Instance<'tcx>, inst: Instance<'tcx>)
+  {
+    let call = if self.stubber.aborts(tcx, original.def_id(), inst.def_id()) {
+      ForbiddenCall::Stubbed(original)
+    } else if inst != original {
+      // replaced by a working implementation.
+      return;
+    } else if tcx.is_foreign_item(inst.def_id()) {
+      // LLVM intrinsics are declared as extern functions.
+      let did = inst.def_id();
+      let name = tcx.codegen_fn_attrs(did).link_name
+        .unwrap_or_else(|| tcx.item_name(did) );
+      if name.as_str().starts_with("llvm.") { return; }
+
+      ForbiddenCall::Foreign(inst)
+    } else {
+      return;
+    };
+    if self.seen.insert(original) {
+      self.found.push((caller, call));
+    }
+  }
+  fn indirect_call(&mut self, caller: MonoItem<'tcx>, callee: ty::Ty<'tcx>,
+                   span: Span)
+  {
+    if self.allow_indirect { return; }
+    if self.seen_indirect.insert(caller) {
+      self.found.push((caller, ForbiddenCall::Indirect(callee, span)));
+    }
+  }
+
+  fn emit(self, tcx: TyCtxt<'tcx>, level: ForbiddenCalls) {
+    for (caller, call) in self.found.iter() {
+      let (msg, span) = match call {
+        ForbiddenCall::Stubbed(inst) => {
+          (format!("`{}` is stubbed to abort on the device", inst), None)
+        },
+        ForbiddenCall::Foreign(inst) => {
+          (format!("`{}` is an extern function without a device definition", inst), None)
+        },
+        ForbiddenCall::Indirect(callee, span) => {
+          (format!("indirect call of `{}`; the device doesn't support indirect calls",
+                   callee), Some(*span))
+        },
+      };
+
+      let mut diag = match (level, span) {
+        (ForbiddenCalls::Allow, _) => { return; },
+        (ForbiddenCalls::Warn, Some(span)) => tcx.sess.struct_span_warn(span, &msg),
+        (ForbiddenCalls::Warn, None) => tcx.sess.struct_warn(&msg),
+        (ForbiddenCalls::Deny, Some(span)) => tcx.sess.struct_span_err(span, &msg),
+        (ForbiddenCalls::Deny, None) => tcx.sess.struct_err(&msg),
+      };
+      let path = call_path(&self.parents, *caller)
+        .into_iter()
+        .map(|item| format!("  `{}`", item) )
+        .collect::<Vec<_>>()
+        .join("\n");
+      diag.note(&format!("reached from the kernel through:\n{}", path));
+      diag.emit();
+    }
+  }
+}
+
+/// The path from the root of `parents` to `item`, inclusive.
+fn call_path<T>(parents: &FxHashMap<T, T>, mut item: T) -> Vec<T>
+  where T: Copy + Eq + Hash,
+{
+  let mut path = vec![item];
+  while let Some(&parent) = parents.get(&item) {
+    path.push(parent);
+    item = parent;
+  }
+  path.reverse();
+  path
+}
+
 fn record_accesses<'tcx>(_tcx: TyCtxt<'tcx>,
                          caller: MonoItem<'tcx>,
                          callees: &[MonoItem<'tcx>],
@@ -485,7 +640,7 @@ fn collect_neighbours<'tcx, F, G>(tcx: TyCtxt<'tcx>,
                                   instance: Instance<'tcx>,
                                   indirect: &mut F,
                                   output: &mut G)
-  where F: FnMut(MonoItem<'tcx>),
+  where F: FnMut(ty::Ty<'tcx>, Span),
         G: FnMut(MonoItem<'tcx>),
 {
   // Some special symbols (like the allocator functions) don't have MIR
@@ -526,7 +681,7 @@ fn collect_neighbours<'tcx, F, G>(tcx: TyCtxt<'tcx>,
         tcx,
         mir: &mir,
         instance,
-        _indirect: indirect,
+        indirect,
         output,
       };
       collector.visit_body(&mir);
@@ -536,20 +691,21 @@ fn collect_neighbours<'tcx, F, G>(tcx: TyCtxt<'tcx>,
 
 
 struct MirNeighborCollector<'a, 'tcx, F, G>
-  where F: FnMut(MonoItem<'tcx>),
+  where F: FnMut(ty::Ty<'tcx>, Span),
         G: FnMut(MonoItem<'tcx>),
 {
   tcx: TyCtxt<'tcx>,
   instance: Instance<'tcx>,
   mir: &'tcx mir::Body<'tcx>,
   /// Some day, we might be able to support indirect functions calls, even if it's emulated
-  /// by something like device side enqueue.
-  _indirect: &'a mut F,
+  /// by something like device side enqueue. For now, these are just
+  /// reported (see `CallChecker`).
+  indirect: &'a mut F,
   output: &'a mut G,
 }
 
 impl<'a, 'tcx, F, G> MirNeighborCollector<'a, 'tcx, F, G>
-  where F: FnMut(MonoItem<'tcx>),
+  where F: FnMut(ty::Ty<'tcx>, Span),
         G: FnMut(MonoItem<'tcx>),
 {
   pub fn monomorphize<T>(&self, value: T) -> T
@@ -565,7 +721,7 @@ impl<'a, 'tcx, F, G> MirNeighborCollector<'a, 'tcx, F, G>
 }
 
 impl<'a, 'tcx, F, G> mir::visit::Visitor<'tcx> for MirNeighborCollector<'a, 'tcx, F, G>
-  where F: FnMut(MonoItem<'tcx>),
+  where F: FnMut(ty::Ty<'tcx>, Span),
         G: FnMut(MonoItem<'tcx>),
 {
 
@@ -670,6 +826,22 @@ impl<'a, 'tcx, F, G> mir::visit::Visitor<'tcx> for MirNeighborCollector<'a, 'tcx
       mir::TerminatorKind::Call { ref func, .. } => {
         let callee_ty = func.ty(self.mir, tcx);
         let callee_ty = self.monomorphize(callee_ty);
+        let indirect = match *callee_ty.kind() {
+          ty::FnPtr(_) => true,
+          ty::FnDef(def_id, substs) => {
+            let instance = Instance::resolve(tcx, ParamEnv::reveal_all(),
+                                             def_id, substs);
+            match instance {
+              Ok(Some(Instance { def: InstanceDef::Virtual(..), .. })) => true,
+              _ => false,
+            }
+          },
+          _ => false,
+        };
+        if indirect {
+          let span = self.mir.source_info(location).span;
+          (self.indirect)(callee_ty, span);
+        }
         visit_fn_use(tcx, callee_ty, true, &mut self.output);
       },
       mir::TerminatorKind::Drop { ref place, .. } |
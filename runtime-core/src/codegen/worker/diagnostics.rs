@@ -0,0 +1,203 @@
+//! Capture of the diagnostics rustc and LLVM emit during codegen. The
+//! worker's sessions emit JSON diagnostics into a `DiagnosticSink` instead of
+//! stderr; these are parsed into `Diagnostic`s and attached to codegen
+//! errors, so callers can inspect (or log) them instead of having to scrape
+//! stderr. LLVM remarks are emitted as notes, so they are captured too.
+
+use std::fmt;
+use std::io::{self, Write, };
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use serde::{Deserialize, Serialize, };
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DiagnosticLevel {
+  #[serde(rename = "error: internal compiler error")]
+  Bug,
+  Error,
+  Warning,
+  Note,
+  Help,
+  FailureNote,
+  #[serde(other)]
+  Other,
+}
+impl DiagnosticLevel {
+  pub fn is_error(&self) -> bool {
+    match self {
+      DiagnosticLevel::Bug | DiagnosticLevel::Error => true,
+      _ => false,
+    }
+  }
+}
+impl fmt::Display for DiagnosticLevel {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let s = match self {
+      DiagnosticLevel::Bug => "error: internal compiler error",
+      DiagnosticLevel::Error => "error",
+      DiagnosticLevel::Warning => "warning",
+      DiagnosticLevel::Note => "note",
+      DiagnosticLevel::Help => "help",
+      DiagnosticLevel::FailureNote => "failure-note",
+      DiagnosticLevel::Other => "diagnostic",
+    };
+    f.pad(s)
+  }
+}
+
+/// A source location, relative to the host's sources.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct DiagnosticSpan {
+  pub file_name: String,
+  pub line_start: usize,
+  pub line_end: usize,
+  pub column_start: usize,
+  pub column_end: usize,
+  pub is_primary: bool,
+  #[serde(default)]
+  pub label: Option<String>,
+}
+impl fmt::Display for DiagnosticSpan {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}:{}:{}", self.file_name, self.line_start, self.column_start)
+  }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct DiagnosticCode {
+  pub code: String,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Diagnostic {
+  pub level: DiagnosticLevel,
+  pub message: String,
+  #[serde(default)]
+  pub code: Option<DiagnosticCode>,
+  #[serde(default)]
+  pub spans: Vec<DiagnosticSpan>,
+  #[serde(default)]
+  pub children: Vec<Diagnostic>,
+  /// The diagnostic as rustc would have printed it, including the source
+  /// snippets. Only present on top level diagnostics.
+  #[serde(default)]
+  pub rendered: Option<String>,
+}
+impl Diagnostic {
+  pub fn primary_span(&self) -> Option<&DiagnosticSpan> {
+    self.spans.iter().find(|span| span.is_primary )
+  }
+}
+impl fmt::Display for Diagnostic {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if let Some(ref rendered) = self.rendered {
+      return f.write_str(rendered.trim_end());
+    }
+
+    write!(f, "{}: {}", self.level, self.message)?;
+    if let Some(span) = self.primary_span() {
+      write!(f, "\n  --> {}", span)?;
+    }
+    for child in self.children.iter() {
+      write!(f, "\n  = {}: {}", child.level, child.message)?;
+    }
+    Ok(())
+  }
+}
+
+/// Every diagnostic emitted during a single codegen, in order.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Diagnostics(pub Vec<Diagnostic>);
+impl Diagnostics {
+  pub fn iter(&self) -> impl Iterator<Item = &Diagnostic> {
+    self.0.iter()
+  }
+  pub fn errors(&self) -> impl Iterator<Item = &Diagnostic> {
+    self.iter().filter(|diag| diag.level.is_error() )
+  }
+  pub fn is_empty(&self) -> bool { self.0.is_empty() }
+  pub fn len(&self) -> usize { self.0.len() }
+
+  /// Forward everything to the log, so diagnostics emitted during a
+  /// successful codegen aren't lost.
+  pub(crate) fn log(&self) {
+    for diag in self.iter() {
+      match diag.level {
+        DiagnosticLevel::Bug | DiagnosticLevel::Error => error!("{}", diag),
+        DiagnosticLevel::Warning => warn!("{}", diag),
+        _ => info!("{}", diag),
+      }
+    }
+  }
+}
+impl fmt::Display for Diagnostics {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    for (i, diag) in self.iter().enumerate() {
+      if i != 0 { writeln!(f)?; }
+      write!(f, "{}", diag)?;
+    }
+    Ok(())
+  }
+}
+
+/// The writer given to the session's emitter. The emitter writes one JSON
+/// object per line.
+#[derive(Clone, Default)]
+pub(crate) struct DiagnosticSink(Arc<Mutex<Vec<u8>>>);
+impl DiagnosticSink {
+  /// Parse and return everything emitted so far, clearing the sink.
+  pub(crate) fn take(&self) -> Diagnostics {
+    let bytes = {
+      let mut buf = self.0.lock();
+      std::mem::replace(&mut *buf, Vec::new())
+    };
+    Self::parse(&bytes)
+  }
+
+  fn parse(bytes: &[u8]) -> Diagnostics {
+    let out = bytes.split(|&b| b == b'\n' )
+      .filter(|line| !line.iter().all(|b| b.is_ascii_whitespace() ) )
+      .filter_map(|line| {
+        // rustc also emits things like artifact notifications here; ignore
+        // anything which isn't a diagnostic.
+        serde_json::from_slice::<Diagnostic>(line)
+          .map_err(|err| {
+            debug!("ignoring unrecognized diagnostic output: {}", err);
+          })
+          .ok()
+      })
+      .collect();
+    Diagnostics(out)
+  }
+}
+impl Write for DiagnosticSink {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.0.lock().extend_from_slice(buf);
+    Ok(buf.len())
+  }
+  fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn parse_json_diagnostics() {
+    let mut sink = DiagnosticSink::default();
+    writeln!(sink, "{}", r#"{"message":"unreachable","code":null,"level":"warning","spans":[{"file_name":"src/lib.rs","byte_start":0,"byte_end":1,"line_start":3,"line_end":3,"column_start":5,"column_end":6,"is_primary":true,"text":[],"label":null,"suggested_replacement":null,"suggestion_applicability":null,"expansion":null}],"children":[],"rendered":"warning: unreachable\n"}"#).unwrap();
+    writeln!(sink, "{}", r#"{"artifact":"foo.o","emit":"obj"}"#).unwrap();
+    writeln!(sink, "{}", r#"{"message":"LLVM ERROR","code":{"code":"E0000","explanation":null},"level":"error","spans":[],"children":[{"message":"in kernel","code":null,"level":"note","spans":[],"children":[],"rendered":null}],"rendered":null}"#).unwrap();
+
+    let diags = sink.take();
+    assert_eq!(diags.len(), 2);
+    assert_eq!(diags.0[0].level, DiagnosticLevel::Warning);
+    assert_eq!(diags.0[0].primary_span().unwrap().to_string(), "src/lib.rs:3:5");
+    assert_eq!(diags.errors().count(), 1);
+    assert_eq!(diags.0[1].to_string(), "error: LLVM ERROR\n  = note: in kernel");
+    assert!(sink.take().is_empty());
+  }
+}
@@ -0,0 +1,401 @@
+//! A persistent, on-disk cache of codegen results. Entries are keyed by
+//! everything which can change the output of codegen: the kernel instance
+//! (including its spec params and platform desc), the full accelerator
+//! target desc, the set of crates loaded into the host process and the
+//! version of the compiler. Any change to these produces a different key, so
+//! stale entries are never returned; they simply age out once the cache
+//! grows past its size limit. Entries are touched when they're loaded, so
+//! the least recently used are removed first.
+//!
+//! Platforms opt in by implementing `PlatformCodegen::encode_codegen_desc`
+//! and `PlatformCodegen::decode_codegen_desc`.
+
+use std::fs::{self, };
+use std::io::{self, Write, ErrorKind, };
+use std::path::{Path, PathBuf, };
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize, };
+
+use tempfile::NamedTempFile;
+
+use rustc_interface::util::version_str;
+
+use crate::AcceleratorTargetDesc;
+use crate::codegen::{CodegenOptions, PlatformCodegen, PKernelDesc, };
+use crate::codegen::products::{PCodegenResults, SerializedCodegenResults, };
+use crate::platform::os::touch;
+use crate::utils::{CreateIfNotExists, StableHash, };
+use crate::utils::env::{codegen_cache_dir, codegen_cache_size_limit,
+                        no_codegen_cache, use_llc, print_opt_remarks, };
+
+/// Bump this whenever the on-disk format or the meaning of any key
+/// component changes.
+const CACHE_VERSION: u32 = 2;
+const ENTRY_EXTENSION: &'static str = "grtcg";
+/// 1GiB.
+const DEFAULT_SIZE_LIMIT: u64 = 1 << 30;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DiskCacheConfig {
+  /// The directory entries are stored in. Entries are actually stored in a
+  /// versioned subdirectory of this path, so multiple versions of Geobacter
+  /// can share the same location.
+  pub path: PathBuf,
+  /// Once the total size of all entries exceeds this, entries are removed,
+  /// least recently used first, until the cache fits again. `None` means no
+  /// limit.
+  pub size_limit: Option<u64>,
+}
+impl DiskCacheConfig {
+  pub fn new<T>(path: T) -> Self
+    where T: Into<PathBuf>,
+  {
+    DiskCacheConfig {
+      path: path.into(),
+      size_limit: Some(DEFAULT_SIZE_LIMIT),
+    }
+  }
+
+  /// The configuration used when a driver is created. Uses
+  /// `GEOBACTER_CODEGEN_CACHE_DIR` and `GEOBACTER_CODEGEN_CACHE_SIZE_LIMIT`
+  /// if set, otherwise the user's cache directory. Returns `None` if
+  /// `GEOBACTER_NO_CODEGEN_CACHE` is set or no location could be found.
+  pub fn from_env() -> Option<Self> {
+    use std::env::var_os;
+
+    if no_codegen_cache() { return None; }
+
+    let path = codegen_cache_dir()
+      .or_else(|| {
+        var_os("XDG_CACHE_HOME")
+          .map(PathBuf::from)
+          .or_else(|| var_os("HOME").map(|home| Path::new(&home).join(".cache") ))
+          .map(|cache| cache.join("geobacter").join("codegen") )
+      })?;
+
+    let mut config = DiskCacheConfig::new(path);
+    if let Some(limit) = codegen_cache_size_limit() {
+      config.size_limit = Some(limit);
+    }
+    Some(config)
+  }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub(crate) struct DiskCacheKey {
+  kernel: u64,
+  spec_params: u64,
+  target_desc: u64,
+  metadata: u64,
+  options: u64,
+  compiler: u64,
+}
+impl DiskCacheKey {
+  pub(crate) fn new<P>(desc: &PKernelDesc<P>,
+                       target_desc: &AcceleratorTargetDesc,
+                       metadata: u64)
+    -> Self
+    where P: PlatformCodegen,
+  {
+    Self::from_parts(desc.stable_hash(), desc.spec_params.stable_hash(),
+                     target_desc, metadata, &desc.options,
+                     version_str().unwrap_or("unknown"))
+  }
+  fn from_parts(kernel: u64, spec_params: u64,
+                target_desc: &AcceleratorTargetDesc,
+                metadata: u64,
+                options: &Option<Arc<CodegenOptions>>,
+                compiler: &str)
+    -> Self
+  {
+    DiskCacheKey {
+      kernel,
+      spec_params,
+      target_desc: target_desc_hash(target_desc),
+      metadata,
+      options: (use_llc(), print_opt_remarks(), options).stable_hash(),
+      compiler: compiler.stable_hash(),
+    }
+  }
+
+  fn file_name(&self) -> String {
+    format!("{:016x}.{}", self.stable_hash(), ENTRY_EXTENSION)
+  }
+}
+
+/// Prefer the serialized form of the target desc: `Target`'s `Hash` impl
+/// isn't guaranteed to be stable across builds of the host program.
+pub(crate) fn target_desc_hash(target_desc: &AcceleratorTargetDesc) -> u64 {
+  rmps::encode::to_vec(target_desc)
+    .map(|bytes| bytes.stable_hash() )
+    .unwrap_or_else(|err| {
+      warn!("failed to serialize accelerator target desc: {}", err);
+      target_desc.stable_hash()
+    })
+}
+
+#[derive(Serialize, Deserialize)]
+struct DiskEntry {
+  version: u32,
+  key: DiskCacheKey,
+  results: SerializedCodegenResults,
+}
+
+pub struct DiskCache {
+  config: DiskCacheConfig,
+  dir: PathBuf,
+}
+impl DiskCache {
+  pub fn new(config: DiskCacheConfig) -> io::Result<Arc<Self>> {
+    let dir = config.path.join(format!("v{}", CACHE_VERSION));
+    dir.create_if_not_exists()?;
+
+    Ok(Arc::new(DiskCache {
+      config,
+      dir,
+    }))
+  }
+
+  pub fn config(&self) -> &DiskCacheConfig { &self.config }
+
+  fn entry_path(&self, key: &DiskCacheKey) -> PathBuf {
+    self.dir.join(key.file_name())
+  }
+
+  /// Returns `None` on a miss, or if the entry is unreadable. Corrupt entries
+  /// are removed.
+  pub(crate) fn load<P>(&self, platform: &P, key: &DiskCacheKey)
+    -> Option<PCodegenResults<P>>
+    where P: PlatformCodegen,
+  {
+    let path = self.entry_path(key);
+    let results = self.read_entry(key)?.decode(platform);
+    if results.is_none() {
+      warn!("removing undecodable codegen cache entry {}", path.display());
+      let _ = fs::remove_file(&path);
+    }
+    results
+  }
+  fn read_entry(&self, key: &DiskCacheKey) -> Option<SerializedCodegenResults> {
+    let path = self.entry_path(key);
+    let bytes = match fs::read(&path) {
+      Ok(bytes) => bytes,
+      Err(err) if err.kind() == ErrorKind::NotFound => { return None; },
+      Err(err) => {
+        warn!("failed to read codegen cache entry {}: {}", path.display(), err);
+        return None;
+      },
+    };
+
+    let results = rmps::decode::from_slice(&bytes)
+      .ok()
+      .filter(|entry: &DiskEntry| entry.version == CACHE_VERSION && entry.key == *key )
+      .map(|entry| entry.results );
+    if results.is_none() {
+      warn!("removing corrupt or mismatched codegen cache entry {}", path.display());
+      let _ = fs::remove_file(&path);
+      return None;
+    }
+
+    // `prune` removes the least recently modified entries first.
+    if let Err(err) = touch(&path) {
+      debug!("failed to touch codegen cache entry {}: {}", path.display(), err);
+    }
+
+    results
+  }
+
+  /// Does nothing if the platform doesn't support persisting its codegen
+  /// descs.
+  pub(crate) fn store<P>(&self, platform: &P, key: &DiskCacheKey,
+                         results: &PCodegenResults<P>)
+    -> io::Result<()>
+    where P: PlatformCodegen,
+  {
+    let results = match SerializedCodegenResults::encode(platform, results) {
+      Some(results) => results,
+      None => { return Ok(()); },
+    };
+    self.write_entry(key, results)
+  }
+  fn write_entry(&self, key: &DiskCacheKey, results: SerializedCodegenResults)
+    -> io::Result<()>
+  {
+    let entry = DiskEntry {
+      version: CACHE_VERSION,
+      key: *key,
+      results,
+    };
+    let bytes = rmps::encode::to_vec(&entry)
+      .map_err(|err| io::Error::new(ErrorKind::InvalidData, err) )?;
+
+    // write to a temporary first so concurrent readers never see a partial entry.
+    let mut tmp = NamedTempFile::new_in(&self.dir)?;
+    tmp.write_all(&bytes)?;
+    tmp.persist(self.entry_path(key))
+      .map_err(|err| err.error )?;
+
+    self.prune()
+  }
+
+  /// Remove the least recently used entries until we're under the size
+  /// limit.
+  fn prune(&self) -> io::Result<()> {
+    let limit = match self.config.size_limit {
+      Some(limit) => limit,
+      None => { return Ok(()); },
+    };
+
+    let mut total = 0u64;
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(&self.dir)? {
+      let entry = entry?;
+      let path = entry.path();
+      if path.extension() != Some(ENTRY_EXTENSION.as_ref()) { continue; }
+
+      let metadata = entry.metadata()?;
+      let modified = metadata.modified()
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+      total += metadata.len();
+      entries.push((modified, metadata.len(), path));
+    }
+    if total <= limit { return Ok(()); }
+
+    entries.sort();
+    for (_, len, path) in entries.into_iter() {
+      if total <= limit { break; }
+
+      match fs::remove_file(&path) {
+        Ok(()) => { },
+        // someone else pruned it
+        Err(err) if err.kind() == ErrorKind::NotFound => { },
+        Err(err) => { return Err(err); },
+      }
+      debug!("pruned codegen cache entry {}", path.display());
+      total -= len;
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::PlatformTargetDesc;
+  use crate::any_key::AnyHash;
+
+  #[derive(Debug, Serialize, Hash, Eq, PartialEq)]
+  struct TestDesc(u32);
+  impl PlatformTargetDesc for TestDesc {
+    fn as_any_hash(&self) -> &dyn AnyHash { self }
+  }
+
+  fn key_with(target_desc: &AcceleratorTargetDesc,
+              options: &Option<Arc<CodegenOptions>>,
+              compiler: &str)
+    -> DiskCacheKey
+  {
+    DiskCacheKey::from_parts(1, 2, target_desc, 3, options, compiler)
+  }
+  fn results() -> SerializedCodegenResults {
+    SerializedCodegenResults::from_outputs(vec![("obj".into(), vec![1, 2, 3])])
+  }
+  fn bytes(results: &SerializedCodegenResults) -> Vec<u8> {
+    rmps::encode::to_vec(results).unwrap()
+  }
+
+  fn write_entry(dir: &Path, name: &str, len: usize) {
+    fs::write(dir.join(format!("{}.{}", name, ENTRY_EXTENSION)), vec![0u8; len])
+      .unwrap();
+  }
+
+  #[test]
+  fn store_load_roundtrip() {
+    let tmp = tempfile::tempdir().unwrap();
+    let cache = DiskCache::new(DiskCacheConfig::new(tmp.path())).unwrap();
+    let key = key_with(&AcceleratorTargetDesc::new(TestDesc(0)), &None, "a");
+
+    assert!(cache.read_entry(&key).is_none());
+    cache.write_entry(&key, results()).unwrap();
+    let loaded = cache.read_entry(&key).unwrap();
+    assert_eq!(bytes(&loaded), bytes(&results()));
+  }
+  #[test]
+  fn key_changes_miss() {
+    let tmp = tempfile::tempdir().unwrap();
+    let cache = DiskCache::new(DiskCacheConfig::new(tmp.path())).unwrap();
+    let target_desc = AcceleratorTargetDesc::new(TestDesc(0));
+    let key = key_with(&target_desc, &None, "a");
+    cache.write_entry(&key, results()).unwrap();
+    assert!(cache.read_entry(&key).is_some());
+
+    let mut other_target_desc = AcceleratorTargetDesc::new(TestDesc(0));
+    other_target_desc.allow_indirect_function_calls = false;
+    let debug = Some(Arc::new(CodegenOptions::debug()));
+    let misses = [
+      key_with(&AcceleratorTargetDesc::new(TestDesc(1)), &None, "a"),
+      key_with(&other_target_desc, &None, "a"),
+      key_with(&target_desc, &debug, "a"),
+      key_with(&target_desc, &None, "b"),
+      DiskCacheKey { metadata: 4, ..key },
+      DiskCacheKey { kernel: 4, ..key },
+      DiskCacheKey { spec_params: 4, ..key },
+    ];
+    for miss in misses.iter() {
+      assert_ne!(*miss, key);
+      assert!(cache.read_entry(miss).is_none(), "{:?} hit", miss);
+    }
+    // and the original is still there:
+    assert!(cache.read_entry(&key).is_some());
+  }
+  #[test]
+  fn load_touches_entries() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut config = DiskCacheConfig::new(tmp.path());
+    config.size_limit = None;
+    let cache = DiskCache::new(config).unwrap();
+    let target_desc = AcceleratorTargetDesc::new(TestDesc(0));
+    let old = key_with(&target_desc, &None, "old");
+    let new = key_with(&target_desc, &None, "new");
+    cache.write_entry(&old, results()).unwrap();
+    cache.write_entry(&new, results()).unwrap();
+    let len = fs::metadata(cache.entry_path(&old)).unwrap().len();
+
+    // `old` is the least recently written; use it. Some filesystems only
+    // have a resolution of a second.
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    assert!(cache.read_entry(&old).is_some());
+
+    let mut config = cache.config().clone();
+    config.size_limit = Some(len);
+    let cache = DiskCache::new(config).unwrap();
+    cache.prune().unwrap();
+    assert!(cache.entry_path(&old).exists());
+    assert!(!cache.entry_path(&new).exists());
+  }
+
+  #[test]
+  fn prune_to_size_limit() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut config = DiskCacheConfig::new(tmp.path());
+    config.size_limit = Some(150);
+    let cache = DiskCache::new(config).unwrap();
+
+    write_entry(&cache.dir, "a", 100);
+    write_entry(&cache.dir, "b", 100);
+    // not an entry; should be left alone:
+    fs::write(cache.dir.join("unrelated"), vec![0u8; 1000]).unwrap();
+
+    cache.prune().unwrap();
+
+    let remaining = fs::read_dir(&cache.dir).unwrap()
+      .filter_map(|e| e.ok() )
+      .filter(|e| e.path().extension() == Some(ENTRY_EXTENSION.as_ref()) )
+      .count();
+    assert_eq!(remaining, 1);
+    assert!(cache.dir.join("unrelated").exists());
+  }
+}
@@ -3,10 +3,11 @@ use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::Read;
 use std::sync::{Weak, Arc, };
+use std::time::Duration;
 use std::path::Path;
 
 use rustc_data_structures::fx::FxHashMap;
-use rustc_data_structures::sync::{Lrc, RwLock, ReadGuard, MappedReadGuard};
+use rustc_data_structures::sync::{Lock, Lrc, RwLock, ReadGuard, MappedReadGuard};
 use rustc_geobacter::TyCtxtKernelInstance;
 use rustc_hir::def_id::DefId;
 use rustc_middle::mir::CustomIntrinsicMirGen;
@@ -41,8 +42,10 @@ pub struct DriverData<'tcx, P>
   /// Needs to be initialized after the TyCtxt is created.
   root_conditions: RwLock<Vec<P::Condition>>,
 
-  /// TODO: allow platform customization.
+  /// The builtin stubs, plus those in the kernel's codegen options.
   pub(super) stubber: crate::codegen::stubbing::Stubber,
+  /// From the kernel's codegen options. Checked during collection.
+  pub(super) forbidden_calls: ForbiddenCalls,
 
   /// maps `LOCAL_CRATE` (ie generated MIR wrappers) to their type.
   /// The local crate provider for `Providers::type_of` uses the HIR
@@ -54,6 +57,9 @@ pub struct DriverData<'tcx, P>
   /// Rust codegen as there will only be one thread accessing it at that time.
   /// And afterwards its immutable.
   pub intrinsics: FxHashMap<Symbol, Lrc<dyn CustomIntrinsicMirGen>>,
+
+  /// Time spent in `collect_and_partition_mono_items`, for the stats.
+  pub(super) collection_time: Lock<Duration>,
 }
 
 // Because UnsafeCell is not inherently thread safe.
@@ -71,7 +77,9 @@ impl<'tcx, P> DriverData<'tcx, P>
                     accels: &'tcx [Weak<P::Device>],
                     target_desc: &'tcx Arc<AcceleratorTargetDesc>,
                     intrinsics: FxHashMap<Symbol, Lrc<dyn CustomIntrinsicMirGen>>,
-                    platform: &'tcx P)
+                    platform: &'tcx P,
+                    stubber: crate::codegen::stubbing::Stubber,
+                    forbidden_calls: ForbiddenCalls)
     -> Self
   {
     DriverData {
@@ -86,11 +94,13 @@ impl<'tcx, P> DriverData<'tcx, P>
       roots: RwLock::new(vec![]),
       root_conditions: RwLock::new(vec![]),
 
-      // TODO allow the platform to customize
-      stubber: Default::default(),
+      stubber,
+      forbidden_calls,
 
       type_of: RwLock::new(Default::default()),
       intrinsics,
+
+      collection_time: Lock::new(Duration::default()),
     }
   }
 
@@ -3,20 +3,47 @@ use std::{fmt, io, };
 use std::error::Error as StdError;
 use std::geobacter::kernel::KernelInstanceRef;
 
+use crate::AcceleratorId;
 use crate::codegen::PlatformCodegen;
+use super::diagnostics::Diagnostics;
+use crate::codegen::SpecParamError;
+use super::host::LayoutMismatch;
 
 #[derive(Debug)]
 pub enum Error<E> {
   Io(Option<KernelInstanceRef<'static>>, io::Error),
   LoadMetadata(Box<dyn StdError + Send + Sync + 'static>),
   ConvertKernelInstance(KernelInstanceRef<'static>),
-  Codegen,
-  Linking,
+  /// A spec param's key function doesn't exist in this program, eg because
+  /// the params were loaded from a different build. See
+  /// `SpecParamsDesc::bind`.
+  UnknownSpecParam(String),
+  /// A spec param's value doesn't match the type its key function returns.
+  SpecParam(SpecParamError),
+  /// rustc or LLVM reported errors during codegen. Includes every
+  /// diagnostic emitted by the session, not just the errors.
+  Codegen(Diagnostics),
+  Linking(Diagnostics),
   InitRoot(E),
   InitConditions(E),
   PreCodegen(E),
   PostCodegen(E),
+  /// A kernel argument type has a different layout on the device than on
+  /// the host.
+  LayoutMismatch(Box<LayoutMismatch>),
+  /// The layout of a kernel argument type couldn't be computed, on either
+  /// the host or the device.
+  Layout(String),
   ContextDead,
+  /// The codegen helper process crashed, or couldn't be run. See
+  /// `CodegenDriver::set_subprocess_codegen`.
+  Subprocess(String),
+  /// The codegen server couldn't be reached, or failed. See
+  /// `CodegenDriver::set_codegen_server`.
+  Server(String),
+  /// The accelerator was removed from its context with
+  /// `Context::retire_accel`.
+  AcceleratorRetired(AcceleratorId),
 }
 pub type PError<P> = Error<<<P as PlatformCodegen>::Device as crate::Device>::Error>;
 
@@ -24,7 +51,25 @@ impl<E> fmt::Display for Error<E>
   where E: fmt::Debug,
 {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    write!(f, "{:?}", self)
+    match self {
+      Error::Codegen(diagnostics) => {
+        write!(f, "codegen failed:\n{}", diagnostics)
+      },
+      Error::Linking(diagnostics) => {
+        write!(f, "linking failed:\n{}", diagnostics)
+      },
+      Error::LayoutMismatch(inner) => fmt::Display::fmt(inner, f),
+      Error::Layout(msg) => {
+        write!(f, "kernel argument layout check failed: {}", msg)
+      },
+      Error::UnknownSpecParam(param) => {
+        write!(f, "spec param `{}` doesn't match any function in this program", param)
+      },
+      Error::SpecParam(inner) => fmt::Display::fmt(inner, f),
+      Error::Subprocess(msg) => write!(f, "subprocess codegen failed: {}", msg),
+      Error::Server(msg) => write!(f, "codegen server failed: {}", msg),
+      _ => write!(f, "{:?}", self),
+    }
   }
 }
 
@@ -38,6 +83,8 @@ impl<E> StdError for Error<E>
       Error::InitConditions(inner) |
       Error::PreCodegen(inner) |
       Error::PostCodegen(inner) => Some(inner),
+      Error::LayoutMismatch(inner) => Some(&**inner),
+      Error::SpecParam(inner) => Some(inner),
       _ => None,
     }
   }
@@ -0,0 +1,282 @@
+//! Host codegen queries. Kernel arguments are written by the host and read
+//! by the device, so every type reachable from them must have the same
+//! layout on both. This computes the layouts of those types in a `TyCtxt`
+//! for each target so they can be compared.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+
+use rustc_middle::ty::{self, Instance, ParamEnv, Ty, TyCtxt, };
+use rustc_middle::ty::layout::{LayoutCx, TyAndLayout, };
+use rustc_target::abi::{FieldsShape, LayoutOf, Variants, VariantIdx, };
+
+use crate::utils::HashSet;
+
+/// The layout of a single type, or of a single enum variant.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TypeLayout {
+  pub size: u64,
+  pub align: u64,
+  /// `(name, offset)` for every field, in declaration order.
+  pub fields: Vec<(String, u64)>,
+}
+
+/// Layouts keyed by the printed type (and, for enums, the variant).
+pub(crate) type TypeLayouts = BTreeMap<String, TypeLayout>;
+
+#[derive(Clone, Debug)]
+pub struct TypeLayoutMismatch {
+  /// The printed type. Enum variants are suffixed with `::Variant`.
+  pub ty: String,
+  /// `None` if the type wasn't reachable on the host.
+  pub host: Option<TypeLayout>,
+  /// `None` if the type wasn't reachable on the device.
+  pub device: Option<TypeLayout>,
+}
+impl fmt::Display for TypeLayoutMismatch {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let (host, device) = match (&self.host, &self.device) {
+      (&Some(ref host), &Some(ref device)) => (host, device),
+      (&Some(_), &None) => {
+        return writeln!(f, "`{}` is only reachable on the host", self.ty);
+      },
+      (&None, _) => {
+        return writeln!(f, "`{}` is only reachable on the device", self.ty);
+      },
+    };
+
+    writeln!(f, "layout of `{}` differs between the host and device:", self.ty)?;
+    writeln!(f, "  size: host {}, device {}", host.size, device.size)?;
+    writeln!(f, "  align: host {}, device {}", host.align, device.align)?;
+    let len = host.fields.len().max(device.fields.len());
+    for i in 0..len {
+      let host = host.fields.get(i);
+      let device = device.fields.get(i);
+      let name = host.or(device).map(|&(ref name, _)| &name[..] ).unwrap();
+      let offset = |f: Option<&(String, u64)>| {
+        f.map(|&(_, offset)| offset.to_string() )
+          .unwrap_or_else(|| "<missing>".into() )
+      };
+      let marker = if host == device { "" } else { " <--" };
+      writeln!(f, "  field `{}` offset: host {}, device {}{}",
+               name, offset(host), offset(device), marker)?;
+    }
+    Ok(())
+  }
+}
+
+/// Every kernel argument type whose layout differs between the host and
+/// device.
+#[derive(Clone, Debug)]
+pub struct LayoutMismatch {
+  pub types: Vec<TypeLayoutMismatch>,
+}
+impl Error for LayoutMismatch { }
+impl fmt::Display for LayoutMismatch {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    for ty in self.types.iter() {
+      fmt::Display::fmt(ty, f)?;
+    }
+    Ok(())
+  }
+}
+
+/// Compute the layout of every type reachable from the arguments of
+/// `instance`. Pointers are followed, so the layouts of the pointees are
+/// included too.
+pub(crate) fn kernel_arg_layouts<'tcx>(tcx: TyCtxt<'tcx>, instance: Instance<'tcx>)
+  -> Result<TypeLayouts, String>
+{
+  let reveal_all = ParamEnv::reveal_all();
+  let sig = instance.ty(tcx, reveal_all).fn_sig(tcx);
+  let sig = tcx.normalize_erasing_late_bound_regions(reveal_all, &sig);
+
+  let mut collector = LayoutCollector {
+    cx: LayoutCx { tcx, param_env: reveal_all, },
+    visited: Default::default(),
+    out: Default::default(),
+  };
+  for &input in sig.inputs().iter() {
+    collector.visit_ty(input)?;
+  }
+
+  Ok(collector.out)
+}
+
+/// Returns every type with a different layout, including types only
+/// present on one side.
+pub(crate) fn check_layouts(host: &TypeLayouts, device: &TypeLayouts)
+  -> Result<(), LayoutMismatch>
+{
+  let mut types = Vec::new();
+  for (ty, host) in host.iter() {
+    let device = device.get(ty);
+    if device != Some(host) {
+      types.push(TypeLayoutMismatch {
+        ty: ty.clone(),
+        host: Some(host.clone()),
+        device: device.cloned(),
+      });
+    }
+  }
+  for (ty, device) in device.iter() {
+    if !host.contains_key(ty) {
+      types.push(TypeLayoutMismatch {
+        ty: ty.clone(),
+        host: None,
+        device: Some(device.clone()),
+      });
+    }
+  }
+
+  if types.is_empty() {
+    Ok(())
+  } else {
+    Err(LayoutMismatch { types, })
+  }
+}
+
+struct LayoutCollector<'tcx> {
+  cx: LayoutCx<'tcx, TyCtxt<'tcx>>,
+  visited: HashSet<Ty<'tcx>>,
+  out: TypeLayouts,
+}
+impl<'tcx> LayoutCollector<'tcx> {
+  fn visit_ty(&mut self, ty: Ty<'tcx>) -> Result<(), String> {
+    if !self.visited.insert(ty) { return Ok(()); }
+
+    // pointees first, as they might not be sized:
+    if let Some(pointee) = ty.builtin_deref(true) {
+      let pointee = match *pointee.ty.kind() {
+        ty::Slice(elem) | ty::Array(elem, _) => elem,
+        ty::Str | ty::Dynamic(..) | ty::Foreign(..) => { return Ok(()); },
+        _ => pointee.ty,
+      };
+      self.visit_ty(pointee)?;
+    }
+
+    match *ty.kind() {
+      // These can't be meaningfully passed to the device anyway.
+      ty::FnDef(..) | ty::FnPtr(..) | ty::Dynamic(..) | ty::Never |
+      ty::Str | ty::Slice(_) | ty::Foreign(_) => { return Ok(()); },
+      _ => { },
+    }
+
+    let layout = self.cx.layout_of(ty)
+      .map_err(|err| format!("failed to compute layout of `{}`: {}", ty, err) )?;
+    match layout.variants {
+      Variants::Multiple { ref variants, .. } => {
+        self.add(ty.to_string(), layout, None);
+        for (idx, _) in variants.iter_enumerated() {
+          let variant = layout.for_variant(&self.cx, idx);
+          let name = match *ty.kind() {
+            ty::Adt(def, _) => def.variants[idx].ident.to_string(),
+            _ => format!("{}", idx.as_usize()),
+          };
+          let fields = self.add(format!("{}::{}", ty, name), variant, Some(idx));
+          for field in fields.into_iter() {
+            self.visit_ty(field)?;
+          }
+        }
+      },
+      Variants::Single { index } => {
+        let fields = self.add(ty.to_string(), layout, Some(index));
+        for field in fields.into_iter() {
+          self.visit_ty(field)?;
+        }
+      },
+    }
+
+    Ok(())
+  }
+
+  /// Returns the field types.
+  fn add(&mut self, key: String, layout: TyAndLayout<'tcx>,
+         variant: Option<VariantIdx>)
+    -> Vec<Ty<'tcx>>
+  {
+    let count = match layout.fields {
+      // only the element type is interesting; the stride is in the size.
+      FieldsShape::Array { .. } => 1,
+      ref fields => fields.count(),
+    };
+
+    let mut fields = Vec::with_capacity(count);
+    let mut tys = Vec::with_capacity(count);
+    for i in 0..count {
+      let name = match (*layout.ty.kind(), variant) {
+        (ty::Adt(def, _), Some(variant)) => {
+          def.variants[variant].fields.get(i)
+            .map(|field| field.ident.to_string() )
+            .unwrap_or_else(|| i.to_string() )
+        },
+        _ => i.to_string(),
+      };
+      fields.push((name, layout.fields.offset(i).bytes()));
+
+      match layout.field(&self.cx, i) {
+        Ok(field) => { tys.push(field.ty); },
+        Err(err) => {
+          warn!("failed to compute layout of field {} of `{}`: {}",
+                i, layout.ty, err);
+        },
+      }
+    }
+
+    self.out.insert(key, TypeLayout {
+      size: layout.size.bytes(),
+      align: layout.align.abi.bytes(),
+      fields,
+    });
+
+    tys
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn layout(size: u64, fields: &[u64]) -> TypeLayout {
+    TypeLayout {
+      size,
+      align: 8,
+      fields: fields.iter()
+        .enumerate()
+        .map(|(i, &offset)| (i.to_string(), offset) )
+        .collect(),
+    }
+  }
+
+  #[test]
+  fn check_layouts_finds_mismatch() {
+    let mut host = TypeLayouts::new();
+    let mut device = TypeLayouts::new();
+    host.insert("A".into(), layout(16, &[0, 8]));
+    device.insert("A".into(), layout(16, &[0, 8]));
+    host.insert("B".into(), layout(16, &[0, 8]));
+    device.insert("B".into(), layout(12, &[0, 4]));
+    host.insert("C".into(), layout(1, &[]));
+    device.insert("D".into(), layout(1, &[]));
+    host.insert("E".into(), layout(8, &[0]));
+    device.insert("E".into(), layout(4, &[0]));
+
+    let err = check_layouts(&host, &device).unwrap_err();
+    let tys: Vec<_> = err.types.iter()
+      .map(|ty| &ty.ty[..] )
+      .collect();
+    assert_eq!(tys, ["B", "C", "E", "D"]);
+    let msg = err.to_string();
+    assert!(msg.contains("field `1` offset: host 8, device 4 <--"), "{}", msg);
+    assert!(msg.contains("`C` is only reachable on the host"), "{}", msg);
+    assert!(msg.contains("`D` is only reachable on the device"), "{}", msg);
+  }
+  #[test]
+  fn check_layouts_matching() {
+    let mut host = TypeLayouts::new();
+    host.insert("A".into(), layout(16, &[0, 8]));
+    let device = host.clone();
+    assert!(check_layouts(&host, &device).is_ok());
+  }
+}
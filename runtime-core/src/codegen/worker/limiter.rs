@@ -0,0 +1,272 @@
+//! A counting semaphore limiting the number of concurrent codegens.
+//!
+//! Blocking the `Context`'s thread pool while waiting for a permit can starve
+//! it, so async codegens are queued here instead, and only submitted to the
+//! pool once they have a permit. Synchronous codegens wait on their own
+//! thread.
+//!
+//! The permit is tracked per thread, so a codegen nested inside another on
+//! the same thread (eg from a platform hook) reuses the outer permit instead
+//! of waiting on itself. A thread also gives up its permit while it waits on
+//! someone else's codegen of the same kernel; see `release_held`.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use parking_lot::{Condvar, Mutex, };
+
+use crate::context::Context;
+
+type QueuedJob = Box<dyn FnOnce(CodegenPermit) + Send>;
+
+thread_local! {
+  /// The permits held by this thread, at most one per limiter.
+  static HELD: RefCell<Vec<CodegenPermit>> = RefCell::new(vec![]);
+}
+
+pub(super) struct CodegenLimiter {
+  state: Mutex<CodegenLimiterState>,
+  cv: Condvar,
+}
+struct CodegenLimiterState {
+  limit: usize,
+  active: usize,
+  /// The number of threads blocked in `acquire`. These are served before
+  /// `queued`.
+  waiting: usize,
+  queued: VecDeque<QueuedJob>,
+}
+
+/// Counts against the limit until dropped.
+pub(super) struct CodegenPermit(Arc<CodegenLimiter>);
+impl Drop for CodegenPermit {
+  fn drop(&mut self) {
+    self.0.release();
+  }
+}
+
+/// Keeps this thread's permit until dropped, unless it was given up early
+/// with `CodegenLimiter::release_held`.
+pub(super) struct HeldPermit {
+  limiter: Arc<CodegenLimiter>,
+  /// False if the thread already held a permit when this was created.
+  owned: bool,
+}
+impl HeldPermit {
+  fn hold(permit: CodegenPermit) -> Self {
+    let limiter = permit.0.clone();
+    HELD.with(|held| held.borrow_mut().push(permit) );
+    HeldPermit {
+      limiter,
+      owned: true,
+    }
+  }
+}
+impl Drop for HeldPermit {
+  fn drop(&mut self) {
+    if !self.owned { return; }
+    // don't drop the permit while `HELD` is borrowed; `release` can run
+    // queued jobs.
+    let permit = HELD.with(|held| {
+      let mut held = held.borrow_mut();
+      let idx = held.iter()
+        .position(|permit| Arc::ptr_eq(&permit.0, &self.limiter) )?;
+      Some(held.swap_remove(idx))
+    });
+    drop(permit);
+  }
+}
+
+impl CodegenLimiter {
+  pub(super) fn new(limit: usize) -> Arc<Self> {
+    Arc::new(CodegenLimiter {
+      state: Mutex::new(CodegenLimiterState {
+        limit: limit.max(1),
+        active: 0,
+        waiting: 0,
+        queued: VecDeque::new(),
+      }),
+      cv: Condvar::new(),
+    })
+  }
+  pub(super) fn limit(&self) -> usize { self.state.lock().limit }
+  pub(super) fn set_limit(self: &Arc<Self>, limit: usize) {
+    self.state.lock().limit = limit.max(1);
+    // the limit could have been raised:
+    self.cv.notify_all();
+    self.start_queued();
+  }
+
+  fn held_by_this_thread(self: &Arc<Self>) -> bool {
+    HELD.with(|held| {
+      held.borrow().iter()
+        .any(|permit| Arc::ptr_eq(&permit.0, self) )
+    })
+  }
+
+  /// Block until a permit is available, unless this thread already holds
+  /// one.
+  pub(super) fn acquire(self: &Arc<Self>) -> HeldPermit {
+    if self.held_by_this_thread() {
+      return HeldPermit {
+        limiter: self.clone(),
+        owned: false,
+      };
+    }
+
+    {
+      let mut state = self.state.lock();
+      state.waiting += 1;
+      while state.active >= state.limit {
+        self.cv.wait(&mut state);
+      }
+      state.waiting -= 1;
+      state.active += 1;
+    }
+    HeldPermit::hold(CodegenPermit(self.clone()))
+  }
+  /// Give up this thread's permit, if it has one, eg before waiting on
+  /// another thread's codegen.
+  pub(super) fn release_held(self: &Arc<Self>) {
+    let permit = HELD.with(|held| {
+      let mut held = held.borrow_mut();
+      let idx = held.iter()
+        .position(|permit| Arc::ptr_eq(&permit.0, self) )?;
+      Some(held.swap_remove(idx))
+    });
+    drop(permit);
+  }
+
+  /// Run `f` on `context`'s thread pool once a permit is available. The
+  /// permit is held by the pool thread while `f` runs. `f` is dropped
+  /// without running if the limiter is dropped first.
+  pub(super) fn spawn(self: &Arc<Self>, context: &Context,
+                      f: Box<dyn FnOnce() + Send>)
+  {
+    let context = context.clone();
+    let job: QueuedJob = Box::new(move |permit| {
+      context.spawn(move || {
+        let _permit = HeldPermit::hold(permit);
+        f();
+      });
+    });
+
+    let mut state = self.state.lock();
+    if state.active < state.limit && state.waiting == 0 {
+      state.active += 1;
+      drop(state);
+      job(CodegenPermit(self.clone()));
+    } else {
+      state.queued.push_back(job);
+    }
+  }
+
+  /// Called when a permit is dropped. Hands the permit to the next queued
+  /// job, if no thread is waiting for it.
+  fn release(self: &Arc<Self>) {
+    let mut state = self.state.lock();
+    if state.waiting == 0 && state.active <= state.limit {
+      if let Some(job) = state.queued.pop_front() {
+        drop(state);
+        job(CodegenPermit(self.clone()));
+        return;
+      }
+    }
+    state.active -= 1;
+    self.cv.notify_one();
+  }
+  fn start_queued(self: &Arc<Self>) {
+    loop {
+      let job = {
+        let mut state = self.state.lock();
+        if state.active >= state.limit || state.waiting != 0 { return; }
+        match state.queued.pop_front() {
+          Some(job) => {
+            state.active += 1;
+            job
+          },
+          None => { return; },
+        }
+      };
+      job(CodegenPermit(self.clone()));
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use std::sync::atomic::{AtomicUsize, Ordering, };
+  use std::sync::mpsc::channel;
+  use std::thread;
+  use std::time::Duration;
+
+  #[test]
+  fn limiter_bounds_concurrency() {
+    let limiter = CodegenLimiter::new(2);
+    let active = Arc::new(AtomicUsize::new(0));
+    let max = Arc::new(AtomicUsize::new(0));
+
+    let threads: Vec<_> = (0..8)
+      .map(|_| {
+        let limiter = limiter.clone();
+        let active = active.clone();
+        let max = max.clone();
+        thread::spawn(move || {
+          let _permit = limiter.acquire();
+          let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+          max.fetch_max(now, Ordering::SeqCst);
+          thread::sleep(Duration::from_millis(10));
+          active.fetch_sub(1, Ordering::SeqCst);
+        })
+      })
+      .collect();
+    for t in threads.into_iter() {
+      t.join().unwrap();
+    }
+
+    assert!(max.load(Ordering::SeqCst) <= 2);
+    assert_eq!(limiter.state.lock().active, 0);
+  }
+  #[test]
+  fn limiter_zero_is_one() {
+    let limiter = CodegenLimiter::new(0);
+    assert_eq!(limiter.limit(), 1);
+    limiter.set_limit(0);
+    assert_eq!(limiter.limit(), 1);
+  }
+  #[test]
+  fn nested_acquire_reuses_permit() {
+    let limiter = CodegenLimiter::new(1);
+    let _outer = limiter.acquire();
+    // would deadlock if it waited:
+    let _inner = limiter.acquire();
+    assert_eq!(limiter.state.lock().active, 1);
+
+    limiter.release_held();
+    assert_eq!(limiter.state.lock().active, 0);
+  }
+  #[test]
+  fn queued_jobs_wait_outside_the_pool() {
+    let context = Context::new().unwrap();
+    let limiter = CodegenLimiter::new(1);
+    let permit = limiter.acquire();
+
+    let (tx, rx) = channel();
+    for i in 0..3 {
+      let tx = tx.clone();
+      limiter.spawn(&context, Box::new(move || tx.send(i).unwrap() ));
+    }
+    // none can start while we hold the only permit:
+    thread::sleep(Duration::from_millis(20));
+    assert!(rx.try_recv().is_err());
+    assert_eq!(limiter.state.lock().queued.len(), 3);
+
+    drop(permit);
+    let mut ran: Vec<i32> = (0..3).map(|_| rx.recv().unwrap() ).collect();
+    ran.sort();
+    assert_eq!(ran, vec![0, 1, 2]);
+  }
+}
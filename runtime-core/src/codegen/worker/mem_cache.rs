@@ -0,0 +1,408 @@
+//! The in-memory cache of codegen results. It's bounded by a memory budget:
+//! the total size of the cached outputs. Once a new entry pushes the cache
+//! over budget, the least recently used entries are evicted until it fits
+//! again. Entries which are in progress, or which are pinned, are never
+//! evicted, so the cache will exceed its budget if those alone don't fit.
+//!
+//! Evicted entries are just dropped from the cache; any holders of the
+//! results keep them alive. They will be loaded from the on-disk cache (or
+//! codegen-ed again) the next time they are requested.
+
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::BinaryHeap;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering, };
+
+use crossbeam_utils::sync::WaitGroup;
+
+use parking_lot::RwLock;
+
+use crate::utils::{HashMap, HashSet, new_hash_set, };
+use crate::utils::env::codegen_memory_cache_limit;
+
+/// 256MiB.
+const DEFAULT_BUDGET: usize = 256 << 20;
+
+pub(super) enum Lookup<V> {
+  Hit(Arc<V>),
+  InProgress(WaitGroup),
+  Miss,
+}
+/// See `MemCache::claim`.
+pub(super) enum Claim<V> {
+  Hit(Arc<V>),
+  /// The caller must produce the value, and then call `finish`.
+  Claimed,
+}
+
+enum State<V> {
+  InProgress(WaitGroup),
+  /// The results, and their size in bytes.
+  Done(Arc<V>, usize),
+}
+struct Entry<V> {
+  state: State<V>,
+  /// The value of `MemCache::clock` when this entry was last used.
+  last_use: AtomicU64,
+}
+
+/// An item of `Inner::lru`. Ordered by `last_use` alone, oldest first, so
+/// `BinaryHeap` pops the least recently used. Ticks are unique, so no two
+/// items of the same entry compare equal unless they're both stale.
+struct LruItem<K> {
+  last_use: u64,
+  key: K,
+}
+impl<K> PartialEq for LruItem<K> {
+  fn eq(&self, rhs: &Self) -> bool { self.last_use == rhs.last_use }
+}
+impl<K> Eq for LruItem<K> { }
+impl<K> PartialOrd for LruItem<K> {
+  fn partial_cmp(&self, rhs: &Self) -> Option<CmpOrdering> { Some(self.cmp(rhs)) }
+}
+impl<K> Ord for LruItem<K> {
+  fn cmp(&self, rhs: &Self) -> CmpOrdering { rhs.last_use.cmp(&self.last_use) }
+}
+
+struct Inner<K, V> {
+  entries: HashMap<K, Entry<V>>,
+  /// The done entries, by their `last_use` when they were pushed. `get` only
+  /// holds the read lock, so it can't update this; instead, stale items are
+  /// re-pushed with the entry's current `last_use` when they're popped.
+  /// Items of removed, in progress or pinned entries are dropped when
+  /// popped (`unpin` pushes the entry again).
+  lru: BinaryHeap<LruItem<K>>,
+  pinned: HashSet<K>,
+  /// The total size of the done entries.
+  size: usize,
+  /// `None` means unbounded.
+  budget: Option<usize>,
+}
+
+pub(super) struct MemCache<K, V> {
+  inner: RwLock<Inner<K, V>>,
+  clock: AtomicU64,
+}
+impl<K, V> MemCache<K, V>
+  where K: Clone + Eq + Hash,
+{
+  pub(super) fn new(budget: Option<usize>) -> Self {
+    MemCache {
+      inner: RwLock::new(Inner {
+        entries: Default::default(),
+        lru: BinaryHeap::new(),
+        pinned: new_hash_set(),
+        size: 0,
+        budget,
+      }),
+      clock: AtomicU64::new(0),
+    }
+  }
+  /// Uses `GEOBACTER_CODEGEN_MEMORY_CACHE_LIMIT`, if set.
+  pub(super) fn from_env() -> Self {
+    Self::new(Some(codegen_memory_cache_limit().unwrap_or(DEFAULT_BUDGET)))
+  }
+
+  fn tick(&self) -> u64 { self.clock.fetch_add(1, Ordering::Relaxed) + 1 }
+
+  pub(super) fn get(&self, key: &K) -> Lookup<V> {
+    let inner = self.inner.read();
+    match inner.entries.get(key) {
+      Some(entry) => {
+        entry.last_use.store(self.tick(), Ordering::Relaxed);
+        match entry.state {
+          State::InProgress(ref wg) => Lookup::InProgress(wg.clone()),
+          State::Done(ref v, _) => Lookup::Hit(v.clone()),
+        }
+      },
+      None => Lookup::Miss,
+    }
+  }
+  /// Get the value of `key`, or claim it for the caller to produce. Concurrent
+  /// claims of the same key are de-duplicated: only one is given
+  /// `Claim::Claimed`, the rest wait for it to `finish`. `on_wait` is called
+  /// before each wait.
+  pub(super) fn claim<F>(&self, key: &K, mut on_wait: F) -> Claim<V>
+    where F: FnMut(),
+  {
+    loop {
+      match self.get(key) {
+        Lookup::Hit(v) => { return Claim::Hit(v); },
+        Lookup::InProgress(progress) => {
+          on_wait();
+          progress.wait();
+        },
+        Lookup::Miss => {
+          if self.start(key) { return Claim::Claimed; }
+          // else someone beat us.
+        },
+      }
+    }
+  }
+  /// Mark `key` as in progress. Returns false if there already is an
+  /// entry for `key`.
+  pub(super) fn start(&self, key: &K) -> bool {
+    let mut inner = self.inner.write();
+    if inner.entries.contains_key(key) { return false; }
+
+    inner.entries.insert(key.clone(), Entry {
+      state: State::InProgress(WaitGroup::new()),
+      last_use: AtomicU64::new(self.tick()),
+    });
+    true
+  }
+  /// Finish the in progress entry for `key`. `None` removes the entry,
+  /// eg because codegen failed. Either way, waiters are woken. Returns the
+  /// number of entries evicted to make room.
+  pub(super) fn finish(&self, key: &K, value: Option<(Arc<V>, usize)>) -> usize {
+    let mut inner = self.inner.write();
+    match value {
+      Some((value, size)) => {
+        let entry = inner.entries.get_mut(key)
+          .expect("finished codegen has no cache entry");
+        let tick = self.tick();
+        entry.state = State::Done(value, size);
+        entry.last_use.store(tick, Ordering::Relaxed);
+        inner.size += size;
+        inner.push_lru(key.clone(), tick);
+        inner.evict_over_budget()
+      },
+      None => {
+        inner.entries.remove(key);
+        0
+      },
+    }
+  }
+
+  /// Remove `key`, unless it's in progress or pinned. Returns true if it
+  /// was removed.
+  pub(super) fn evict(&self, key: &K) -> bool {
+    let mut inner = self.inner.write();
+    if !inner.evictable(key) { return false; }
+    inner.remove(key);
+    true
+  }
+  /// Remove every entry which isn't in progress or pinned. Returns the number
+  /// of entries removed.
+  pub(super) fn clear(&self) -> usize {
+    let mut inner = self.inner.write();
+    let keys: Vec<_> = inner.entries.keys()
+      .filter(|key| inner.evictable(key) )
+      .cloned()
+      .collect();
+    for key in keys.iter() {
+      inner.remove(key);
+    }
+    keys.len()
+  }
+
+  /// Never evict `key`. `key` doesn't need to be in the cache yet.
+  pub(super) fn pin(&self, key: K) {
+    self.inner.write().pinned.insert(key);
+  }
+  /// Allow `key` to be evicted again. Doesn't evict anything immediately.
+  pub(super) fn unpin(&self, key: &K) {
+    let mut inner = self.inner.write();
+    if !inner.pinned.remove(key) { return; }
+    let last_use = match inner.entries.get(key) {
+      Some(entry) if matches!(entry.state, State::Done(..)) => {
+        entry.last_use.load(Ordering::Relaxed)
+      },
+      _ => { return; },
+    };
+    inner.push_lru(key.clone(), last_use);
+  }
+
+  pub(super) fn budget(&self) -> Option<usize> { self.inner.read().budget }
+  /// Evicts entries if the new budget is smaller than the current size.
+  /// Returns the number of entries evicted.
+  pub(super) fn set_budget(&self, budget: Option<usize>) -> usize {
+    let mut inner = self.inner.write();
+    inner.budget = budget;
+    inner.evict_over_budget()
+  }
+  /// The total size of the cached results, in bytes.
+  pub(super) fn size(&self) -> usize { self.inner.read().size }
+  pub(super) fn len(&self) -> usize { self.inner.read().entries.len() }
+}
+impl<K, V> Inner<K, V>
+  where K: Clone + Eq + Hash,
+{
+  fn evictable(&self, key: &K) -> bool {
+    match self.entries.get(key) {
+      Some(&Entry { state: State::Done(..), .. }) => !self.pinned.contains(key),
+      _ => false,
+    }
+  }
+  fn remove(&mut self, key: &K) {
+    if let Some(Entry { state: State::Done(_, size), .. }) = self.entries.remove(key) {
+      self.size -= size;
+    }
+  }
+  fn push_lru(&mut self, key: K, last_use: u64) {
+    self.lru.push(LruItem { last_use, key, });
+
+    // Items of removed entries are only dropped when popped, which doesn't
+    // happen while we're under budget. Rebuild before they pile up.
+    if self.lru.len() > 2 * self.entries.len() + 32 {
+      let entries = &self.entries;
+      let pinned = &self.pinned;
+      self.lru = entries.iter()
+        .filter(|&(key, entry)| {
+          matches!(entry.state, State::Done(..)) && !pinned.contains(key)
+        })
+        .map(|(key, entry)| LruItem {
+          last_use: entry.last_use.load(Ordering::Relaxed),
+          key: key.clone(),
+        })
+        .collect();
+    }
+  }
+  fn evict_over_budget(&mut self) -> usize {
+    let budget = match self.budget {
+      Some(budget) => budget,
+      None => { return 0; },
+    };
+
+    let mut evicted = 0;
+    while self.size > budget {
+      let item = match self.lru.pop() {
+        Some(item) => item,
+        // everything left is in progress or pinned.
+        None => break,
+      };
+      let last_use = match self.entries.get(&item.key) {
+        Some(entry) if self.evictable(&item.key) => {
+          entry.last_use.load(Ordering::Relaxed)
+        },
+        _ => continue,
+      };
+      if last_use != item.last_use {
+        // used since it was pushed.
+        self.lru.push(LruItem { last_use, key: item.key, });
+        continue;
+      }
+
+      self.remove(&item.key);
+      evicted += 1;
+    }
+
+    evicted
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn insert(cache: &MemCache<u32, ()>, key: u32, size: usize) -> usize {
+    assert!(cache.start(&key));
+    cache.finish(&key, Some((Arc::new(()), size)))
+  }
+  fn has(cache: &MemCache<u32, ()>, key: u32) -> bool {
+    match cache.get(&key) {
+      Lookup::Hit(_) => true,
+      _ => false,
+    }
+  }
+
+  #[test]
+  fn lru_eviction() {
+    let cache = MemCache::new(Some(30));
+    insert(&cache, 0, 10);
+    insert(&cache, 1, 10);
+    insert(&cache, 2, 10);
+    // use 0, so 1 is now the least recently used:
+    assert!(has(&cache, 0));
+
+    assert_eq!(insert(&cache, 3, 10), 1);
+    assert!(!has(&cache, 1));
+    assert!(has(&cache, 0));
+    assert_eq!(cache.size(), 30);
+
+    assert_eq!(cache.set_budget(Some(10)), 2);
+    assert_eq!(cache.len(), 1);
+    assert!(has(&cache, 0));
+  }
+
+  #[test]
+  fn concurrent_claims_are_deduplicated() {
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+    use std::time::Duration;
+
+    let cache = Arc::new(MemCache::<u32, u32>::new(None));
+    let produced = Arc::new(AtomicUsize::new(0));
+    let threads: Vec<_> = (0..8)
+      .map(|_| {
+        let cache = cache.clone();
+        let produced = produced.clone();
+        thread::spawn(move || {
+          match cache.claim(&0, || { }) {
+            Claim::Hit(v) => *v,
+            Claim::Claimed => {
+              produced.fetch_add(1, Ordering::SeqCst);
+              thread::sleep(Duration::from_millis(20));
+              cache.finish(&0, Some((Arc::new(42), 4)));
+              42
+            },
+          }
+        })
+      })
+      .collect();
+
+    for t in threads.into_iter() {
+      assert_eq!(t.join().unwrap(), 42);
+    }
+    assert_eq!(produced.load(Ordering::SeqCst), 1);
+  }
+
+  #[test]
+  fn pinned_and_in_progress_are_kept() {
+    let cache = MemCache::new(Some(10));
+    cache.pin(0);
+    insert(&cache, 0, 10);
+    assert!(cache.start(&1));
+
+    assert_eq!(insert(&cache, 2, 10), 1);
+    assert!(has(&cache, 0));
+    assert!(!has(&cache, 2));
+    assert!(!cache.evict(&0));
+    assert!(!cache.evict(&1));
+    assert_eq!(cache.clear(), 0);
+
+    cache.unpin(&0);
+    assert!(cache.evict(&0));
+    assert_eq!(cache.size(), 0);
+    match cache.get(&1) {
+      Lookup::InProgress(_) => { },
+      _ => panic!("in progress entry was evicted"),
+    }
+  }
+  #[test]
+  fn unpinned_entries_are_evictable() {
+    let cache = MemCache::new(Some(20));
+    cache.pin(0);
+    insert(&cache, 0, 10);
+    insert(&cache, 1, 10);
+    assert_eq!(insert(&cache, 2, 10), 1);
+    assert!(!has(&cache, 1));
+
+    cache.unpin(&0);
+    assert!(has(&cache, 2));
+    // 0 is now the least recently used:
+    assert_eq!(insert(&cache, 3, 10), 1);
+    assert!(!has(&cache, 0));
+    assert!(has(&cache, 2));
+  }
+  #[test]
+  fn removed_entries_dont_pile_up() {
+    let cache = MemCache::new(Some(1 << 20));
+    for _ in 0..1000 {
+      insert(&cache, 0, 10);
+      assert_eq!(cache.clear(), 1);
+    }
+    assert!(cache.inner.read().lru.len() < 100);
+  }
+}
@@ -5,18 +5,29 @@
 //! This thread also stores a cache of previously completed codegens. It
 //! used to be stored in the Context, however now that `KernelDesc` is
 //! parameterized by the PlatformCodegen trait, it makes more sense to
-//! store it here.
-//! TODO the cache is in memory only. It would be a good idea to be able
-//! write it to disk to free some memory.
+//! store it here. It's bounded by a memory budget, evicting the least
+//! recently used results; see `mem_cache`.
+//! Independent kernels are codegen-ed concurrently on the `Context`'s thread
+//! pool, up to a configurable limit. Concurrent requests for the same kernel
+//! are de-duplicated: only the first does codegen, the rest wait for it.
+//! Completed codegens are also written to a persistent on-disk cache (see
+//! `disk_cache`), if the platform supports it, so they survive across
+//! process restarts.
+//! Cache hits and misses, and the time spent in each phase of codegen, are
+//! recorded in `stats`.
+//! Optionally, each codegen can run in a helper subprocess, so LLVM crashes
+//! are reported as errors; see `subprocess`. Or codegen can be done by a
+//! server shared with other processes; see `server`.
 //!
 
 use std::any::Any;
 use std::collections::{BTreeMap, };
 use std::io::{self, };
+use std::path::{Path, PathBuf, };
 use std::mem::{self, drop, };
-use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError, };
 use std::sync::{Arc, Weak, Once, };
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering, };
+use std::time::{Duration, Instant, };
 
 use rustc_ast::ast;
 use rustc_middle;
@@ -31,7 +42,7 @@ use rustc_session::config::OutputFilenames;
 use rustc_data_structures::fx::{FxHashMap};
 use rustc_data_structures::sync::{Lrc, WorkerLocal, };
 use rustc_feature as feature_gate;
-use rustc_hir::def_id::{CrateNum, DefId, DefIdMap, LocalDefId};
+use rustc_hir::def_id::{CrateNum, DefId, DefIdMap, LocalDefId, LOCAL_CRATE, };
 use rustc_metadata;
 use rustc_metadata::{creader::CrateLoader, creader::CStore, };
 use rustc_incremental;
@@ -39,39 +50,60 @@ use rustc_resolve::Resolver;
 use rustc_span::DUMMY_SP;
 use rustc_span::symbol::{Symbol, };
 
-use crossbeam_utils::sync::WaitGroup;
 
-use parking_lot::RwLock;
+use parking_lot::{RwLock, };
 
 use tempfile::{Builder as TDBuilder, };
 
-use crate::{AcceleratorTargetDesc, context::Context, };
-use crate::utils::{HashMap, StableHash, };
-
+use crate::{Accelerator, AcceleratorTargetDesc, context::Context, };
+use crate::utils::{StableHash, };
+
+use self::artifacts::{ArtifactSink, KernelArtifactsDesc, };
+use self::diagnostics::DiagnosticSink;
+use self::disk_cache::{DiskCache, DiskCacheConfig, DiskCacheKey, target_desc_hash, };
+use self::host::TypeLayouts;
+use self::limiter::CodegenLimiter;
+use self::mem_cache::{Claim, Lookup, MemCache, };
+use self::server::ServerClient;
+use self::stats::{CodegenStats, KernelCodegenStats, PhaseTimes, StatsRecorder, };
 use self::error::IntoErrorWithKernelInstance;
 pub use self::driver_data::DriverData;
 
+pub mod artifacts;
 mod collector;
+pub mod diagnostics;
+pub mod disk_cache;
 pub mod error;
 mod driver_data;
+pub mod host;
+mod limiter;
+mod mem_cache;
+pub mod stats;
+pub mod server;
+pub mod subprocess;
 mod util;
 
-use super::{PlatformCodegen, PKernelDesc, };
+use super::{CodegenOptions, ForbiddenCalls, PlatformCodegen, PKernelDesc, };
+use super::request::{Request, RequestError, };
+use super::spec_params::{SpecParam, SpecParamError, };
+use super::stubbing::{Stubber, generated_stub_body, };
 use super::products::*;
 use crate::codegen::worker::error::PError;
 use crate::metadata::{CrateMetadataLoader, CrateMetadata, CrateNameHash, DummyMetadataLoader};
-use crate::utils::env::{use_llc, print_opt_remarks};
+use crate::utils::env::{use_llc, print_opt_remarks, codegen_concurrency,
+                        codegen_subprocess, codegen_server, };
 
 const CRATE_NAME: &'static str = "geobacter-cross-codegen";
 
-// TODO we need to create a talk to a "host codegen" so that we can ensure
-// that adt's have the same layout in the shader/kernel as on the host.
-// TODO XXX only one codegen query is allowed at a time.
-// TODO codegen worker workers (ie codegen multiple functions concurrently)
+// Kernel argument types are checked to have the same layout in the
+// kernel as on the host; see `host`.
 // Note: recreate the session/tyctxt on *every* codegen. It is not safe to reuse.
 
 static SETUP_RUSTC_INTERFACE_CALLBACKS: Once = Once::new();
 
+/// A codegen running in the background; see `CodegenDriver::codegen_async`.
+pub type CodegenRequest<P> = Request<Arc<PCodegenResults<P>>, error::PError<P>>;
+
 pub struct CodegenDriver<P>(WorkerTranslatorData<P>)
   where P: PlatformCodegen;
 impl<P> CodegenDriver<P>
@@ -91,7 +123,14 @@ impl<P> CodegenDriver<P>
       platform,
       target_desc: accel_desc,
       accels: Default::default(),
-      cache: Default::default(),
+      cache: Arc::new(MemCache::from_env()),
+      disk_cache: RwLock::new(default_disk_cache()),
+      limiter: CodegenLimiter::new(default_codegen_concurrency(context)),
+      options: RwLock::new(None),
+      artifacts: RwLock::new(ArtifactSink::from_env()),
+      stats: Default::default(),
+      subprocess: AtomicBool::new(codegen_subprocess()),
+      server: RwLock::new(default_codegen_server::<P>()),
     };
     Ok(CodegenDriver(inner))
   }
@@ -101,51 +140,262 @@ impl<P> CodegenDriver<P>
   {
     self.0.codegen_kernel(desc)
   }
+  /// Run `codegen` on the context's thread pool. Cancelling the request
+  /// doesn't stop a codegen which has already started, it still finishes
+  /// and is cached for other requests of the same kernel.
+  /// The request waits for a codegen permit (see
+  /// `set_max_concurrent_codegens`) before it's submitted to the pool, so
+  /// pool threads are never blocked on the limit. Results already in memory
+  /// are returned immediately.
+  pub fn codegen_async(self: Arc<Self>, mut desc: PKernelDesc<P>) -> CodegenRequest<P> {
+    self.0.resolve_options(&mut desc);
+    if let Lookup::Hit(results) = self.0.cache.get(&desc) {
+      self.0.stats.cache_hit();
+      return Request::ready(Ok(results));
+    }
+
+    let this = self.clone();
+    self.spawn(move || this.0.codegen_kernel(desc) )
+  }
+  /// Run `f` on the context's thread pool once it has a codegen permit,
+  /// like `codegen_async`, eg to codegen and then load a kernel. Codegens
+  /// `f` runs on this driver use its permit instead of waiting for another,
+  /// so no pool thread is blocked on the limit.
+  pub fn spawn<F, T, E>(&self, f: F) -> Request<T, E>
+    where F: FnOnce() -> Result<T, E> + Send + 'static,
+          T: Send + 'static,
+          E: Send + 'static,
+  {
+    let context = self.0.context.clone();
+    let limiter = self.0.limiter.clone();
+    Request::spawn_with(move |job| limiter.spawn(&context, job), f)
+  }
+  /// Look for `desc` in the context's kernel bundles. Doesn't run codegen.
+  pub fn bundled(&self, desc: &PKernelDesc<P>) -> Option<Arc<PCodegenResults<P>>> {
+    let mut desc = desc.clone();
+    self.0.resolve_options(&mut desc);
+    self.0.context.kernel_bundles()
+      .iter()
+      .find_map(|bundle| {
+        bundle.get(&self.0.platform, &desc, &self.0.target_desc)
+      })
+  }
+  pub fn target_desc(&self) -> &Arc<AcceleratorTargetDesc> { &self.0.target_desc }
+  pub fn platform(&self) -> &P { &self.0.platform }
+
+  /// Codegen every kernel in `descs` concurrently on the `Context`'s thread
+  /// pool, subject to the concurrency limit, and wait for all of them. Like
+  /// `codegen_async`, kernels are queued until they have a permit, so no
+  /// pool thread is blocked waiting for one. The results are in the same
+  /// order as `descs`.
+  pub fn codegen_all<I>(self: &Arc<Self>, descs: I)
+    -> Vec<Result<Arc<PCodegenResults<P>>, RequestError<error::PError<P>>>>
+    where I: IntoIterator<Item = PKernelDesc<P>>,
+  {
+    let requests: Vec<_> = descs.into_iter()
+      .map(|desc| self.clone().codegen_async(desc) )
+      .collect();
+    requests.into_iter()
+      .map(Request::wait)
+      .collect()
+  }
+
+  /// Set the maximum number of kernels which will be codegen-ed at the same
+  /// time. Codegens already running are allowed to finish. Zero is treated
+  /// as one.
+  pub fn set_max_concurrent_codegens(&self, limit: usize) {
+    self.0.limiter.set_limit(limit);
+  }
+  pub fn max_concurrent_codegens(&self) -> usize {
+    self.0.limiter.limit()
+  }
+  /// When `accel` is retired, it's forgotten. Every result in the
+  /// in-memory cache is for this driver's target desc, so if no other accel
+  /// with that desc remains, the results which aren't pinned are dropped
+  /// too. Codegens in progress still finish.
   pub fn add_accel(&self, accel: &Arc<P::Device>) {
-    let mut this = self.0.accels.write();
-    this.push(Arc::downgrade(accel));
+    self.0.accels.write().push(Arc::downgrade(accel));
+
+    let id = accel.id();
+    let accels = Arc::downgrade(&self.0.accels);
+    let cache = Arc::downgrade(&self.0.cache);
+    self.0.context.on_accel_retired(id, Box::new(move || {
+      let remaining = match accels.upgrade() {
+        Some(accels) => {
+          let mut accels = accels.write();
+          accels.retain(|accel| {
+            accel.upgrade()
+              .map(|accel| accel.id() != id )
+              .unwrap_or(false)
+          });
+          accels.len()
+        },
+        None => 0,
+      };
+      if remaining != 0 { return; }
+      if let Some(cache) = cache.upgrade() {
+        cache.clear();
+      }
+    }));
   }
-}
 
-#[allow(dead_code)]
-pub(crate) enum Message<P>
-  where P: PlatformCodegen,
-{
-  /// So we can know when to exit.
-  AddAccel(Weak<P::Device>),
-  Codegen {
-    desc: PKernelDesc<P>,
-    ret: Sender<Result<Arc<PCodegenResults<P>>, error::PError<P>>>,
-  },
+  /// Replace the on-disk cache configuration. `None` disables the on-disk
+  /// cache. Codegen results already in memory are unaffected.
+  pub fn set_disk_cache(&self, config: Option<DiskCacheConfig>) -> io::Result<()> {
+    let cache = match config {
+      Some(config) => Some(DiskCache::new(config)?),
+      None => None,
+    };
+    *self.0.disk_cache.write() = cache;
+    Ok(())
+  }
+  pub fn disk_cache_config(&self) -> Option<DiskCacheConfig> {
+    self.0.disk_cache.read()
+      .as_ref()
+      .map(|cache| cache.config().clone() )
+  }
+
+  /// Set the codegen options used for kernels which don't override them.
+  /// `None` reverts to the context's options. Only affects kernels compiled
+  /// after this call.
+  pub fn set_codegen_options(&self, options: Option<CodegenOptions>) {
+    *self.0.options.write() = options.map(Arc::new);
+  }
+  /// The options kernels without an override are compiled with.
+  pub fn codegen_options(&self) -> Arc<CodegenOptions> {
+    self.0.default_options()
+  }
+
+  /// Set the memory budget of the in-memory cache, in bytes. `None` removes
+  /// the limit. Least recently used results are evicted if the cache is
+  /// over the new budget.
+  pub fn set_cache_budget(&self, budget: Option<usize>) {
+    let evicted = self.0.cache.set_budget(budget);
+    self.0.stats.evictions(evicted);
+  }
+  pub fn cache_budget(&self) -> Option<usize> { self.0.cache.budget() }
+  /// The total size of the results in the in-memory cache, in bytes.
+  pub fn cache_size(&self) -> usize { self.0.cache.size() }
+  /// Remove the results of `desc` from the in-memory cache. Results which
+  /// are pinned or still being codegen-ed are kept. Returns true if the
+  /// results were removed.
+  pub fn evict(&self, desc: &PKernelDesc<P>) -> bool {
+    let mut desc = desc.clone();
+    self.0.resolve_options(&mut desc);
+    let evicted = self.0.cache.evict(&desc);
+    self.0.stats.evictions(evicted as usize);
+    evicted
+  }
+  /// Remove every result from the in-memory cache, except those which are
+  /// pinned or still being codegen-ed. The on-disk cache is unaffected.
+  pub fn clear(&self) {
+    let evicted = self.0.cache.clear();
+    self.0.stats.evictions(evicted);
+  }
+  /// Never evict the results of `desc` from the in-memory cache, until
+  /// `unpin` is called. `desc` doesn't have to have been codegen-ed yet.
+  pub fn pin(&self, desc: &PKernelDesc<P>) {
+    let mut desc = desc.clone();
+    self.0.resolve_options(&mut desc);
+    self.0.cache.pin(desc);
+  }
+  pub fn unpin(&self, desc: &PKernelDesc<P>) {
+    let mut desc = desc.clone();
+    self.0.resolve_options(&mut desc);
+    self.0.cache.unpin(&desc);
+  }
+
+  /// Run each codegen in a helper subprocess, so crashes in LLVM are
+  /// returned as errors. The program must call `run_codegen_helper` at the
+  /// start of `main`; see `subprocess`. Ignored for platforms which don't
+  /// support it.
+  pub fn set_subprocess_codegen(&self, enabled: bool) {
+    self.0.subprocess.store(enabled, Ordering::Relaxed);
+  }
+  pub fn subprocess_codegen(&self) -> bool {
+    self.0.subprocess.load(Ordering::Relaxed)
+  }
+
+  /// Send codegens to the `CodegenServer` listening at `path`, instead of
+  /// running them in this process. `None` reverts to local codegen. Fails
+  /// if the server can't be reached, or was built from a different binary.
+  /// Ignored for platforms which don't support it; see `server`.
+  pub fn set_codegen_server(&self, path: Option<&Path>) -> io::Result<()> {
+    let server = match path {
+      Some(path) => Some(Arc::new(ServerClient::connect::<P>(path)?)),
+      None => None,
+    };
+    *self.0.server.write() = server;
+    Ok(())
+  }
+  pub fn codegen_server(&self) -> Option<PathBuf> {
+    self.0.server.read()
+      .as_ref()
+      .map(|server| server.path().to_owned() )
+  }
+
+  /// Set what happens to the intermediates of future codegens.
+  pub fn set_artifact_sink(&self, sink: ArtifactSink) {
+    *self.0.artifacts.write() = sink;
+  }
+  /// A snapshot of the cache and timing statistics of this driver.
+  pub fn stats(&self) -> CodegenStats { self.0.stats.snapshot() }
+  pub fn reset_stats(&self) { self.0.stats.reset() }
+
+  pub fn artifact_sink(&self) -> ArtifactSink {
+    self.0.artifacts.read().clone()
+  }
 }
 
-type IntrinsicsMap = FxHashMap<Symbol, Lrc<dyn CustomIntrinsicMirGen>>;
+fn default_codegen_concurrency(context: &Context) -> usize {
+  codegen_concurrency()
+    .unwrap_or_else(|| context.thread_pool().current_num_threads() )
+}
 
-enum MaybeInProgress<P>
+fn default_codegen_server<P>() -> Option<Arc<ServerClient>>
   where P: PlatformCodegen,
 {
-  InProgress(WaitGroup),
-  Done(Arc<PCodegenResults<P>>),
+  let path = codegen_server()?;
+  ServerClient::connect::<P>(&path)
+    .map(Arc::new)
+    .map_err(|err| {
+      warn!("failed to connect to the codegen server at {}, continuing without: {}",
+            path.display(), err);
+    })
+    .ok()
 }
-impl<P> Clone for MaybeInProgress<P>
-  where P: PlatformCodegen,
-{
-  fn clone(&self) -> Self {
-    match self {
-      MaybeInProgress::InProgress(p) => MaybeInProgress::InProgress(p.clone()),
-      MaybeInProgress::Done(r) => MaybeInProgress::Done(r.clone()),
-    }
-  }
+
+fn default_disk_cache() -> Option<Arc<DiskCache>> {
+  let config = DiskCacheConfig::from_env()?;
+  DiskCache::new(config)
+    .map_err(|err| {
+      warn!("failed to initialize the on-disk codegen cache, continuing without: {}",
+            err);
+    })
+    .ok()
 }
 
+type IntrinsicsMap = FxHashMap<Symbol, Lrc<dyn CustomIntrinsicMirGen>>;
+
 pub struct WorkerTranslatorData<P>
   where P: PlatformCodegen,
 {
   pub(self) context: Context,
   pub(crate) platform: P,
   pub target_desc: Arc<AcceleratorTargetDesc>,
-  pub accels: RwLock<Vec<Weak<P::Device>>>,
-  cache: RwLock<HashMap<PKernelDesc<P>, MaybeInProgress<P>>>,
+  pub accels: Arc<RwLock<Vec<Weak<P::Device>>>>,
+  /// Shared with the context's retirement hooks; see `add_accel`.
+  cache: Arc<MemCache<PKernelDesc<P>, PCodegenResults<P>>>,
+  disk_cache: RwLock<Option<Arc<DiskCache>>>,
+  limiter: Arc<CodegenLimiter>,
+  /// `None` uses the context's options.
+  options: RwLock<Option<Arc<CodegenOptions>>>,
+  artifacts: RwLock<ArtifactSink>,
+  stats: StatsRecorder,
+  /// Codegen in a helper process; see `subprocess`.
+  subprocess: AtomicBool,
+  /// Codegen on a server; see `server`. Takes precedence over `subprocess`.
+  server: RwLock<Option<Arc<ServerClient>>>,
 }
 impl<P> WorkerTranslatorData<P>
   where P: PlatformCodegen,
@@ -155,134 +405,65 @@ impl<P> WorkerTranslatorData<P>
              platform: P)
     -> io::Result<CodegenDriver<P>>
   {
-    use std::thread::Builder;
-
-    let (_tx, rx) = channel();
-    let context = ctx.clone();
-
-    let name = format!("codegen thread for {}",
-                       target_desc.target.llvm_target);
-
-    let f = move || {
-      info!("codegen thread for {} startup",
-            target_desc.target.llvm_target);
-
-      let mut data = WorkerTranslatorData {
-        context,
-        platform,
-        target_desc,
-        accels: Default::default(),
-        cache: Default::default(),
-      };
-
-      data.thread(&rx);
-    };
-
-    let _ = Builder::new()
-      .name(name)
-      .spawn(f)?;
-
-    unimplemented!("TODO: host codegen queries");
+    CodegenDriver::new(ctx, target_desc, platform)
   }
 
-  /// Only used for host codegen queries.
-  fn thread(&mut self, rx: &Receiver<Message<P>>) {
-
-    enum InternalMessage<D> {
-      Timeout,
-      AddAccel(Weak<D>),
-    }
-
-    let to = Duration::from_secs(10);
-
-    let mut recv_msg = None;
-
-    // Ensure we don't timeout and quit before we've received the
-    // first message (which should be an add accel message)
-    let mut first_msg = true;
-
-    'outer: loop {
-      let internal_msg = 'inner: loop {
-        let msg = recv_msg
-          .take()
-          .unwrap_or_else(|| {
-            rx.recv_timeout(to)
-          });
-        let msg = match msg {
-          Ok(msg) => msg,
-          Err(RecvTimeoutError::Timeout) => {
-            break 'inner InternalMessage::Timeout;
-          },
-          Err(error) => {
-            warn!("receive error: {:?}", error);
-            return;
-          },
-        };
-
-        match msg {
-          Message::AddAccel(accel) => {
-            first_msg = false;
-            break 'inner InternalMessage::AddAccel(accel);
-          },
-          _ if first_msg => {
-            panic!("first message must be Message::AddAccel; \
-                    fix your Accelerator impl");
-          },
-          Message::Codegen {
-            desc,
-            ret,
-          } => {
-            let result = self.codegen_kernel(desc);
-            let _ = ret.send(result);
-          },
-        }
-      };
-
-      match internal_msg {
-        InternalMessage::Timeout => { },
-        InternalMessage::AddAccel(accel) => {
-          self.accels.write().push(accel);
-          continue 'outer;
-        },
-      }
-
-      let live = {
-        let accels = self.accels.read();
-        accels.iter()
-          .any(|a| a.upgrade().is_some())
-      };
-      if !live && !first_msg { return; }
+  fn default_options(&self) -> Arc<CodegenOptions> {
+    self.options.read().clone()
+      .unwrap_or_else(|| self.context.codegen_options() )
+  }
 
-      // else wait for the next message, at which point we will reinitialize.
-      match rx.recv() {
-        Err(error) => {
-          warn!("receive error: {:?}", error);
-          return;
-        },
-        Ok(msg) => {
-          recv_msg = Some(Ok(msg));
-        },
-      }
+  /// Run `f` with the Rustc span globals set.
+  fn with_span_globals<F, R>(&self, f: F) -> R
+    where F: FnOnce() -> R + Send,
+          R: Send,
+  {
+    // the span globals are per context, so another context's threads won't do.
+    if !self.context.in_thread_pool() {
+      self.context.with_rustc_span_globals(f)
+    } else {
+      f()
     }
   }
-  fn initialize_sess<F, R>(&self, f: F) -> Result<R, error::PError<P>>
-    where F: FnOnce(Session, CStore) -> Result<R, error::PError<P>> + Send,
+  fn initialize_sess<F, R>(&self, options: &CodegenOptions, f: F)
+    -> Result<R, error::PError<P>>
+    where F: FnOnce(Session, CStore, DiagnosticSink, Duration) -> Result<R, error::PError<P>> + Send,
+          R: Send,
+  {
+    self.initialize_sess_for(false, options, f)
+  }
+  /// If `host` is true, the session will target the host instead of the
+  /// accelerator. Such sessions are only used for queries, never codegen.
+  /// Diagnostics are emitted into the sink passed to `f` instead of stderr.
+  /// `f` is also passed the time taken to load the crate metadata.
+  fn initialize_sess_for<F, R>(&self, host: bool, options: &CodegenOptions, f: F)
+    -> Result<R, error::PError<P>>
+    where F: FnOnce(Session, CStore, DiagnosticSink, Duration) -> Result<R, error::PError<P>> + Send,
           R: Send,
   {
-    use rustc_data_structures::rayon::*;
+    use rustc_errors::ColorConfig;
     use rustc_session::{DiagnosticOutput, Limit};
+    use rustc_session::config::{ErrorOutputType, HumanReadableErrorType, };
 
     let f = move || {
-      let metadata = self.context.load_metadata()
-        .map_err(error::Error::LoadMetadata)?;
-
-      let mut opts = create_rustc_options();
-      self.platform.modify_rustc_session_options(&self.target_desc,
-                                                 &mut opts);
-
+      let mut metadata_load = Duration::default();
+      let metadata = stats::time(&mut metadata_load, || {
+        self.context.load_metadata()
+      }).map_err(error::Error::LoadMetadata)?;
+
+      let mut opts = create_rustc_options(options);
+      if !host {
+        self.platform.modify_rustc_session_options(&self.target_desc,
+                                                   &mut opts);
+      }
+      opts.error_format = ErrorOutputType::Json {
+        pretty: false,
+        json_rendered: HumanReadableErrorType::Default(ColorConfig::Never),
+      };
 
+      let sink = DiagnosticSink::default();
       let registry = rustc_driver::diagnostics_registry();
-      let diag = DiagnosticOutput::Default;
+      let diag = DiagnosticOutput::Raw(Box::new(sink.clone()));
       let lint_caps = Default::default();
       let mut sess = rustc_session::build_session(opts, None, registry,
                                                   diag, lint_caps, None, None);
@@ -297,7 +478,11 @@ impl<P> WorkerTranslatorData<P>
       // TODO hash the accelerator target desc
       let dis = self::util::compute_crate_disambiguator(&sess);
       sess.crate_disambiguator.set(dis).unwrap();
-      self.target_desc.rustc_target_options(&mut sess.target.target);
+      if host {
+        sess.target.target = self.target_desc.host_target.clone();
+      } else {
+        self.target_desc.rustc_target_options(&mut sess.target.target);
+      }
 
       // initialize the cstore for this codegen:
       // We have to do this everytime because the CStore does some
@@ -306,6 +491,7 @@ impl<P> WorkerTranslatorData<P>
       // XXX fix upstream to remove that implicit assumption, that is
       // 1 cstore per 1 tcx (1 to 1 and onto).
       let mut cstore = CStore::default();
+      let metadata_start = Instant::now();
       {
         let mut loader = CrateMetadataLoader::default();
         let CrateMetadata(meta) = loader.build(&metadata, &mut cstore)
@@ -322,91 +508,218 @@ impl<P> WorkerTranslatorData<P>
         }
       }
 
-      f(sess, cstore)
+      metadata_load += metadata_start.elapsed();
+
+      f(sess, cstore, sink, metadata_load)
     };
 
-    if current_thread_index().is_none() {
-      self.context.with_rustc_span_globals(f)
-    } else {
-      f()
+    self.with_span_globals(f)
+  }
+  /// Resolve the options now, so they're part of the cache key.
+  fn resolve_options(&self, desc: &mut PKernelDesc<P>) {
+    if desc.options.is_none() {
+      desc.options = Some(self.default_options());
     }
   }
   fn codegen_kernel(&self, desc: PKernelDesc<P>)
     -> Result<Arc<PCodegenResults<P>>, error::PError<P>>
   {
-    use std::collections::hash_map::Entry;
-    loop {
-      loop {
-        let cache = self.cache.read();
-        if let Some(results) = cache.get(&desc).cloned() {
-          // we *MUST* drop the read lock before waiting on in progress codegen.
-          drop(cache);
-
-          let progress = match results {
-            MaybeInProgress::InProgress(progress) => progress,
-            MaybeInProgress::Done(results) => {
-              return Ok(results);
-            },
-          };
+    self.codegen_kernel_timed(desc).map(|(results, _)| results )
+  }
+  /// Like `codegen_kernel`, but also returns the times of the codegen, if
+  /// it was run by this call (ie the results weren't cached).
+  pub(super) fn codegen_kernel_timed(&self, mut desc: PKernelDesc<P>)
+    -> Result<(Arc<PCodegenResults<P>>, Option<PhaseTimes>), error::PError<P>>
+  {
+    self.resolve_options(&mut desc);
+
+    let claim = self.cache.claim(&desc, || {
+      debug!("{:?}: waiting for existing codegen..", desc.instance);
+      self.stats.in_progress_wait();
+      // we don't need our permit for this; don't hold up other codegens.
+      self.limiter.release_held();
+    });
+    if let Claim::Hit(results) = claim {
+      self.stats.cache_hit();
+      return Ok((results, None));
+    }
 
-          debug!("{:?}: waiting for existing codegen..", desc.instance);
+    let result = self.codegen_kernel_disk_cached(&desc);
 
-          progress.wait();
-          continue;
-        } else {
-          break;
-        }
+    // we still need to unblock other threads if codegen failed.
+    let finished = match result {
+      Ok((ref result, _)) => Some((result.clone(), result_size(result))),
+      Err(_) => {
+        self.stats.failure();
+        None
+      },
+    };
+    self.stats.evictions(self.cache.finish(&desc, finished));
+
+    result
+  }
+  /// Check the on-disk cache before actually running codegen, and store
+  /// the results afterwards. Neither happens when using a codegen server,
+  /// which has its own cache.
+  fn codegen_kernel_disk_cached(&self, desc: &PKernelDesc<P>)
+    -> Result<(Arc<PCodegenResults<P>>, Option<PhaseTimes>), error::PError<P>>
+  {
+    let server = self.server.read().clone();
+    if let Some(server) = server {
+      if let Some(results) = self.codegen_on_server(&server, desc) {
+        return results.map(|results| (Arc::new(results), None) );
       }
+    }
 
-      let mut cache = self.cache.write();
-      match cache.entry(desc.clone()) {
-        Entry::Occupied(_) => {
-          // someone beat us.
-          continue;
-        },
-        Entry::Vacant(v) => {
-          v.insert(MaybeInProgress::InProgress(WaitGroup::new()));
-          break;
-        },
+    let codegen = || {
+      // Held until the session is torn down. Acquired before
+      // `initialize_sess` so callers outside the pool wait on their own
+      // thread, instead of in the pool.
+      let _permit = self.limiter.acquire();
+      debug!("{:?}: starting codegen", desc.instance);
+      self.stats.cache_miss();
+
+      let start = Instant::now();
+      let record = |mut times: PhaseTimes, results: &PCodegenResults<P>| {
+        times.total = start.elapsed();
+        self.stats.kernel(KernelCodegenStats {
+          kernel: desc.instance.name.to_owned(),
+          hash: (desc.stable_hash(), target_desc_hash(&self.target_desc)).stable_hash(),
+          times,
+          output_sizes: results.outputs.iter()
+            .map(|(&ty, data)| (output_type_name(ty).to_owned(), data.len()) )
+            .collect(),
+        });
+        times
+      };
+
+      if self.subprocess.load(Ordering::Relaxed) {
+        if let Some(results) = self.codegen_in_subprocess(desc) {
+          let (results, times) = results?;
+          let times = record(times, &results);
+          return Ok((Arc::new(results), Some(times)));
+        }
       }
+
+      let mut host_layouts_time = Duration::default();
+      let host_layouts = stats::time(&mut host_layouts_time, || {
+        self.host_layouts(desc)
+      })?;
+
+      let options = desc.options.clone().unwrap_or_default();
+      self.initialize_sess(&options, |sess, cstore, diagnostics, metadata_load| {
+        let mut times = PhaseTimes {
+          host_layouts: host_layouts_time,
+          metadata_load,
+          ..Default::default()
+        };
+        let results = self.codegen_kernel_inner(desc.clone(),
+                                                host_layouts.as_deref(),
+                                                sess,
+                                                cstore,
+                                                diagnostics,
+                                                &mut times)?;
+        let times = record(times, &results);
+        Ok((Arc::new(results), Some(times)))
+      })
+    };
+
+    let disk_cache = self.disk_cache.read().clone();
+    let disk_cache = match disk_cache {
+      Some(disk_cache) => disk_cache,
+      None => { return codegen(); },
+    };
+
+    let key = self.with_span_globals(|| {
+      self.context.metadata_hash()
+        .map(|metadata| DiskCacheKey::new(desc, &self.target_desc, metadata) )
+        .map_err(error::Error::LoadMetadata)
+    })?;
+    if let Some(results) = disk_cache.load(&self.platform, &key) {
+      info!("codegen cache hit {:?}", desc.instance);
+      self.stats.disk_cache_hit();
+      return Ok((Arc::new(results), None));
     }
 
-    let result = self.initialize_sess(|sess, cstore, | {
-      self.codegen_kernel_inner(desc.clone(),
-                                sess,
-                                cstore)
-          .map(Arc::new)
-    });
+    let (results, times) = codegen()?;
+    if let Err(err) = disk_cache.store(&self.platform, &key, &results) {
+      warn!("failed to write codegen cache entry for {:?}: {}",
+            desc.instance, err);
+    }
 
-    let mut cache = self.cache.write();
-    match result {
-      Ok(ref result) => {
-        let entry = cache.get_mut(&desc).unwrap();
-        *entry = MaybeInProgress::Done(result.clone());
-      },
-      Err(_) => {
-        // we still need to unblock other threads
-        cache.remove(&desc);
-      },
+    Ok((results, times))
+  }
+  /// Compute the host layouts of `desc`'s kernel argument types. Not
+  /// needed when the accelerator *is* the host.
+  ///
+  /// Layouts are a function of the `TyCtxt`'s target, so this needs a
+  /// session of its own. It's only run once per kernel per context though;
+  /// the result is shared by every accelerator.
+  fn host_layouts(&self, desc: &PKernelDesc<P>)
+    -> Result<Option<Arc<TypeLayouts>>, error::PError<P>>
+  {
+    use rustc_geobacter::TyCtxtKernelInstance;
+
+    if self.target_desc.is_host() { return Ok(None); }
+
+    if let Some(layouts) = self.context.host_layouts(desc.instance) {
+      return Ok(Some(layouts));
     }
 
-    result
+    // only used for queries; the options don't matter.
+    let options = CodegenOptions::default();
+    let layouts = self.initialize_sess_for(true, &options, |sess, cstore, diagnostics, _| {
+      let mut local_providers = Providers::default();
+      self::util::default_provide(&mut local_providers);
+      let mut extern_providers = local_providers.clone();
+      self::util::default_provide_extern(&mut extern_providers);
+
+      let out = OutputFilenames::new(
+        Default::default(),
+        "host-query".into(),
+        None,
+        Default::default(),
+        sess.opts.output_types.clone(),
+      );
+
+      let layouts = enter_tcx(&sess, cstore, local_providers, extern_providers,
+                              None, &out, None,
+                              |tcx, _| -> Result<TypeLayouts, error::PError<P>> {
+        let instance = tcx.convert_kernel_instance(desc.instance)
+          .ok_or_else(|| error::Error::ConvertKernelInstance(desc.instance) )?;
+        self::host::kernel_arg_layouts(tcx, instance)
+          .map_err(error::Error::Layout)
+      });
+      diagnostics.take().log();
+      layouts
+    })?;
+
+    Ok(Some(self.context.set_host_layouts(desc.instance, layouts)))
   }
   fn codegen_kernel_inner(&self,
                           desc: PKernelDesc<P>,
+                          host_layouts: Option<&TypeLayouts>,
                           sess: Session,
-                          cstore: CStore)
+                          cstore: CStore,
+                          diagnostics: DiagnosticSink,
+                          times: &mut PhaseTimes)
     -> Result<PCodegenResults<P>, error::PError<P>>
   {
     use self::util::get_codegen_backend;
-    use rustc_hir::definitions::Definitions;
 
     let context = &self.context;
 
     let instance = desc.instance;
+    let options = desc.options.clone().unwrap_or_default();
     let hash = instance.stable_hash();
     info!("translating {:?}, hash: 0x{:x}",
           instance, hash);
+    let artifacts_desc = KernelArtifactsDesc {
+      instance,
+      hash: (desc.stable_hash(), target_desc_hash(&self.target_desc)).stable_hash(),
+      target: format!("{} {}", self.target_desc.target.llvm_target,
+                      self.target_desc.target.options.cpu),
+    };
 
     let codegen = get_codegen_backend(&sess.opts);
     codegen.init(&sess);
@@ -437,34 +750,6 @@ impl<P> WorkerTranslatorData<P>
       sess.opts.output_types.clone(),
     );
 
-    let krate = create_empty_hir_crate();
-    let dep_graph = rustc_middle::dep_graph::DepGraph::new(Default::default(),
-                                                           Default::default());
-    let arenas = WorkerLocal::new(|_| Arena::default());
-
-    let ast_krate = ast::Crate {
-      module: ast::Mod {
-        inner: DUMMY_SP,
-        items: vec![],
-        inline: false,
-        unsafety: ast::Unsafe::No,
-      },
-      attrs: vec![],
-      span: DUMMY_SP,
-      proc_macros: Default::default(),
-    };
-    let resolver_arenas = Resolver::arenas();
-    let crate_name = CRATE_NAME;
-    let crate_loader = CrateLoader::new_from_cstore(&sess, &DummyMetadataLoader,
-                                                    crate_name, cstore);
-    let resolver = Resolver::new_with_cloader(&sess, &ast_krate, crate_name,
-                                              &resolver_arenas, crate_loader);
-    let mut resolutions = resolver.into_outputs();
-    let definitions: &Definitions = arenas.alloc(mem::replace(
-      &mut resolutions.definitions,
-      Definitions::new(crate_name, sess.local_crate_disambiguator()),
-    ));
-
     let mut intrinsics = IntrinsicsMap::default();
     {
       rustc_geobacter::intrinsics::insert_generic_intrinsics(|k, v| {
@@ -473,9 +758,11 @@ impl<P> WorkerTranslatorData<P>
       });
     }
     {
+      // platforms can replace generic intrinsics, but not their own:
+      let generic: Vec<Symbol> = intrinsics.keys().cloned().collect();
       let mut inserter = |k: &str, v: Lrc<dyn CustomIntrinsicMirGen + 'static>| {
         let k = Symbol::intern(k);
-        assert!(intrinsics.insert(k, v).is_none());
+        assert!(intrinsics.insert(k, v).is_none() || generic.contains(&k));
       };
       self.platform
         .insert_intrinsics(&self.target_desc, &mut inserter);
@@ -483,39 +770,41 @@ impl<P> WorkerTranslatorData<P>
         .insert_kernel_intrinsics(&desc, &mut inserter);
     }
 
-    let accels = self.accels.read().clone();
+    let accels: Vec<_> = self.accels.read()
+      .iter()
+      .filter(|accel| {
+        accel.upgrade()
+          .map(|accel| !self.context.is_accel_retired(accel.id()) )
+          .unwrap_or(false)
+      })
+      .cloned()
+      .collect();
+
+    let platform_stubs = self.platform.stubs();
+    let stubber = match desc.options.as_ref() {
+      Some(options) => Stubber::new(&platform_stubs, &options.stubs),
+      None => Stubber::new(&platform_stubs, &Default::default()),
+    };
+    let forbidden_calls = desc.options.as_ref()
+      .map(|options| options.forbidden_calls )
+      .unwrap_or(ForbiddenCalls::Allow);
 
     let driver_data: DriverData<P> =
       DriverData::new(context.clone(),
                       &accels,
                       &self.target_desc,
                       intrinsics,
-                      &self.platform);
+                      &self.platform,
+                      stubber,
+                      forbidden_calls);
     let driver_data: DriverData<'static, P> = unsafe {
       ::std::mem::transmute(driver_data)
     };
     let driver_data = Box::new(driver_data) as Box<dyn Any + Send + Sync>;
 
-    let gcx = TyCtxt::create_global_ctxt(
-      &sess,
-      Lrc::new(rustc_lint::LintStore::new()),
-      local_providers,
-      extern_providers,
-      &arenas,
-      resolutions,
-      &krate,
-      &definitions,
-      dep_graph.clone(),
-      disk_cache,
-      CRATE_NAME,
-      &out,
-      Some(driver_data),
-    );
-    let icx = ty::tls::ImplicitCtxt::new(&gcx);
-
-    let results: Result<PCodegenResults<P>, PError<P>> = ty::tls::enter_context(&icx, |icx| {
-      let tcx = icx.tcx;
-
+    let results = enter_tcx(&sess, cstore, local_providers, extern_providers,
+                            disk_cache, &out, Some(driver_data),
+                            |tcx, dep_graph| -> Result<PCodegenResults<P>, PError<P>> {
       // Do some initialization of the DepGraph that can only be done with the
       // tcx available.
       tcx.sess.time("dep graph tcx init", || rustc_incremental::dep_graph_tcx_init(tcx));
@@ -525,10 +814,11 @@ impl<P> WorkerTranslatorData<P>
         unsafe {
           let spec_data = &mut *pd.spec_data.get();
 
-          for (&k, v) in desc.spec_params.iter() {
-            let instance = tcx.convert_kernel_instance(k)
-              .ok_or_else(|| error::Error::ConvertKernelInstance(k))?;
-            spec_data.insert(instance, v.clone());
+          for (k, param) in desc.spec_params.iter() {
+            let instance = tcx.convert_kernel_instance(k.as_ref())
+              .ok_or_else(|| error::Error::UnknownSpecParam(k.name.clone()) )?;
+            check_spec_param(tcx, instance, &k.name, param)?;
+            spec_data.insert(instance, param.value.clone());
           }
         }
         Ok(())
@@ -545,67 +835,160 @@ impl<P> WorkerTranslatorData<P>
              })
            })?;
 
+      if let Some(host_layouts) = host_layouts {
+        use rustc_geobacter::TyCtxtKernelInstance;
+
+        let instance = tcx.convert_kernel_instance(instance)
+          .ok_or_else(|| error::Error::ConvertKernelInstance(instance) )?;
+        let device_layouts = tcx.sess.time("kernel argument layout check", || {
+          self::host::kernel_arg_layouts(tcx, instance)
+        }).map_err(error::Error::Layout)?;
+        self::host::check_layouts(host_layouts, &device_layouts)
+          .map_err(|err| error::Error::LayoutMismatch(Box::new(err)) )?;
+      }
+
+      // Run collection, and with it the stubbing of every reachable
+      // function, before codegen proper. Errors reported by either, eg a
+      // no-op stub of a function which returns a value, or a denied
+      // forbidden call (see `CallChecker`), would otherwise only be logged.
+      tcx.sess.time("collect and partition mono items", || {
+        tcx.collect_and_partition_mono_items(LOCAL_CRATE);
+      });
+      if tcx.sess.has_errors() {
+        return Err(error::Error::Codegen(diagnostics.take()));
+      }
+
       let metadata = EncodedMetadata::new();
       let need_metadata_module = false;
 
-      let ongoing_codegen = tcx.sess.time("codegen", || {
-        let _prof_timer = tcx.prof.generic_activity("codegen_crate");
-        codegen.codegen_crate(tcx, metadata, need_metadata_module)
+      let mut llvm_codegen = Duration::default();
+      let ongoing_codegen = stats::time(&mut llvm_codegen, || {
+        tcx.sess.time("codegen", || {
+          let _prof_timer = tcx.prof.generic_activity("codegen_crate");
+          codegen.codegen_crate(tcx, metadata, need_metadata_module)
+        })
       });
 
-      let codegen_results = tcx.sess.time("LLVM codegen",
-           || {
-             codegen.join_codegen(ongoing_codegen, &sess, &dep_graph)
-               .map_err(|_| {
-                 error::Error::Codegen
-               })
-           })?;
-      tcx.sess.time("link",
-                    || {
-                      codegen.link(&sess, codegen_results, &out)
-                        .map_err(|_| {
-                          error::Error::Linking
-                        })
-                    })?;
-
-      let results = DriverData::<P>::with(tcx, |tcx, pd| {
-        pd.post_codegen(tcx, &tmpdir.path(), &out)
+      let codegen_results = stats::time(&mut llvm_codegen, || {
+        tcx.sess.time("LLVM codegen",
+             || {
+               codegen.join_codegen(ongoing_codegen, &sess, dep_graph)
+                 .map_err(|_| {
+                   error::Error::Codegen(diagnostics.take())
+                 })
+             })
+      })?;
+      // Stub bodies not reached by collection are generated on demand
+      // during codegen, so check again before linking:
+      if tcx.sess.has_errors() {
+        return Err(error::Error::Codegen(diagnostics.take()));
+      }
+      // collection was run above, so isn't part of `llvm_codegen`:
+      times.collection = DriverData::<P>::with(tcx, |_, pd| {
+        *pd.collection_time.lock()
+      });
+      times.llvm_codegen = llvm_codegen;
+      stats::time(&mut times.link, || {
+        tcx.sess.time("link",
+                      || {
+                        codegen.link(&sess, codegen_results, &out)
+                          .map_err(|_| {
+                            error::Error::Linking(diagnostics.take())
+                          })
+                      })
+      })?;
+
+      let results = stats::time(&mut times.post_codegen, || {
+        DriverData::<P>::with(tcx, |tcx, pd| {
+          pd.post_codegen(tcx, &tmpdir.path(), &out)
+        })
       })?;
 
       Ok(results)
     });
 
-    let mut results = results?;
+    // anything left over was emitted during a successful codegen:
+    diagnostics.take().log();
+
+    let results = results.and_then(|mut results| {
+      stats::time(&mut times.post_codegen, || {
+        self.platform
+          .post_codegen(&self.target_desc,
+                        &options,
+                        tmpdir.path(),
+                        &mut results)
+      }).map_err(error::Error::PostCodegen)?;
+      Ok(results)
+    });
 
-    let output_dir = tmpdir.into_path();
+    // Keep the intermediates of failed codegens too; they are usually
+    // more interesting than those of successful ones.
+    let sink = self.artifacts.read().clone();
+    if let Err(err) = sink.retire(tmpdir, artifacts_desc) {
+      warn!("failed to retire codegen intermediates of {:?}: {}",
+            instance, err);
+    }
 
-    self.platform
-      .post_codegen(&self.target_desc,
-                    &output_dir,
-                    &mut results)
-      .map_err(error::Error::PostCodegen)?;
+    let results = results?;
     // check that the platform actually inserted an exe entry:
     debug_assert!(results.exe_ref().is_some(),
       "internal platform codegen error: platform didn't insert an Exe \
        output type into the results");
 
-    info!("codegen intermediates dir: {}", output_dir.display());
     info!("codegen complete {:?}, hash: 0x{:x}",
           instance, hash);
 
     Ok(results)
   }
 }
-pub fn create_rustc_options() -> rustc_session::config::Options {
+
+/// The memory used by `results`, for the in-memory cache's budget.
+fn result_size<CD>(results: &CodegenResults<CD>) -> usize {
+  results.outputs.values()
+    .map(|output| output.len() )
+    .sum()
+}
+
+/// Check that `param` was defined with the type its key function
+/// (`instance`) returns, and is the size of that type on the device.
+fn check_spec_param<'tcx, E>(tcx: TyCtxt<'tcx>, instance: ty::Instance<'tcx>,
+                             name: &str, param: &SpecParam)
+  -> Result<(), error::Error<E>>
+{
+  let reveal_all = ty::ParamEnv::reveal_all();
+  let sig = instance.ty(tcx, reveal_all).fn_sig(tcx);
+  let sig = tcx.normalize_erasing_late_bound_regions(reveal_all, &sig);
+  let ty = sig.output();
+  let size = tcx.layout_of(reveal_all.and(ty))
+    .map_err(|err| {
+      error::Error::Layout(format!("spec param `{}` of type `{}`: {}",
+                                   name, ty, err))
+    })?
+    .size.bytes() as usize;
+
+  // `tcx.type_id_hash` is what `std::intrinsics::type_id` evaluates to.
+  if tcx.type_id_hash(ty) != param.type_id || size != param.value.len() {
+    return Err(error::Error::SpecParam(SpecParamError::TypeMismatch {
+      param: name.into(),
+      expected: ty.to_string(),
+      expected_size: size,
+      found: param.ty.clone(),
+      found_size: param.value.len(),
+    }));
+  }
+
+  Ok(())
+}
+
+pub fn create_rustc_options(options: &CodegenOptions) -> rustc_session::config::Options {
   use rustc_session::config::*;
   use rustc_target::spec::*;
 
   let mut opts = Options::default();
   // We need to have the tcx build the def_path_hash_to_def_id map:
   opts.debugging_opts.query_dep_graph = true;
-  opts.optimize = OptLevel::No;
-  opts.optimize = OptLevel::Aggressive;
-  //opts.debuginfo = DebugInfo::Limited;
+  opts.optimize = options.opt_level.into();
+  opts.debuginfo = options.debuginfo.into();
 
   let output = (OutputType::Bitcode, None);
   let ir_out = (OutputType::LlvmAssembly, None);
@@ -613,8 +996,7 @@ pub fn create_rustc_options() -> rustc_session::config::Options {
   out.push(output);
   out.push(ir_out);
 
-  if opts.optimize == OptLevel::Aggressive && !use_llc() {
-    // prevent LLVM from taking us down if we're not optimizing:
+  if !use_llc() {
     let asm = (OutputType::Assembly, None);
     let obj = (OutputType::Object, None);
     out.push(asm);
@@ -623,7 +1005,7 @@ pub fn create_rustc_options() -> rustc_session::config::Options {
   opts.output_types = OutputTypes::new(&out);
 
   let print_remarks = print_opt_remarks();
-  if print_remarks {
+  if print_remarks && opts.debuginfo == DebugInfo::None {
     opts.debuginfo = DebugInfo::Limited;
   }
   // Avoid a mystery warning about not being about to remove a temp
@@ -653,29 +1035,96 @@ pub fn create_rustc_options() -> rustc_session::config::Options {
     }
     opts.cg.passes.push("simplifycfg".into());
   }
+  opts.cg.passes.extend(options.passes.iter().cloned());
   opts.debugging_opts.print_llvm_passes = false;
   opts.cg.llvm_args.push("-spec-exec-only-if-divergent-target".into());
-  opts.cg.llvm_args.push("-amdgpu-early-inline-all".into());
-  opts.cg.llvm_args.push("-amdgpu-prelink".into());
   opts.cg.llvm_args.push("-sroa-strict-inbounds".into());
-  opts.debugging_opts.polly =
-    opts.optimize == OptLevel::Aggressive;
-  opts.cg.llvm_args.push("-polly-run-inliner".into());
-  opts.cg.llvm_args.push("-polly-register-tiling".into());
-  opts.cg.llvm_args.push("-polly-check-vectorizable".into());
-  opts.cg.llvm_args.push("-enable-polly-aligned".into());
-  opts.cg.llvm_args.push("-polly-vectorizer=stripmine".into());
-  //opts.cg.llvm_args.push("-polly-position=early".into());
-  opts.cg.llvm_args.push("-polly-enable-polyhedralinfo".into());
+  opts.debugging_opts.polly = options.polly_enabled();
+  if opts.debugging_opts.polly {
+    opts.cg.llvm_args.push("-polly-run-inliner".into());
+    opts.cg.llvm_args.push("-polly-register-tiling".into());
+    opts.cg.llvm_args.push("-polly-check-vectorizable".into());
+    opts.cg.llvm_args.push("-enable-polly-aligned".into());
+    opts.cg.llvm_args.push("-polly-vectorizer=stripmine".into());
+    //opts.cg.llvm_args.push("-polly-position=early".into());
+    opts.cg.llvm_args.push("-polly-enable-polyhedralinfo".into());
+  }
 
   // Disable these alignment assumptions inserted during optimization:
   // They aren't really helpful and in fact block a lot of possible mem2reg promotions as a
   // result of their presence.
   opts.cg.llvm_args
     .push("-preserve-alignment-assumptions-during-inlining=false".into());
+  opts.cg.llvm_args.extend(options.llvm_args.iter().cloned());
   opts
 }
 
+/// Create a `TyCtxt` for the crates loaded into `cstore`, and run `f` with
+/// it. Used for both codegen and host queries.
+fn enter_tcx<'a, F, R>(sess: &'a Session, cstore: CStore,
+                       local_providers: Providers,
+                       extern_providers: Providers,
+                       disk_cache: Option<rustc_middle::ty::query::OnDiskCache<'a>>,
+                       out: &'a OutputFilenames,
+                       driver_data: Option<Box<dyn Any + Send + Sync>>,
+                       f: F)
+  -> R
+  where F: for<'tcx> FnOnce(TyCtxt<'tcx>, &rustc_middle::dep_graph::DepGraph) -> R,
+{
+  use rustc_hir::definitions::Definitions;
+
+  let krate = create_empty_hir_crate();
+  let dep_graph = rustc_middle::dep_graph::DepGraph::new(Default::default(),
+                                                         Default::default());
+  let arenas = WorkerLocal::new(|_| Arena::default());
+
+  let ast_krate = create_empty_ast_crate();
+  let resolver_arenas = Resolver::arenas();
+  let crate_name = CRATE_NAME;
+  let crate_loader = CrateLoader::new_from_cstore(sess, &DummyMetadataLoader,
+                                                  crate_name, cstore);
+  let resolver = Resolver::new_with_cloader(sess, &ast_krate, crate_name,
+                                            &resolver_arenas, crate_loader);
+  let mut resolutions = resolver.into_outputs();
+  let definitions: &Definitions = arenas.alloc(mem::replace(
+    &mut resolutions.definitions,
+    Definitions::new(crate_name, sess.local_crate_disambiguator()),
+  ));
+
+  let gcx = TyCtxt::create_global_ctxt(
+    sess,
+    Lrc::new(rustc_lint::LintStore::new()),
+    local_providers,
+    extern_providers,
+    &arenas,
+    resolutions,
+    &krate,
+    &definitions,
+    dep_graph.clone(),
+    disk_cache,
+    CRATE_NAME,
+    out,
+    driver_data,
+  );
+  let icx = ty::tls::ImplicitCtxt::new(&gcx);
+
+  ty::tls::enter_context(&icx, |icx| f(icx.tcx, &dep_graph) )
+}
+
+pub fn create_empty_ast_crate() -> ast::Crate {
+  ast::Crate {
+    module: ast::Mod {
+      inner: DUMMY_SP,
+      items: vec![],
+      inline: false,
+      unsafety: ast::Unsafe::No,
+    },
+    attrs: vec![],
+    span: DUMMY_SP,
+    proc_macros: Default::default(),
+  }
+}
+
 pub fn create_empty_hir_crate<'hir>() -> rustc_hir::Crate<'hir> {
   use rustc_hir::*;
 
@@ -726,8 +1175,15 @@ impl<P> WorkerTranslatorData<P>
         rustc_metadata::provide_extern(&mut providers);
 
         let def_id = DriverData::<P>::with(tcx, |tcx, pd| {
-          pd.stubber.stub_def_id(tcx, def_id)
+          if pd.stubber.generated_stub(tcx, def_id).is_some() {
+            return None;
+          }
+          Some(pd.stubber.stub_def_id(tcx, def_id))
         });
+        let def_id = match def_id {
+          Some(def_id) => def_id,
+          None => { return true; },
+        };
 
         (providers.is_mir_available)(tcx, def_id)
       },
@@ -736,8 +1192,16 @@ impl<P> WorkerTranslatorData<P>
         rustc_metadata::provide_extern(&mut providers);
 
         let def_id = DriverData::<P>::with(tcx, |tcx, pd| {
-          pd.stubber.stub_def_id(tcx, def_id)
+          if let Some(stub) = pd.stubber.generated_stub(tcx, def_id) {
+            let body = generated_stub_body(tcx, def_id, stub);
+            return Err(&*tcx.arena.alloc(body));
+          }
+          Ok(pd.stubber.stub_def_id(tcx, def_id))
         });
+        let def_id = match def_id {
+          Ok(def_id) => def_id,
+          Err(body) => { return body; },
+        };
 
         (providers.optimized_mir)(tcx, def_id)
       },
@@ -876,7 +1340,10 @@ impl<P> WorkerTranslatorData<P>
       entry_fn,
       collect_and_partition_mono_items: |tcx, cnum| {
         DriverData::<P>::with(tcx, move |tcx, pd| {
-          collector::collect_and_partition_mono_items(tcx, pd, cnum)
+          let start = Instant::now();
+          let r = collector::collect_and_partition_mono_items(tcx, pd, cnum);
+          *pd.collection_time.lock() += start.elapsed();
+          r
         })
       },
       // we need to override this because otherwise rustc will get confused
@@ -0,0 +1,484 @@
+//! A codegen server, shared by many processes on the same machine, so each
+//! doesn't have to load the crate metadata and run rustc itself.
+//!
+//! The server listens on a Unix socket, in a directory only its user can
+//! access. Clients must be built from the same binary as the server, as
+//! kernels are identified by their (serialized) kernel instance, which is
+//! only meaningful within a single build; this is checked when a client
+//! connects. Requests carry the accelerator target
+//! desc, so a single server can codegen for any number of accelerators. It
+//! keeps a `CodegenDriver` per target desc, so results are cached (in memory
+//! and on disk) across all of its clients.
+//!
+//! Clients use it with `CodegenDriver::set_codegen_server`, or by setting
+//! `GEOBACTER_CODEGEN_SERVER` to the socket path. Everything else about the
+//! driver works as usual, though the on-disk cache of the client isn't used,
+//! and codegen stats are recorded by the server. The requests and responses
+//! are the same as those used for subprocess codegen (see `subprocess`);
+//! platforms opt in the same way.
+
+use std::any::type_name;
+use std::fmt;
+use std::fs;
+use std::geobacter::kernel::KernelInstanceRef;
+use std::io::{self, Read, Write, ErrorKind, };
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, };
+use std::os::unix::net::{UnixListener, UnixStream, };
+use std::path::{Path, PathBuf, };
+use std::sync::Arc;
+use std::thread;
+
+use parking_lot::{Condvar, Mutex, };
+
+use serde::{Deserialize, Serialize, };
+use serde::de::DeserializeOwned;
+
+use crate::AcceleratorTargetDesc;
+use crate::codegen::{CodegenKernelInstance, PlatformCodegen, PKernelDesc, };
+use crate::codegen::products::PCodegenResults;
+use crate::context::Context;
+use crate::platform::os::binary_id;
+use crate::utils::HashMap;
+use super::{CodegenDriver, WorkerTranslatorData, };
+use super::disk_cache::target_desc_hash;
+use super::error::{Error, PError, };
+use super::subprocess::{HelperRequest, HelperResponse, respond, };
+
+/// Bump this whenever the messages change.
+const PROTOCOL_VERSION: u32 = 1;
+/// Larger messages are assumed to be garbage.
+const MAX_MESSAGE_SIZE: usize = 1 << 30;
+/// The default for `CodegenServer::set_max_clients`.
+const DEFAULT_MAX_CLIENTS: usize = 64;
+
+/// Sent by the client when it connects. The server replies with a
+/// `Result<(), String>`.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+struct Hello {
+  version: u32,
+  /// `type_name` of the platform.
+  platform: String,
+  /// Identifies the build of the program; see `binary_id`.
+  binary: u64,
+}
+impl Hello {
+  fn new<P>() -> io::Result<Self>
+    where P: PlatformCodegen,
+  {
+    Ok(Hello {
+      version: PROTOCOL_VERSION,
+      platform: type_name::<P>().into(),
+      binary: binary_id()?,
+    })
+  }
+  fn check(&self, client: &Hello) -> Result<(), String> {
+    if self.version != client.version {
+      Err(format!("protocol version mismatch: server has {}, client has {}",
+                  self.version, client.version))
+    } else if self.platform != client.platform {
+      Err(format!("platform mismatch: server is for `{}`, client is for `{}`",
+                  self.platform, client.platform))
+    } else if self.binary != client.binary {
+      Err("the client wasn't built from the same binary as the server".into())
+    } else {
+      Ok(())
+    }
+  }
+}
+
+/// Create `dir` if needed, accessible only by us. If it already exists, it
+/// must already be so.
+fn private_dir(dir: &Path) -> io::Result<()> {
+  match fs::DirBuilder::new().recursive(true).mode(0o700).create(dir) {
+    Ok(()) => { },
+    Err(err) if err.kind() == ErrorKind::AlreadyExists => { },
+    Err(err) => { return Err(err); },
+  }
+  let metadata = fs::metadata(dir)?;
+  let uid = unsafe { libc::getuid() };
+  if metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+    let msg = format!("{} must be a directory only accessible by its owner (mode \
+                       0700) to hold a codegen server socket", dir.display());
+    return Err(io::Error::new(ErrorKind::PermissionDenied, msg));
+  }
+  Ok(())
+}
+
+fn invalid_data<E>(err: E) -> io::Error
+  where E: fmt::Display,
+{
+  io::Error::new(ErrorKind::InvalidData, err.to_string())
+}
+/// Messages are prefixed by their length, as a little endian `u32`.
+fn write_message<W, T>(w: &mut W, msg: &T) -> io::Result<()>
+  where W: Write,
+        T: Serialize,
+{
+  let bytes = rmps::encode::to_vec(msg).map_err(invalid_data)?;
+  if bytes.len() > MAX_MESSAGE_SIZE {
+    return Err(invalid_data("message too large"));
+  }
+  w.write_all(&(bytes.len() as u32).to_le_bytes())?;
+  w.write_all(&bytes)?;
+  w.flush()
+}
+fn read_message<R, T>(r: &mut R) -> io::Result<T>
+  where R: Read,
+        T: DeserializeOwned,
+{
+  let mut len = [0u8; 4];
+  r.read_exact(&mut len)?;
+  let len = u32::from_le_bytes(len) as usize;
+  if len > MAX_MESSAGE_SIZE {
+    return Err(invalid_data("message too large"));
+  }
+  let mut bytes = vec![0u8; len];
+  r.read_exact(&mut bytes)?;
+  rmps::decode::from_slice(&bytes).map_err(invalid_data)
+}
+
+fn client_handshake(stream: &mut UnixStream, hello: &Hello) -> io::Result<()> {
+  write_message(stream, hello)?;
+  let reply: Result<(), String> = read_message(stream)?;
+  reply.map_err(|msg| io::Error::new(ErrorKind::ConnectionRefused, msg) )
+}
+/// Returns false if the client was rejected.
+fn server_handshake(stream: &mut UnixStream, hello: &Hello) -> io::Result<bool> {
+  let client: Hello = read_message(stream)?;
+  let reply = hello.check(&client);
+  write_message(stream, &reply)?;
+  if let Err(ref msg) = reply {
+    warn!("rejected codegen client: {}", msg);
+  }
+  Ok(reply.is_ok())
+}
+
+pub struct CodegenServer<P>
+  where P: PlatformCodegen,
+{
+  context: Context,
+  platform: P,
+  path: PathBuf,
+  listener: UnixListener,
+  hello: Hello,
+  /// The number of clients being served, and the limit.
+  clients: Mutex<(usize, usize)>,
+  client_done: Condvar,
+  /// By `target_desc_hash`.
+  drivers: Mutex<HashMap<u64, Arc<CodegenDriver<P>>>>,
+  /// Requests name kernels by value, but `KernelDesc` needs a `'static`
+  /// instance, so each distinct instance is leaked once.
+  instances: Mutex<HashMap<CodegenKernelInstance, KernelInstanceRef<'static>>>,
+}
+impl<P> CodegenServer<P>
+  where P: PlatformCodegen,
+        <P::Device as crate::Device>::Error: fmt::Debug,
+{
+  /// Listen on `path`. Its directory is created if needed, and must only be
+  /// accessible by the current user, since anyone who can connect can run
+  /// codegen in this process. A socket left there by a server which is no
+  /// longer running is replaced. Call `serve` to start accepting clients.
+  pub fn bind<T>(context: &Context, platform: P, path: T) -> io::Result<Arc<Self>>
+    where T: Into<PathBuf>,
+  {
+    let path = path.into();
+    if let Some(dir) = path.parent() {
+      private_dir(dir)?;
+    }
+    let listener = match UnixListener::bind(&path) {
+      Ok(listener) => listener,
+      Err(err) if err.kind() == ErrorKind::AddrInUse => {
+        if UnixStream::connect(&path).is_ok() {
+          return Err(err);
+        }
+        fs::remove_file(&path)?;
+        UnixListener::bind(&path)?
+      },
+      Err(err) => { return Err(err); },
+    };
+
+    Ok(Arc::new(CodegenServer {
+      context: context.clone(),
+      platform,
+      path,
+      listener,
+      hello: Hello::new::<P>()?,
+      clients: Mutex::new((0, DEFAULT_MAX_CLIENTS)),
+      client_done: Condvar::new(),
+      drivers: Default::default(),
+      instances: Default::default(),
+    }))
+  }
+
+  pub fn path(&self) -> &Path { &self.path }
+
+  /// Set the maximum number of clients served at the same time. Once
+  /// reached, new clients aren't accepted until another disconnects. Zero
+  /// is treated as one.
+  pub fn set_max_clients(&self, limit: usize) {
+    self.clients.lock().1 = limit.max(1);
+    self.client_done.notify_all();
+  }
+  pub fn max_clients(&self) -> usize { self.clients.lock().1 }
+
+  /// Get the driver used for clients with `target_desc`, eg to configure
+  /// its caches or look at its stats. It's created if needed.
+  pub fn driver(&self, target_desc: Arc<AcceleratorTargetDesc>)
+    -> io::Result<Arc<CodegenDriver<P>>>
+  {
+    let key = target_desc_hash(&target_desc);
+    let mut drivers = self.drivers.lock();
+    if let Some(driver) = drivers.get(&key) {
+      return Ok(driver.clone());
+    }
+
+    let driver = Arc::new(CodegenDriver::new(&self.context, target_desc,
+                                             self.platform.clone())?);
+    // in case `GEOBACTER_CODEGEN_SERVER` is set in this process too:
+    driver.set_codegen_server(None)?;
+    drivers.insert(key, driver.clone());
+    Ok(driver)
+  }
+
+  /// Accept clients until the listener fails. Each client is served on its
+  /// own thread, up to `max_clients` at a time.
+  pub fn serve(self: &Arc<Self>) -> io::Result<()> {
+    loop {
+      {
+        let mut clients = self.clients.lock();
+        while clients.0 >= clients.1 {
+          self.client_done.wait(&mut clients);
+        }
+        clients.0 += 1;
+      }
+      let slot = ClientSlot(self.clone());
+
+      let (stream, _) = self.listener.accept()?;
+      thread::Builder::new()
+        .name("geobacter codegen client".into())
+        .spawn(move || {
+          if let Err(err) = slot.0.serve_client(stream) {
+            warn!("codegen client error: {}", err);
+          }
+        })?;
+    }
+  }
+  fn serve_client(&self, mut stream: UnixStream) -> io::Result<()> {
+    if !server_handshake(&mut stream, &self.hello)? {
+      return Ok(());
+    }
+
+    loop {
+      let request: HelperRequest = match read_message(&mut stream) {
+        Ok(request) => request,
+        // the client hung up
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => { return Ok(()); },
+        Err(err) => { return Err(err); },
+      };
+      let response = self.codegen(request);
+      write_message(&mut stream, &response)?;
+    }
+  }
+  fn codegen(&self, request: HelperRequest) -> HelperResponse {
+    let intern = |instance: CodegenKernelInstance| {
+      *self.instances.lock()
+        .entry(instance.clone())
+        .or_insert_with(|| {
+          KernelInstanceRef {
+            name: Box::leak(instance.name.into_boxed_str()),
+            instance: Box::leak(instance.instance.into_boxed_slice()),
+          }
+        })
+    };
+    let (target_desc, desc) = match request.into_desc(&self.platform, intern) {
+      Ok(v) => v,
+      Err(msg) => { return HelperResponse::Failed(msg); },
+    };
+    match self.driver(Arc::new(target_desc)) {
+      Ok(driver) => respond(&driver, desc),
+      Err(err) => HelperResponse::Failed(format!("failed to create driver: {}", err)),
+    }
+  }
+}
+impl<P> Drop for CodegenServer<P>
+  where P: PlatformCodegen,
+{
+  fn drop(&mut self) {
+    let _ = fs::remove_file(&self.path);
+  }
+}
+
+/// Counts a client against `CodegenServer::max_clients` until dropped.
+struct ClientSlot<P>(Arc<CodegenServer<P>>)
+  where P: PlatformCodegen;
+impl<P> Drop for ClientSlot<P>
+  where P: PlatformCodegen,
+{
+  fn drop(&mut self) {
+    self.0.clients.lock().0 -= 1;
+    self.0.client_done.notify_one();
+  }
+}
+
+/// The client side of a connection to a codegen server.
+pub(super) struct ServerClient {
+  path: PathBuf,
+  hello: Hello,
+  /// Connections not currently used by a request.
+  idle: Mutex<Vec<UnixStream>>,
+}
+impl ServerClient {
+  /// Fails if the server can't be reached, or rejects us.
+  pub(super) fn connect<P>(path: &Path) -> io::Result<Self>
+    where P: PlatformCodegen,
+  {
+    let client = ServerClient {
+      path: path.to_owned(),
+      hello: Hello::new::<P>()?,
+      idle: Mutex::new(vec![]),
+    };
+    let stream = client.open()?;
+    client.idle.lock().push(stream);
+    Ok(client)
+  }
+  pub(super) fn path(&self) -> &Path { &self.path }
+
+  fn open(&self) -> io::Result<UnixStream> {
+    let mut stream = UnixStream::connect(&self.path)?;
+    client_handshake(&mut stream, &self.hello)?;
+    Ok(stream)
+  }
+  fn call(&self, request: &HelperRequest) -> io::Result<HelperResponse> {
+    let stream = loop {
+      let idle = self.idle.lock().pop();
+      match idle {
+        Some(stream) if is_stale(&stream) => continue,
+        Some(stream) => break stream,
+        None => break self.open()?,
+      }
+    };
+    self.call_on(stream, request)
+  }
+  fn call_on(&self, mut stream: UnixStream, request: &HelperRequest)
+    -> io::Result<HelperResponse>
+  {
+    write_message(&mut stream, request)?;
+    let response = read_message(&mut stream)?;
+    self.idle.lock().push(stream);
+    Ok(response)
+  }
+}
+
+/// Has the server hung up on this idle connection, eg because it was
+/// restarted? Nothing is sent on idle connections, so anything readable
+/// means it's unusable.
+fn is_stale(stream: &UnixStream) -> bool {
+  if stream.set_nonblocking(true).is_err() {
+    return true;
+  }
+  let stale = match (&*stream).read(&mut [0u8; 1]) {
+    Err(err) if err.kind() == ErrorKind::WouldBlock => false,
+    _ => true,
+  };
+  stale || stream.set_nonblocking(false).is_err()
+}
+
+impl<P> WorkerTranslatorData<P>
+  where P: PlatformCodegen,
+{
+  /// Returns `None` if the platform doesn't support remote codegen.
+  pub(super) fn codegen_on_server(&self, server: &ServerClient, desc: &PKernelDesc<P>)
+    -> Option<Result<PCodegenResults<P>, PError<P>>>
+  {
+    let request = match self.helper_request(desc) {
+      Some(request) => request,
+      None => {
+        debug!("{:?}: platform doesn't support remote codegen, \
+                using this process", desc.instance);
+        return None;
+      },
+    };
+
+    let response = server.call(&request)
+      .map_err(|err| Error::Server(format!("{}: {}", server.path().display(), err)) );
+    Some(response
+      .and_then(|response| self.decode_response(response, Error::Server) )
+      .map(|(results, _)| results ))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn hello(binary: u64) -> Hello {
+    Hello {
+      version: PROTOCOL_VERSION,
+      platform: "test".into(),
+      binary,
+    }
+  }
+
+  #[test]
+  fn handshake() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("codegen.sock");
+    let listener = UnixListener::bind(&path).unwrap();
+
+    let server = thread::spawn(move || {
+      listener.incoming()
+        .take(2)
+        .map(|stream| {
+          let mut stream = stream.unwrap();
+          let accepted = server_handshake(&mut stream, &hello(1)).unwrap();
+          if accepted {
+            let msg: Vec<u32> = read_message(&mut stream).unwrap();
+            write_message(&mut stream, &msg.iter().sum::<u32>()).unwrap();
+          }
+          accepted
+        })
+        .collect::<Vec<_>>()
+    });
+
+    let mut stream = UnixStream::connect(&path).unwrap();
+    client_handshake(&mut stream, &hello(1)).unwrap();
+    write_message(&mut stream, &vec![1u32, 2, 3]).unwrap();
+    let sum: u32 = read_message(&mut stream).unwrap();
+    assert_eq!(sum, 6);
+
+    // a different build of the program is rejected:
+    let mut stream = UnixStream::connect(&path).unwrap();
+    let err = client_handshake(&mut stream, &hello(2)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+
+    assert_eq!(server.join().unwrap(), vec![true, false]);
+  }
+
+  #[test]
+  fn stale_connections() {
+    let (client, server) = UnixStream::pair().unwrap();
+    assert!(!is_stale(&client));
+    drop(server);
+    assert!(is_stale(&client));
+  }
+
+  #[test]
+  fn socket_dir_must_be_private() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let sockets = dir.path().join("a/b");
+    private_dir(&sockets).unwrap();
+    let mode = fs::metadata(&sockets).unwrap().mode();
+    assert_eq!(mode & 0o777, 0o700);
+
+    fs::set_permissions(&sockets, fs::Permissions::from_mode(0o755)).unwrap();
+    let err = private_dir(&sockets).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+  }
+
+  #[test]
+  fn binary_id_is_stable() {
+    assert_eq!(binary_id().unwrap(), binary_id().unwrap());
+  }
+}
@@ -0,0 +1,201 @@
+//! Counters and phase timings of a `CodegenDriver`, for tracking compile
+//! times without having to scrape the logs. Use `CodegenDriver::stats` to
+//! get a snapshot.
+
+use std::collections::{BTreeMap, VecDeque, };
+use std::ops::AddAssign;
+use std::time::{Duration, Instant, };
+
+use parking_lot::Mutex;
+
+use serde::{Deserialize, Serialize, };
+
+/// How many `KernelCodegenStats` a driver keeps; older ones are only
+/// counted in the totals.
+pub const MAX_KERNEL_STATS: usize = 256;
+
+/// Time spent in each phase of a codegen.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PhaseTimes {
+  /// Computing the host layouts of the kernel's argument types, in a
+  /// session of their own. Zero when the layouts were already cached in the
+  /// context, or when the accelerator is the host.
+  pub host_layouts: Duration,
+  /// Loading the crate metadata of this process into the session.
+  pub metadata_load: Duration,
+  /// Collecting and partitioning the mono items reachable from the kernel.
+  pub collection: Duration,
+  /// Translation to LLVM IR and LLVM's optimization and code generation,
+  /// not including collection.
+  pub llvm_codegen: Duration,
+  /// Rustc's linking of the codegen units.
+  pub link: Duration,
+  /// The platform's `post_codegen` hooks, ie platform linking.
+  pub post_codegen: Duration,
+  /// The whole codegen, including the above phases. Doesn't include time
+  /// spent waiting for a codegen slot.
+  pub total: Duration,
+}
+impl AddAssign for PhaseTimes {
+  fn add_assign(&mut self, rhs: Self) {
+    self.host_layouts += rhs.host_layouts;
+    self.metadata_load += rhs.metadata_load;
+    self.collection += rhs.collection;
+    self.llvm_codegen += rhs.llvm_codegen;
+    self.link += rhs.link;
+    self.post_codegen += rhs.post_codegen;
+    self.total += rhs.total;
+  }
+}
+
+/// A single completed codegen.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct KernelCodegenStats {
+  /// The name of the kernel instance.
+  pub kernel: String,
+  /// The same hash as is used to name kept codegen artifacts.
+  pub hash: u64,
+  pub times: PhaseTimes,
+  /// The size of each output, in bytes, by output type, eg `exe` or
+  /// `llvm-bc`.
+  pub output_sizes: BTreeMap<String, usize>,
+}
+
+/// A snapshot of a driver's statistics. Everything is counted from when the
+/// driver was created or its statistics were last reset.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct CodegenStats {
+  /// Requests answered from the in-memory cache. Includes requests which
+  /// first had to wait for an in progress codegen.
+  pub cache_hits: u64,
+  /// Requests answered from the on-disk cache.
+  pub disk_cache_hits: u64,
+  /// Requests which had to run codegen.
+  pub cache_misses: u64,
+  /// Requests which waited on another request's codegen of the same kernel.
+  pub in_progress_waits: u64,
+  /// Codegens which failed.
+  pub failures: u64,
+  /// Results evicted from the in-memory cache, either to stay within its
+  /// memory budget or explicitly.
+  pub evictions: u64,
+  /// Successful codegens, including those no longer in `kernels`.
+  pub codegens: u64,
+  /// The sum of the times of every successful codegen.
+  pub total_times: PhaseTimes,
+  /// The sum of the output sizes of every successful codegen, by output
+  /// type.
+  pub total_output_sizes: BTreeMap<String, u64>,
+  /// The last `MAX_KERNEL_STATS` successful codegens, oldest first.
+  pub kernels: VecDeque<KernelCodegenStats>,
+}
+
+#[derive(Default)]
+pub(super) struct StatsRecorder(Mutex<CodegenStats>);
+impl StatsRecorder {
+  pub(super) fn snapshot(&self) -> CodegenStats { self.0.lock().clone() }
+  pub(super) fn reset(&self) {
+    *self.0.lock() = Default::default();
+  }
+
+  pub(super) fn cache_hit(&self) { self.0.lock().cache_hits += 1; }
+  pub(super) fn disk_cache_hit(&self) { self.0.lock().disk_cache_hits += 1; }
+  pub(super) fn cache_miss(&self) { self.0.lock().cache_misses += 1; }
+  pub(super) fn in_progress_wait(&self) { self.0.lock().in_progress_waits += 1; }
+  pub(super) fn failure(&self) { self.0.lock().failures += 1; }
+  pub(super) fn evictions(&self, count: usize) {
+    self.0.lock().evictions += count as u64;
+  }
+  pub(super) fn kernel(&self, kernel: KernelCodegenStats) {
+    let mut stats = self.0.lock();
+    stats.codegens += 1;
+    stats.total_times += kernel.times;
+    for (ty, &size) in kernel.output_sizes.iter() {
+      *stats.total_output_sizes.entry(ty.clone()).or_default() += size as u64;
+    }
+    if stats.kernels.len() == MAX_KERNEL_STATS {
+      stats.kernels.pop_front();
+    }
+    stats.kernels.push_back(kernel);
+  }
+}
+
+/// Run `f`, adding the time it took to `time`.
+pub(super) fn time<F, R>(time: &mut Duration, f: F) -> R
+  where F: FnOnce() -> R,
+{
+  let start = Instant::now();
+  let r = f();
+  *time += start.elapsed();
+  r
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn totals_and_snapshot_roundtrip() {
+    let stats = StatsRecorder::default();
+    stats.cache_miss();
+    stats.cache_miss();
+    stats.cache_hit();
+    stats.in_progress_wait();
+
+    let times = PhaseTimes {
+      llvm_codegen: Duration::from_millis(3),
+      total: Duration::from_millis(5),
+      ..Default::default()
+    };
+    for hash in 0..2 {
+      stats.kernel(KernelCodegenStats {
+        kernel: "kernel".into(),
+        hash,
+        times,
+        output_sizes: vec![("exe".to_string(), 16)].into_iter().collect(),
+      });
+    }
+
+    let snapshot = stats.snapshot();
+    assert_eq!(snapshot.cache_misses, 2);
+    assert_eq!(snapshot.cache_hits, 1);
+    assert_eq!(snapshot.in_progress_waits, 1);
+    assert_eq!(snapshot.total_times.llvm_codegen, Duration::from_millis(6));
+    assert_eq!(snapshot.total_times.total, Duration::from_millis(10));
+    assert_eq!(snapshot.kernels.len(), 2);
+    assert_eq!(snapshot.codegens, 2);
+    assert_eq!(snapshot.total_output_sizes["exe"], 32);
+
+    let json = serde_json::to_string(&snapshot).unwrap();
+    let decoded: CodegenStats = serde_json::from_str(&json).unwrap();
+    assert_eq!(decoded, snapshot);
+
+    stats.reset();
+    assert_eq!(stats.snapshot(), CodegenStats::default());
+  }
+
+  #[test]
+  fn kernels_are_capped() {
+    let stats = StatsRecorder::default();
+    let times = PhaseTimes {
+      total: Duration::from_millis(1),
+      ..Default::default()
+    };
+    let count = MAX_KERNEL_STATS as u64 + 10;
+    for hash in 0..count {
+      stats.kernel(KernelCodegenStats {
+        kernel: "kernel".into(),
+        hash,
+        times,
+        output_sizes: Default::default(),
+      });
+    }
+
+    let snapshot = stats.snapshot();
+    assert_eq!(snapshot.kernels.len(), MAX_KERNEL_STATS);
+    assert_eq!(snapshot.kernels.front().unwrap().hash, 10);
+    assert_eq!(snapshot.kernels.back().unwrap().hash, count - 1);
+    assert_eq!(snapshot.codegens, count);
+    assert_eq!(snapshot.total_times.total, Duration::from_millis(count));
+  }
+}
@@ -0,0 +1,310 @@
+//! Codegen in a helper subprocess, so LLVM fatal errors, assertion failures
+//! and crashes become errors instead of taking the host process down.
+//! Enabled with `CodegenDriver::set_subprocess_codegen`, or by setting
+//! `GEOBACTER_CODEGEN_SUBPROCESS`.
+//!
+//! The helper is the current executable, run again with
+//! `GEOBACTER_CODEGEN_HELPER` set to the platform's name. Programs which use
+//! this mode must call `run_codegen_helper` (or their platform crate's
+//! wrapper) at the very start of `main`: in the helper, it runs the codegen
+//! and exits. Each codegen gets its own helper, which reads the kernel desc
+//! and target desc from stdin and writes the results to a file named by
+//! `GEOBACTER_CODEGEN_HELPER_OUTPUT` (stdout isn't used, as LLVM and rustc
+//! write to it). Helpers never start helpers of their own: a helper which
+//! doesn't handle helper mode fails its codegen instead of relaunching
+//! itself again.
+//!
+//! Platforms opt in by implementing `PlatformCodegen::encode_kernel_desc`,
+//! `decode_kernel_desc` and `decode_target_desc`, in addition to the codegen
+//! desc encoding needed for the on-disk cache. Kernels of platforms which
+//! don't are always codegen-ed in process.
+
+use std::any::type_name;
+use std::env::{current_exe, var, var_os, };
+use std::error::Error as StdError;
+use std::fmt;
+use std::fs;
+use std::geobacter::kernel::KernelInstanceRef;
+use std::io::{self, Read, Write, };
+use std::process::{Command, Stdio, exit, };
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, };
+
+use serde::{Deserialize, Serialize, };
+use serde::de::DeserializeOwned;
+
+use tempfile::NamedTempFile;
+
+use crate::{AcceleratorTargetDesc, PlatformTargetDesc, };
+use crate::codegen::{CodegenKernelInstance, CodegenOptions, KernelDesc,
+                     PlatformCodegen, PKernelDesc, SpecParamsDesc, };
+use crate::codegen::products::{PCodegenResults, SerializedCodegenResults, };
+use crate::context::Context;
+use crate::utils::env::codegen_helper_timeout;
+use super::{CodegenDriver, WorkerTranslatorData, };
+use super::diagnostics::Diagnostics;
+use super::error::{Error, PError, };
+use super::stats::PhaseTimes;
+
+const HELPER_ENV: &'static str = "GEOBACTER_CODEGEN_HELPER";
+const OUTPUT_ENV: &'static str = "GEOBACTER_CODEGEN_HELPER_OUTPUT";
+/// Removed from the helper's environment; see
+/// `crate::utils::env::codegen_subprocess`.
+const SUBPROCESS_ENV: &'static str = "GEOBACTER_CODEGEN_SUBPROCESS";
+/// The default for `GEOBACTER_CODEGEN_HELPER_TIMEOUT`.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// How often to check if the helper has exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How much of the helper's stderr to include in errors.
+const STDERR_TAIL: usize = 4096;
+
+/// Also used by the codegen server; see `server`.
+#[derive(Serialize, Deserialize)]
+pub(super) struct HelperRequest {
+  /// Serialized with `rmp_serde::to_vec_named`.
+  target_desc: Vec<u8>,
+  instance: CodegenKernelInstance,
+  spec_params: SpecParamsDesc,
+  options: Option<CodegenOptions>,
+  platform_desc: Vec<u8>,
+}
+impl HelperRequest {
+  /// `intern` provides the `'static` kernel instance the desc needs.
+  pub(super) fn into_desc<P, F>(self, platform: &P, intern: F)
+    -> Result<(AcceleratorTargetDesc, PKernelDesc<P>), String>
+    where P: PlatformCodegen,
+          F: FnOnce(CodegenKernelInstance) -> KernelInstanceRef<'static>,
+  {
+    let target_desc = platform.decode_target_desc(&self.target_desc)
+      .ok_or("failed to decode the accelerator target desc")?;
+    let platform_desc = platform.decode_kernel_desc(&self.platform_desc)
+      .ok_or("failed to decode the kernel desc")?;
+
+    let desc = KernelDesc {
+      instance: intern(self.instance),
+      spec_params: self.spec_params,
+      options: self.options.map(Arc::new),
+      platform_desc,
+    };
+    Ok((target_desc, desc))
+  }
+}
+#[derive(Serialize, Deserialize)]
+pub(super) enum HelperResponse {
+  Done {
+    results: SerializedCodegenResults,
+    times: PhaseTimes,
+  },
+  Codegen(Diagnostics),
+  Linking(Diagnostics),
+  Failed(String),
+}
+
+/// Decode a target desc for `PlatformCodegen::decode_target_desc`, where
+/// `T` is the platform's target desc type.
+pub fn decode_target_desc<T>(bytes: &[u8]) -> Option<AcceleratorTargetDesc>
+  where T: PlatformTargetDesc + DeserializeOwned,
+{
+  let mut de = rmps::Deserializer::new(bytes);
+  AcceleratorTargetDesc::deserialize_platform::<T, _>(&mut de)
+    .map_err(|err| {
+      warn!("failed to decode accelerator target desc: {}", err);
+    })
+    .ok()
+}
+
+/// Call this at the start of `main` when using subprocess codegen. If this
+/// process is a codegen helper for platform `P`, this runs the codegen and
+/// exits; otherwise, it does nothing.
+pub fn run_codegen_helper<P>(platform: P)
+  where P: PlatformCodegen,
+        <P::Device as crate::Device>::Error: fmt::Debug,
+{
+  match var(HELPER_ENV) {
+    Ok(ref name) if name == type_name::<P>() => { },
+    _ => { return; },
+  }
+
+  let code = match helper_main(platform) {
+    Ok(()) => 0,
+    Err(err) => {
+      eprintln!("codegen helper failed: {}", err);
+      1
+    },
+  };
+  exit(code);
+}
+fn helper_main<P>(platform: P) -> Result<(), Box<dyn StdError>>
+  where P: PlatformCodegen,
+        <P::Device as crate::Device>::Error: fmt::Debug,
+{
+  let output = var_os(OUTPUT_ENV)
+    .ok_or("no codegen helper output path")?;
+
+  let mut input = vec![];
+  io::stdin().read_to_end(&mut input)?;
+  let request: HelperRequest = rmps::decode::from_slice(&input)?;
+
+  // This process exits after this codegen, so leaking is fine.
+  let (target_desc, desc) = request.into_desc(&platform, leak_instance)?;
+
+  let context = Context::new()?;
+  let driver = CodegenDriver::new(&context, Arc::new(target_desc),
+                                  platform.clone())?;
+  // the parent checks and fills in the on-disk cache:
+  driver.set_disk_cache(None)?;
+  driver.set_subprocess_codegen(false);
+
+  let response = respond(&driver, desc);
+  fs::write(output, rmps::encode::to_vec(&response)?)?;
+  Ok(())
+}
+fn leak_instance(instance: CodegenKernelInstance) -> KernelInstanceRef<'static> {
+  KernelInstanceRef {
+    name: Box::leak(instance.name.into_boxed_str()),
+    instance: Box::leak(instance.instance.into_boxed_slice()),
+  }
+}
+/// Codegen `desc` with `driver`. The times are zeroed if the results were
+/// already cached.
+pub(super) fn respond<P>(driver: &CodegenDriver<P>, desc: PKernelDesc<P>) -> HelperResponse
+  where P: PlatformCodegen,
+        <P::Device as crate::Device>::Error: fmt::Debug,
+{
+  match driver.0.codegen_kernel_timed(desc) {
+    Ok((results, times)) => {
+      match SerializedCodegenResults::encode(&driver.0.platform, &results) {
+        Some(results) => HelperResponse::Done {
+          results,
+          times: times.unwrap_or_default(),
+        },
+        None => {
+          HelperResponse::Failed("platform can't encode its codegen descs".into())
+        },
+      }
+    },
+    Err(Error::Codegen(diagnostics)) => HelperResponse::Codegen(diagnostics),
+    Err(Error::Linking(diagnostics)) => HelperResponse::Linking(diagnostics),
+    Err(err) => HelperResponse::Failed(err.to_string()),
+  }
+}
+
+impl<P> WorkerTranslatorData<P>
+  where P: PlatformCodegen,
+{
+  pub(super) fn helper_request(&self, desc: &PKernelDesc<P>) -> Option<HelperRequest> {
+    let target_desc = rmps::encode::to_vec_named(&*self.target_desc)
+      .map_err(|err| {
+        warn!("failed to serialize accelerator target desc: {}", err);
+      })
+      .ok()?;
+    Some(HelperRequest {
+      target_desc,
+      instance: desc.instance.into(),
+      spec_params: desc.spec_params.clone(),
+      options: desc.options.as_ref().map(|options| (**options).clone() ),
+      platform_desc: self.platform.encode_kernel_desc(&desc.platform_desc)?,
+    })
+  }
+
+  /// Returns `None` if the platform doesn't support subprocess codegen.
+  pub(super) fn codegen_in_subprocess(&self, desc: &PKernelDesc<P>)
+    -> Option<Result<(PCodegenResults<P>, PhaseTimes), PError<P>>>
+  {
+    let request = match self.helper_request(desc) {
+      Some(request) => request,
+      None => {
+        debug!("{:?}: platform doesn't support subprocess codegen, \
+                using this process", desc.instance);
+        return None;
+      },
+    };
+
+    Some(self.run_helper(&request)
+      .and_then(|response| self.decode_response(response, Error::Subprocess) ))
+  }
+  fn run_helper(&self, request: &HelperRequest) -> Result<HelperResponse, PError<P>> {
+    // We'd be relaunching ourselves forever.
+    if let Some(name) = var_os(HELPER_ENV) {
+      return Err(Error::Subprocess(format!("this process is a codegen helper \
+                                            ({}={:?}) which didn't handle helper \
+                                            mode; `run_codegen_helper` must be \
+                                            called at the start of `main`",
+                                           HELPER_ENV, name)));
+    }
+
+    let request = rmps::encode::to_vec(request)
+      .map_err(|err| Error::Subprocess(format!("failed to encode request: {}", err)) )?;
+    let output = NamedTempFile::new()?;
+
+    let mut child = Command::new(current_exe()?)
+      .env(HELPER_ENV, type_name::<P>())
+      .env(OUTPUT_ENV, output.path())
+      .env_remove(SUBPROCESS_ENV)
+      .stdin(Stdio::piped())
+      .stdout(Stdio::null())
+      .stderr(Stdio::piped())
+      .spawn()?;
+
+    // Neither of these block the timeout below if the helper hangs.
+    let mut stdin = child.stdin.take().unwrap();
+    thread::spawn(move || {
+      // if the helper died early, the exit status is more useful:
+      let _ = stdin.write_all(&request);
+    });
+    let mut stderr = child.stderr.take().unwrap();
+    let stderr = thread::spawn(move || {
+      let mut out = vec![];
+      let _ = stderr.read_to_end(&mut out);
+      out
+    });
+
+    let timeout = codegen_helper_timeout().unwrap_or(DEFAULT_TIMEOUT);
+    let start = Instant::now();
+    let status = loop {
+      if let Some(status) = child.try_wait()? {
+        break status;
+      }
+      if start.elapsed() >= timeout {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(Error::Subprocess(format!("codegen helper timed out after {:?}",
+                                             timeout)));
+      }
+      thread::sleep(POLL_INTERVAL);
+    };
+    if !status.success() {
+      let stderr = stderr.join().unwrap_or_default();
+      let stderr = &stderr[stderr.len().saturating_sub(STDERR_TAIL)..];
+      return Err(Error::Subprocess(format!("codegen helper exited with {}:\n{}",
+                                           status,
+                                           String::from_utf8_lossy(stderr))));
+    }
+
+    let bytes = fs::read(output.path())?;
+    if bytes.is_empty() {
+      return Err(Error::Subprocess("codegen helper exited without writing its \
+                                    results; `run_codegen_helper` must be called \
+                                    at the start of `main`".into()));
+    }
+    rmps::decode::from_slice(&bytes)
+      .map_err(|err| Error::Subprocess(format!("failed to decode response: {}", err)) )
+  }
+  /// `fail` creates the error for failures of the helper itself.
+  pub(super) fn decode_response(&self, response: HelperResponse,
+                                fail: fn(String) -> PError<P>)
+    -> Result<(PCodegenResults<P>, PhaseTimes), PError<P>>
+  {
+    match response {
+      HelperResponse::Done { results, times, } => {
+        let results = results.decode(&self.platform)
+          .ok_or_else(|| fail("failed to decode codegen results".into()) )?;
+        Ok((results, times))
+      },
+      HelperResponse::Codegen(diagnostics) => Err(Error::Codegen(diagnostics)),
+      HelperResponse::Linking(diagnostics) => Err(Error::Linking(diagnostics)),
+      HelperResponse::Failed(msg) => Err(fail(msg)),
+    }
+  }
+}
@@ -3,6 +3,7 @@ use std::any::Any;
 use std::collections::hash_map::{Entry, };
 use std::error::Error;
 use std::fmt::Debug;
+use std::geobacter::kernel::KernelInstanceRef;
 use std::intrinsics::likely;
 use std::sync::{Arc, Weak, atomic::AtomicUsize, atomic::Ordering, };
 
@@ -11,12 +12,15 @@ use parking_lot::{Mutex, RwLock, RwLockUpgradableReadGuard, MappedRwLockReadGuar
                   RwLockReadGuard, RwLockWriteGuard, };
 
 use rustc_span::SessionGlobals;
-use rustc_data_structures::rayon::ThreadPoolBuilder;
+use rustc_data_structures::rayon::{ThreadPool, ThreadPoolBuilder, };
 
 use crate::{Accelerator, AcceleratorId, AcceleratorTargetDesc, Device};
-use crate::codegen::{PlatformCodegen, CodegenDriver, PKernelDesc};
-use crate::metadata::{context_metadata, LoadedCrateMetadata, };
-use crate::utils::{HashMap, };
+use crate::codegen::{PlatformCodegen, CodegenDriver, CodegenOptions, PKernelDesc};
+use crate::codegen::bundle::{BundleError, KernelBundle, };
+use crate::codegen::host::TypeLayouts;
+use crate::metadata::{context_metadata, crate_metadata_hash, LoadedCrateMetadata, };
+use crate::codegen::error::Error as CodegenError;
+use crate::utils::{HashMap, HashSet, new_hash_set, };
 
 pub use rustc_session::config::OutputType;
 
@@ -25,6 +29,8 @@ type Translators = HashMap<
   Weak<dyn Any + Send + Sync + 'static>,
 >;
 
+type RetireHook = Box<dyn FnOnce() + Send + Sync>;
+
 type MappedReadResult<'a, T> = Result<
   MappedRwLockReadGuard<'a, T>,
   Box<dyn Error + Send + Sync + 'static>,
@@ -58,9 +64,26 @@ impl AsyncCodegenMetadataLoader {
 
 /// This structure should be used like you'd use a singleton.
 struct ContextData {
-  #[allow(dead_code)]
   session_globals: Arc<SessionGlobals>,
+  /// Every thread in this pool has `session_globals` set.
+  pool: ThreadPool,
   metadata: AsyncCodegenMetadataLoader,
+  /// Computed lazily from `metadata`. See `Context::metadata_hash`.
+  metadata_hash: RwLock<Option<u64>>,
+  /// The program's bundles are loaded on first use.
+  kernel_bundles: RwLock<Option<Vec<Arc<KernelBundle>>>>,
+  /// Used by every codegen driver which doesn't have its own options.
+  codegen_options: RwLock<Arc<CodegenOptions>>,
+  /// Host layouts of kernel argument types, by kernel. These don't depend
+  /// on the accelerator, so they're shared by every codegen driver.
+  host_layouts: RwLock<HashMap<KernelInstanceRef<'static>, Arc<TypeLayouts>>>,
+  /// Keyed by the address of the kernel's `ModuleContextData` stash.
+  module_data: RwLock<HashMap<usize, Arc<ModuleData>>>,
+  /// Every `ModuleData` created for this context, wherever it is stored, so
+  /// retiring an accelerator can drop its entries.
+  all_module_data: Mutex<Vec<Weak<ModuleData>>>,
+  /// Run when the accelerator is retired; see `on_accel_retired`.
+  retire_hooks: Mutex<HashMap<AcceleratorId, Vec<RetireHook>>>,
 
   next_accel_id: AtomicUsize,
 
@@ -69,6 +92,8 @@ struct ContextData {
 /// Data that will be wrapped in a rw mutex.
 struct ContextDataMut {
   accelerators: IndexVec<AcceleratorId, Option<Arc<dyn Accelerator>>>,
+  /// Ids are never reused, so these are retired forever.
+  retired: HashSet<AcceleratorId>,
 
   translators: Translators,
 }
@@ -79,49 +104,45 @@ pub struct Context(Arc<ContextData>);
 unsafe impl Send for Context { }
 unsafe impl Sync for Context { }
 
-static mut CTX: Option<WeakContext> = None;
+lazy_static::lazy_static! {
+  /// The first context created which is still alive. Guarded by a mutex so
+  /// that concurrently created contexts agree on which is the global one.
+  static ref CTX: Mutex<WeakContext> = Mutex::new(WeakContext::new());
+}
+static NEXT_CONTEXT_ID: AtomicUsize = AtomicUsize::new(0);
 
 impl Context {
+  /// The first context created, if it's still alive. Prefer passing
+  /// contexts explicitly; there can be more than one.
   pub fn global() -> Option<Context> {
-    // CTX can only be written during context initialization, which will run an acquire fence.
-    // Otherwise this races, but that's NBD because this will just return None in that case
-    unsafe {
-      if let Some(ctx) = CTX.clone() {
-        if let Some(ctx) = ctx.upgrade() {
-          return Some(ctx);
-        }
-      }
-    }
-    None
+    CTX.lock().upgrade()
   }
 
+  /// Create a new context. Every context is independent: each has its own
+  /// accelerators, codegen drivers, metadata, and thread pool. The first
+  /// is also made available through `Context::global`.
   pub fn new() -> Result<Context, Box<dyn Error>> {
-    use crate::rustc_span::edition::Edition;
+    use std::sync::Once;
 
-    if let Some(ctx) = Self::global() {
-      return Ok(ctx);
-    }
+    use crate::rustc_span::edition::Edition;
 
-    lazy_static::lazy_static! {
-      static ref INIT: Mutex<()> = parking_lot::const_mutex(());
-    }
-    let _ = INIT.lock(); // ensure only one of us is created
-    if let Some(ctx) = Self::global() {
-      return Ok(ctx);
-    }
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+      crate::utils::env::initialize();
 
-    crate::utils::env::initialize();
+      crate::rustc_driver::init_rustc_env_logger();
+    });
 
-    crate::rustc_driver::init_rustc_env_logger();
+    let context_id = NEXT_CONTEXT_ID.fetch_add(1, Ordering::Relaxed);
 
     let session_globals = rustc_span::SessionGlobals::new(Edition::Edition2018);
     let session_globals = Arc::new(session_globals);
     let pool_globals = session_globals.clone();
 
-    ThreadPoolBuilder::new()
+    let pool = ThreadPoolBuilder::new()
       // give us a huge stack (for codegen's use):
       .stack_size(32 * 1024 * 1024)
-      .thread_name(|id| format!("grt-core-worker-{}", id) )
+      .thread_name(move |id| format!("grt-core-{}-worker-{}", context_id, id) )
       .deadlock_handler(|| unsafe { crate::rustc_middle::ty::query::handle_deadlock() })
       .spawn_handler(move |tb| {
         let mut b = std::thread::Builder::new();
@@ -140,18 +161,27 @@ impl Context {
 
         Ok(())
       })
-      .build_global()?;
+      .build()?;
 
     let accelerators = IndexVec::new();
     let translators: Translators = Default::default();
 
     let data = ContextDataMut {
       accelerators,
+      retired: new_hash_set(),
       translators,
     };
     let data = ContextData {
       session_globals,
+      pool,
       metadata: AsyncCodegenMetadataLoader::default(),
+      metadata_hash: RwLock::new(None),
+      kernel_bundles: RwLock::new(None),
+      codegen_options: Default::default(),
+      host_layouts: Default::default(),
+      module_data: Default::default(),
+      all_module_data: Default::default(),
+      retire_hooks: Default::default(),
 
       next_accel_id: AtomicUsize::new(0),
 
@@ -160,10 +190,12 @@ impl Context {
     let data = Arc::new(data);
     let context = Context(data);
 
-    // now install the global ref:
-    let weak = context.downgrade_ref();
-    unsafe {
-      CTX = Some(weak);
+    // install the global ref, if there isn't a global context already:
+    {
+      let mut global = CTX.lock();
+      if global.upgrade().is_none() {
+        *global = context.downgrade_ref();
+      }
     }
 
     Ok(context)
@@ -172,6 +204,68 @@ impl Context {
   pub(crate) fn load_metadata(&self) -> LoadedMetadataResult {
     self.0.metadata.load()
   }
+  /// A hash of the name and SVH of every crate we have metadata for. This
+  /// changes whenever any crate linked into this process is rebuilt.
+  /// Must be called with the Rustc span globals set.
+  pub(crate) fn metadata_hash(&self)
+    -> Result<u64, Box<dyn Error + Send + Sync + 'static>>
+  {
+    if let Some(hash) = *self.0.metadata_hash.read() {
+      return Ok(hash);
+    }
+
+    let hash = crate_metadata_hash(&*self.load_metadata()?);
+    *self.0.metadata_hash.write() = Some(hash);
+    Ok(hash)
+  }
+
+  /// Make the kernels in `bundle` available to all accelerators in this
+  /// context. Bundles embedded into or placed next to the program are
+  /// loaded automatically. `bundle` must have been produced by this build
+  /// of the program.
+  pub fn add_kernel_bundle(&self, bundle: KernelBundle) -> Result<(), BundleError> {
+    bundle.check_build()?;
+    let mut bundles = self.0.kernel_bundles.write();
+    bundles.get_or_insert_with(KernelBundle::load_program_bundles)
+      .push(Arc::new(bundle));
+    Ok(())
+  }
+  pub(crate) fn kernel_bundles(&self) -> Vec<Arc<KernelBundle>> {
+    if let Some(ref bundles) = *self.0.kernel_bundles.read() {
+      return bundles.clone();
+    }
+
+    let mut bundles = self.0.kernel_bundles.write();
+    bundles.get_or_insert_with(KernelBundle::load_program_bundles)
+      .clone()
+  }
+
+  pub(crate) fn host_layouts(&self, kernel: KernelInstanceRef<'static>)
+    -> Option<Arc<TypeLayouts>>
+  {
+    self.0.host_layouts.read().get(&kernel).cloned()
+  }
+  /// Returns the layouts already stored, if another thread beat us.
+  pub(crate) fn set_host_layouts(&self, kernel: KernelInstanceRef<'static>,
+                                 layouts: TypeLayouts)
+    -> Arc<TypeLayouts>
+  {
+    self.0.host_layouts.write()
+      .entry(kernel)
+      .or_insert_with(|| Arc::new(layouts) )
+      .clone()
+  }
+
+  /// The codegen options used for kernels on accelerators which don't
+  /// override them.
+  pub fn codegen_options(&self) -> Arc<CodegenOptions> {
+    self.0.codegen_options.read().clone()
+  }
+  /// Only affects kernels compiled after this call; already compiled
+  /// kernels aren't recompiled.
+  pub fn set_codegen_options(&self, options: CodegenOptions) {
+    *self.0.codegen_options.write() = Arc::new(options);
+  }
 
   #[doc(hidden)]
   #[inline(always)]
@@ -182,7 +276,7 @@ impl Context {
     use rustc_span::SESSION_GLOBALS;
 
     let mut out = None;
-    rustc_data_structures::rayon::scope(|s| {
+    self.0.pool.scope(|s| {
       s.spawn(|_| {
         let o = SESSION_GLOBALS.set(&*self.0.session_globals, f);
         out = Some(o);
@@ -191,6 +285,18 @@ impl Context {
     out.unwrap()
   }
 
+  /// Run `f` on this context's thread pool.
+  pub fn spawn<F>(&self, f: F)
+    where F: FnOnce() + Send + 'static,
+  {
+    self.0.pool.spawn(f)
+  }
+  pub(crate) fn thread_pool(&self) -> &ThreadPool { &self.0.pool }
+  /// Is the current thread one of this context's?
+  pub(crate) fn in_thread_pool(&self) -> bool {
+    self.0.pool.current_thread_index().is_some()
+  }
+
   pub fn downgrade_ref(&self) -> WeakContext {
     WeakContext(Arc::downgrade(&self.0))
   }
@@ -284,6 +390,60 @@ impl Context {
 
     Ok(())
   }
+
+  /// Remove an accelerator from this context, eg after a device reset.
+  /// Every kernel loaded onto it is dropped from the module data, its
+  /// codegen driver's in-memory results are dropped, and the context's
+  /// reference to it (and thus its codegen driver) is released; the driver
+  /// is dropped once nothing else uses it. Objects which still hold
+  /// the accelerator (eg modules) will get an error when next used with it.
+  ///
+  /// Returns the accelerator, or `None` if it wasn't in this context or was
+  /// already retired. The id is never reused.
+  pub fn retire_accel(&self, id: AcceleratorId) -> Option<Arc<dyn Accelerator>> {
+    let accel = {
+      let mut w = self.0.m.write();
+      let accel = w.accelerators.get_mut(id)?.take()?;
+      w.retired.insert(id);
+      accel
+    };
+
+    let all = {
+      let mut all = self.0.all_module_data.lock();
+      all.retain(|data| data.strong_count() != 0 );
+      all.clone()
+    };
+    for data in all.iter().filter_map(Weak::upgrade) {
+      data.remove(id);
+    }
+
+    let hooks = self.0.retire_hooks.lock().remove(&id);
+    for hook in hooks.into_iter().flatten() {
+      hook();
+    }
+
+    {
+      // the translator map only holds weak refs; forget drivers which are
+      // already gone.
+      let mut w = self.0.m.write();
+      w.translators.retain(|_, cg| cg.strong_count() != 0 );
+    }
+
+    info!("retired accelerator {:?}", id);
+    Some(accel)
+  }
+  /// Run `f` when `id` is retired, eg to drop state derived from it. `f`
+  /// is dropped without running if `id` is never retired.
+  pub(crate) fn on_accel_retired(&self, id: AcceleratorId, f: RetireHook) {
+    self.0.retire_hooks.lock()
+      .entry(id)
+      .or_default()
+      .push(f);
+  }
+  /// Was `id` removed with `retire_accel`?
+  pub fn is_accel_retired(&self, id: AcceleratorId) -> bool {
+    self.0.m.read().retired.contains(&id)
+  }
 }
 
 impl ContextDataMut { }
@@ -313,6 +473,10 @@ impl WeakContext {
     self.0.upgrade()
       .map(|v| Context(v) )
   }
+  /// Does this refer to `context`? Doesn't upgrade.
+  pub fn is(&self, context: &Context) -> bool {
+    Weak::as_ptr(&self.0) == Arc::as_ptr(&context.0)
+  }
 }
 
 /// Platform and device specific module Stuff. Put your API handles
@@ -397,10 +561,20 @@ pub struct ModuleData {
   entries: RwLock<IndexVec<AcceleratorId, Option<Arc<dyn PlatformModuleData>>>>,
 }
 impl ModuleData {
-  fn new(ctxt: &Context) -> ModuleData {
-    ModuleData {
+  fn new(ctxt: &Context) -> Arc<ModuleData> {
+    let data = Arc::new(ModuleData {
       ctxt: ctxt.downgrade_ref(),
       entries: Default::default(),
+    });
+    ctxt.0.all_module_data.lock()
+      .push(Arc::downgrade(&data));
+    data
+  }
+  /// Drop the entry of a retired accelerator.
+  fn remove(&self, accel_id: AcceleratorId) {
+    let mut entries = self.entries.write();
+    if let Some(entry) = entries.get_mut(accel_id) {
+      entry.take();
     }
   }
   fn get<D>(&self, accel_id: AcceleratorId,
@@ -433,6 +607,14 @@ impl ModuleData {
           P: PlatformCodegen<Device = D>,
   {
     let accel_id = accel.id();
+    match self.ctxt.upgrade() {
+      Some(ctxt) if ctxt.is_accel_retired(accel_id) => {
+        return Err(CodegenError::AcceleratorRetired(accel_id).into());
+      },
+      Some(_) => { },
+      None => { return Err(CodegenError::ContextDead.into()); },
+    }
+
     if let Some(entry) = self.get::<D>(accel_id, expect_platform_ty) {
       return Ok(entry);
     }
@@ -454,7 +636,10 @@ impl ModuleData {
       }
     }
 
-    let codegen = codegen.codegen(desc)?;
+    let codegen = match codegen.bundled(&desc) {
+      Some(results) => results,
+      None => codegen.codegen(desc)?,
+    };
 
     // upgrade the read to a write
     let mut guard = RwLockUpgradableReadGuard::upgrade(guard);
@@ -463,6 +648,12 @@ impl ModuleData {
     }
 
     let module = D::load_kernel(accel, &*codegen)?;
+    // don't keep modules for accelerators retired while we were compiling:
+    if let Some(ctxt) = self.ctxt.upgrade() {
+      if ctxt.is_accel_retired(accel_id) {
+        return Err(CodegenError::AcceleratorRetired(accel_id).into());
+      }
+    }
     guard[accel_id] = Some(module.clone());
     return Ok(module);
   }
@@ -471,10 +662,19 @@ impl ModuleData {
 /// No PhantomData on this, this object doesn't own the arguments or return
 /// values of the function it represents.
 /// Internal.
+/// The stash holds the data of the first context to use the function; every
+/// other live context keeps its own data in the context.
 pub struct ModuleContextData(&'static AtomicUsize);
 
 impl ModuleContextData {
   pub fn upgrade(&self, context: &Context) -> Option<Arc<ModuleData>> {
+    match self.upgrade_stashed() {
+      Some(arc) if arc.ctxt.is(context) => Some(arc),
+      _ => self.upgrade_in(context),
+    }
+  }
+  /// The data in the global stash, if its context is still alive.
+  fn upgrade_stashed(&self) -> Option<Arc<ModuleData>> {
     let ptr_usize = self.0.load(Ordering::Acquire);
     if ptr_usize == 0 { return None; }
     let ptr = ptr_usize as *const ModuleData;
@@ -485,14 +685,18 @@ impl ModuleContextData {
     Arc::into_raw(arc);
 
     let arc = arc_clone;
-    let expected_context = arc.ctxt.upgrade();
-    if expected_context.is_none() { return None; }
-    let expected_context = expected_context.unwrap();
-    assert!(expected_context == context,
-            "there are two context's live at the same time");
+    if arc.ctxt.upgrade().is_none() { return None; }
 
     Some(arc)
   }
+  fn key(&self) -> usize { self.0 as *const AtomicUsize as usize }
+  /// The data stored with `context`, used when the stash belongs to another
+  /// context.
+  fn upgrade_in(&self, context: &Context) -> Option<Arc<ModuleData>> {
+    context.0.module_data.read()
+      .get(&self.key())
+      .cloned()
+  }
 
   /// Drops all globally stored data. This doesn't clear any
   /// data stored with any context.
@@ -525,7 +729,6 @@ impl ModuleContextData {
     let mut cached = self.upgrade(context);
     if unlikely(cached.is_none()) {
       let data = ModuleData::new(context);
-      let data = Arc::new(data);
       let data_ptr = Arc::into_raw(data.clone());
       let data_usize = data_ptr as usize;
 
@@ -540,12 +743,21 @@ impl ModuleContextData {
         },
         Ok(_) => unreachable!(),
         Err(actual_data_usize) => {
-          // either someone beat us, or the data is from an old context.
+          // either someone beat us, the data is from an old context, or the
+          // stash belongs to another live context.
           let cached2 = self.upgrade(context);
           if cached2.is_some() {
             // someone beat us.
             unsafe { Arc::from_raw(data_ptr) };
             cached = cached2;
+          } else if self.upgrade_stashed().is_some() {
+            // another context owns the stash; keep ours with our context.
+            unsafe { Arc::from_raw(data_ptr) };
+            let mut map = context.0.module_data.write();
+            let data = map.entry(self.key())
+              .or_insert(data)
+              .clone();
+            cached = Some(data);
           } else {
             // the data is old. we need to clean up, while allowing for
             // possible races in this process.
@@ -565,13 +777,9 @@ impl ModuleContextData {
                 cached = Some(data);
               },
               Err(_) => {
-                // someone beat us.
+                // someone beat us; retry, they may have been another context.
                 unsafe { Arc::from_raw(data_ptr) };
-                let data = self.upgrade(context)
-                  .expect("someone beat us in setting context \
-                           module data, but didn't set it to \
-                           value data");
-                cached = Some(data);
+                cached = Some(self.get_cache_data(context));
               },
             }
           }
@@ -635,4 +843,29 @@ mod test {
     data.drop();
     assert!(data.is_none());
   }
+
+  #[test]
+  fn retire_unknown_accel() {
+    let ctx = Context::new().unwrap();
+    let id = ctx.take_accel_id();
+    assert!(ctx.retire_accel(id).is_none());
+    assert!(!ctx.is_accel_retired(id));
+  }
+
+  #[test]
+  fn function_module_data_per_context() {
+    fn f() { }
+    let data = ModuleContextData::get(&f);
+    let first = context();
+    let second = Context::new().unwrap();
+    assert!(first != &second);
+
+    let first_data = data.get_cache_data(first);
+    let second_data = data.get_cache_data(&second);
+    assert!(!Arc::ptr_eq(&first_data, &second_data));
+    assert!(first_data.ctxt.is(first));
+    assert!(second_data.ctxt.is(&second));
+    assert!(Arc::ptr_eq(&first_data, &data.get_cache_data(first)));
+    assert!(Arc::ptr_eq(&second_data, &data.get_cache_data(&second)));
+  }
 }
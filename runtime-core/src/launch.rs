@@ -0,0 +1,305 @@
+//! A platform neutral way to launch kernels. Each platform runtime implements
+//! `KernelLauncher` for its kernel objects, so code written against these
+//! traits can dispatch to any accelerator. Platform specific features (queues,
+//! dependencies, fences, etc) are still only available from the platform's
+//! own types.
+
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll, Waker, };
+use std::thread;
+
+use parking_lot::{Condvar, Mutex, };
+
+use crate::AcceleratorId;
+
+pub type LaunchError = Box<dyn Error + Send + Sync + 'static>;
+
+/// The size of a dispatch, in workitems, along each axis. The workgroup
+/// size is part of the kernel. Unused axes should be one.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct LaunchGrid {
+  pub x: u32,
+  pub y: u32,
+  pub z: u32,
+}
+impl LaunchGrid {
+  pub const fn new(x: u32, y: u32, z: u32) -> Self {
+    LaunchGrid { x, y, z, }
+  }
+  pub fn len(&self) -> u64 {
+    self.x as u64 * self.y as u64 * self.z as u64
+  }
+  pub fn is_zero(&self) -> bool {
+    self.x == 0 || self.y == 0 || self.z == 0
+  }
+}
+impl From<u32> for LaunchGrid {
+  fn from(x: u32) -> Self { LaunchGrid::new(x, 1, 1) }
+}
+impl From<(u32, u32)> for LaunchGrid {
+  fn from((x, y): (u32, u32)) -> Self { LaunchGrid::new(x, y, 1) }
+}
+impl From<(u32, u32, u32)> for LaunchGrid {
+  fn from((x, y, z): (u32, u32, u32)) -> Self { LaunchGrid::new(x, y, z) }
+}
+
+/// Implemented by the platforms for their in-flight launches.
+pub trait LaunchCompletion: Send + Sync + 'static {
+  /// Doesn't block.
+  fn is_complete(&self) -> bool;
+  /// Block until the launch has finished. Only called once.
+  fn wait(&self) -> Result<(), LaunchError>;
+}
+
+/// An in-flight launch. Block on it with `wait`, or `.await` it.
+///
+/// Dropping this *blocks* the dropping thread until the launch has finished,
+/// because the launch may still be using its arguments. In async code, await
+/// it instead of dropping it. Failures are only logged on drop.
+#[must_use]
+pub struct Completion(Arc<CompletionState>);
+struct CompletionState {
+  launch: Option<Box<dyn LaunchCompletion>>,
+  result: Mutex<CompletionResult>,
+  cvar: Condvar,
+}
+enum CompletionResult {
+  /// Nobody is waiting yet.
+  Pending,
+  /// A thread is waiting on the launch for `poll`.
+  Waiting(Option<Waker>),
+  Done(Result<(), LaunchError>),
+  Taken,
+}
+impl Completion {
+  pub fn new<T>(launch: T) -> Self
+    where T: LaunchCompletion,
+  {
+    Completion(Arc::new(CompletionState {
+      launch: Some(Box::new(launch)),
+      result: Mutex::new(CompletionResult::Pending),
+      cvar: Condvar::new(),
+    }))
+  }
+  /// A launch which has already finished, eg on a synchronous platform.
+  pub fn ready(result: Result<(), LaunchError>) -> Self {
+    Completion(Arc::new(CompletionState {
+      launch: None,
+      result: Mutex::new(CompletionResult::Done(result)),
+      cvar: Condvar::new(),
+    }))
+  }
+
+  pub fn is_complete(&self) -> bool {
+    match *self.0.result.lock() {
+      CompletionResult::Done(_) | CompletionResult::Taken => true,
+      _ => self.0.launch.as_ref().unwrap().is_complete(),
+    }
+  }
+  /// Block until the launch has finished.
+  pub fn wait(self) -> Result<(), LaunchError> {
+    self.wait_ref()
+  }
+  fn wait_ref(&self) -> Result<(), LaunchError> {
+    let mut result = self.0.result.lock();
+    loop {
+      match std::mem::replace(&mut *result, CompletionResult::Taken) {
+        CompletionResult::Pending => {
+          drop(result);
+          return self.0.launch.as_ref().unwrap().wait();
+        },
+        CompletionResult::Done(r) => { return r; },
+        CompletionResult::Taken => { return Ok(()); },
+        waiting @ CompletionResult::Waiting(_) => {
+          *result = waiting;
+          self.0.cvar.wait(&mut result);
+        },
+      }
+    }
+  }
+}
+impl Future for Completion {
+  type Output = Result<(), LaunchError>;
+  fn poll(self: Pin<&mut Self>, cx: &mut TaskContext) -> Poll<Self::Output> {
+    let mut result = self.0.result.lock();
+    match std::mem::replace(&mut *result, CompletionResult::Taken) {
+      CompletionResult::Done(r) => Poll::Ready(r),
+      CompletionResult::Taken => Poll::Ready(Ok(())),
+      CompletionResult::Waiting(_) => {
+        *result = CompletionResult::Waiting(Some(cx.waker().clone()));
+        Poll::Pending
+      },
+      CompletionResult::Pending => {
+        *result = CompletionResult::Waiting(Some(cx.waker().clone()));
+        drop(result);
+
+        // The platforms only offer blocking waits, so wait on another
+        // thread.
+        let state = self.0.clone();
+        thread::Builder::new()
+          .name("grt-core-launch-wait".into())
+          .spawn(move || {
+            let r = state.launch.as_ref().unwrap().wait();
+            let mut result = state.result.lock();
+            let prev = std::mem::replace(&mut *result, CompletionResult::Done(r));
+            state.cvar.notify_all();
+            drop(result);
+            if let CompletionResult::Waiting(Some(waker)) = prev {
+              waker.wake();
+            }
+          })
+          .expect("failed to spawn launch wait thread");
+        Poll::Pending
+      },
+    }
+  }
+}
+impl Drop for Completion {
+  fn drop(&mut self) {
+    if let Err(err) = self.wait_ref() {
+      warn!("dropped failed launch: {}", err);
+    }
+  }
+}
+impl fmt::Debug for Completion {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("Completion")
+      .field("complete", &self.is_complete())
+      .finish()
+  }
+}
+
+/// Launches a kernel with arguments `A`. `A` is a platform's kernel
+/// argument type; implement each platform's kernel trait for it to launch
+/// the same kernel everywhere. Object safe, so launchers for different
+/// platforms can be stored together, eg as `Box<dyn KernelLauncher<A>>`.
+pub trait KernelLauncher<A> {
+  /// The accelerator the kernel will run on.
+  fn accel_id(&self) -> AcceleratorId;
+
+  /// Compile the kernel, if it isn't already, then start it over `grid`.
+  ///
+  /// # Safety
+  ///
+  /// The kernel runs asynchronously. Anything `args` references must
+  /// outlive the returned completion, and must not be accessed by the host
+  /// until the launch has finished. Platforms may have extra requirements;
+  /// see their implementations.
+  unsafe fn launch(&mut self, grid: LaunchGrid, args: A)
+    -> Result<Completion, LaunchError>;
+}
+impl<'a, A, T> KernelLauncher<A> for &'a mut T
+  where T: KernelLauncher<A> + ?Sized,
+{
+  fn accel_id(&self) -> AcceleratorId { (**self).accel_id() }
+  unsafe fn launch(&mut self, grid: LaunchGrid, args: A)
+    -> Result<Completion, LaunchError>
+  {
+    (**self).launch(grid, args)
+  }
+}
+impl<A, T> KernelLauncher<A> for Box<T>
+  where T: KernelLauncher<A> + ?Sized,
+{
+  fn accel_id(&self) -> AcceleratorId { (**self).accel_id() }
+  unsafe fn launch(&mut self, grid: LaunchGrid, args: A)
+    -> Result<Completion, LaunchError>
+  {
+    (**self).launch(grid, args)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::sync::atomic::{AtomicBool, Ordering, };
+  use std::time::Duration;
+
+  struct Sleep(Arc<AtomicBool>);
+  impl LaunchCompletion for Sleep {
+    fn is_complete(&self) -> bool { self.0.load(Ordering::Acquire) }
+    fn wait(&self) -> Result<(), LaunchError> {
+      thread::sleep(Duration::from_millis(10));
+      self.0.store(true, Ordering::Release);
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn wait_blocks_until_complete() {
+    let done = Arc::new(AtomicBool::new(false));
+    let c = Completion::new(Sleep(done.clone()));
+    assert!(!c.is_complete());
+    c.wait().unwrap();
+    assert!(done.load(Ordering::Acquire));
+  }
+
+  struct ThreadWaker(thread::Thread);
+  fn waker(t: thread::Thread) -> Waker {
+    use std::task::{RawWaker, RawWakerVTable, };
+
+    unsafe fn clone(p: *const ()) -> RawWaker {
+      let t = &*(p as *const ThreadWaker);
+      let t = Box::new(ThreadWaker(t.0.clone()));
+      RawWaker::new(Box::into_raw(t) as *const (), &VTABLE)
+    }
+    unsafe fn wake(p: *const ()) {
+      wake_by_ref(p);
+      drop_(p);
+    }
+    unsafe fn wake_by_ref(p: *const ()) {
+      (*(p as *const ThreadWaker)).0.unpark();
+    }
+    unsafe fn drop_(p: *const ()) {
+      drop(Box::from_raw(p as *mut ThreadWaker));
+    }
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop_);
+
+    let t = Box::new(ThreadWaker(t));
+    unsafe { Waker::from_raw(RawWaker::new(Box::into_raw(t) as *const (), &VTABLE)) }
+  }
+
+  #[test]
+  fn poll_wakes_on_completion() {
+    let done = Arc::new(AtomicBool::new(false));
+    let mut c = Completion::new(Sleep(done.clone()));
+    let waker = waker(thread::current());
+    let mut cx = TaskContext::from_waker(&waker);
+    let mut polls = 0;
+    let r = loop {
+      polls += 1;
+      match Pin::new(&mut c).poll(&mut cx) {
+        Poll::Ready(r) => break r,
+        // spurious unparks are allowed, so just poll again.
+        Poll::Pending => thread::park(),
+      }
+    };
+    r.unwrap();
+    assert!(polls > 1);
+    assert!(done.load(Ordering::Acquire));
+    assert!(c.is_complete());
+  }
+
+  #[test]
+  fn wait_after_poll() {
+    let done = Arc::new(AtomicBool::new(false));
+    let mut c = Completion::new(Sleep(done.clone()));
+    let waker = waker(thread::current());
+    let mut cx = TaskContext::from_waker(&waker);
+    assert!(Pin::new(&mut c).poll(&mut cx).is_pending());
+    // the wait thread is running; this must block on it, not return early.
+    c.wait().unwrap();
+    assert!(done.load(Ordering::Acquire));
+  }
+
+  #[test]
+  fn ready_is_complete() {
+    let c = Completion::ready(Err("failed".into()));
+    assert!(c.is_complete());
+    assert!(c.wait().is_err());
+  }
+}
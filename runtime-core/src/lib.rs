@@ -18,6 +18,8 @@ extern crate indexed_vec as indexvec;
 #[macro_use]
 extern crate tracing;
 extern crate owning_ref;
+extern crate rmp_serde as rmps;
+extern crate serde_json;
 extern crate rustc_ast;
 extern crate rustc_codegen_ssa;
 extern crate rustc_data_structures;
@@ -62,15 +64,19 @@ use crate::codegen::{CodegenDriver, PlatformCodegen};
 use crate::codegen::products::PCodegenResults;
 use crate::context::{Context, PlatformModuleData, };
 use crate::rustc_target::spec::{abi::Abi, Target, TargetTriple, };
-use crate::serde::Serialize;
+use crate::serde::{Deserialize, Deserializer, Serialize, };
 
 pub mod context;
 pub mod codegen;
+pub mod launch;
 mod metadata;
 mod platform;
 mod serde_utils;
 mod utils;
 
+pub use crate::metadata::{MetadataLoadingError, METADATA_SIDECAR_EXTENSION,
+                          metadata_sidecar_path, write_metadata_sidecar, };
+
 indexvec::newtype_index!(AcceleratorId);
 
 /// A common interface to a specific compute device. Note this doesn't
@@ -202,6 +208,43 @@ impl AcceleratorTargetDesc {
     }
   }
 
+  /// `AcceleratorTargetDesc` can't implement `Deserialize` directly, as the
+  /// platform desc is a trait object. Use this with the platform's concrete
+  /// `PlatformTargetDesc` type instead, eg to load a desc previously
+  /// serialized from a real device for offline codegen.
+  pub fn deserialize_platform<'de, T, D>(deserializer: D) -> Result<Self, D::Error>
+    where T: PlatformTargetDesc + Deserialize<'de>,
+          D: Deserializer<'de>,
+  {
+    #[derive(Deserialize)]
+    #[serde(bound = "T: Deserialize<'de>")]
+    struct De<T> {
+      allow_indirect_function_calls: bool,
+      #[serde(with = "serde_utils::abi")]
+      kernel_abi: Abi,
+      #[serde(with = "serde_utils::Target")]
+      host_target: Target,
+      #[serde(flatten, with = "serde_utils::Target")]
+      target: Target,
+      platform: T,
+    }
+
+    let De {
+      allow_indirect_function_calls,
+      kernel_abi,
+      host_target,
+      target,
+      platform,
+    } = De::<T>::deserialize(deserializer)?;
+    Ok(AcceleratorTargetDesc {
+      allow_indirect_function_calls,
+      kernel_abi,
+      host_target,
+      target,
+      platform: Box::new(platform),
+    })
+  }
+
   pub fn host_target() -> Target {
     let host = host_triple();
     let triple = TargetTriple::from_triple(host);
@@ -257,6 +300,56 @@ impl Hash for AcceleratorTargetDesc {
     platform.hash(hasher);
   }
 }
+impl AcceleratorTargetDesc {
+  /// A hash of only the parts of this desc which affect codegen. Codegen
+  /// results keyed by this can be shared by devices which differ only in
+  /// runtime details, eg whether they have a large PCIe BAR.
+  ///
+  /// This hashes the serialized form of those parts: `Target`'s `Hash`
+  /// impl isn't guaranteed to be stable across builds of the host program,
+  /// and this hash is persisted, eg in kernel bundles.
+  pub fn codegen_hash(&self) -> u64 {
+    #[derive(Serialize)]
+    struct CodegenKey<'a, T>
+      where T: Serialize + ?Sized,
+    {
+      allow_indirect_function_calls: bool,
+      #[serde(with = "serde_utils::abi")]
+      kernel_abi: Abi,
+      #[serde(serialize_with = "serde_utils::Target::serialize")]
+      host_target: &'a Target,
+      #[serde(serialize_with = "serde_utils::Target::serialize")]
+      target: &'a Target,
+      platform: &'a T,
+    }
+    fn encode<T>(this: &AcceleratorTargetDesc, platform: &T)
+      -> Result<Vec<u8>, rmps::encode::Error>
+      where T: Serialize + ?Sized,
+    {
+      rmps::encode::to_vec(&CodegenKey {
+        allow_indirect_function_calls: this.allow_indirect_function_calls,
+        kernel_abi: this.kernel_abi,
+        host_target: &this.host_target,
+        target: &this.target,
+        platform,
+      })
+    }
+
+    let bytes = match self.platform.codegen_key() {
+      Some(key) => encode(self, key),
+      None => encode(self, &*self.platform),
+    };
+    let mut hasher = seahash::SeaHasher::new();
+    match bytes {
+      Ok(bytes) => bytes.hash(&mut hasher),
+      Err(err) => {
+        warn!("failed to serialize accelerator target desc: {}", err);
+        self.hash(&mut hasher);
+      },
+    }
+    hasher.finish()
+  }
+}
 
 /// Info specific to a OS/API combo. This must not contain device-unique
 /// data. An example of data to not include would be a PCIe address of a
@@ -266,6 +359,11 @@ pub trait PlatformTargetDesc
         Self: AnyHash + Sync + Send + 'static,
 {
   fn as_any_hash(&self) -> &dyn AnyHash;
+  /// The parts of this desc which affect codegen. `None`, the default,
+  /// means all of it; override this to leave out data only used at runtime.
+  /// This is hashed in its serialized form, so it must be stable across
+  /// builds of the host program.
+  fn codegen_key(&self) -> Option<&dyn erased_serde::Serialize> { None }
 
   fn downcast_ref(this: &dyn PlatformTargetDesc) -> Option<&Self>
     where Self: Sized,
@@ -348,6 +446,16 @@ mod test {
     assert!(MyTargetDesc::downcast_box(b).is_ok());
   }
 
+  #[test]
+  fn target_desc_serde_roundtrip() {
+    let desc = AcceleratorTargetDesc::new(MyTargetDesc);
+    let bytes = rmps::encode::to_vec_named(&desc).unwrap();
+    let mut de = rmps::Deserializer::new(&bytes[..]);
+    let desc2 = AcceleratorTargetDesc::deserialize_platform::<MyTargetDesc, _>(&mut de)
+      .unwrap();
+    assert_eq!(desc, desc2);
+  }
+
   #[derive(Debug)]
   struct MyAccelerator;
 
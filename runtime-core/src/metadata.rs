@@ -8,11 +8,11 @@ use std::{fmt};
 use std::ops::{Deref};
 use std::sync::{Arc, };
 
+use goblin::archive::Archive;
 use goblin::elf::Elf;
+use goblin::mach::{Mach, MachO, };
 use goblin::pe::PE;
 
-use memmap::Mmap;
-
 use rustc_data_structures::fx::{FxHashMap, };
 use rustc_data_structures::sync::MetadataRef;
 use rustc_data_structures::owning_ref::{OwningRef, };
@@ -27,7 +27,7 @@ use rustc_span::symbol::{Symbol};
 
 use snap::read::FrameDecoder;
 
-use crate::utils::{new_hash_set, };
+use crate::utils::{new_hash_set, StableHash, };
 
 #[derive(Debug)]
 pub enum MetadataLoadingError {
@@ -38,6 +38,11 @@ pub enum MetadataLoadingError {
   Header,
   Deflate(PathBuf, String, io::Error),
   ObjectFormat(goblin::error::Error),
+  /// The file isn't an object format we can load metadata from.
+  UnsupportedFormat(PathBuf),
+  /// The metadata sidecar at this path was extracted from a different build
+  /// of the executable.
+  SidecarMismatch(PathBuf),
 }
 impl Error for MetadataLoadingError { }
 impl fmt::Display for MetadataLoadingError {
@@ -52,6 +57,14 @@ impl fmt::Display for MetadataLoadingError {
       &MetadataLoadingError::Header => f.pad("corrupt/unsupported metadata header"),
       &MetadataLoadingError::Deflate(_, _, ref e) => fmt::Display::fmt(e, f),
       &MetadataLoadingError::ObjectFormat(ref e) => fmt::Display::fmt(e, f),
+      &MetadataLoadingError::UnsupportedFormat(ref path) => {
+        write!(f, "can't load metadata from {}: unsupported object format",
+               path.display())
+      },
+      &MetadataLoadingError::SidecarMismatch(ref path) => {
+        write!(f, "metadata sidecar {} belongs to a different build of this executable",
+               path.display())
+      },
     }
   }
 }
@@ -303,6 +316,9 @@ impl SharedMetadataBlob {
     MetadataBlob::new(inner)
   }
 
+  /// The uncompressed metadata.
+  pub fn bytes(&self) -> &[u8] { &self.0 }
+
   pub fn unwrap(self) -> MetadataBlob {
     let SharedMetadataBlob(_, inner) = self;
     inner
@@ -355,16 +371,35 @@ impl Metadata {
       MmapOptions::new().map(&src_file)?
     };
 
-    match Object::parse(&src_buffer)? {
-      Object::Elf(elf) => Metadata::new_elf(src, &src_buffer, elf),
-      Object::PE(pe) => Metadata::new_pe(src, &src_buffer, pe),
+    Metadata::new_object(src, &src_buffer)
+  }
+  fn new_object(src: CrateSource, src_buffer: &[u8])
+    -> Result<Metadata, MetadataLoadingError>
+  {
+    use goblin::Object;
 
-      // TODO?
-      _ => panic!("TODO: can't load from this object format: {}", src.as_path().display()),
+    match Object::parse(src_buffer)? {
+      Object::Elf(elf) => Metadata::new_elf(src, src_buffer, elf),
+      Object::PE(pe) => Metadata::new_pe(src, src_buffer, pe),
+      Object::Mach(Mach::Binary(macho)) => Metadata::new_macho(src, macho),
+      Object::Mach(Mach::Fat(fat)) => {
+        // Use the first arch which has metadata.
+        for i in 0..fat.narches {
+          match Metadata::new_macho(src.clone(), fat.get(i)?) {
+            Err(MetadataLoadingError::SectionMissing) => { continue; },
+            v => { return v; },
+          }
+        }
+        Err(MetadataLoadingError::SectionMissing)
+      },
+      Object::Archive(archive) => Metadata::new_archive(src, src_buffer, archive),
+      Object::Unknown(_) => {
+        Err(MetadataLoadingError::UnsupportedFormat(src.as_path().into()))
+      },
     }
   }
 
-  fn new_elf(src: CrateSource, src_buffer: &Mmap, object: Elf)
+  fn new_elf(src: CrateSource, src_buffer: &[u8], object: Elf)
     -> Result<Metadata, MetadataLoadingError>
   {
     use std::io::{Read};
@@ -401,8 +436,10 @@ impl Metadata {
       })
       .collect();
 
+    // Stripped objects still have the section, but not the symbols.
     let owner_index = syms.iter()
-      .position(|&(ref sym, _)| sym.st_value == 0 );
+      .position(|&(ref sym, _)| sym.st_value == 0 )
+      .ok_or(MetadataLoadingError::SectionMissing)?;
 
     let all: Vec<_> = syms.into_par_iter()
       .map(|(sym, name)| {
@@ -457,11 +494,11 @@ impl Metadata {
 
     Ok(Metadata {
       src,
-      owner_index: owner_index.unwrap(),
+      owner_index,
       all,
     })
   }
-  fn new_pe(src: CrateSource, src_buffer: &Mmap, object: PE)
+  fn new_pe(src: CrateSource, src_buffer: &[u8], object: PE)
     -> Result<Metadata, MetadataLoadingError>
   {
     use std::io::{Read};
@@ -556,15 +593,277 @@ impl Metadata {
     })
   }
 
+  /// Mach-O has no symbol sizes, so just walk the section. The linker
+  /// places the owner's metadata first, like on ELF.
+  fn new_macho(src: CrateSource, object: MachO)
+    -> Result<Metadata, MetadataLoadingError>
+  {
+    let mut region = None;
+    for segment in object.segments.iter() {
+      for (section, data) in segment.sections()? {
+        if section.segname()? == MACHO_METADATA_SEGMENT_NAME &&
+          section.name()? == MACHO_METADATA_SECTION_NAME
+        {
+          region = Some(data);
+        }
+      }
+    }
+    let region = region.ok_or(MetadataLoadingError::SectionMissing)?;
+
+    Ok(Metadata {
+      all: Metadata::read_concatenated(&src, region)?,
+      src,
+      owner_index: 0,
+    })
+  }
+  /// `.rlib`s store their crate's metadata uncompressed in a `lib.rmeta`
+  /// member. Static libraries only contain objects, so look for metadata in
+  /// those instead.
+  fn new_archive(src: CrateSource, src_buffer: &[u8], archive: Archive)
+    -> Result<Metadata, MetadataLoadingError>
+  {
+    let mut owner_index = None;
+    let mut all = Vec::new();
+    for member in archive.members() {
+      let bytes = archive.extract(member, src_buffer)?;
+      if member == RLIB_METADATA_MEMBER {
+        if !bytes.starts_with(METADATA_HEADER) {
+          return Err(MetadataLoadingError::Header);
+        }
+        owner_index = Some(all.len());
+        let name = format!("{}({})", src.as_path().display(), member);
+        all.push((name, SharedMetadataBlob::new(bytes.to_vec())));
+        continue;
+      }
+
+      let object = match Metadata::new_object(src.clone(), bytes) {
+        Ok(object) => object,
+        // Most members won't have metadata, and some might not be objects
+        // at all (eg the symbol table).
+        Err(MetadataLoadingError::SectionMissing) |
+        Err(MetadataLoadingError::UnsupportedFormat(_)) |
+        Err(MetadataLoadingError::ObjectFormat(_)) => { continue; },
+        Err(err) => {
+          warn!("skipping member {} of {}: {}", member,
+                src.as_path().display(), err);
+          continue;
+        },
+      };
+      if owner_index.is_none() {
+        owner_index = Some(all.len() + object.owner_index);
+      }
+      all.extend(object.all);
+    }
+
+    Ok(Metadata {
+      src,
+      owner_index: owner_index.ok_or(MetadataLoadingError::SectionMissing)?,
+      all,
+    })
+  }
+
+  /// Read every compressed metadata blob in `region`, which is a series of
+  /// `METADATA_HEADER`, name length, name, compressed length, compressed
+  /// bytes, possibly padded with zeros in between.
+  fn read_concatenated(src: &CrateSource, region: &[u8])
+    -> Result<Vec<(String, SharedMetadataBlob)>, MetadataLoadingError>
+  {
+    use std::io::{Read};
+
+    use crate::rustc_data_structures::rayon::prelude::*;
+
+    fn take<'a>(region: &'a [u8], pos: &mut usize, len: usize)
+      -> Result<&'a [u8], MetadataLoadingError>
+    {
+      let end = pos.checked_add(len)
+        .filter(|&end| end <= region.len() )
+        .ok_or(MetadataLoadingError::Header)?;
+      let out = &region[*pos..end];
+      *pos = end;
+      Ok(out)
+    }
+
+    let mut all_compressed = Vec::new();
+    let mut pos = 0;
+    loop {
+      // skip alignment padding:
+      while pos < region.len() && region[pos] == 0 {
+        pos += 1;
+      }
+      if pos == region.len() { break; }
+
+      if take(region, &mut pos, METADATA_HEADER.len())? != METADATA_HEADER {
+        return Err(MetadataLoadingError::Header);
+      }
+      let mut le_len = [0u8; 4];
+      le_len.copy_from_slice(take(region, &mut pos, 4)?);
+      let sym_name_len: usize = u32::from_le_bytes(le_len)
+        .try_into()
+        .map_err(|_| MetadataLoadingError::Header )?;
+      let sym_name = std::str::from_utf8(take(region, &mut pos, sym_name_len)?)?;
+
+      let mut le_len = [0u8; 8];
+      le_len.copy_from_slice(take(region, &mut pos, 8)?);
+      let comp_len: usize = u64::from_le_bytes(le_len)
+        .try_into()
+        .map_err(|_| MetadataLoadingError::Header )?;
+      let compressed_bytes = take(region, &mut pos, comp_len)?;
+
+      all_compressed.push((sym_name, compressed_bytes));
+    }
+
+    all_compressed.into_par_iter()
+      .map(|(name, compressed_bytes)| {
+        let mut inflated = Vec::new();
+        FrameDecoder::new(compressed_bytes)
+          .read_to_end(&mut inflated)
+          .map_err(|e| {
+            MetadataLoadingError::Deflate(src.as_path().into(), name.into(), e)
+          })?;
+
+        Ok((name.to_string(), SharedMetadataBlob::new(inflated)))
+      })
+      .collect()
+  }
+
+  /// Load metadata previously extracted with `write_metadata_sidecar`.
+  /// `src` should be the binary the metadata was extracted from; the
+  /// sidecar must have been written for `exe_id` (see `platform::os::exe_id`).
+  fn from_sidecar(src: CrateSource, path: &Path, exe_id: u64)
+    -> Result<Metadata, MetadataLoadingError>
+  {
+    const HEADER_LEN: usize = SIDECAR_MAGIC.len() + 8;
+
+    let bytes = std::fs::read(path)?;
+    if !bytes.starts_with(SIDECAR_MAGIC) || bytes.len() < HEADER_LEN {
+      return Err(MetadataLoadingError::Header);
+    }
+    let mut id = [0u8; 8];
+    id.copy_from_slice(&bytes[SIDECAR_MAGIC.len()..HEADER_LEN]);
+    if u64::from_le_bytes(id) != exe_id {
+      return Err(MetadataLoadingError::SidecarMismatch(path.into()));
+    }
+    let all = Metadata::read_concatenated(&src, &bytes[HEADER_LEN..])?;
+    if all.is_empty() {
+      return Err(MetadataLoadingError::SectionMissing);
+    }
+
+    Ok(Metadata {
+      src,
+      // `write_concatenated` writes the owner first
+      owner_index: 0,
+      all,
+    })
+  }
+  /// The inverse of `read_concatenated`, with the owner first.
+  fn write_concatenated(&self, out: &mut Vec<u8>) -> io::Result<()> {
+    let owner = &self.all[self.owner_index];
+    write_blob(out, &owner.0, owner.1.bytes())?;
+    for (i, &(ref name, ref blob)) in self.all.iter().enumerate() {
+      if i == self.owner_index { continue; }
+      write_blob(out, name, blob.bytes())?;
+    }
+    Ok(())
+  }
+
   pub fn owner_blob(&self) -> &MetadataBlob {
     &self.all[self.owner_index].1
   }
 }
 
+fn write_blob(out: &mut Vec<u8>, name: &str, data: &[u8]) -> io::Result<()> {
+  use std::io::Write;
+  use snap::write::FrameEncoder;
+
+  let mut compressed = Vec::new();
+  {
+    let mut encoder = FrameEncoder::new(&mut compressed);
+    encoder.write_all(data)?;
+    encoder.flush()?;
+  }
+  out.extend_from_slice(METADATA_HEADER);
+  out.extend_from_slice(&(name.len() as u32).to_le_bytes());
+  out.extend_from_slice(name.as_bytes());
+  out.extend_from_slice(&(compressed.len() as u64).to_le_bytes());
+  out.extend_from_slice(&compressed);
+  Ok(())
+}
+
+/// Appended to the executable's file name to get the path its metadata
+/// sidecar is expected at (eg `my-app.grtmd`).
+pub const METADATA_SIDECAR_EXTENSION: &'static str = "grtmd";
+/// Bump the last byte whenever the format changes. Followed by the
+/// `exe_id` of the executable the metadata was extracted from, in little
+/// endian.
+const SIDECAR_MAGIC: &'static [u8] = b"GRTMD\0\0\x02";
+
+/// Extract the metadata of every crate in the binary `exe` into `out`, so
+/// `exe` can be stripped. Place `out` next to the stripped binary, with the
+/// name `metadata_sidecar_path` expects, or point
+/// `GEOBACTER_METADATA_SIDECAR` at it. The sidecar is only loaded by builds
+/// of `exe` (stripped or not).
+pub fn write_metadata_sidecar(exe: &Path, out: &Path)
+  -> Result<(), MetadataLoadingError>
+{
+  use crate::platform::os::exe_id;
+
+  let metadata = Metadata::new(CrateSource::Mapped(exe.into()))?;
+  let mut bytes = SIDECAR_MAGIC.to_vec();
+  bytes.extend_from_slice(&exe_id(exe)?.to_le_bytes());
+  metadata.write_concatenated(&mut bytes)?;
+  std::fs::write(out, bytes)?;
+  Ok(())
+}
+/// `GEOBACTER_METADATA_SIDECAR` if set, otherwise the path of the current
+/// executable with `METADATA_SIDECAR_EXTENSION` appended.
+pub fn metadata_sidecar_path() -> io::Result<PathBuf> {
+  use crate::platform::os::self_exe_path;
+  use crate::utils::env::metadata_sidecar;
+
+  if let Some(path) = metadata_sidecar() {
+    return Ok(path);
+  }
+
+  let mut path = self_exe_path()?.into_os_string();
+  path.push(".");
+  path.push(METADATA_SIDECAR_EXTENSION);
+  Ok(path.into())
+}
+
+/// Falls back to the sidecar if the executable has been stripped.
+fn load_exe_metadata(src: CrateSource) -> Result<Metadata, MetadataLoadingError> {
+  load_exe_metadata_with(src, &metadata_sidecar_path()?)
+}
+fn load_exe_metadata_with(src: CrateSource, sidecar: &Path)
+  -> Result<Metadata, MetadataLoadingError>
+{
+  use crate::platform::os::exe_id;
+
+  // Stripping removes the owner symbol, if not the whole section; either
+  // way this is `SectionMissing`.
+  match Metadata::new(src.clone()) {
+    Err(MetadataLoadingError::SectionMissing) => { },
+    v => { return v; },
+  }
+
+  if !sidecar.exists() {
+    return Err(MetadataLoadingError::SectionMissing);
+  }
+  debug!("loading executable metadata from sidecar {}", sidecar.display());
+  let id = exe_id(&src)?;
+  Metadata::from_sidecar(src, sidecar, id)
+}
+
 #[cfg(not(target_os = "macos"))]
 const METADATA_SECTION_NAME: &'static str = ".rustc";
 #[cfg(target_os = "macos")]
 const METADATA_SECTION_NAME: &'static str = "__DATA,.rustc";
+const MACHO_METADATA_SEGMENT_NAME: &'static str = "__DATA";
+const MACHO_METADATA_SECTION_NAME: &'static str = ".rustc";
+/// The archive member `.rlib`s store their metadata in.
+const RLIB_METADATA_MEMBER: &'static str = "lib.rmeta";
+/// Extensions of the files searched for metadata, besides `DLL_EXTENSION`.
+const ARCHIVE_EXTENSIONS: &'static [&'static str] = &["rlib", "a"];
 
 pub struct DummyMetadataLoader;
 impl MetadataLoader for DummyMetadataLoader {
@@ -583,6 +882,22 @@ impl MetadataLoader for DummyMetadataLoader {
 }
 pub(crate) type LoadedCrateMetadata = Box<[Metadata]>;
 
+/// Hash the name and SVH of every crate present in `md`. The result doesn't
+/// depend on load order and uses the crate name strings (not `Symbol`s), so
+/// it's stable across processes.
+pub(crate) fn crate_metadata_hash(md: &[Metadata]) -> u64 {
+  let mut crates: Vec<(String, u64)> = md.iter()
+    .flat_map(|object| object.all.iter() )
+    .map(|&(_, ref blob)| {
+      let root = blob.get_root();
+      (root.name().to_string(), root.hash().as_u64())
+    })
+    .collect();
+  crates.sort();
+  crates.dedup();
+  crates.stable_hash()
+}
+
 /// Loads the Rust metadata for all linked crates. This data isn't light;
 /// you'll probably want to store it somewhere and reuse it a lot.
 pub(crate) fn context_metadata()
@@ -598,7 +913,7 @@ pub(crate) fn context_metadata()
 
   let this = CrateSource::Mapped(self_exe_path()?.canonicalize()?);
   let mut mapped = BTreeSet::new();
-  mapped.insert(this);
+  mapped.insert(this.clone());
   let mut unique_metadata = new_hash_set();
 
   let search_mapped = dylib_search_paths()
@@ -616,8 +931,15 @@ pub(crate) fn context_metadata()
               }
             })
             .filter(|path| {
-              let extension = path.extension();
-              if extension != Some(DLL_EXTENSION.as_ref()) { return false; }
+              let extension = match path.extension() {
+                Some(extension) => extension,
+                None => { return false; },
+              };
+              if extension != OsStr::new(DLL_EXTENSION) &&
+                !ARCHIVE_EXTENSIONS.iter().any(|&ext| extension == OsStr::new(ext) )
+              {
+                return false;
+              }
               // skip other toolchains
               // XXX revisit this when deployment code is written.
               if path.components().any(|v| v == Component::Normal(OsStr::new(".rustup"))) {
@@ -635,8 +957,25 @@ pub(crate) fn context_metadata()
 
   let mapped = mapped.into_iter() // XXX using .into_par_iter() causes a deadlock...
     .filter_map(|mapped| {
-      match Metadata::new(mapped) {
+      let is_exe = mapped == this;
+      let path = mapped.as_path().to_path_buf();
+      let metadata = if is_exe {
+        load_exe_metadata(mapped)
+      } else {
+        Metadata::new(mapped)
+      };
+      match metadata {
         Err(MetadataLoadingError::SectionMissing) => { None },
+        Err(err @ MetadataLoadingError::UnsupportedFormat(_)) => {
+          debug!("{}", err);
+          None
+        },
+        // Without our own metadata nothing can be codegen-ed, but the
+        // other files are just candidates; one bad file shouldn't stop us.
+        Err(err) if !is_exe => {
+          warn!("skipping metadata of {}: {}", path.display(), err);
+          None
+        },
         v => Some(v),
       }
     })
@@ -661,3 +1000,103 @@ pub(crate) fn context_metadata()
 
   Ok(out_metadata.into_boxed_slice())
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn encode(out: &mut Vec<u8>, name: &str, data: &[u8]) {
+    write_blob(out, name, data).unwrap();
+  }
+
+  #[test]
+  fn read_concatenated_skips_padding() {
+    let mut region = Vec::new();
+    encode(&mut region, "rust_metadata_a", b"a");
+    region.extend_from_slice(&[0u8; 7]);
+    encode(&mut region, "rust_metadata_b", b"b");
+    region.extend_from_slice(&[0u8; 16]);
+
+    let src = CrateSource::Mapped("test".into());
+    let all = Metadata::read_concatenated(&src, &region).unwrap();
+    let names: Vec<_> = all.iter().map(|&(ref name, _)| &name[..] ).collect();
+    assert_eq!(names, ["rust_metadata_a", "rust_metadata_b"]);
+
+    // truncated:
+    let err = Metadata::read_concatenated(&src, &region[..region.len() - 20]);
+    assert!(matches!(err, Err(MetadataLoadingError::Header)));
+  }
+
+  #[test]
+  fn sidecar_roundtrip() {
+    let src = CrateSource::Mapped("test".into());
+    let metadata = Metadata {
+      src: src.clone(),
+      owner_index: 1,
+      all: vec![
+        ("rust_metadata_dep".into(), SharedMetadataBlob::new(b"dep".to_vec())),
+        ("rust_metadata_owner".into(), SharedMetadataBlob::new(b"owner".to_vec())),
+      ],
+    };
+    let mut bytes = SIDECAR_MAGIC.to_vec();
+    bytes.extend_from_slice(&1u64.to_le_bytes());
+    metadata.write_concatenated(&mut bytes).unwrap();
+
+    let tmp = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(tmp.path(), bytes).unwrap();
+    let r = Metadata::from_sidecar(src.clone(), tmp.path(), 2);
+    assert!(matches!(r, Err(MetadataLoadingError::SidecarMismatch(_))));
+    let metadata = Metadata::from_sidecar(src, tmp.path(), 1).unwrap();
+    assert_eq!(metadata.owner_index, 0);
+    assert_eq!(metadata.all[0].0, "rust_metadata_owner");
+    assert_eq!(metadata.all[0].1.bytes(), b"owner");
+    assert_eq!(metadata.all[1].1.bytes(), b"dep");
+  }
+
+  #[test]
+  fn stripped_exe_uses_sidecar() {
+    use std::process::Command;
+    use crate::platform::os::self_exe_path;
+
+    let exe = self_exe_path().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let stripped = dir.path().join("stripped");
+    let sidecar = dir.path().join("stripped.grtmd");
+
+    let status = Command::new("strip")
+      .arg("--strip-all")
+      .arg("-o").arg(&stripped)
+      .arg(&exe)
+      .status()
+      .expect("failed to run strip");
+    assert!(status.success());
+
+    let src = CrateSource::Mapped(stripped.clone());
+    let r = Metadata::new(src.clone());
+    assert!(matches!(r, Err(MetadataLoadingError::SectionMissing)));
+    let r = load_exe_metadata_with(src.clone(), &sidecar);
+    assert!(matches!(r, Err(MetadataLoadingError::SectionMissing)));
+
+    write_metadata_sidecar(&exe, &sidecar).unwrap();
+    let original = Metadata::new(CrateSource::Mapped(exe)).unwrap();
+    let loaded = load_exe_metadata_with(src, &sidecar).unwrap();
+    assert_eq!(loaded.all.len(), original.all.len());
+    assert_eq!(loaded.all[loaded.owner_index].1.bytes(),
+               original.all[original.owner_index].1.bytes());
+
+    // a sidecar of another build is rejected:
+    let other = dir.path().join("other.grtmd");
+    let mut bytes = std::fs::read(&sidecar).unwrap();
+    bytes[SIDECAR_MAGIC.len()] ^= 1;
+    std::fs::write(&other, bytes).unwrap();
+    let r = load_exe_metadata_with(CrateSource::Mapped(stripped), &other);
+    assert!(matches!(r, Err(MetadataLoadingError::SidecarMismatch(_))));
+  }
+
+  #[test]
+  fn unknown_format_is_an_error() {
+    let src = CrateSource::SearchPaths("README.txt".into());
+    let r = Metadata::new_object(src, b"just some text, not an object file");
+    assert!(matches!(r, Err(MetadataLoadingError::UnsupportedFormat(_))));
+  }
+}
@@ -20,6 +20,95 @@ pub fn self_exe_path() -> Result<PathBuf, IoError> {
   Ok(read_link(P)?)
 }
 
+/// `exe_id` of the current executable. Computed once per process.
+pub fn binary_id() -> Result<u64, IoError> {
+  use std::lazy::SyncOnceCell;
+
+  static ID: SyncOnceCell<u64> = SyncOnceCell::new();
+  if let Some(&id) = ID.get() {
+    return Ok(id);
+  }
+
+  let id = exe_id(&self_exe_path()?)?;
+  Ok(*ID.get_or_init(|| id ))
+}
+/// Identifies the build of the executable at `path`: a hash of its GNU build
+/// ID, if it has one, otherwise of its loadable segments. Neither changes
+/// when the executable is stripped.
+pub fn exe_id(path: &std::path::Path) -> Result<u64, IoError> {
+  use std::fs::File;
+
+  use goblin::elf::Elf;
+  use goblin::elf::note::NT_GNU_BUILD_ID;
+  use goblin::elf::program_header::PT_LOAD;
+  use memmap::MmapOptions;
+
+  use crate::utils::StableHash;
+
+  let exe = File::open(path)?;
+  let bytes = unsafe {
+    MmapOptions::new().map(&exe)?
+  };
+  let bytes = &bytes[..];
+  let elf = match Elf::parse(bytes) {
+    Ok(elf) => elf,
+    Err(_) => { return Ok(seahash::hash(bytes)); },
+  };
+
+  let build_id = elf.iter_note_headers(bytes)
+    .and_then(|mut notes| {
+      notes.find_map(|note| {
+        note.ok()
+          .filter(|note| note.n_type == NT_GNU_BUILD_ID )
+          .map(|note| note.desc )
+      })
+    });
+  if let Some(build_id) = build_id {
+    return Ok(build_id.stable_hash());
+  }
+
+  let mut hasher = seahash::SeaHasher::new();
+  for header in elf.program_headers.iter() {
+    if header.p_type != PT_LOAD { continue; }
+    let segment = bytes.get(header.file_range())
+      .ok_or_else(|| {
+        IoError::new(std::io::ErrorKind::InvalidData,
+                     "executable segment is out of bounds")
+      })?;
+    std::hash::Hasher::write(&mut hasher, segment);
+  }
+  Ok(std::hash::Hasher::finish(&hasher))
+}
+
+/// Set the access and modification times of `path` to now.
+pub fn touch(path: &std::path::Path) -> Result<(), IoError> {
+  use std::ffi::CString;
+  use std::ptr::null;
+
+  let path = CString::new(path.as_os_str().as_bytes())
+    .map_err(|err| IoError::new(std::io::ErrorKind::InvalidInput, err) )?;
+  if unsafe { utimensat(AT_FDCWD, path.as_ptr(), null(), 0) } != 0 {
+    return Err(IoError::last_os_error());
+  }
+  Ok(())
+}
+
+/// Block until an exclusive advisory lock on `file` is acquired. It's
+/// released when `file` is closed.
+pub fn lock_exclusive(file: &std::fs::File) -> Result<(), IoError> {
+  use std::os::unix::io::AsRawFd;
+
+  loop {
+    if unsafe { flock(file.as_raw_fd(), LOCK_EX) } == 0 {
+      return Ok(());
+    }
+    let err = IoError::last_os_error();
+    if err.kind() != std::io::ErrorKind::Interrupted {
+      return Err(err);
+    }
+  }
+}
+
 fn runpaths() -> Vec<PathBuf> {
 
   #[cfg(target_pointer_width = "64")]
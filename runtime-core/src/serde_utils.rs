@@ -138,7 +138,6 @@ pub mod abi {
 
   pub type Output = spec::abi::Abi;
 
-  #[allow(dead_code)] // for completeness.
   pub fn deserialize<'de, D>(deserializer: D) -> Result<Output, D::Error>
     where D: Deserializer<'de>,
   {
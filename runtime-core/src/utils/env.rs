@@ -1,8 +1,10 @@
 
 //! Debugging environmental variables
 
-use std::env::var;
+use std::env::{var, var_os, };
+use std::path::PathBuf;
 use std::sync::atomic::*;
+use std::time::Duration;
 
 static USE_LLC: AtomicBool = AtomicBool::new(false);
 static OPT_REMARKS: AtomicBool = AtomicBool::new(false);
@@ -33,3 +35,64 @@ pub fn use_llc() -> bool {
 pub fn print_opt_remarks() -> bool {
   OPT_REMARKS.load(Ordering::Acquire)
 }
+
+/// `GEOBACTER_CODEGEN_CACHE_DIR`
+pub fn codegen_cache_dir() -> Option<PathBuf> {
+  var_os(key("CODEGEN_CACHE_DIR"))
+    .map(PathBuf::from)
+}
+/// `GEOBACTER_CODEGEN_CACHE_SIZE_LIMIT`, in bytes.
+pub fn codegen_cache_size_limit() -> Option<u64> {
+  var(key("CODEGEN_CACHE_SIZE_LIMIT")).ok()?
+    .parse()
+    .ok()
+}
+/// `GEOBACTER_CODEGEN_MEMORY_CACHE_LIMIT`: the memory budget of a driver's
+/// in-memory codegen cache, in bytes.
+pub fn codegen_memory_cache_limit() -> Option<usize> {
+  var(key("CODEGEN_MEMORY_CACHE_LIMIT")).ok()?
+    .parse()
+    .ok()
+}
+/// `GEOBACTER_CODEGEN_SUBPROCESS`: codegen in a helper subprocess by
+/// default.
+pub fn codegen_subprocess() -> bool {
+  b("CODEGEN_SUBPROCESS")
+}
+/// `GEOBACTER_CODEGEN_HELPER_TIMEOUT`: how long a codegen helper subprocess
+/// may run, in seconds.
+pub fn codegen_helper_timeout() -> Option<Duration> {
+  var(key("CODEGEN_HELPER_TIMEOUT")).ok()?
+    .parse()
+    .ok()
+    .map(Duration::from_secs)
+}
+/// `GEOBACTER_CODEGEN_SERVER`: the socket of a codegen server to use by
+/// default.
+pub fn codegen_server() -> Option<PathBuf> {
+  var_os(key("CODEGEN_SERVER"))
+    .map(PathBuf::from)
+}
+/// `GEOBACTER_NO_CODEGEN_CACHE`
+pub fn no_codegen_cache() -> bool {
+  b("NO_CODEGEN_CACHE")
+}
+/// `GEOBACTER_CODEGEN_ARTIFACTS_DIR`: keep codegen intermediates here.
+pub fn codegen_artifacts_dir() -> Option<PathBuf> {
+  var_os(key("CODEGEN_ARTIFACTS_DIR"))
+    .map(PathBuf::from)
+}
+/// `GEOBACTER_METADATA_SIDECAR`: where to load the executable's crate
+/// metadata from if it has been stripped.
+pub fn metadata_sidecar() -> Option<PathBuf> {
+  var_os(key("METADATA_SIDECAR"))
+    .map(PathBuf::from)
+}
+/// `GEOBACTER_CODEGEN_CONCURRENCY`: the maximum number of kernels which
+/// will be codegen-ed at the same time.
+pub fn codegen_concurrency() -> Option<usize> {
+  var(key("CODEGEN_CONCURRENCY")).ok()?
+    .parse()
+    .ok()
+    .filter(|&v| v != 0)
+}
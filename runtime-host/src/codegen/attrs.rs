@@ -0,0 +1,28 @@
+
+use grt_core::codegen::attrs::*;
+
+use rustc_ast::ast::MetaItem;
+use rustc_middle::ty::TyCtxt;
+use rustc_span::symbol::Symbol;
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub enum Condition {
+  /// `platform = "host"`
+  Platform,
+}
+impl ConditionItem for Condition {
+  fn parse_name_value(tcx: TyCtxt, item: &MetaItem) -> Option<Self> {
+    if item.has_name(Symbol::intern("platform")) {
+      return match item.value_str() {
+        Some(platform) if platform.as_str() == "host" => Some(Condition::Platform),
+        // some other platform.
+        _ => None,
+      };
+    }
+    let msg = format!("unknown attr key `{}`; expected `platform`",
+                      item.name_or_empty());
+    tcx.sess.span_err(item.span, &msg);
+
+    None
+  }
+}
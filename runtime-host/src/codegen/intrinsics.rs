@@ -0,0 +1,132 @@
+//! The device workitem/workgroup id intrinsics (as used by
+//! `std::geobacter::amdgpu::workitem`), lowered to calls which read the
+//! current workitem of the host dispatch. This lets device kernel code run on
+//! the host unchanged.
+
+use std::fmt;
+use std::geobacter::kernel::{KernelInstanceRef, OptionalKernelFn, };
+
+use rustc_geobacter::TyCtxtKernelInstance;
+use rustc_index::vec::Idx;
+use rustc_middle::mir::*;
+use rustc_middle::ty::{self, List, Ty, TyCtxt, };
+use rustc_span::DUMMY_SP;
+
+use crate::module::intrinsics::*;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum Block {
+  Workitem,
+  Workgroup,
+}
+impl fmt::Display for Block {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Block::Workitem => write!(f, "workitem"),
+      Block::Workgroup => write!(f, "workgroup"),
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum Axis {
+  X,
+  Y,
+  Z,
+}
+impl fmt::Display for Axis {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Axis::X => write!(f, "x"),
+      Axis::Y => write!(f, "y"),
+      Axis::Z => write!(f, "z"),
+    }
+  }
+}
+
+/// The id of the current workitem or workgroup along one axis.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct AxisId {
+  pub block: Block,
+  pub axis: Axis,
+}
+impl AxisId {
+  pub fn permutations() -> Vec<Self> {
+    let mut out = Vec::with_capacity(6);
+    for &block in [Block::Workitem, Block::Workgroup].iter() {
+      for &axis in [Axis::X, Axis::Y, Axis::Z].iter() {
+        out.push(AxisId { block, axis, });
+      }
+    }
+    out
+  }
+  /// The name of the device intrinsic, eg `geobacter_amdgpu_workitem_x_id`.
+  pub fn name(&self) -> String {
+    format!("geobacter_amdgpu_{}_{}_id", self.block, self.axis)
+  }
+  /// The host function which implements this intrinsic.
+  fn host_fn(&self) -> KernelInstanceRef<'static> {
+    match (self.block, self.axis) {
+      (Block::Workitem, Axis::X) => workitem_x_id.kernel_instance(),
+      (Block::Workitem, Axis::Y) => workitem_y_id.kernel_instance(),
+      (Block::Workitem, Axis::Z) => workitem_z_id.kernel_instance(),
+      (Block::Workgroup, Axis::X) => workgroup_x_id.kernel_instance(),
+      (Block::Workgroup, Axis::Y) => workgroup_y_id.kernel_instance(),
+      (Block::Workgroup, Axis::Z) => workgroup_z_id.kernel_instance(),
+    }
+  }
+}
+impl CustomIntrinsicMirGen for AxisId {
+  fn mirgen_simple_intrinsic<'tcx>(&self, tcx: TyCtxt<'tcx>,
+                                   _instance: ty::Instance<'tcx>,
+                                   mir: &mut Body<'tcx>)
+  {
+    let source_info = SourceInfo::outermost(DUMMY_SP);
+
+    let callee = tcx.expect_instance(self.host_fn());
+    let func = Operand::function_handle(tcx, callee.def_id(), callee.substs,
+                                        DUMMY_SP);
+
+    let call_bb = mir.basic_blocks().next_index();
+    let ret_bb = BasicBlock::new(call_bb.index() + 1);
+    mir.basic_blocks_mut().push(BasicBlockData::new(Some(Terminator {
+      source_info,
+      kind: TerminatorKind::Call {
+        func,
+        args: vec![],
+        destination: Some((Place::return_place(), ret_bb)),
+        cleanup: None,
+        from_hir_call: false,
+        fn_span: DUMMY_SP,
+      },
+    })));
+    mir.basic_blocks_mut().push(BasicBlockData::new(Some(Terminator {
+      source_info,
+      kind: TerminatorKind::Return,
+    })));
+  }
+
+  fn generic_parameter_count<'tcx>(&self, _tcx: TyCtxt<'tcx>) -> usize { 0 }
+  fn inputs<'tcx>(&self, tcx: TyCtxt<'tcx>) -> &'tcx List<Ty<'tcx>> {
+    tcx.intern_type_list(&[])
+  }
+  fn output<'tcx>(&self, tcx: TyCtxt<'tcx>) -> Ty<'tcx> {
+    tcx.types.u32
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn names() {
+    let names: Vec<_> = AxisId::permutations()
+      .iter()
+      .map(AxisId::name)
+      .collect();
+    assert_eq!(names.len(), 6);
+    assert!(names.contains(&"geobacter_amdgpu_workitem_x_id".to_string()));
+    assert!(names.contains(&"geobacter_amdgpu_workgroup_z_id".to_string()));
+  }
+}
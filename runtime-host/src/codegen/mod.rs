@@ -0,0 +1,161 @@
+//! Kernels are compiled for the host triple, then linked into a shared
+//! object which is `dlopen`-ed by `HostAccel::load_kernel`.
+
+use std::env::var_os;
+use std::fs::{self, };
+use std::path::Path;
+use std::process::Command;
+use std::sync::Arc;
+
+use tracing::*;
+
+use grt_core::AcceleratorTargetDesc;
+use grt_core::codegen::*;
+use grt_core::codegen::help::LlvmBuildRoot;
+use grt_core::codegen::products::*;
+
+use rustc_data_structures::sync::Lrc;
+use rustc_middle::mir::CustomIntrinsicMirGen;
+use rustc_middle::ty::{Instance, TyCtxt, };
+
+use serde::{Deserialize, Serialize, };
+
+use crate::error::Error;
+
+pub mod attrs;
+pub mod intrinsics;
+
+/// Nothing is needed from the host compiler.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct HostKernelDesc;
+impl PlatformKernelDesc for HostKernelDesc { }
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct HostCodegenDesc;
+impl PlatformCodegenDesc for HostCodegenDesc { }
+
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct HostPlatformCodegen;
+
+/// Call at the start of `main` in programs which enable subprocess codegen;
+/// see `grt_core::codegen::run_codegen_helper`.
+pub fn run_codegen_helper() {
+  grt_core::codegen::run_codegen_helper(HostPlatformCodegen::default());
+}
+
+impl PlatformCodegen for HostPlatformCodegen {
+  type Device = super::HostAccel;
+  type KernelDesc = HostKernelDesc;
+  type CodegenDesc = HostCodegenDesc;
+  type Condition = attrs::Condition;
+
+  /// The kernel code *is* host code, so the usual host intrinsics (and
+  /// `platform()`) are already correct. The device workitem/workgroup id
+  /// intrinsics are implemented against the current dispatch.
+  fn insert_intrinsics<F>(&self, _: &Arc<AcceleratorTargetDesc>, into: &mut F)
+    where F: for<'a> FnMut(&'a str, Lrc<dyn CustomIntrinsicMirGen>),
+  {
+    for id in intrinsics::AxisId::permutations().into_iter() {
+      into(&id.name(), Lrc::new(id));
+    }
+  }
+
+  /// Both descs are empty.
+  fn encode_codegen_desc(&self, _desc: &HostCodegenDesc) -> Option<Vec<u8>> {
+    Some(vec![])
+  }
+  fn decode_codegen_desc(&self, _bytes: &[u8]) -> Option<HostCodegenDesc> {
+    Some(HostCodegenDesc)
+  }
+  fn encode_kernel_desc(&self, _desc: &HostKernelDesc) -> Option<Vec<u8>> {
+    Some(vec![])
+  }
+  fn decode_kernel_desc(&self, _bytes: &[u8]) -> Option<HostKernelDesc> {
+    Some(HostKernelDesc)
+  }
+  fn decode_target_desc(&self, bytes: &[u8]) -> Option<AcceleratorTargetDesc> {
+    decode_target_desc::<crate::HostTargetDesc>(bytes)
+  }
+
+  fn root<'tcx>(&self, desc: PKernelDesc<Self>,
+                instance: Instance<'tcx>,
+                _tcx: TyCtxt<'tcx>,
+                _dd: &DriverData<'tcx, Self>)
+    -> Result<PCodegenDesc<'tcx, Self>, Error>
+  {
+    Ok(CodegenDesc {
+      instance,
+      kernel_instance: desc.instance.into(),
+      spec_params: desc.spec_params,
+      platform_desc: HostCodegenDesc,
+    })
+  }
+  fn root_conditions<'tcx>(&self, _root: &PCodegenDesc<Self>,
+                           _tcx: TyCtxt<'tcx>,
+                           _dd: &DriverData<'tcx, Self>)
+    -> Result<Vec<Self::Condition>, Error>
+  {
+    Ok(vec![attrs::Condition::Platform])
+  }
+  fn pre_codegen<'tcx>(&self, _tcx: TyCtxt<'tcx>,
+                       _dd: &DriverData<'tcx, Self>)
+    -> Result<(), Error>
+  {
+    Ok(())
+  }
+  fn post_codegen(&self,
+                  _target_desc: &Arc<AcceleratorTargetDesc>,
+                  options: &CodegenOptions,
+                  tdir: &Path,
+                  codegen: &mut PCodegenResults<Self>)
+    -> Result<(), Error>
+  {
+    let obj = tdir.join("codegen.o");
+    if let Some(obj_data) = codegen.take_object() {
+      fs::write(&obj, obj_data)?;
+    } else {
+      // fallback to invoking llc manually:
+      let bc = codegen.take_bitcode()
+        .ok_or_else(|| Error::Generic("codegen produced neither an object nor bitcode".into()) )?;
+
+      let linked_bc = tdir.join("linked.bc");
+      fs::write(&linked_bc, bc)?;
+
+      let llvm = LlvmBuildRoot::find()?;
+      let mut llc = Command::new(llvm.llc());
+      llc.current_dir(tdir)
+        .arg(&linked_bc)
+        .arg("-relocation-model=pic")
+        .arg(options.opt_level.llc_arg())
+        .arg("-filetype=obj")
+        .arg("-o").arg(&obj);
+      run_cmd(llc)?;
+    }
+
+    // anything not defined in the kernel is resolved against the process
+    // when the object is loaded.
+    let so = tdir.join("kernel.so");
+    let mut cc = Command::new(var_os("CC").unwrap_or_else(|| "cc".into() ));
+    cc.current_dir(tdir)
+      .arg("-shared")
+      .arg("-o").arg(&so)
+      .arg(&obj);
+    run_cmd(cc)?;
+
+    info!("linked host kernel {}", codegen.root().symbol);
+
+    codegen.put_exe(fs::read(&so)?);
+
+    Ok(())
+  }
+}
+
+pub fn run_cmd(mut cmd: Command) -> Result<(), Error> {
+  info!("running command {:?}", cmd);
+  let mut child = cmd.spawn()?;
+  if !child.wait()?.success() {
+    Err(Error::Cmd(format!("command failed: {:?}", cmd)))
+  } else {
+    Ok(())
+  }
+}
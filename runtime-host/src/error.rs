@@ -0,0 +1,160 @@
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::geobacter::kernel::KernelInstanceRef;
+use std::io::Error as IoError;
+
+use grt_core::codegen::diagnostics::Diagnostics;
+
+use crate::module::Dim3;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+  Generic(Box<dyn StdError + Send + Sync + 'static>),
+  LoadRustcMetadata(Box<dyn StdError + Send + Sync + 'static>),
+  Io(IoError),
+  Cmd(String),
+  ConvertKernelInstance(KernelInstanceRef<'static>),
+  ContextDead,
+  /// The accelerator was retired from its context.
+  AcceleratorRetired(grt_core::AcceleratorId),
+  Codegen(Diagnostics),
+  Linking(Diagnostics),
+  CodegenInitRoot(Box<Error>),
+  CodegenInitConditions(Box<Error>),
+  CodegenPreCodegen(Box<Error>),
+  CodegenPostCodegen(Box<Error>),
+  KernelArgLayoutMismatch(Box<grt_core::codegen::host::LayoutMismatch>),
+  /// The layout of a kernel argument type couldn't be computed.
+  KernelArgLayout(String),
+  UnknownSpecParam(String),
+  SpecParam(grt_core::codegen::SpecParamError),
+  /// The codegen helper process failed; see
+  /// `CodegenDriver::set_subprocess_codegen`.
+  CodegenSubprocess(String),
+  /// The codegen server couldn't be reached, or failed; see
+  /// `CodegenDriver::set_codegen_server`.
+  CodegenServer(String),
+  /// `dlopen` or `dlsym` failed; contains `dlerror()`.
+  Dl(String),
+  MissingKernelSymbol(String),
+  /// The dispatch grid has a zero length along one or more of it's axes.
+  ZeroGridLaunchAxis,
+  /// The kernel's workgroup has a zero length along one or more of it's axes.
+  ZeroWorkgroupAxis,
+  /// The kernel's workgroup has more than `module::MAX_WORKGROUP_LEN`
+  /// workitems.
+  WorkgroupTooLarge(Dim3),
+  Overflow,
+  /// A workitem panicked. The id is of the first workitem to do so.
+  KernelPanicked {
+    workgroup_id: Dim3,
+    workitem_id: Dim3,
+  },
+}
+impl Error {
+  /// The rustc/LLVM diagnostics emitted by a failed codegen, if this is a
+  /// codegen error.
+  pub fn diagnostics(&self) -> Option<&Diagnostics> {
+    match self {
+      Error::Codegen(diagnostics) |
+      Error::Linking(diagnostics) => Some(diagnostics),
+      Error::CodegenInitConditions(inner) |
+      Error::CodegenInitRoot(inner) |
+      Error::CodegenPostCodegen(inner) |
+      Error::CodegenPreCodegen(inner) => inner.diagnostics(),
+      _ => None,
+    }
+  }
+}
+impl StdError for Error {
+  fn source(&self) -> Option<&(dyn StdError + 'static)> {
+    match self {
+      Error::Generic(ref inner) => Some(&**inner),
+      Error::Io(inner) => Some(inner),
+      Error::CodegenInitConditions(inner) |
+      Error::CodegenInitRoot(inner) |
+      Error::CodegenPostCodegen(inner) |
+      Error::CodegenPreCodegen(inner) => Some(inner),
+      Error::KernelArgLayoutMismatch(inner) => Some(&**inner),
+      Error::SpecParam(inner) => Some(inner),
+      _ => None,
+    }
+  }
+}
+impl From<IoError> for Error {
+  #[inline(always)]
+  fn from(v: IoError) -> Self {
+    Error::Io(v)
+  }
+}
+impl From<grt_core::codegen::error::Error<Error>> for Error {
+  #[inline(always)]
+  fn from(v: grt_core::codegen::error::Error<Error>) -> Self {
+    use grt_core::codegen::error::Error::*;
+
+    match v {
+      Io(_, err) => Error::Io(err),
+      LoadMetadata(err) => Error::LoadRustcMetadata(err),
+      ConvertKernelInstance(ki) => Error::ConvertKernelInstance(ki),
+      Codegen(diagnostics) => Error::Codegen(diagnostics),
+      Linking(diagnostics) => Error::Linking(diagnostics),
+      InitRoot(inner) => Error::CodegenInitRoot(Box::new(inner)),
+      InitConditions(inner) => Error::CodegenInitConditions(Box::new(inner)),
+      PreCodegen(inner) => Error::CodegenPreCodegen(Box::new(inner)),
+      PostCodegen(inner) => Error::CodegenPostCodegen(Box::new(inner)),
+      LayoutMismatch(inner) => Error::KernelArgLayoutMismatch(inner),
+      Layout(msg) => Error::KernelArgLayout(msg),
+      UnknownSpecParam(param) => Error::UnknownSpecParam(param),
+      SpecParam(inner) => Error::SpecParam(inner),
+      Subprocess(msg) => Error::CodegenSubprocess(msg),
+      Server(msg) => Error::CodegenServer(msg),
+      ContextDead => Error::ContextDead,
+      AcceleratorRetired(id) => Error::AcceleratorRetired(id),
+    }
+  }
+}
+impl From<Box<dyn StdError + Send + Sync + 'static>> for Error {
+  fn from(v: Box<dyn StdError + Send + Sync + 'static>) -> Self {
+    v.downcast()
+      .map(|v| *v )
+      .or_else(|v| {
+        v.downcast()
+          .map(|v: Box<IoError>| Error::Io(*v) )
+      })
+      .unwrap_or_else(Error::Generic)
+  }
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Error::Codegen(diagnostics) => {
+        write!(f, "codegen failed:\n{}", diagnostics)
+      },
+      Error::Linking(diagnostics) => {
+        write!(f, "linking failed:\n{}", diagnostics)
+      },
+      Error::UnknownSpecParam(param) => {
+        write!(f, "spec param `{}` doesn't match any function in this program", param)
+      },
+      Error::SpecParam(inner) => fmt::Display::fmt(inner, f),
+      Error::CodegenSubprocess(msg) => write!(f, "subprocess codegen failed: {}", msg),
+      Error::CodegenServer(msg) => write!(f, "codegen server failed: {}", msg),
+      Error::KernelArgLayoutMismatch(inner) => fmt::Display::fmt(inner, f),
+      Error::KernelArgLayout(msg) => {
+        write!(f, "kernel argument layout check failed: {}", msg)
+      },
+      Error::WorkgroupTooLarge(wg) => {
+        write!(f, "workgroup {:?} has more than {} workitems", wg,
+               crate::module::MAX_WORKGROUP_LEN)
+      },
+      Error::KernelPanicked { workgroup_id, workitem_id, } => {
+        write!(f, "workitem {:?} of workgroup {:?} panicked",
+               workitem_id, workgroup_id)
+      },
+      _ => write!(f, "{:?}", self),
+    }
+  }
+}
@@ -0,0 +1,170 @@
+//! Crate for running kernels on the host CPU. Kernels are codegen-ed for the
+//! host triple, through the same codegen driver the GPU platforms use, and
+//! the grid is emulated with threads. Intended for testing kernel logic on
+//! machines without a GPU; it's not fast.
+
+#![feature(rustc_private)]
+#![feature(geobacter)]
+
+extern crate rustc_ast;
+extern crate rustc_data_structures;
+extern crate rustc_geobacter;
+extern crate rustc_index;
+extern crate rustc_middle;
+extern crate rustc_span;
+
+use std::any::Any;
+use std::error::Error as StdError;
+use std::fmt;
+use std::geobacter::platform::Platform;
+use std::sync::Arc;
+
+use grt_core::{AcceleratorId, Accelerator, AcceleratorTargetDesc, PlatformTargetDesc, Device};
+use grt_core::codegen::CodegenDriver;
+use grt_core::codegen::products::PCodegenResults;
+use grt_core::context::*;
+
+use serde::*;
+
+pub use crate::error::Error;
+pub use crate::module::{Dim3, FuncModule, Kernel, WorkItem, };
+
+pub mod codegen;
+pub mod error;
+pub mod module;
+
+mod utils;
+
+pub struct HostAccel {
+  id: AcceleratorId,
+  ctx: Context,
+  /// The maximum number of workgroups run at the same time.
+  concurrency: usize,
+
+  target_desc: Arc<AcceleratorTargetDesc>,
+
+  self_codegen: Option<Arc<CodegenDriver<codegen::HostPlatformCodegen>>>,
+}
+
+impl HostAccel {
+  fn new_raw(id: AcceleratorId, ctx: &Context, concurrency: usize)
+    -> Result<Arc<Self>, Error>
+  {
+    let target_desc = AcceleratorTargetDesc::new(HostTargetDesc);
+    let out = HostAccel {
+      id,
+      ctx: ctx.clone(),
+      concurrency,
+      target_desc: Arc::new(target_desc),
+      self_codegen: None,
+    };
+
+    let mut out = Arc::new(out);
+
+    ctx.initialize_accel(&mut out)?;
+
+    Ok(out)
+  }
+  /// Runs as many workgroups at the same time as there are CPUs.
+  #[inline(always)]
+  pub fn new(ctx: &Context) -> Result<Arc<Self>, Error> {
+    Self::with_concurrency(ctx, num_cpus::get())
+  }
+  #[inline(always)]
+  pub fn with_concurrency(ctx: &Context, concurrency: usize) -> Result<Arc<Self>, Error> {
+    let id = ctx.take_accel_id();
+    Self::new_raw(id, ctx, concurrency.max(1))
+  }
+
+  #[inline(always)]
+  pub fn ctx(&self) -> &Context { &self.ctx }
+  #[inline(always)]
+  pub fn concurrency(&self) -> usize { self.concurrency }
+}
+
+impl Accelerator for HostAccel {
+  #[inline(always)]
+  fn id(&self) -> AcceleratorId { self.id.clone() }
+
+  /// The host isn't one of the platforms in `Platform`.
+  #[inline(always)]
+  fn platform(&self) -> Option<Platform> { None }
+
+  #[inline(always)]
+  fn accel_target_desc(&self) -> &Arc<AcceleratorTargetDesc> {
+    &self.target_desc
+  }
+
+  fn set_accel_target_desc(&mut self, desc: Arc<AcceleratorTargetDesc>) {
+    self.target_desc = desc;
+  }
+
+  fn create_target_codegen(self: &mut Arc<Self>, ctxt: &Context)
+    -> Result<Arc<dyn Any + Send + Sync + 'static>, Box<dyn StdError + Send + Sync + 'static>>
+    where Self: Sized,
+  {
+    let cg = CodegenDriver::new(ctxt,
+                                self.accel_target_desc().clone(),
+                                Default::default())?;
+    let cg_sync = Arc::new(cg);
+    Arc::get_mut(self)
+      .expect("there should only be a single ref at this point")
+      .self_codegen = Some(cg_sync.clone());
+
+    cg_sync.add_accel(self);
+
+    Ok(cg_sync)
+  }
+
+  fn set_target_codegen(self: &mut Arc<Self>,
+                        codegen_comms: Arc<dyn Any + Send + Sync + 'static>)
+    where Self: Sized,
+  {
+    let cg = codegen_comms
+      .downcast()
+      .expect("unexpected codegen type?");
+
+    Arc::get_mut(self)
+      .expect("there should only be a single ref at this point")
+      .self_codegen = Some(cg);
+
+    self.codegen().add_accel(self);
+  }
+}
+
+impl Device for HostAccel {
+  type Error = Error;
+  type Codegen = codegen::HostPlatformCodegen;
+  type TargetDesc = HostTargetDesc;
+  type ModuleData = module::HostModuleData;
+
+  fn codegen(&self) -> &Arc<CodegenDriver<Self::Codegen>> {
+    self.self_codegen
+      .as_ref()
+      .expect("we are uninitialized?")
+  }
+
+  fn load_kernel(self: &Arc<Self>, codegen: &PCodegenResults<Self::Codegen>)
+    -> Result<Arc<Self::ModuleData>, Error>
+  {
+    Ok(Arc::new(module::HostModuleData::load(codegen)?))
+  }
+}
+impl fmt::Debug for HostAccel {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("HostAccel")
+      .field("id", &self.id)
+      .field("concurrency", &self.concurrency)
+      .field("target_desc", &self.target_desc)
+      .finish()
+  }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, Hash)]
+pub struct HostTargetDesc;
+
+impl PlatformTargetDesc for HostTargetDesc {
+  fn as_any_hash(&self) -> &dyn any_key::AnyHash {
+    self
+  }
+}
@@ -0,0 +1,1015 @@
+//! Loading and running kernels on the host.
+//!
+//! A dispatch is emulated by running workgroups concurrently, and running
+//! every workitem of a workgroup on its own thread, so that barriers behave
+//! as they would on a GPU. Workitems of the rounded up portion of the grid
+//! (ie past the end of the grid, in the last workgroup along an axis) are
+//! not run at all.
+
+use std::cell::Cell;
+use std::ffi::{CStr, CString, };
+use std::fmt;
+use std::geobacter::kernel::OptionalKernelFn;
+use std::io::Write;
+use std::marker::PhantomData;
+use std::os::raw::c_void;
+use std::panic::{self, AssertUnwindSafe, };
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, };
+use std::thread::JoinHandle;
+
+use crossbeam_utils::thread;
+
+use parking_lot::{Condvar, Mutex, };
+
+use grt_core::{Accelerator, AcceleratorId, Device, };
+use grt_core::codegen as core_codegen;
+use grt_core::codegen::{CodegenOptions, PKernelDesc, };
+use grt_core::codegen::products::PCodegenResults;
+use grt_core::context::{ModuleContextData, PlatformModuleData, };
+use grt_core::launch::{self as core_launch, LaunchGrid, };
+
+use crate::{Error, HostAccel, };
+use crate::codegen::{HostKernelDesc, HostPlatformCodegen, };
+
+/// Workitem stack size. Same as Rust's default.
+const WORKITEM_STACK_SIZE: usize = 2 * 1024 * 1024;
+/// Alignment of workgroup local memory.
+const GROUP_LOCAL_ALIGN: usize = 16;
+/// The largest workgroup, in workitems. Every workitem gets its own thread.
+pub const MAX_WORKGROUP_LEN: usize = 1024;
+/// The most workitem threads alive at once, across every concurrently run
+/// workgroup.
+const MAX_WORKITEM_THREADS: usize = 4 * MAX_WORKGROUP_LEN;
+
+/// A grid, workgroup, or id thereof.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub struct Dim3 {
+  pub x: u32,
+  pub y: u32,
+  pub z: u32,
+}
+impl Dim3 {
+  pub const fn new(x: u32, y: u32, z: u32) -> Self {
+    Dim3 { x, y, z, }
+  }
+  pub const fn one() -> Self { Dim3::new(1, 1, 1) }
+
+  pub fn len(&self) -> usize {
+    self.x as usize * self.y as usize * self.z as usize
+  }
+  pub fn is_zero(&self) -> bool {
+    self.x == 0 || self.y == 0 || self.z == 0
+  }
+  /// Row major, x fastest.
+  pub fn linear_id(&self, id: Dim3) -> usize {
+    (id.z as usize * self.y as usize + id.y as usize) * self.x as usize +
+      id.x as usize
+  }
+  /// The inverse of `linear_id`.
+  pub fn from_linear_id(&self, id: usize) -> Dim3 {
+    let x = self.x as usize;
+    let y = self.y as usize;
+    Dim3 {
+      x: (id % x) as u32,
+      y: ((id / x) % y) as u32,
+      z: (id / (x * y)) as u32,
+    }
+  }
+  /// The number of workgroups of size `wg` needed to cover this grid.
+  /// Fails if the rounded up grid doesn't fit in a `u32` along an axis,
+  /// which also means `mul_add` can't overflow for any workitem of the
+  /// dispatch.
+  fn workgroups(&self, wg: Dim3) -> Result<Dim3, Error> {
+    let axis = |grid: u32, wg: u32| {
+      grid.checked_add(wg - 1)
+        .map(|v| v / wg )
+        .ok_or(Error::Overflow)
+    };
+    Ok(Dim3 {
+      x: axis(self.x, wg.x)?,
+      y: axis(self.y, wg.y)?,
+      z: axis(self.z, wg.z)?,
+    })
+  }
+  fn mul_add(&self, rhs: Dim3, add: Dim3) -> Result<Dim3, Error> {
+    let axis = |lhs: u32, rhs: u32, add: u32| {
+      lhs.checked_mul(rhs)
+        .and_then(|v| v.checked_add(add) )
+        .ok_or(Error::Overflow)
+    };
+    Ok(Dim3 {
+      x: axis(self.x, rhs.x, add.x)?,
+      y: axis(self.y, rhs.y, add.y)?,
+      z: axis(self.z, rhs.z, add.z)?,
+    })
+  }
+  fn contains(&self, id: Dim3) -> bool {
+    id.x < self.x && id.y < self.y && id.z < self.z
+  }
+}
+impl From<u32> for Dim3 {
+  fn from(x: u32) -> Self { Dim3::new(x, 1, 1) }
+}
+impl From<(u32, u32)> for Dim3 {
+  fn from((x, y): (u32, u32)) -> Self { Dim3::new(x, y, 1) }
+}
+impl From<(u32, u32, u32)> for Dim3 {
+  fn from((x, y, z): (u32, u32, u32)) -> Self { Dim3::new(x, y, z) }
+}
+impl From<LaunchGrid> for Dim3 {
+  fn from(v: LaunchGrid) -> Self { Dim3::new(v.x, v.y, v.z) }
+}
+
+/// Kernel arguments. `kernel` is run once per workitem.
+pub trait Kernel: Sync + Sized {
+  const WORKGROUP: Dim3 = Dim3::one();
+  /// Bytes of workgroup local memory, shared by every workitem in a
+  /// workgroup. Zeroed before the workgroup starts.
+  const GROUP_LOCAL_SIZE: usize = 0;
+
+  fn kernel(&self, item: &WorkItem);
+}
+
+/// The barrier every workitem of a workgroup shares. Poisoned when a
+/// workitem panics, so that the others don't wait forever.
+#[repr(C)]
+struct GroupBarrier {
+  state: Mutex<BarrierState>,
+  cvar: Condvar,
+}
+struct BarrierState {
+  len: usize,
+  waiting: usize,
+  generation: usize,
+  poisoned: bool,
+}
+impl GroupBarrier {
+  fn new(len: usize) -> Self {
+    GroupBarrier {
+      state: Mutex::new(BarrierState {
+        len,
+        waiting: 0,
+        generation: 0,
+        poisoned: false,
+      }),
+      cvar: Condvar::new(),
+    }
+  }
+  fn wait(&self) {
+    let mut state = self.state.lock();
+    if state.poisoned {
+      panic!("another workitem in this workgroup panicked");
+    }
+    let generation = state.generation;
+    state.waiting += 1;
+    if state.waiting == state.len {
+      state.waiting = 0;
+      state.generation = state.generation.wrapping_add(1);
+      self.cvar.notify_all();
+      return;
+    }
+    while generation == state.generation && !state.poisoned {
+      self.cvar.wait(&mut state);
+    }
+    if state.poisoned {
+      panic!("another workitem in this workgroup panicked");
+    }
+  }
+  fn poison(&self) {
+    self.state.lock().poisoned = true;
+    self.cvar.notify_all();
+  }
+}
+
+/// The ids of the current workitem, and its workgroup's shared resources.
+/// Passed to `Kernel::kernel`.
+#[repr(C)]
+pub struct WorkItem {
+  grid: Dim3,
+  workgroup: Dim3,
+  workgroup_id: Dim3,
+  workitem_id: Dim3,
+  global_id: Dim3,
+  barrier: *const GroupBarrier,
+  group_local: *mut u8,
+  group_local_len: usize,
+}
+impl WorkItem {
+  /// The size of the whole dispatch.
+  #[inline(always)]
+  pub fn grid_size(&self) -> Dim3 { self.grid }
+  #[inline(always)]
+  pub fn workgroup_size(&self) -> Dim3 { self.workgroup }
+  #[inline(always)]
+  pub fn workgroup_id(&self) -> Dim3 { self.workgroup_id }
+  /// The id within the workgroup.
+  #[inline(always)]
+  pub fn workitem_id(&self) -> Dim3 { self.workitem_id }
+  #[inline(always)]
+  pub fn global_id(&self) -> Dim3 { self.global_id }
+  #[inline(always)]
+  pub fn global_linear_id(&self) -> usize {
+    self.grid.linear_id(self.global_id())
+  }
+  #[inline(always)]
+  pub fn is_wi0(&self) -> bool {
+    self.workitem_id == Dim3::default()
+  }
+
+  /// Wait for every (in grid) workitem in this workgroup to get here.
+  #[inline(always)]
+  pub fn barrier(&self) {
+    unsafe { (*self.barrier).wait() }
+  }
+
+  /// This workgroup's local memory: `Kernel::GROUP_LOCAL_SIZE` bytes,
+  /// aligned to 16 bytes.
+  #[inline(always)]
+  pub fn group_local_ptr(&self) -> *mut u8 { self.group_local }
+  #[inline(always)]
+  pub fn group_local_len(&self) -> usize { self.group_local_len }
+  /// Workgroup local memory as a `T`. Panics if `T` is larger or more
+  /// aligned than the local memory.
+  /// Unsafe because `T` must be valid when zeroed, and access must be
+  /// synchronized by the kernel (ie with `barrier` or atomics).
+  pub unsafe fn group_local<T>(&self) -> &T
+    where T: Sync,
+  {
+    assert!(std::mem::size_of::<T>() <= self.group_local_len,
+            "workgroup local memory is too small");
+    assert!(std::mem::align_of::<T>() <= GROUP_LOCAL_ALIGN);
+    &*(self.group_local as *const T)
+  }
+}
+
+thread_local! {
+  /// Set by `launch_kernel` for the duration of the kernel.
+  static CURRENT: Cell<*const WorkItem> = Cell::new(ptr::null());
+}
+fn with_current<F, R>(f: F) -> R
+  where F: FnOnce(&WorkItem) -> R,
+{
+  CURRENT.with(|current| {
+    let current = current.get();
+    assert!(!current.is_null(), "not running in a host kernel");
+    f(unsafe { &*current })
+  })
+}
+
+/// The id of the current workitem within its workgroup. Panics outside of
+/// a kernel.
+pub fn workitem_id() -> Dim3 { with_current(WorkItem::workitem_id) }
+pub fn workgroup_id() -> Dim3 { with_current(WorkItem::workgroup_id) }
+pub fn workgroup_size() -> Dim3 { with_current(WorkItem::workgroup_size) }
+pub fn grid_size() -> Dim3 { with_current(WorkItem::grid_size) }
+pub fn global_id() -> Dim3 { with_current(WorkItem::global_id) }
+/// `WorkItem::barrier` of the current workitem.
+pub fn barrier() { with_current(WorkItem::barrier) }
+
+/// The per-axis ids the device workitem intrinsics are lowered to; see
+/// `crate::codegen::intrinsics`.
+pub(crate) mod intrinsics {
+  pub fn workitem_x_id() -> u32 { super::workitem_id().x }
+  pub fn workitem_y_id() -> u32 { super::workitem_id().y }
+  pub fn workitem_z_id() -> u32 { super::workitem_id().z }
+  pub fn workgroup_x_id() -> u32 { super::workgroup_id().x }
+  pub fn workgroup_y_id() -> u32 { super::workgroup_id().y }
+  pub fn workgroup_z_id() -> u32 { super::workgroup_id().z }
+}
+
+/// The kernel root. Compiled into the kernel's shared object, so `CURRENT`
+/// here is the kernel's copy.
+fn launch_kernel<A>(item: &WorkItem, args: &A) -> bool
+  where A: Kernel,
+{
+  CURRENT.with(|current| current.set(item) );
+  let r = panic::catch_unwind(AssertUnwindSafe(|| args.kernel(item) ));
+  CURRENT.with(|current| current.set(ptr::null()) );
+  if r.is_err() {
+    unsafe { (*item.barrier).poison(); }
+  }
+  r.is_ok()
+}
+type KernelEntry = unsafe extern "C" fn(*const WorkItem, *const c_void) -> bool;
+
+/// The desc of `A`'s kernel, with no spec params defined.
+pub(crate) fn kernel_desc<A>() -> PKernelDesc<HostPlatformCodegen>
+  where A: Kernel,
+{
+  let f = launch_kernel::<A>;
+  core_codegen::KernelDesc {
+    instance: f.kernel_instance(),
+    spec_params: Default::default(),
+    options: None,
+    platform_desc: HostKernelDesc,
+  }
+}
+
+/// A loaded kernel shared object.
+pub struct HostModuleData {
+  lib: *mut c_void,
+  entry: KernelEntry,
+  symbol: String,
+}
+unsafe impl Send for HostModuleData { }
+unsafe impl Sync for HostModuleData { }
+impl HostModuleData {
+  pub(crate) fn load(codegen: &PCodegenResults<HostPlatformCodegen>)
+    -> Result<Self, Error>
+  {
+    let exe = codegen.exe_ref()
+      .ok_or_else(|| Error::Generic("no shared object from post_codegen".into()) )?;
+    let symbol = codegen.root().symbol.clone();
+
+    // the file is deleted once we've loaded it.
+    let mut file = tempfile::Builder::new()
+      .prefix("geobacter-host-kernel-")
+      .suffix(".so")
+      .tempfile()?;
+    file.write_all(exe)?;
+    file.flush()?;
+
+    let path = CString::new(file.path().to_str().unwrap())
+      .expect("unexpected null char");
+    let lib = unsafe { libc::dlopen(path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+    if lib.is_null() {
+      return Err(Error::Dl(dlerror()));
+    }
+
+    let csymbol = CString::new(symbol.clone())
+      .expect("unexpected null char");
+    let entry = unsafe { libc::dlsym(lib, csymbol.as_ptr()) };
+    if entry.is_null() {
+      unsafe { libc::dlclose(lib); }
+      return Err(Error::MissingKernelSymbol(symbol));
+    }
+
+    Ok(HostModuleData {
+      lib,
+      entry: unsafe { std::mem::transmute(entry) },
+      symbol,
+    })
+  }
+  pub fn symbol(&self) -> &str { &self.symbol }
+}
+impl Drop for HostModuleData {
+  fn drop(&mut self) {
+    unsafe { libc::dlclose(self.lib); }
+  }
+}
+impl fmt::Debug for HostModuleData {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("HostModuleData")
+      .field("lib", &self.lib)
+      .field("symbol", &self.symbol)
+      .finish()
+  }
+}
+impl PlatformModuleData for HostModuleData {
+  fn eq(&self, rhs: &dyn PlatformModuleData) -> bool {
+    let rhs: Option<&Self> = Self::downcast_ref(rhs);
+    if let Some(rhs) = rhs {
+      self.lib == rhs.lib && self.symbol == rhs.symbol
+    } else {
+      false
+    }
+  }
+}
+
+fn dlerror() -> String {
+  unsafe {
+    let err = libc::dlerror();
+    if err.is_null() {
+      "unknown dl error".into()
+    } else {
+      CStr::from_ptr(err).to_string_lossy().into_owned()
+    }
+  }
+}
+
+/// A kernel, `A`, on a host accelerator.
+pub struct FuncModule<A>
+  where A: Kernel,
+{
+  device: Arc<HostAccel>,
+  context_data: ModuleContextData,
+  module_data: Option<Arc<HostModuleData>>,
+  options: Option<Arc<CodegenOptions>>,
+  _args: PhantomData<fn(&A)>,
+}
+impl<A> FuncModule<A>
+  where A: Kernel,
+{
+  pub fn new(device: &Arc<HostAccel>) -> Self {
+    let f = launch_kernel::<A>;
+    FuncModule {
+      device: device.clone(),
+      context_data: ModuleContextData::get(&f),
+      module_data: None,
+      options: None,
+      _args: PhantomData,
+    }
+  }
+  pub fn device(&self) -> &Arc<HostAccel> { &self.device }
+
+  /// Override the accelerator's codegen options for this kernel. If this
+  /// function was already compiled, it will be compiled again.
+  pub fn set_codegen_options(&mut self, options: Option<CodegenOptions>) {
+    self.module_data.take();
+    self.options = options.map(Arc::new);
+  }
+  pub fn codegen_options(&self) -> Option<&Arc<CodegenOptions>> {
+    self.options.as_ref()
+  }
+
+  fn desc(&self) -> PKernelDesc<HostPlatformCodegen> {
+    let mut desc = kernel_desc::<A>();
+    desc.options = self.options.clone();
+    desc
+  }
+  fn compile_internal(&mut self) -> Result<&HostModuleData, Error> {
+    if self.device.ctx().is_accel_retired(self.device.id()) {
+      self.module_data.take();
+      return Err(Error::AcceleratorRetired(self.device.id()));
+    }
+    if self.module_data.is_none() {
+      let module_data = self.context_data
+        .get_cache_data(self.device.ctx())
+        .compile(&self.device, self.desc(),
+                 self.device.codegen(),
+                 cfg!(test))?;
+      self.module_data = Some(module_data);
+    }
+    Ok(self.module_data.as_ref().unwrap())
+  }
+  pub fn compile(&mut self) -> Result<(), Error> {
+    self.compile_internal()?;
+    Ok(())
+  }
+
+  /// Run `args` over `grid` and wait for every workitem to finish.
+  pub fn launch<G>(&mut self, grid: G, args: &A) -> Result<(), Error>
+    where G: Into<Dim3>,
+  {
+    let (dispatch, wg_count) = self.dispatch(grid.into(), args)?;
+    dispatch.run(wg_count, self.device.concurrency())
+  }
+
+  /// Checks the grid and compiles. `args` must outlive the returned
+  /// `Dispatch`.
+  fn dispatch(&mut self, grid: Dim3, args: *const A)
+    -> Result<(Dispatch, Dim3), Error>
+  {
+    if grid.is_zero() { return Err(Error::ZeroGridLaunchAxis); }
+    if A::WORKGROUP.is_zero() { return Err(Error::ZeroWorkgroupAxis); }
+    if A::WORKGROUP.len() > MAX_WORKGROUP_LEN {
+      return Err(Error::WorkgroupTooLarge(A::WORKGROUP));
+    }
+    let wg_count = grid.workgroups(A::WORKGROUP)?;
+    wg_count.len().checked_mul(A::WORKGROUP.len())
+      .ok_or(Error::Overflow)?;
+
+    let entry = self.compile_internal()?.entry;
+    let dispatch = Dispatch {
+      entry,
+      args: args as *const c_void,
+      grid,
+      workgroup: A::WORKGROUP,
+      group_local_len: A::GROUP_LOCAL_SIZE,
+    };
+    Ok((dispatch, wg_count))
+  }
+}
+/// The launch is run on a new thread, which owns `args` until the launch
+/// finishes.
+impl<A> core_launch::KernelLauncher<A> for FuncModule<A>
+  where A: Kernel + Send + 'static,
+{
+  fn accel_id(&self) -> AcceleratorId { self.device.id() }
+  unsafe fn launch(&mut self, grid: LaunchGrid, args: A)
+    -> Result<core_launch::Completion, core_launch::LaunchError>
+  {
+    let args = Box::new(args);
+    let (dispatch, wg_count) = self.dispatch(grid.into(), &*args)?;
+    let concurrency = self.device.concurrency();
+
+    let done = Arc::new(AtomicBool::new(false));
+    let thread_done = done.clone();
+    let thread = std::thread::Builder::new()
+      .name(format!("grt-host-{:?}-launch", self.device.id()))
+      .spawn(move || {
+        let r = dispatch.run(wg_count, concurrency);
+        drop(args);
+        thread_done.store(true, Ordering::Release);
+        r
+      })?;
+
+    Ok(core_launch::Completion::new(LaunchCompletion {
+      done,
+      thread: Mutex::new(Some(thread)),
+    }))
+  }
+}
+impl<A> Clone for FuncModule<A>
+  where A: Kernel,
+{
+  fn clone(&self) -> Self {
+    FuncModule {
+      device: self.device.clone(),
+      context_data: self.context_data,
+      module_data: self.module_data.clone(),
+      options: self.options.clone(),
+      _args: PhantomData,
+    }
+  }
+}
+
+/// The type erased parts of a launch.
+struct Dispatch {
+  entry: KernelEntry,
+  args: *const c_void,
+  grid: Dim3,
+  workgroup: Dim3,
+  group_local_len: usize,
+}
+unsafe impl Send for Dispatch { }
+unsafe impl Sync for Dispatch { }
+impl Dispatch {
+  fn run(&self, wg_count: Dim3, concurrency: usize) -> Result<(), Error> {
+    let next_wg = AtomicUsize::new(0);
+    let failed: Mutex<Option<Error>> = Mutex::new(None);
+    // each workgroup runs a thread per workitem:
+    let max_workers = (MAX_WORKITEM_THREADS / self.workgroup.len()).max(1);
+    let workers = concurrency.max(1)
+      .min(max_workers)
+      .min(wg_count.len());
+
+    let scope = thread::scope(|s| {
+      for _ in 0..workers {
+        s.spawn(|_| {
+          loop {
+            let wg = next_wg.fetch_add(1, Ordering::Relaxed);
+            if wg >= wg_count.len() || failed.lock().is_some() { break; }
+            let wg = wg_count.from_linear_id(wg);
+            if let Err(err) = self.run_workgroup(wg) {
+              failed.lock().get_or_insert(err);
+            }
+          }
+        });
+      }
+    });
+    if let Err(err) = scope {
+      // only workitem threads can panic, and those are caught.
+      panic::resume_unwind(err);
+    }
+
+    match failed.into_inner() {
+      Some(err) => Err(err),
+      None => Ok(()),
+    }
+  }
+  fn run_workgroup(&self, workgroup_id: Dim3) -> Result<(), Error> {
+    // `dispatch` checked that the rounded up grid fits, so this can't
+    // overflow:
+    let items: Vec<(Dim3, Dim3)> = (0..self.workgroup.len())
+      .map(|wi| self.workgroup.from_linear_id(wi) )
+      .map(|wi| Ok((wi, workgroup_id.mul_add(self.workgroup, wi)?)) )
+      .filter(|r: &Result<_, Error>| {
+        r.as_ref().map(|&(_, id)| self.grid.contains(id) ).unwrap_or(true)
+      })
+      .collect::<Result<_, _>>()?;
+
+    let barrier = GroupBarrier::new(items.len());
+    // u128 for the alignment
+    let mut group_local = vec![0u128; (self.group_local_len + 15) / 16];
+    let group_local_ptr = group_local.as_mut_ptr() as *mut u8;
+
+    let item = |(workitem_id, global_id)| WorkItem {
+      grid: self.grid,
+      workgroup: self.workgroup,
+      workgroup_id,
+      workitem_id,
+      global_id,
+      barrier: &barrier,
+      group_local: group_local_ptr,
+      group_local_len: self.group_local_len,
+    };
+    let run = |item: WorkItem| -> Result<(), Error> {
+      if unsafe { (self.entry)(&item, self.args) } {
+        Ok(())
+      } else {
+        Err(Error::KernelPanicked {
+          workgroup_id,
+          workitem_id: item.workitem_id,
+        })
+      }
+    };
+
+    if items.len() == 1 {
+      // no need for another thread.
+      return run(item(items[0]));
+    }
+
+    let items: Vec<_> = items.into_iter().map(item).collect();
+    let mut first_err = None;
+    thread::scope(|s| {
+      let mut handles = Vec::with_capacity(items.len());
+      for item in items.into_iter() {
+        let spawned = s.builder()
+          .stack_size(WORKITEM_STACK_SIZE)
+          .spawn(move |_| run(item) );
+        match spawned {
+          Ok(handle) => handles.push(handle),
+          Err(err) => {
+            // the workitems already running would wait at the barrier
+            // forever for this one:
+            barrier.poison();
+            first_err = Some(Error::Io(err));
+            break;
+          },
+        }
+      }
+      for handle in handles {
+        if let Err(err) = handle.join().unwrap() {
+          first_err.get_or_insert(err);
+        }
+      }
+    }).unwrap();
+
+    drop(group_local);
+
+    match first_err {
+      Some(err) => Err(err),
+      None => Ok(()),
+    }
+  }
+}
+
+/// `WorkItem` contains raw pointers to its workgroup's shared state, which
+/// outlives every workitem thread.
+unsafe impl Send for WorkItem { }
+unsafe impl Sync for WorkItem { }
+
+struct LaunchCompletion {
+  done: Arc<AtomicBool>,
+  thread: Mutex<Option<JoinHandle<Result<(), Error>>>>,
+}
+impl core_launch::LaunchCompletion for LaunchCompletion {
+  fn is_complete(&self) -> bool { self.done.load(Ordering::Acquire) }
+  fn wait(&self) -> Result<(), core_launch::LaunchError> {
+    let thread = self.thread.lock().take()
+      .expect("launch already waited on");
+    match thread.join() {
+      Ok(r) => Ok(r?),
+      Err(err) => panic::resume_unwind(err),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::sync::atomic::AtomicU32;
+
+  use crate::utils::test::*;
+
+  struct GlobalIds<'a> {
+    out: &'a [AtomicU32],
+  }
+  impl<'a> Kernel for GlobalIds<'a> {
+    const WORKGROUP: Dim3 = Dim3::new(4, 2, 1);
+    fn kernel(&self, item: &WorkItem) {
+      let id = item.global_linear_id();
+      self.out[id].store(id as u32 + 1, Ordering::Relaxed);
+    }
+  }
+
+  #[test]
+  fn global_ids() {
+    let dev = device();
+    // not a multiple of the workgroup size:
+    let grid = Dim3::new(7, 3, 1);
+    let out: Vec<_> = (0..grid.len()).map(|_| AtomicU32::new(0) ).collect();
+    let args = GlobalIds { out: &out, };
+    let mut m = FuncModule::new(&dev);
+    m.launch(grid, &args).unwrap();
+    for (id, v) in out.iter().enumerate() {
+      assert_eq!(v.load(Ordering::Relaxed), id as u32 + 1);
+    }
+  }
+
+  struct GlobalIdsOwned {
+    out: Arc<Vec<AtomicU32>>,
+  }
+  impl Kernel for GlobalIdsOwned {
+    const WORKGROUP: Dim3 = Dim3::new(4, 2, 1);
+    fn kernel(&self, item: &WorkItem) {
+      let id = item.global_linear_id();
+      self.out[id].store(id as u32 + 1, Ordering::Relaxed);
+    }
+  }
+
+  #[test]
+  fn kernel_launcher() {
+    use grt_core::launch::KernelLauncher;
+
+    let dev = device();
+    let grid = LaunchGrid::new(7, 3, 1);
+    let out: Arc<Vec<_>> = Arc::new((0..grid.len()).map(|_| AtomicU32::new(0) ).collect());
+    let args = GlobalIdsOwned { out: out.clone(), };
+    let mut m: Box<dyn KernelLauncher<GlobalIdsOwned>> = Box::new(FuncModule::new(&dev));
+    assert_eq!(m.accel_id(), dev.id());
+    unsafe { m.launch(grid, args) }.unwrap()
+      .wait().unwrap();
+    for (id, v) in out.iter().enumerate() {
+      assert_eq!(v.load(Ordering::Relaxed), id as u32 + 1);
+    }
+  }
+
+  struct WorkgroupSum<'a> {
+    input: &'a [u32],
+    out: &'a [AtomicU32],
+  }
+  impl<'a> Kernel for WorkgroupSum<'a> {
+    const WORKGROUP: Dim3 = Dim3::new(8, 1, 1);
+    const GROUP_LOCAL_SIZE: usize = 8 * 4;
+    fn kernel(&self, item: &WorkItem) {
+      let local: &[AtomicU32; 8] = unsafe { item.group_local() };
+      let wi = item.workitem_id().x as usize;
+      local[wi].store(self.input[item.global_linear_id()], Ordering::Relaxed);
+      item.barrier();
+      if item.is_wi0() {
+        let sum = local.iter().map(|v| v.load(Ordering::Relaxed) ).sum();
+        self.out[item.workgroup_id().x as usize].store(sum, Ordering::Relaxed);
+      }
+    }
+  }
+
+  #[test]
+  fn workgroup_local_and_barrier() {
+    let dev = device();
+    let input: Vec<u32> = (0..32).collect();
+    let out: Vec<_> = (0..4).map(|_| AtomicU32::new(0) ).collect();
+    let args = WorkgroupSum { input: &input, out: &out, };
+    let mut m = FuncModule::new(&dev);
+    m.launch(32u32, &args).unwrap();
+    for (wg, v) in out.iter().enumerate() {
+      let expected: u32 = input[wg * 8..(wg + 1) * 8].iter().sum();
+      assert_eq!(v.load(Ordering::Relaxed), expected);
+    }
+  }
+
+  struct Panics;
+  impl Kernel for Panics {
+    const WORKGROUP: Dim3 = Dim3::new(4, 1, 1);
+    fn kernel(&self, item: &WorkItem) {
+      if item.workitem_id().x == 2 {
+        panic!("workitem 2");
+      }
+      // the others must not deadlock:
+      item.barrier();
+    }
+  }
+
+  #[test]
+  fn panics_are_reported() {
+    let dev = device();
+    let mut m = FuncModule::new(&dev);
+    match m.launch(4u32, &Panics) {
+      Err(Error::KernelPanicked { .. }) => { },
+      r => panic!("unexpected launch result: {:?}", r),
+    }
+  }
+
+  #[inline(never)]
+  fn stubbed_value() -> u32 { 1 }
+  fn replacement_value() -> u32 { 2 }
+
+  struct CallsStubbed<'a> {
+    out: &'a [AtomicU32],
+  }
+  impl<'a> Kernel for CallsStubbed<'a> {
+    const WORKGROUP: Dim3 = Dim3::new(4, 1, 1);
+    fn kernel(&self, item: &WorkItem) {
+      self.out[item.global_linear_id()].store(stubbed_value(), Ordering::Relaxed);
+    }
+  }
+
+  #[test]
+  fn stub_replaces_function() {
+    use grt_core::codegen::stubbing::Stub;
+
+    let dev = device();
+    let out: Vec<_> = (0..4).map(|_| AtomicU32::new(0) ).collect();
+    let args = CallsStubbed { out: &out, };
+    let mut m = FuncModule::new(&dev);
+    let path = concat!(module_path!(), "::stubbed_value");
+    let options = CodegenOptions::default()
+      .with_path_stub(path, Stub::replace(replacement_value));
+    m.set_codegen_options(Some(options));
+    m.launch(4u32, &args).unwrap();
+    for v in out.iter() {
+      assert_eq!(v.load(Ordering::Relaxed), 2);
+    }
+  }
+
+  #[test]
+  fn noop_stub_of_non_unit_fn_fails() {
+    use grt_core::codegen::stubbing::Stub;
+
+    let dev = device();
+    let out: Vec<_> = (0..4).map(|_| AtomicU32::new(0) ).collect();
+    let args = CallsStubbed { out: &out, };
+    let mut m = FuncModule::<CallsStubbed>::new(&dev);
+    let path = concat!(module_path!(), "::stubbed_value");
+    let options = CodegenOptions::default()
+      .with_path_stub(path, Stub::Noop);
+    m.set_codegen_options(Some(options));
+    let err = m.compile().unwrap_err();
+    let diagnostics = err.diagnostics()
+      .expect("expected a codegen error");
+    assert!(diagnostics.to_string().contains("can't be a no-op stub"),
+            "unexpected diagnostics: {}", diagnostics);
+    // nothing was run:
+    assert!(m.launch(4u32, &args).is_err());
+    assert!(out.iter().all(|v| v.load(Ordering::Relaxed) == 0 ));
+  }
+
+  #[inline(never)]
+  fn forbidden() -> u32 { 1 }
+
+  struct CallsForbidden<'a> {
+    out: &'a [AtomicU32],
+  }
+  impl<'a> Kernel for CallsForbidden<'a> {
+    fn kernel(&self, item: &WorkItem) {
+      self.out[item.global_linear_id()].store(forbidden(), Ordering::Relaxed);
+    }
+  }
+
+  fn forbidden_calls_module(level: grt_core::codegen::ForbiddenCalls)
+    -> FuncModule<CallsForbidden<'static>>
+  {
+    use grt_core::codegen::stubbing::Stub;
+
+    let mut m = FuncModule::new(&device());
+    let options = CodegenOptions::default()
+      .with_path_stub(concat!(module_path!(), "::forbidden"), Stub::Abort)
+      .with_forbidden_calls(level);
+    m.set_codegen_options(Some(options));
+    m
+  }
+
+  #[test]
+  fn forbidden_call_denied() {
+    use grt_core::codegen::ForbiddenCalls;
+
+    let mut m = forbidden_calls_module(ForbiddenCalls::Deny);
+    let err = m.compile().unwrap_err();
+    let diagnostics = err.diagnostics()
+      .expect("expected a codegen error")
+      .to_string();
+    assert!(diagnostics.contains("is stubbed to abort on the device"),
+            "unexpected diagnostics: {}", diagnostics);
+    // reported with the path from the kernel:
+    assert!(diagnostics.contains("reached from the kernel through"),
+            "unexpected diagnostics: {}", diagnostics);
+  }
+
+  #[inline(never)]
+  fn replacement() -> u32 { 2 }
+
+  #[test]
+  fn replaced_call_allowed() {
+    use grt_core::codegen::ForbiddenCalls;
+    use grt_core::codegen::stubbing::Stub;
+
+    // replacements are real implementations, so aren't forbidden:
+    let mut m: FuncModule<CallsForbidden<'static>> = FuncModule::new(&device());
+    let options = CodegenOptions::default()
+      .with_path_stub(concat!(module_path!(), "::forbidden"), Stub::replace(replacement))
+      .with_forbidden_calls(ForbiddenCalls::Deny);
+    m.set_codegen_options(Some(options));
+    m.compile().unwrap();
+  }
+
+  #[test]
+  fn forbidden_call_warned() {
+    use grt_core::codegen::ForbiddenCalls;
+
+    let mut m = forbidden_calls_module(ForbiddenCalls::Warn);
+    m.compile().unwrap();
+  }
+
+  #[test]
+  fn dim3_linear_id_roundtrip() {
+    let d = Dim3::new(3, 4, 5);
+    for i in 0..d.len() {
+      assert_eq!(d.linear_id(d.from_linear_id(i)), i);
+    }
+    assert_eq!(Dim3::new(7, 3, 1).workgroups(Dim3::new(4, 2, 1)).unwrap(),
+               Dim3::new(2, 2, 1));
+  }
+
+  #[test]
+  fn dim3_overflow() {
+    match Dim3::new(u32::max_value(), 1, 1).workgroups(Dim3::new(4, 1, 1)) {
+      Err(Error::Overflow) => { },
+      r => panic!("unexpected result: {:?}", r),
+    }
+    match Dim3::new(2, 1, 1).mul_add(Dim3::new(u32::max_value(), 1, 1), Dim3::default()) {
+      Err(Error::Overflow) => { },
+      r => panic!("unexpected result: {:?}", r),
+    }
+    assert_eq!(Dim3::new(2, 1, 1).mul_add(Dim3::new(4, 1, 1), Dim3::new(3, 0, 0)).unwrap(),
+               Dim3::new(11, 1, 1));
+  }
+
+  struct TooLarge;
+  impl Kernel for TooLarge {
+    const WORKGROUP: Dim3 = Dim3::new(MAX_WORKGROUP_LEN as u32 + 1, 1, 1);
+    fn kernel(&self, _: &WorkItem) { }
+  }
+
+  #[test]
+  fn workgroup_too_large() {
+    let dev = device();
+    let mut m = FuncModule::new(&dev);
+    match m.launch(1u32, &TooLarge) {
+      Err(Error::WorkgroupTooLarge(wg)) => assert_eq!(wg, TooLarge::WORKGROUP),
+      r => panic!("unexpected launch result: {:?}", r),
+    }
+  }
+
+  /// Written against the device intrinsics, as an AMDGPU kernel would be.
+  struct DeviceIds<'a> {
+    out: &'a [AtomicU32],
+  }
+  impl<'a> Kernel for DeviceIds<'a> {
+    const WORKGROUP: Dim3 = Dim3::new(4, 2, 1);
+    fn kernel(&self, item: &WorkItem) {
+      use std::geobacter::amdgpu::workitem::*;
+
+      let wg = Dim3::new(XAxis.workgroup_id() as _, YAxis.workgroup_id() as _,
+                         ZAxis.workgroup_id() as _);
+      let wi = Dim3::new(XAxis.workitem_id() as _, YAxis.workitem_id() as _,
+                         ZAxis.workitem_id() as _);
+      let id = item.global_linear_id();
+      let ok = wg == item.workgroup_id() && wi == item.workitem_id();
+      self.out[id].store(ok as u32 + 1, Ordering::Relaxed);
+    }
+  }
+
+  #[test]
+  fn device_workitem_intrinsics() {
+    let dev = device();
+    let grid = Dim3::new(7, 3, 1);
+    let out: Vec<_> = (0..grid.len()).map(|_| AtomicU32::new(0) ).collect();
+    let args = DeviceIds { out: &out, };
+    let mut m = FuncModule::new(&dev);
+    m.launch(grid, &args).unwrap();
+    for v in out.iter() {
+      assert_eq!(v.load(Ordering::Relaxed), 2);
+    }
+  }
+
+  #[test]
+  fn codegen_server() {
+    use grt_core::codegen::CodegenServer;
+    use grt_core::context::Context;
+
+    // new contexts, so nothing is already cached in memory:
+    let ctx = Context::new().unwrap();
+    let dev = HostAccel::new(&ctx).unwrap();
+    dev.codegen().set_disk_cache(None).unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("codegen.sock");
+    let server_ctx = Context::new().unwrap();
+    let server = CodegenServer::bind(&server_ctx, HostPlatformCodegen, path.clone())
+      .unwrap();
+    let server_driver = server.driver(dev.accel_target_desc().clone()).unwrap();
+    server_driver.set_disk_cache(None).unwrap();
+    {
+      let server = server.clone();
+      std::thread::spawn(move || server.serve() );
+    }
+
+    dev.codegen().set_codegen_server(Some(&path)).unwrap();
+    let grid = Dim3::new(7, 3, 1);
+    let out: Vec<_> = (0..grid.len()).map(|_| AtomicU32::new(0) ).collect();
+    let args = GlobalIds { out: &out, };
+    let mut m = FuncModule::new(&dev);
+    m.launch(grid, &args).unwrap();
+    for (id, v) in out.iter().enumerate() {
+      assert_eq!(v.load(Ordering::Relaxed), id as u32 + 1);
+    }
+
+    // it was codegen-ed by the server, not us:
+    assert_eq!(server_driver.stats().codegens, 1);
+    assert_eq!(dev.codegen().stats().codegens, 0);
+  }
+}
@@ -0,0 +1,16 @@
+pub use std::sync::Arc;
+
+use crate::*;
+
+lazy_static::lazy_static! {
+  static ref DEV: Arc<HostAccel> = {
+    let ctx = grt_core::context::Context::new()
+      .expect("create context");
+    HostAccel::new(&ctx)
+      .unwrap()
+  };
+}
+
+pub fn device() -> Arc<HostAccel> {
+  DEV.clone()
+}
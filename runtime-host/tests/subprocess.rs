@@ -0,0 +1,81 @@
+//! Subprocess codegen, end to end. The codegen helper is this executable, so
+//! these tests need a `main` which handles helper mode, instead of the
+//! default test harness.
+
+use std::env::{remove_var, set_var, var_os, };
+use std::process::exit;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering, };
+
+use grt_core::Device;
+use grt_core::context::Context;
+
+use geobacter_runtime_host::{Dim3, Error, FuncModule, HostAccel, Kernel, WorkItem, };
+use geobacter_runtime_host::codegen::run_codegen_helper;
+
+/// Set to act like a program which doesn't call `run_codegen_helper`.
+const UNHANDLED_ENV: &'static str = "GEOBACTER_TEST_UNHANDLED_HELPER";
+
+struct GlobalIds<'a> {
+  out: &'a [AtomicU32],
+}
+impl<'a> Kernel for GlobalIds<'a> {
+  const WORKGROUP: Dim3 = Dim3::new(4, 1, 1);
+  fn kernel(&self, item: &WorkItem) {
+    let id = item.global_linear_id();
+    self.out[id].store(id as u32 + 1, Ordering::Relaxed);
+  }
+}
+
+/// A new context each time, so nothing is cached in memory.
+fn device() -> Arc<HostAccel> {
+  let ctx = Context::new().expect("create context");
+  let dev = HostAccel::new(&ctx).unwrap();
+  dev.codegen().set_disk_cache(None).unwrap();
+  dev.codegen().set_subprocess_codegen(true);
+  dev
+}
+fn launch(dev: &Arc<HostAccel>) -> Result<Vec<u32>, Error> {
+  let grid = Dim3::new(8, 1, 1);
+  let out: Vec<_> = (0..grid.len()).map(|_| AtomicU32::new(0) ).collect();
+  FuncModule::new(dev).launch(grid, &GlobalIds { out: &out, })?;
+  Ok(out.iter().map(|v| v.load(Ordering::Relaxed) ).collect())
+}
+
+fn helper_codegen() {
+  let out = launch(&device()).unwrap();
+  assert_eq!(out, (1..=8).collect::<Vec<_>>());
+}
+fn helper_mode_not_handled() {
+  set_var(UNHANDLED_ENV, "1");
+  let err = launch(&device()).unwrap_err();
+  remove_var(UNHANDLED_ENV);
+
+  match err {
+    Error::CodegenSubprocess(ref msg) => {
+      assert!(msg.contains("didn't handle helper mode"), "{}", msg);
+    },
+    err => panic!("unexpected error: {}", err),
+  }
+}
+
+fn main() {
+  if var_os(UNHANDLED_ENV).is_none() {
+    run_codegen_helper();
+  } else if var_os("GEOBACTER_CODEGEN_HELPER").is_some() {
+    // Go on to codegen like the program normally would. This must fail
+    // instead of starting another helper.
+    match launch(&device()) {
+      Ok(_) => exit(0),
+      Err(err) => {
+        eprintln!("{}", err);
+        exit(1);
+      },
+    }
+  }
+
+  helper_codegen();
+  println!("helper_codegen ... ok");
+  helper_mode_not_handled();
+  println!("helper_mode_not_handled ... ok");
+}
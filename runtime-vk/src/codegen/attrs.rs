@@ -1,26 +1,128 @@
 
+use grt_core::{AcceleratorTargetDesc, PlatformTargetDesc, };
 use grt_core::codegen::attrs::*;
 
 use rustc_ast::ast::MetaItem;
 use rustc_middle::ty::TyCtxt;
 use rustc_span::symbol::Symbol;
 
+use crate::VkTargetDesc;
+
 #[derive(Clone, Debug, Hash, Eq, PartialEq)]
 pub enum Condition {
-  /// XXX make more specific
+  /// `platform = "spirv"`
   Platform,
+  /// `capability = "i64"`: a SPIR-V capability enabled for the device, using
+  /// the target feature names, eg `i8`, `f16`, `i64-atomics`, or
+  /// `variable-pointers`.
+  Capability(String),
+  /// `extension = "VK_KHR_shader_float16_int8"`: a device extension enabled
+  /// on the device.
+  Extension(String),
 }
 impl ConditionItem for Condition {
   fn parse_name_value(tcx: TyCtxt, item: &MetaItem) -> Option<Self> {
-    if item.has_name(Symbol::intern("platform")) &&
-      item.value_str().unwrap().as_str() == "spirv" {
-      return Some(Condition::Platform);
+    let value_str = || {
+      let v = item.value_str().map(|v| v.as_str().to_string() );
+      if v.is_none() {
+        tcx.sess.span_err(item.span, "expected a string literal");
+      }
+      v
+    };
+
+    if item.has_name(Symbol::intern("platform")) {
+      return match value_str() {
+        Some(ref platform) if platform == "spirv" => Some(Condition::Platform),
+        // some other platform.
+        _ => None,
+      };
+    } else if item.has_name(Symbol::intern("capability")) {
+      return value_str().map(Condition::Capability);
+    } else if item.has_name(Symbol::intern("extension")) {
+      return value_str().map(Condition::Extension);
     }
-    let msg = format!("unknown attr key `{}`; (no keys currently)",
+    let msg = format!("unknown attr key `{}`; expected one of `platform`, \
+                       `capability`, or `extension`",
                       item.name_or_empty());
     tcx.sess.span_err(item.span, &msg);
 
 
     None
   }
+
+  fn eval(&self, target_desc: &AcceleratorTargetDesc, root_conditions: &[Self]) -> bool {
+    match self {
+      Condition::Platform => root_conditions.contains(self),
+      Condition::Capability(cap) => {
+        has_capability(&target_desc.target.options.features, cap)
+      },
+      Condition::Extension(ext) => {
+        VkTargetDesc::downcast_ref(&*target_desc.platform)
+          .map(|desc| desc.extensions.contains(ext) )
+          .unwrap_or_default()
+      },
+    }
+  }
+}
+
+fn has_capability(features: &str, cap: &str) -> bool {
+  features.split(',')
+    .any(|f| f.starts_with('+') && &f[1..] == cap )
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use serde::Serialize;
+
+  fn target_desc(features: &str, extensions: &[&str]) -> AcceleratorTargetDesc {
+    let mut desc = AcceleratorTargetDesc::new(VkTargetDesc {
+      extensions: extensions.iter().map(|ext| ext.to_string() ).collect(),
+    });
+    desc.target.options.features = features.into();
+    desc
+  }
+
+  #[test]
+  fn capability() {
+    let desc = target_desc("+shader,+i8,-i64,+variable-pointers", &[]);
+    let cap = |cap: &str| Condition::Capability(cap.into()).eval(&desc, &[]);
+    assert!(cap("shader"));
+    assert!(cap("i8"));
+    assert!(cap("variable-pointers"));
+    // disabled, missing, or only a prefix of an enabled capability:
+    assert!(!cap("i64"));
+    assert!(!cap("f16"));
+    assert!(!cap("i"));
+    assert!(!cap("variable-pointers-storage-buffer"));
+  }
+
+  #[test]
+  fn extension() {
+    let desc = target_desc("+shader", &["VK_KHR_shader_float16_int8"]);
+    let ext = |ext: &str| Condition::Extension(ext.into()).eval(&desc, &[]);
+    assert!(ext("VK_KHR_shader_float16_int8"));
+    assert!(!ext("VK_KHR_variable_pointers"));
+    assert!(!ext("VK_KHR_shader_float16"));
+  }
+
+  #[derive(Debug, Eq, PartialEq, Hash, Serialize)]
+  struct OtherTargetDesc;
+  impl PlatformTargetDesc for OtherTargetDesc {
+    fn as_any_hash(&self) -> &dyn any_key::AnyHash { self }
+  }
+
+  #[test]
+  fn extension_of_other_platform() {
+    let desc = AcceleratorTargetDesc::new(OtherTargetDesc);
+    let cond = Condition::Extension("VK_KHR_shader_float16_int8".into());
+    assert!(!cond.eval(&desc, &[]));
+  }
+
+  #[test]
+  fn platform() {
+    let desc = target_desc("+shader", &[]);
+    assert!(Condition::Platform.eval(&desc, &[Condition::Platform]));
+    assert!(!Condition::Platform.eval(&desc, &[]));
+  }
 }
@@ -1,4 +1,3 @@
-use std::env::var_os;
 use std::fs::File;
 use std::geobacter::platform::{Platform, spirv};
 use std::io::{Write, Read};
@@ -102,6 +101,7 @@ impl PlatformCodegen for VkPlatformCodegen {
   }
   fn post_codegen(&self,
                   target_desc: &Arc<AcceleratorTargetDesc>,
+                  options: &CodegenOptions,
                   tdir: &Path,
                   codegen: &mut PCodegenResults<Self>)
     -> Result<(), Error>
@@ -121,19 +121,7 @@ impl PlatformCodegen for VkPlatformCodegen {
         out.write_all(&bc)?;
       }
 
-      // be helpful if this var isn't set:
-      if var_os("RUST_BUILD_ROOT").is_none() &&
-        var_os("LLVM_BUILD").is_none() {
-        println!("Due to some required LLVM patches, I need a build of \
-                 Geobacter's LLVM");
-        println!("You shouldn't need to build this separately; it should \
-                 have been built with Rust");
-        println!("Set either LLVM_BUILD or RUST_BUILD_ROOT \
-                 (RUST_BUILD_ROOT takes priority)");
-        println!("XXX Temporary");
-      }
-
-      let llvm = LlvmBuildRoot::default();
+      let llvm = LlvmBuildRoot::find()?;
       let llc = llvm.llc();
       let llc_cmd = || {
         let mut cmd = Command::new(&llc);
@@ -141,7 +129,7 @@ impl PlatformCodegen for VkPlatformCodegen {
           .arg(&linked_bc)
           .arg(format!("-mcpu={}", target_desc.target.options.cpu))
           .arg(format!("-mattr={}", target_desc.target.options.features))
-          .arg("-O3");
+          .arg(options.opt_level.llc_arg());
         cmd
       };
 
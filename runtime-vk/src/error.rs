@@ -3,6 +3,9 @@ use std::error::Error as StdError;
 use std::fmt;
 use std::geobacter::kernel::KernelInstanceRef;
 use std::io::Error as IoError;
+use std::num::NonZeroU32;
+
+use grt_core::codegen::diagnostics::Diagnostics;
 
 #[derive(Debug)]
 #[non_exhaustive]
@@ -13,16 +16,52 @@ pub enum Error {
   Cmd(String),
   ConvertKernelInstance(KernelInstanceRef<'static>),
   ContextDead,
-  Codegen,
-  Linking,
+  /// The accelerator was retired from its context.
+  AcceleratorRetired(grt_core::AcceleratorId),
+  Codegen(Diagnostics),
+  Linking(Diagnostics),
   CodegenInitRoot(Box<Error>),
   CodegenInitConditions(Box<Error>),
   CodegenPreCodegen(Box<Error>),
   CodegenPostCodegen(Box<Error>),
+  KernelArgLayoutMismatch(Box<grt_core::codegen::host::LayoutMismatch>),
+  /// The layout of a kernel argument type couldn't be computed.
+  KernelArgLayout(String),
+  UnknownSpecParam(String),
+  SpecParam(grt_core::codegen::SpecParamError),
+  /// The codegen helper process failed; see
+  /// `CodegenDriver::set_subprocess_codegen`.
+  CodegenSubprocess(String),
+  /// The codegen server couldn't be reached, or failed; see
+  /// `CodegenDriver::set_codegen_server`.
+  CodegenServer(String),
   MissingSpirVObject,
   OutOfHostMemory,
   OutOfDeviceMemory,
   MissingRequiredFeature,
+  MissingComputeEntry,
+  /// A launcher's workgroup size doesn't match the `LocalSize` its kernel
+  /// was compiled with.
+  WorkgroupSizeMismatch {
+    declared: Option<(NonZeroU32, NonZeroU32, NonZeroU32)>,
+    launch: (NonZeroU32, NonZeroU32, NonZeroU32),
+  },
+  ZeroGridLaunchAxis,
+}
+impl Error {
+  /// The rustc/LLVM diagnostics emitted by a failed codegen, if this is a
+  /// codegen error.
+  pub fn diagnostics(&self) -> Option<&Diagnostics> {
+    match self {
+      Error::Codegen(diagnostics) |
+      Error::Linking(diagnostics) => Some(diagnostics),
+      Error::CodegenInitConditions(inner) |
+      Error::CodegenInitRoot(inner) |
+      Error::CodegenPostCodegen(inner) |
+      Error::CodegenPreCodegen(inner) => inner.diagnostics(),
+      _ => None,
+    }
+  }
 }
 impl StdError for Error {
   fn source(&self) -> Option<&(dyn StdError + 'static)> {
@@ -33,6 +72,8 @@ impl StdError for Error {
       Error::CodegenInitRoot(inner) |
       Error::CodegenPostCodegen(inner) |
       Error::CodegenPreCodegen(inner) => Some(inner),
+      Error::KernelArgLayoutMismatch(inner) => Some(&**inner),
+      Error::SpecParam(inner) => Some(inner),
       _ => None,
     }
   }
@@ -61,13 +102,20 @@ impl From<grt_core::codegen::error::Error<Error>> for Error {
       Io(_, err) => Error::Io(err),
       LoadMetadata(err) => Error::LoadRustcMetadata(err),
       ConvertKernelInstance(ki) => Error::ConvertKernelInstance(ki),
-      Codegen => Error::Codegen,
-      Linking => Error::Linking,
+      Codegen(diagnostics) => Error::Codegen(diagnostics),
+      Linking(diagnostics) => Error::Linking(diagnostics),
       InitRoot(inner) => Error::CodegenInitRoot(Box::new(inner)),
       InitConditions(inner) => Error::CodegenInitConditions(Box::new(inner)),
       PreCodegen(inner) => Error::CodegenPreCodegen(Box::new(inner)),
       PostCodegen(inner) => Error::CodegenPostCodegen(Box::new(inner)),
+      LayoutMismatch(inner) => Error::KernelArgLayoutMismatch(inner),
+      Layout(msg) => Error::KernelArgLayout(msg),
+      UnknownSpecParam(param) => Error::UnknownSpecParam(param),
+      SpecParam(inner) => Error::SpecParam(inner),
+      Subprocess(msg) => Error::CodegenSubprocess(msg),
+      Server(msg) => Error::CodegenServer(msg),
       ContextDead => Error::ContextDead,
+      AcceleratorRetired(id) => Error::AcceleratorRetired(id),
     }
   }
 }
@@ -85,6 +133,32 @@ impl From<Box<dyn StdError + Send + Sync + 'static>> for Error {
 
 impl fmt::Display for Error {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    write!(f, "{:?}", self)
+    match self {
+      Error::Codegen(diagnostics) => {
+        write!(f, "codegen failed:\n{}", diagnostics)
+      },
+      Error::Linking(diagnostics) => {
+        write!(f, "linking failed:\n{}", diagnostics)
+      },
+      Error::UnknownSpecParam(param) => {
+        write!(f, "spec param `{}` doesn't match any function in this program", param)
+      },
+      Error::SpecParam(inner) => fmt::Display::fmt(inner, f),
+      Error::CodegenSubprocess(msg) => write!(f, "subprocess codegen failed: {}", msg),
+      Error::CodegenServer(msg) => write!(f, "codegen server failed: {}", msg),
+      Error::WorkgroupSizeMismatch { declared: Some(declared), launch, } => {
+        write!(f, "launch workgroup size {:?} doesn't match the kernel's {:?}",
+               launch, declared)
+      },
+      Error::WorkgroupSizeMismatch { declared: None, launch, } => {
+        write!(f, "launch workgroup size {:?} given for a kernel without a declared size",
+               launch)
+      },
+      Error::KernelArgLayoutMismatch(inner) => fmt::Display::fmt(inner, f),
+      Error::KernelArgLayout(msg) => {
+        write!(f, "kernel argument layout check failed: {}", msg)
+      },
+      _ => write!(f, "{:?}", self),
+    }
   }
 }
@@ -0,0 +1,117 @@
+//! Compute kernel dispatch through `grt_core::launch`. Each launch records
+//! and submits its own command buffer, so this is only intended for simple
+//! uses; build command buffers directly to batch dispatches.
+
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::time::Duration;
+
+use grt_core::{Accelerator, AcceleratorId, };
+use grt_core::launch::*;
+
+use vk::command_buffer::{AutoCommandBufferBuilder, CommandBuffer, };
+use vk::descriptor::DescriptorSet;
+use vk::descriptor::pipeline_layout::PipelineLayout;
+use vk::device::Queue;
+use vk::pipeline::ComputePipeline;
+use vk::sync::{FenceSignalFuture, FlushError, GpuFuture, };
+
+use crate::{Error, VkAccel, };
+use crate::module::{SpirvModule, StaticPipelineLayoutDesc, };
+
+/// The arguments of a launch: the descriptor sets to bind, in set order.
+pub type VkLaunchArgs = Vec<Arc<dyn DescriptorSet + Send + Sync>>;
+
+type Pipeline = ComputePipeline<PipelineLayout<StaticPipelineLayoutDesc>>;
+
+/// Dispatches a compiled compute kernel on `queue`. The launch grid is
+/// rounded up to a multiple of the workgroup size, so the kernel must
+/// bounds check its invocation ids itself.
+pub struct VkLauncher {
+  accel: Arc<VkAccel>,
+  queue: Arc<Queue>,
+  pipeline: Arc<Pipeline>,
+  workgroup_size: (NonZeroU32, NonZeroU32, NonZeroU32),
+}
+impl VkLauncher {
+  /// `workgroup_size` must be the size `module` was compiled with.
+  pub fn new(accel: &Arc<VkAccel>, module: &SpirvModule, queue: Arc<Queue>,
+             workgroup_size: (NonZeroU32, NonZeroU32, NonZeroU32))
+    -> Result<Self, Error>
+  {
+    let entry = module.compute_entry_ref()
+      .ok_or(Error::MissingComputeEntry)?;
+    let declared = entry.workgroup_size();
+    if declared != Some(workgroup_size) {
+      return Err(Error::WorkgroupSizeMismatch {
+        declared,
+        launch: workgroup_size,
+      });
+    }
+    let pipeline = ComputePipeline::new(accel.device().clone(),
+                                        &entry, &())
+      .map_err(|err| Error::Generic(Box::new(err)) )?;
+
+    Ok(VkLauncher {
+      accel: accel.clone(),
+      queue,
+      pipeline: Arc::new(pipeline),
+      workgroup_size,
+    })
+  }
+
+  #[inline(always)]
+  pub fn queue(&self) -> &Arc<Queue> { &self.queue }
+  #[inline(always)]
+  pub fn workgroup_size(&self) -> (NonZeroU32, NonZeroU32, NonZeroU32) {
+    self.workgroup_size
+  }
+
+  fn workgroups(&self, grid: LaunchGrid) -> [u32; 3] {
+    let (x, y, z) = self.workgroup_size;
+    let ceil = |l: u32, r: NonZeroU32| {
+      l / r.get() + (l % r.get() != 0) as u32
+    };
+    [ceil(grid.x, x), ceil(grid.y, y), ceil(grid.z, z), ]
+  }
+}
+impl KernelLauncher<VkLaunchArgs> for VkLauncher {
+  fn accel_id(&self) -> AcceleratorId { self.accel.id() }
+
+  /// The descriptor sets keep their resources alive until the launch has
+  /// finished, but the host must not access them until then.
+  unsafe fn launch(&mut self, grid: LaunchGrid, args: VkLaunchArgs)
+    -> Result<Completion, LaunchError>
+  {
+    if grid.is_zero() {
+      return Err(Error::ZeroGridLaunchAxis.into());
+    }
+
+    let device = self.accel.device().clone();
+    let mut cmd_buf = AutoCommandBufferBuilder::new(device, self.queue.family())?;
+    cmd_buf.dispatch(self.workgroups(grid), self.pipeline.clone(),
+                     args, ())?;
+    let cmd_buf = cmd_buf.build()?;
+
+    let fence = cmd_buf.execute(self.queue.clone())?
+      .then_signal_fence_and_flush()?;
+    Ok(Completion::new(FenceCompletion(fence)))
+  }
+}
+
+struct FenceCompletion<F>(FenceSignalFuture<F>)
+  where F: GpuFuture;
+impl<F> LaunchCompletion for FenceCompletion<F>
+  where F: GpuFuture + Send + Sync + 'static,
+{
+  fn is_complete(&self) -> bool {
+    match self.0.wait(Some(Duration::from_secs(0))) {
+      Err(FlushError::Timeout) => false,
+      _ => true,
+    }
+  }
+  fn wait(&self) -> Result<(), LaunchError> {
+    self.0.wait(None)?;
+    Ok(())
+  }
+}
@@ -14,7 +14,7 @@ extern crate rustc_span;
 extern crate rustc_target;
 
 use std::any::Any;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, };
 use std::error::Error as StdError;
 use std::ffi::CString;
 use std::fmt;
@@ -39,6 +39,7 @@ pub use crate::error::Error;
 
 pub mod codegen;
 pub mod error;
+pub mod launch;
 pub mod module;
 
 mod serde_utils;
@@ -62,7 +63,13 @@ impl VkAccel {
       return Err(Error::MissingRequiredFeature);
     }
 
-    let target_desc = AcceleratorTargetDesc::new(VkTargetDesc);
+    let extensions = vk::device::RawDeviceExtensions::from(dev.loaded_extensions())
+      .iter()
+      .map(|ext| ext.to_string_lossy().into_owned() )
+      .collect();
+    let target_desc = AcceleratorTargetDesc::new(VkTargetDesc {
+      extensions,
+    });
     let mut out = VkAccel {
       id,
       ctx: ctx.clone(),
@@ -183,6 +190,7 @@ impl Device for VkAccel {
         .expect("unexpected null char"),
       spirv,
       exe_model: entry.platform.exe_model,
+      workgroup_size: entry.platform.workgroup_size,
       shader: entry.platform.interface,
     }));
 
@@ -222,6 +230,7 @@ impl VkAccel {
     let desc = KernelDesc {
       instance,
       spec_params: Default::default(),
+      options: None,
       platform_desc: desc,
     };
 
@@ -252,6 +261,7 @@ impl VkAccel {
     let desc = KernelDesc {
       instance,
       spec_params: Default::default(),
+      options: None,
       platform_desc: desc,
     };
 
@@ -270,6 +280,7 @@ impl VkAccel {
     let desc = KernelDesc {
       instance,
       spec_params: Default::default(),
+      options: None,
       platform_desc: desc,
     };
 
@@ -449,7 +460,11 @@ impl VkAccel {
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Hash)]
-pub struct VkTargetDesc;
+pub struct VkTargetDesc {
+  /// The names of the device extensions enabled on the device, eg
+  /// `VK_KHR_shader_float16_int8`.
+  pub extensions: BTreeSet<String>,
+}
 
 impl PlatformTargetDesc for VkTargetDesc {
   fn as_any_hash(&self) -> &dyn any_key::AnyHash {
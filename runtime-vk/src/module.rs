@@ -6,6 +6,7 @@ use std::geobacter::platform::spirv::ExeModel;
 use std::geobacter::spirv::pipeline_layout::*;
 use std::geobacter::spirv::shader_interface::*;
 use std::iter::Iterator;
+use std::num::NonZeroU32;
 use std::ops::Range;
 use std::slice;
 use std::sync::Arc;
@@ -26,6 +27,8 @@ pub struct SpirvEntryPoint {
   pub(crate) name: CString,
   pub(crate) spirv: vk::pipeline::shader::ShaderModule,
   pub(crate) exe_model: ExeModel,
+  /// The `LocalSize` execution mode, if one was declared.
+  pub(crate) workgroup_size: Option<(NonZeroU32, NonZeroU32, NonZeroU32)>,
   /// If Some(..), this function is a shader; if None, it is a kernel.
   pub(crate) shader: Option<CodegenShaderInterface>,
 }
@@ -204,6 +207,14 @@ pub struct SpirvGraphicsShaderRef<'a> {
   entry: u16,
 }
 
+impl<'a> SpirvComputeKernelRef<'a> {
+  /// The workgroup size the kernel was compiled with, if any.
+  #[inline(always)]
+  pub fn workgroup_size(&self) -> Option<(NonZeroU32, NonZeroU32, NonZeroU32)> {
+    self.module.entries[self.entry as usize].workgroup_size
+  }
+}
+
 impl<'a> SpirvGraphicsShaderRef<'a> {
   #[inline(always)]
   fn entry(&self) -> &SpirvEntryPoint {